@@ -0,0 +1,51 @@
+/*
+ * C interface of raw-to-img, available when the crate is built with the ffi feature:
+ *
+ *     cargo rustc --release --lib --features ffi --crate-type cdylib
+ *     cargo rustc --release --lib --features ffi --crate-type staticlib
+ */
+
+#ifndef RAW_TO_IMG_H
+#define RAW_TO_IMG_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct {
+    /* 0 = JPEG, 1 = PNG, 2 = TIFF, 3 = QOI */
+    uint32_t encode_type;
+    uint8_t jpeg_quality;
+    /* number of files converted in parallel (1 converts them one after another) */
+    uint32_t threads;
+    /* rename outputs that already exist instead of skipping their inputs */
+    bool rename_existing;
+} raw_to_img_options;
+
+/* called after every finished file with the number of finished files, the number of files found so far,
+ * the input file and whether it failed */
+typedef void (*raw_to_img_progress)(void *user_data, size_t done, size_t total, const char *file, bool failed);
+
+/* fills options with the defaults of the command line tool */
+void raw_to_img_options_default(raw_to_img_options *options);
+
+/* converts the file or directory input into output
+ *
+ * Returns the number of files that failed (0 if all succeeded) or -1 if the conversion could not be started,
+ * in which case raw_to_img_last_error() describes why. options and progress may be NULL. */
+int raw_to_img_convert(const char *input, const char *output, const raw_to_img_options *options,
+                       raw_to_img_progress progress, void *user_data);
+
+/* message of the last error of raw_to_img_convert() on this thread, NULL if there was none
+ * (valid until the next call of raw_to_img_convert() on the same thread) */
+const char *raw_to_img_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,78 @@
+use crate::*;
+
+
+/// Manual corrections of a single image, usually given in a sidecar next to its raw (see `OverrideResolver`)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Adjustments {
+    /// Exposure compensation in EV
+    pub exposure: f32,
+    /// Multipliers of the red, green and blue channel in linear light
+    pub white_balance: Option<[f32; 3]>,
+    /// Part of the image to keep, in the orientation before `rotation`
+    pub crop: Option<Crop>,
+    /// Quarter turns applied after cropping
+    pub rotation: pixelops::Rotation,
+}
+
+/// Part of an image to keep
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Crop {
+    /// Pixels removed from the left, top, right and bottom edge
+    Margins([usize; 4]),
+    /// Rectangle to keep as `x`, `y`, `width` and `height` in pixels
+    Rect([usize; 4]),
+    /// Left, top, right and bottom edge of the part to keep, relative to the width and height (0 to 1)
+    Relative([f32; 4]),
+}
+
+
+impl Adjustments {
+    /// Whether these adjustments change the image at all
+    pub fn is_identity(&self) -> bool {
+        self.exposure == 0.0 && self.white_balance.is_none() && self.crop.is_none()
+            && self.rotation == pixelops::Rotation::None
+    }
+
+    /// Applies the exposure compensation and the white balance
+    pub fn apply(&self, image: &mut imagepipe::SRGBImage) {
+        if self.exposure == 0.0 && self.white_balance.is_none() {
+            return;
+        }
+        let gain = 2f32.powf(self.exposure);
+        let multipliers = self.white_balance.unwrap_or([1.0; 3]);
+        let luts: [[u8; 256]; 3] = std::array::from_fn(|c| pixelops::linear_curve_lut(|v| v * gain * multipliers[c]));
+        for pixel in image.data.chunks_exact_mut(3) {
+            for (value, lut) in pixel.iter_mut().zip(&luts) {
+                *value = lut[*value as usize];
+            }
+        }
+    }
+
+    /// Crops and rotates the image
+    pub fn reframe(&self, image: &mut imagepipe::SRGBImage) {
+        if let Some(crop) = self.crop {
+            let margins = crop.margins(image.width, image.height);
+            let (data, width, height) = pixelops::crop_rgb8(&image.data, image.width, image.height, margins);
+            *image = imagepipe::SRGBImage { data, width, height };
+        }
+        if self.rotation != pixelops::Rotation::None {
+            let (data, width, height) = pixelops::rotate_rgb8(&image.data, image.width, image.height, self.rotation);
+            *image = imagepipe::SRGBImage { data, width, height };
+        }
+    }
+}
+
+impl Crop {
+    /// Pixels to remove from the left, top, right and bottom edge of an image of the given size
+    pub fn margins(&self, width: usize, height: usize) -> [usize; 4] {
+        match *self {
+            Crop::Margins(margins) => margins,
+            Crop::Rect([x, y, w, h]) => [x, y, width.saturating_sub(x + w), height.saturating_sub(y + h)],
+            Crop::Relative([left, top, right, bottom]) => {
+                let edge = |v: f32, size: usize| (v.clamp(0.0, 1.0) * size as f32).round() as usize;
+                [edge(left, width), edge(top, height),
+                 width.saturating_sub(edge(right, width)), height.saturating_sub(edge(bottom, height))]
+            },
+        }
+    }
+}
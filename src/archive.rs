@@ -0,0 +1,249 @@
+use crate::*;
+
+#[cfg(feature = "archive")]
+use std::collections::HashSet;
+#[cfg(feature = "archive")]
+use std::sync::Mutex;
+
+
+/// Extensions of files that are compressed already and stored as they are in zip archives
+#[cfg(feature = "archive")]
+const COMPRESSED_EXTENSIONS: [&str; 4] = [
+    "jpg", "jpeg", "png", "qoi",
+];
+
+/// Most files extracted from a single archive
+#[cfg(feature = "archive")]
+const MAX_ENTRIES: usize = 1_000_000;
+
+/// Archive formats accepted as input
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+}
+
+/// Archive that all outputs are written into (`--output-archive`)
+///
+/// Outputs are added as soon as their jobs finish, so the archive grows while the run goes on.
+/// Zip archives, plain tar archives and zstd compressed tar archives (`.tar.zst`) are supported.
+#[cfg(feature = "archive")]
+pub struct ArchiveStorage {
+    path: PathBuf,
+    /// `None` once the archive is finished
+    writer: Mutex<Option<ArchiveWriter>>,
+    /// Keys of all entries written so far
+    written: Mutex<HashSet<String>>,
+}
+
+/// Space and number of entries an archive may still take up while it is extracted
+///
+/// Archives (crafted or corrupt) can claim far more data than they contain, so entries are
+/// checked against it both by their declared size and while they are written.
+#[cfg(feature = "archive")]
+#[derive(Copy, Clone)]
+struct ExtractBudget {
+    bytes: u64,
+    entries: usize,
+}
+
+#[cfg(feature = "archive")]
+enum ArchiveWriter {
+    Zip(zip::ZipWriter<io::BufWriter<fs::File>>),
+    Tar(tar::Builder<io::BufWriter<fs::File>>),
+    TarZst(tar::Builder<zstd::Encoder<'static, io::BufWriter<fs::File>>>),
+}
+
+
+/// Format of the archive at `path` judging by its extension, `None` if it is no archive
+pub fn archive_format(path: &Path) -> Option<ArchiveFormat> {
+    if has_extension(path, &["zip"]) {
+        Some(ArchiveFormat::Zip)
+    } else if has_extension(path, &["tar"]) {
+        Some(ArchiveFormat::Tar)
+    } else {
+        None
+    }
+}
+
+/// Extracts the files of `archive` into a new directory below `dir` and returns that directory
+///
+/// The directory is named after the archive and mirrors its internal structure, so converting it
+/// yields outputs laid out like the archive. Entries whose paths would escape the directory are skipped.
+/// Extracting fails if the files do not fit into the free space of `dir` or there are too many of them.
+#[cfg(feature = "archive")]
+pub fn extract_archive(archive: &Path, dir: &Path) -> Result<PathBuf, String> {
+    let format = archive_format(archive).ok_or_else(|| format!("{:?} is not a zip or tar archive", archive))?;
+    let target = dir.join(archive.file_stem().unwrap_or_default());
+    fs::create_dir_all(&target).map_err(|e| format!("unable to create {:?} ({})", target, e))?;
+
+    let start = Instant::now();
+    println!("Extracting {:?}", archive);
+    let file = fs::File::open(archive).map_err(|e| format!("unable to open {:?} ({})", archive, e))?;
+    let mut budget = ExtractBudget { bytes: free_space(&target).unwrap_or(u64::MAX), entries: MAX_ENTRIES };
+    let extracted = match format {
+        ArchiveFormat::Zip => extract_zip(file, &target, &mut budget),
+        ArchiveFormat::Tar => extract_tar(file, &target, &mut budget),
+    }.map_err(|e| format!("unable to extract {:?} ({})", archive, e))?;
+
+    println!("Extracted {} files from {:?} in {}", extracted, archive, fmt_duration(&start.elapsed()));
+    Ok(target)
+}
+
+#[cfg(not(feature = "archive"))]
+pub fn extract_archive(archive: &Path, _dir: &Path) -> Result<PathBuf, String> {
+    Err(format!("unable to extract {:?} (this build does not support archives, enable the archive feature)", archive))
+}
+
+#[cfg(feature = "archive")]
+impl ExtractBudget {
+    /// Takes an entry of `size` bytes from the budget
+    fn claim(&mut self, size: u64) -> Result<(), String> {
+        if self.entries == 0 {
+            return Err(format!("more than {} files", MAX_ENTRIES));
+        }
+        if size > self.bytes {
+            return Err(format!("the files do not fit into the free space ({} left)", fmt_bytes(self.bytes)));
+        }
+        self.entries -= 1;
+        self.bytes -= size;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "archive")]
+fn extract_zip(file: fs::File, target: &Path, budget: &mut ExtractBudget) -> Result<usize, String> {
+    let mut zip = zip::ZipArchive::new(io::BufReader::new(file)).map_err(|e| e.to_string())?;
+
+    // the central directory lists all sizes, so archives that can not fit fail before anything is written
+    let mut declared = *budget;
+    for i in 0..zip.len() {
+        let entry = zip.by_index_raw(i).map_err(|e| e.to_string())?;
+        if !entry.is_dir() {
+            declared.claim(entry.size())?;
+        }
+    }
+
+    let mut extracted = 0;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(|e| e.to_string())?;
+        if entry.is_dir() {
+            continue;
+        }
+        let Some(name) = entry.enclosed_name() else {
+            println!("Skipping {:?} (path leaves the archive)", entry.name());
+            continue;
+        };
+
+        let path = target.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let size = entry.size();
+        budget.claim(size)?;
+        let mut output = fs::File::create(&path).map_err(|e| format!("{:?}: {}", path, e))?;
+        // the declared size is not enforced by the decompressor
+        let copied = io::copy(&mut io::Read::take(&mut entry, size + 1), &mut output)
+            .map_err(|e| format!("{:?}: {}", path, e))?;
+        if copied > size {
+            return Err(format!("{:?} is larger than declared in the archive", entry.name()));
+        }
+        extracted += 1;
+    }
+    Ok(extracted)
+}
+
+#[cfg(feature = "archive")]
+fn extract_tar(file: fs::File, target: &Path, budget: &mut ExtractBudget) -> Result<usize, String> {
+    let mut tar = tar::Archive::new(io::BufReader::new(file));
+    let mut extracted = 0;
+    for entry in tar.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        // tar entries contain exactly as much data as their header says
+        budget.claim(entry.size())?;
+        // unpack_in() creates missing parent directories and refuses paths outside of target
+        if entry.unpack_in(target).map_err(|e| e.to_string())? {
+            extracted += 1;
+        } else {
+            println!("Skipping {:?} (path leaves the archive)", entry.path().unwrap_or_default());
+        }
+    }
+    Ok(extracted)
+}
+
+#[cfg(feature = "archive")]
+impl ArchiveStorage {
+    /// Creates the archive `path`, choosing the format by its extension
+    pub fn create(path: &Path) -> Result<ArchiveStorage, String> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+        if path.exists() {
+            return Err(format!("Output archive {:?} already exists", path));
+        }
+        let create = || fs::File::create(path)
+            .map(io::BufWriter::new)
+            .map_err(|e| format!("unable to create {:?} ({})", path, e));
+
+        let writer = if name.ends_with(".zip") {
+            ArchiveWriter::Zip(zip::ZipWriter::new(create()?))
+        } else if name.ends_with(".tar") {
+            ArchiveWriter::Tar(tar::Builder::new(create()?))
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            let encoder = zstd::Encoder::new(create()?, 0).map_err(|e| e.to_string())?;
+            ArchiveWriter::TarZst(tar::Builder::new(encoder))
+        } else {
+            return Err(format!("unsupported archive {:?} (expected .zip, .tar or .tar.zst)", path));
+        };
+        Ok(ArchiveStorage { path: path.to_path_buf(), writer: Mutex::new(Some(writer)), written: Mutex::default() })
+    }
+}
+
+#[cfg(feature = "archive")]
+impl Storage for ArchiveStorage {
+    fn exists(&self, key: &str) -> Result<bool, String> {
+        Ok(self.written.lock().unwrap().contains(key))
+    }
+
+    fn put(&self, path: &Path, key: &str) -> Result<(), String> {
+        let mut writer = self.writer.lock().unwrap();
+        let error = |e: &dyn std::fmt::Display| format!("unable to add {} ({})", self.describe(key), e);
+        match writer.as_mut().ok_or_else(|| error(&"archive is already finished"))? {
+            ArchiveWriter::Zip(zip) => {
+                let mut file = fs::File::open(path).map_err(|e| error(&e))?;
+                let size = file.metadata().map_err(|e| error(&e))?.len();
+                let method = match has_extension(path, &COMPRESSED_EXTENSIONS) {
+                    true => zip::CompressionMethod::Stored,
+                    false => zip::CompressionMethod::Deflated,
+                };
+                let options = zip::write::SimpleFileOptions::default()
+                    .compression_method(method)
+                    .large_file(size >= u32::MAX as u64);
+                zip.start_file(key, options).map_err(|e| error(&e))?;
+                io::copy(&mut file, zip).map_err(|e| error(&e))?;
+            },
+            ArchiveWriter::Tar(tar) => tar.append_path_with_name(path, key).map_err(|e| error(&e))?,
+            ArchiveWriter::TarZst(tar) => tar.append_path_with_name(path, key).map_err(|e| error(&e))?,
+        }
+        self.written.lock().unwrap().insert(key.to_string());
+        Ok(())
+    }
+
+    fn describe(&self, key: &str) -> String {
+        format!("{}:{}", self.path.display(), key)
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        let error = |e: io::Error| format!("unable to finish {:?} ({})", self.path, e);
+        let mut file = match self.writer.lock().unwrap().take() {
+            Some(ArchiveWriter::Zip(zip)) => zip.finish().map_err(|e| error(e.into()))?,
+            Some(ArchiveWriter::Tar(tar)) => tar.into_inner().map_err(error)?,
+            Some(ArchiveWriter::TarZst(tar)) => tar.into_inner().and_then(|zst| zst.finish()).map_err(error)?,
+            None => return Ok(()),
+        };
+        io::Write::flush(&mut file).map_err(error)?;
+        println!("Wrote {:?}", self.path);
+        Ok(())
+    }
+}
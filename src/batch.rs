@@ -0,0 +1,960 @@
+use crate::*;
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use threadpool::ThreadPool;
+
+
+/// Parameters controlling how the jobs of a batch are scheduled
+#[derive(Clone)]
+pub struct BatchSettings {
+    /// Number of threads to run in parallel (1 runs all jobs on the calling thread)
+    ///
+    /// With multiple threads, these threads only decode raws and handle ignored files,
+    /// while encoding and file transfers happen on separate threads.
+    pub threads: usize,
+    /// Number of threads encoding decoded raws (only used with more than one thread)
+    pub encode_threads: usize,
+    /// Maximum number of decoded images waiting to be encoded
+    pub encode_queue: usize,
+    /// Number of threads copying or moving large files (only used with more than one thread)
+    pub io_threads: usize,
+    /// Number of threads copying or moving small files (only used with more than one thread)
+    pub small_file_threads: usize,
+    /// Files of at most this many bytes are copied or moved by the small-file threads
+    pub small_file_size: u64,
+    /// Files are only started once their size did not change for this long (see `is_settled()`)
+    ///
+    /// Files that are still being written (e.g. by a card reader) are put at the end of the queue
+    /// and checked again later, instead of converting a truncated raw.
+    pub stable_for: Option<time::Duration>,
+    /// Deferred files that did not settle within this time fail instead of keeping the batch from finishing
+    pub max_deferral: time::Duration,
+}
+
+/// Scheduling of a running batch that can be changed from other threads
+///
+/// Clones share their state, so one control can steer several batches (e.g. in watch mode).
+#[derive(Clone, Default)]
+pub struct RunControl {
+    paused: Arc<AtomicBool>,
+    /// Number of decode threads, 0 until a parallel batch starts
+    threads: Arc<AtomicUsize>,
+}
+
+/// Progress events emitted while a batch is running
+pub enum Progress<'a> {
+    Started { total: usize, threads: usize, encode_threads: usize, io_threads: usize, small_file_threads: usize },
+    /// More files were found while the batch was already running (see `Batch::with_stream()`)
+    Discovered { count: usize },
+    /// A file is still being written and was put at the end of the queue (see `BatchSettings::stable_for`)
+    Deferred { file: &'a Path },
+    /// A job was started (handed to the worker threads in parallel runs)
+    JobStarted { file: &'a Path },
+    JobFailed { file: &'a Path, error: &'a Error },
+    JobFinished { file: &'a Path, done: usize, total: usize, statistics: &'a Statistics },
+    /// The batch was cancelled, `unprocessed` lists the files that were never started
+    Cancelled { done: usize, total: usize, unprocessed: &'a [PathBuf] },
+}
+
+/// Order in which the files of a batch are started
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum JobOrder {
+    /// By path
+    Name,
+    /// By modification time, oldest first
+    Mtime,
+    /// By file size, smallest first
+    Size,
+    /// Shuffled
+    Random,
+}
+
+/// How outputs are disambiguated when a batch is flattened into a single directory
+#[derive(Clone)]
+pub enum FlattenNames {
+    /// Append `_1`, `_2`, ... to colliding names
+    Suffix,
+    /// Rename colliding outputs according to a template ({stem}, {dir} and {path} are replaced)
+    Template(String),
+}
+
+/// Time to wait for a streamed file before checking on the running jobs again
+const STREAM_POLL_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+/// Files currently being worked on, keyed by the name of the worker thread
+pub type Activity = Arc<Mutex<BTreeMap<String, String>>>;
+
+/// A set of files that are converted from an input tree into an output tree
+pub struct Batch {
+    files: Vec<PathBuf>,
+    /// Files that are still being discovered and added to the batch while it runs
+    incoming: Mutex<Option<Receiver<PathBuf>>>,
+    input_base: PathBuf,
+    output_base: PathBuf,
+    settings: JobSettings,
+    batch_settings: BatchSettings,
+    cancelled: Arc<AtomicBool>,
+    control: RunControl,
+    activity: Activity,
+    /// Subdirectories that individual files are placed in (relative to their regular output directory)
+    routes: HashMap<PathBuf, PathBuf>,
+    /// Replacement file stems for individual output files
+    names: HashMap<PathBuf, OsString>,
+    /// Put all outputs directly into the output base
+    flatten: Option<FlattenNames>,
+    /// Plan outputs as if the output directory was empty (see `for_verification()`)
+    verifying: bool,
+}
+
+/// Jobs of a running batch, those of the known files planned up front and streamed files planned on arrival
+struct Jobs<'b> {
+    batch: &'b Batch,
+    planned: std::vec::IntoIter<(PathBuf, Result<Job, Error>)>,
+    incoming: Option<Receiver<PathBuf>>,
+    overrides: OverrideResolver,
+    /// Reserved output paths (output path -> input file)
+    claimed: HashMap<PathBuf, PathBuf>,
+    /// Files that were still being written when their turn came, in the order they were put back
+    deferred: VecDeque<Deferred>,
+    /// Number of files known so far
+    total: usize,
+}
+
+/// A planned job waiting for its file to be written completely
+struct Deferred {
+    file: PathBuf,
+    job: Result<Job, Error>,
+    /// Last size seen and the time since which it has not changed
+    size: Option<u64>,
+    since: Instant,
+    /// When the file was deferred first
+    deferred: Instant,
+}
+
+enum NextJob {
+    Ready(PathBuf, Result<Job, Error>),
+    /// No new file arrived in time, but more may follow
+    Pending,
+    Done,
+}
+
+/// Outcomes of the jobs of a batch collected so far
+#[derive(Default)]
+struct Results {
+    statistics: Statistics,
+    unprocessed: Vec<PathBuf>,
+    received: usize,
+}
+
+/// Outcome of a job, `None` if it was never started
+type JobResult = (PathBuf, Option<Result<Statistics, Error>>);
+
+/// Sends the result of a dispatched job, or a failure if it is dropped without one (i.e. the job panicked)
+///
+/// The run waits for a result of every dispatched job, so a job that never reports would block it forever.
+struct ResultGuard {
+    file: Option<PathBuf>,
+    tx: Sender<JobResult>,
+    /// Error reported for a job that is dropped in the current stage
+    lost: fn(String) -> Error,
+}
+
+
+impl Default for BatchSettings {
+    fn default() -> BatchSettings {
+        BatchSettings {
+            threads: 1,
+            encode_threads: 2,
+            encode_queue: 4,
+            io_threads: 2,
+            small_file_threads: 4,
+            small_file_size: 1024 * 1024,
+            stable_for: None,
+            max_deferral: time::Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl RunControl {
+    /// Holds back new jobs until resumed, jobs that are already running are finished regularly
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Number of decode threads of the running batch (0 if no parallel batch has started yet)
+    pub fn threads(&self) -> usize {
+        self.threads.load(Ordering::Relaxed)
+    }
+
+    /// Changes the number of decode threads, which only works for batches running with more than one thread
+    pub fn set_threads(&self, threads: usize) -> Result<(), String> {
+        if threads == 0 {
+            return Err(String::from("number of threads must be at least 1"));
+        }
+        match self.threads.load(Ordering::Relaxed) {
+            0 => Err(String::from("the number of threads can only be changed for parallel runs")),
+            _ => { self.threads.store(threads, Ordering::Relaxed); Ok(()) },
+        }
+    }
+}
+
+impl Batch {
+    pub fn new(files: Vec<PathBuf>, input_base: &Path, output_base: &Path,
+               settings: &JobSettings, batch_settings: &BatchSettings) -> Batch {
+        Batch {
+            files,
+            incoming: Mutex::new(None),
+            input_base: input_base.to_path_buf(),
+            output_base: output_base.to_path_buf(),
+            settings: settings.clone(),
+            batch_settings: batch_settings.clone(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            control: RunControl::default(),
+            activity: Activity::default(),
+            routes: HashMap::new(),
+            names: HashMap::new(),
+            flatten: None,
+            verifying: false,
+        }
+    }
+
+    /// Creates a batch containing all files below `input_base`
+    pub fn from_dir(input_base: &Path, output_base: &Path, max_depth: Option<usize>, follow_hidden: bool,
+                    settings: &JobSettings, batch_settings: &BatchSettings) -> Batch {
+        let files = scan_dir(input_base, max_depth, follow_hidden, true);
+        Batch::new(files, input_base, output_base, settings, batch_settings)
+    }
+
+    /// Groups the raws of this batch into bursts and handles them according to `mode`
+    ///
+    /// Returns the batch along with the number of bursts found and frames skipped.
+    pub fn group_bursts(mut self, gap: time::Duration, mode: BurstMode) -> (Batch, usize, usize) {
+        self.finish_scan();
+        let plan = plan_bursts(std::mem::take(&mut self.files), gap, mode, &self.settings);
+        self.files = plan.files;
+        self.routes = plan.routes;
+        (self, plan.bursts, plan.skipped)
+    }
+
+    /// Puts each group of files into its own output subdirectory, named after its first file and `suffix`
+    ///
+    /// With `numbered` set, the files of a group are renamed to the subdirectory name followed by
+    /// their position in the group. Returns the output directories of the groups.
+    pub fn route_groups(&mut self, groups: &[Vec<PathBuf>], suffix: &str, numbered: bool) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        for group in groups {
+            let first = &group[0];
+            let subdir = PathBuf::from(suffixed_stem(first, &format!("_{}", suffix)));
+            if self.flatten.is_some() {
+                dirs.push(self.output_base.join(&subdir));
+            } else if let Some(parent) = switch_base(first, &self.input_base, &self.output_base).ok().as_deref().and_then(Path::parent) {
+                dirs.push(parent.join(&subdir));
+            }
+            for (i, file) in group.iter().enumerate() {
+                self.routes.insert(file.clone(), subdir.clone());
+                if numbered {
+                    let mut name = subdir.clone().into_os_string();
+                    name.push(format!("_{:03}", i + 1));
+                    self.names.insert(file.clone(), name);
+                }
+            }
+        }
+        dirs
+    }
+
+    /// Rejects raws whose sharpness (see `sharpness()`) is below `threshold`
+    ///
+    /// Rejected frames are put into a `rejected` subdirectory or skipped, depending on `action`.
+    /// Files that were already assigned to a group are left alone, as focus stacks deliberately
+    /// contain frames that are mostly out of focus. Returns the number of rejected frames.
+    pub fn reject_blurry(&mut self, threshold: f64, action: BlurryAction) -> usize {
+        self.finish_scan();
+        let blurry: HashSet<PathBuf> = self.files.iter()
+            .filter(|f| !self.is_routed(f) && matches!(file_kind(f, self.settings.detect_by_content), FileKind::Raw))
+            .filter(|f| {
+                let score = sharpness(f, &self.settings);
+                if score < threshold {
+                    println!("Rejecting {:?} (sharpness {:.1})", f, score);
+                }
+                score < threshold
+            })
+            .cloned()
+            .collect();
+
+        match action {
+            BlurryAction::Reject => for file in &blurry {
+                self.routes.insert(file.clone(), PathBuf::from("rejected"));
+            },
+            BlurryAction::Skip => self.files.retain(|f| !blurry.contains(f)),
+        }
+        blurry.len()
+    }
+
+    /// Removes the files that the index lists as processed and unchanged since, returning their number
+    ///
+    /// Files whose outputs were deleted since are processed again.
+    pub fn skip_synced(&mut self, index: &Index) -> usize {
+        self.finish_scan();
+        let before = self.files.len();
+        let exists = |output: &Path| match &self.settings.output_storage {
+            Some(remote) => remote.exists(output),
+            None => output.exists(),
+        };
+        self.files.retain(|f| !f.is_file() || !index.is_synced(f, exists));
+        before - self.files.len()
+    }
+
+    /// Sorts the files by `order`, in descending order if `reverse` is set
+    ///
+    /// Files whose metadata can not be read are sorted as if they were empty and infinitely old.
+    pub fn ordered(mut self, order: JobOrder, reverse: bool) -> Batch {
+        self.finish_scan();
+        match order {
+            JobOrder::Name => self.files.sort(),
+            JobOrder::Mtime => self.files.sort_by_cached_key(|f| f.metadata().and_then(|m| m.modified()).ok()),
+            JobOrder::Size => self.files.sort_by_cached_key(|f| f.metadata().map(|m| m.len()).unwrap_or(0)),
+            JobOrder::Random => {
+                let state = std::collections::hash_map::RandomState::new();
+                self.files.sort_by_cached_key(|f| state.hash_one(f));
+            },
+        }
+        if reverse {
+            self.files.reverse();
+        }
+        self
+    }
+
+    /// Splits the batch into batches of at most `size` files that are run one after another
+    ///
+    /// The chunks share the flags, activity, groups and names of this batch.
+    pub fn into_chunks(mut self, size: usize) -> Vec<Batch> {
+        self.finish_scan();
+        let size = size.max(1);
+        if self.files.len() <= size {
+            return vec![self];
+        }
+        self.files.chunks(size)
+            .map(|files| Batch {
+                files: files.to_vec(),
+                incoming: Mutex::new(None),
+                input_base: self.input_base.clone(),
+                output_base: self.output_base.clone(),
+                settings: self.settings.clone(),
+                batch_settings: self.batch_settings.clone(),
+                cancelled: self.cancelled.clone(),
+                control: self.control.clone(),
+                activity: self.activity.clone(),
+                routes: self.routes.clone(),
+                names: self.names.clone(),
+                flatten: self.flatten.clone(),
+            })
+            .collect()
+    }
+
+    /// Whether a file has already been assigned to a group by `route_groups` or `group_bursts`
+    pub fn is_routed(&self, file: &Path) -> bool {
+        self.routes.contains_key(file)
+    }
+
+    /// Files of the batch, only those found so far while the input is still being scanned
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Waits until all files of the batch are known, for steps that need the complete list
+    pub fn finish_scan(&mut self) {
+        let incoming = match self.incoming.get_mut().unwrap().take() {
+            Some(incoming) => incoming,
+            None => return,
+        };
+        let progress = ScanProgress::new(true);
+        for file in incoming {
+            self.files.push(file);
+            progress.update(self.files.len());
+        }
+        progress.clear();
+        self.files.sort();
+    }
+
+    /// Returns a flag that stops the batch from starting new jobs once set
+    ///
+    /// Jobs that are already running are finished regularly.
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    /// Returns the control for pausing the batch and changing its number of threads
+    pub fn control(&self) -> RunControl {
+        self.control.clone()
+    }
+
+    /// Returns the files that are currently being worked on, keyed by worker thread
+    pub fn activity_handle(&self) -> Activity {
+        self.activity.clone()
+    }
+
+    /// Puts all outputs directly into the output base instead of mirroring the input tree
+    ///
+    /// Outputs with colliding names are renamed according to `names`.
+    pub fn flattened(mut self, names: FlattenNames) -> Batch {
+        self.flatten = Some(names);
+        self
+    }
+
+    /// Plans the outputs of the batch like the run that converted it did, for comparing them against its output tree
+    ///
+    /// That run found no outputs in place yet, so existing files do not make outputs move to other names.
+    pub fn for_verification(mut self) -> Batch {
+        self.verifying = true;
+        self
+    }
+
+    /// Jobs of all files of the batch as a run would plan them, without running them
+    pub fn planned_jobs(&mut self) -> Vec<(PathBuf, Result<Job, Error>)> {
+        self.finish_scan();
+        let mut overrides = OverrideResolver::new(&self.input_base);
+        let mut claimed = HashMap::new();
+        self.files.iter()
+            .map(|file| (file.clone(), self.job(&mut overrides, &mut claimed, file, &mut |_: Progress| ())))
+            .collect()
+    }
+
+    /// Adds files to the batch as they arrive from `files` (e.g. from `spawn_scan()`)
+    ///
+    /// Running the batch starts with the files that are already known and picks up new ones while
+    /// jobs are running, so work begins before a large input tree has been scanned completely.
+    pub fn with_stream(mut self, files: Receiver<PathBuf>) -> Batch {
+        self.incoming = Mutex::new(Some(files));
+        self
+    }
+
+    /// Uses an existing flag for cancelling the batch, so it can be shared with other batches
+    pub fn with_cancel_handle(mut self, cancelled: Arc<AtomicBool>) -> Batch {
+        self.cancelled = cancelled;
+        self
+    }
+
+    /// Uses an existing control, so it can be shared with other batches
+    pub fn with_control(mut self, control: RunControl) -> Batch {
+        self.control = control;
+        self
+    }
+
+    pub fn run(&self, on_progress: &mut dyn FnMut(Progress)) -> Statistics {
+        let start = Instant::now();
+        let cpu_start = process_cpu_time();
+        let mut statistics = if self.batch_settings.threads > 1 {
+            self.run_parallel(on_progress)
+        } else {
+            self.run_single(on_progress)
+        };
+        statistics.wall_time = start.elapsed();
+        if let (Some(cpu_start), Some(cpu_end)) = (cpu_start, process_cpu_time()) {
+            statistics.cpu_time = cpu_end.saturating_sub(cpu_start);
+        }
+        statistics
+    }
+
+    /// Resolves the jobs of all known files up front
+    ///
+    /// Output paths are reserved for the whole batch here, so two files never end up
+    /// writing the same output. Output directories are only created once something is written into them,
+    /// so skipped files leave no empty directories behind.
+    /// Files that are still streaming in are planned one by one as they arrive (see `Jobs`).
+    fn plan(&self, on_progress: &mut dyn FnMut(Progress)) -> Jobs<'_> {
+        let mut overrides = OverrideResolver::new(&self.input_base);
+        let mut claimed = HashMap::new();
+        let jobs: Vec<_> = self.files.iter()
+            .map(|file| (file.clone(), self.job(&mut overrides, &mut claimed, file, on_progress)))
+            .collect();
+
+        Jobs {
+            batch: self,
+            total: jobs.len(),
+            planned: jobs.into_iter(),
+            incoming: self.incoming.lock().unwrap().take(),
+            overrides,
+            claimed,
+            deferred: VecDeque::new(),
+        }
+    }
+
+    /// Creates the job of `file`, reserving its output path in `claimed` (output path -> input file)
+    fn job(&self, overrides: &mut OverrideResolver, claimed: &mut HashMap<PathBuf, PathBuf>, file: &Path,
+           on_progress: &mut dyn FnMut(Progress)) -> Result<Job, Error> {
+        let mut file_settings = match overrides.resolve(file, &self.settings) {
+            Ok(file_settings) => file_settings,
+            Err(e) => {
+                on_progress(Progress::JobFailed { file, error: &Error::Config(e) });
+                self.settings.clone()
+            },
+        };
+        let converted = file_settings.on_raw == ParsableAction::Parse
+            && matches!(file_kind(file, file_settings.detect_by_content), FileKind::Raw);
+
+        // the job itself writes the first variant, the others are written along with it
+        let variant_encoders: Vec<_> = file_settings.variants.iter().map(|v| v.encoder(&file_settings)).collect();
+        if let (true, Some(encoder)) = (converted, variant_encoders.first()) {
+            file_settings.encoder = *encoder;
+        }
+
+        let mut output_file = target_path(file, &self.input_base, &self.output_base, &file_settings)?;
+        if let (Some(_), Some(name)) = (&self.flatten, output_file.file_name()) {
+            let mut dir = match (converted, file_settings.variants.first()) {
+                (true, Some(variant)) => self.output_base.join(&variant.name),
+                _ => self.output_base.clone(),
+            };
+            // flattening only drops the input directories, files still go to the directory of their kind
+            if let Some(route) = file_settings.routes.get(file_kind(file, file_settings.detect_by_content)) {
+                dir.push(route);
+            }
+            output_file = dir.join(name);
+        }
+        if let (Some(subdir), Some(parent), Some(name)) = (self.routes.get(file), output_file.parent(), output_file.file_name()) {
+            output_file = parent.join(subdir).join(name);
+        }
+        if let Some(stem) = self.names.get(file) {
+            let mut name = stem.clone();
+            if let Some(extension) = output_file.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            output_file.set_file_name(name);
+        }
+
+        // files from different input directories collide when flattened
+        let exists = |path: &Path| !self.verifying && path.exists();
+        if self.flatten.is_some() && claimed.contains_key(&output_file) {
+            if let Some(FlattenNames::Template(template)) = &self.flatten {
+                output_file.set_file_name(flattened_name(template, file, &self.input_base, &output_file));
+            }
+            if claimed.contains_key(&output_file) {
+                output_file = unused_path(&output_file, |p| claimed.contains_key(p) || exists(p))?;
+            }
+        }
+
+        // resolve conflicts with existing files and with the outputs of other files of this batch
+        let taken = |path: &Path| claimed.contains_key(path) || exists(path);
+        if taken(&output_file) {
+            match file_settings.on_existing {
+                ExistingAction::Rename => output_file = unused_path(&output_file, taken)?,
+                ExistingAction::Ignore => if let Some(owner) = claimed.get(&output_file) {
+                    return Err(Error::Path(format!("{:?} would overwrite the output of {:?}, it will be ignored", file, owner)));
+                },
+            }
+        }
+        claimed.insert(output_file.clone(), file.to_path_buf());
+
+        let mut job = Job::new(file, &output_file, &file_settings);
+        if !converted {
+            return Ok(job);
+        }
+
+        if let Some(first) = file_settings.variants.first() {
+            let variants = file_settings.variants.iter().zip(variant_encoders).skip(1)
+                .map(|(variant, encoder)| {
+                    let path = variant.path(&output_file, &self.output_base, first, encoder);
+                    (variant.clone(), encoder, path)
+                })
+                .collect();
+            job = job.with_variants(variants);
+        }
+
+        // kept originals and quarantined raws mirror the input tree below their directory
+        let below_output = |dir: &Path| match (&self.flatten, file.file_name()) {
+            (Some(_), Some(name)) => Ok(self.output_base.join(dir).join(name)),
+            _ => switch_base(file, &self.input_base, &self.output_base.join(dir)),
+        };
+        if let Some(dir) = &file_settings.keep_raws {
+            job = job.keep_original(&below_output(dir)?);
+        }
+        if let Some(dir) = &file_settings.quarantine {
+            job = job.quarantine_to(&below_output(dir)?);
+        }
+        Ok(job)
+    }
+
+    fn run_single(&self, on_progress: &mut dyn FnMut(Progress)) -> Statistics {
+        on_progress(Progress::Started { total: self.files.len(), threads: 1, encode_threads: 0, io_threads: 0, small_file_threads: 0 });
+
+        let mut results = Results::default();
+        let mut last_job_time = Instant::now();
+        let mut jobs = self.plan(on_progress);
+        loop {
+            let (file, job) = match jobs.next(on_progress) {
+                NextJob::Ready(file, job) => (file, job),
+                NextJob::Pending if !self.cancelled.load(Ordering::Relaxed) => continue,
+                NextJob::Pending => {
+                    let unprocessed = jobs.cancel();
+                    on_progress(Progress::Cancelled { done: results.statistics.total.count() as usize, total: jobs.total, unprocessed: &unprocessed });
+                    break;
+                },
+                NextJob::Done => break,
+            };
+
+            wait_while_paused(&self.control.paused, &self.cancelled);
+            if self.cancelled.load(Ordering::Relaxed) {
+                let mut unprocessed = vec![file];
+                unprocessed.extend(jobs.cancel());
+                on_progress(Progress::Cancelled { done: results.statistics.total.count() as usize, total: jobs.total, unprocessed: &unprocessed });
+                break;
+            }
+
+            on_progress(Progress::JobStarted { file: &file });
+            set_activity(&self.activity, Some(&file));
+            let result = job.and_then(|job| job.run());
+            set_activity(&self.activity, None);
+            results.record(file, Some(result), jobs.total, &mut last_job_time, on_progress);
+        }
+
+        results.statistics
+    }
+
+    /// Runs the batch as a pipeline
+    ///
+    /// Decoding runs on the main pool, while decoded images are passed to a separate set of
+    /// encoding threads through a bounded queue. The queue bounds the number of decoded images
+    /// kept in memory when encoding can not keep up. Copying and moving files happens on dedicated
+    /// I/O pools, so large transfers do not block decoding.
+    fn run_parallel(&self, on_progress: &mut dyn FnMut(Progress)) -> Statistics {
+        // a thread count changed at runtime carries over to later batches sharing the control
+        let threads = match self.control.threads() {
+            0 => self.batch_settings.threads,
+            threads => threads,
+        };
+        self.control.threads.store(threads, Ordering::Relaxed);
+        let encode_threads = self.batch_settings.encode_threads.max(1);
+        let io_threads = self.batch_settings.io_threads.max(1);
+        let small_file_threads = self.batch_settings.small_file_threads.max(1);
+        on_progress(Progress::Started { total: self.files.len(), threads, encode_threads, io_threads, small_file_threads });
+
+        let mut last_job_time = time::Instant::now();
+        let mut pool = ThreadPool::with_name(String::from("decode"), threads);
+        let io_pool = ThreadPool::with_name(String::from("io"), io_threads);
+        let small_file_pool = ThreadPool::with_name(String::from("small-files"), small_file_threads);
+        let (tx, rx) = channel();
+        let (decoded_tx, decoded_rx) = sync_channel::<(ResultGuard, DecodedJob)>(self.batch_settings.encode_queue);
+        let decoded_rx = Arc::new(Mutex::new(decoded_rx));
+
+        let encoders: Vec<_> = (0..encode_threads).map(|i| {
+            let decoded_rx = decoded_rx.clone();
+            let activity = self.activity.clone();
+            thread::Builder::new().name(format!("encode-{}", i)).spawn(move || loop {
+                let next = decoded_rx.lock().unwrap().recv();
+                match next {
+                    Ok((mut guard, decoded)) => {
+                        guard.lost = Error::Encode;
+                        set_activity(&activity, guard.file());
+                        let stats = decoded.run_encode();
+                        set_activity(&activity, None);
+                        guard.send(Some(Ok(stats)));
+                    },
+                    Err(_) => break,
+                }
+            }).expect("unable to spawn encoder thread")
+        }).collect();
+
+        let mut results = Results::default();
+        let mut jobs = self.plan(on_progress);
+        let mut dispatched = 0;
+        loop {
+            // report the jobs finished so far while more files are being discovered
+            while let Ok((file, result)) = rx.try_recv() {
+                results.record(file, result, jobs.total, &mut last_job_time, on_progress);
+                self.adjust_threads(&mut pool);
+            }
+            if self.cancelled.load(Ordering::Relaxed) {
+                results.unprocessed.extend(jobs.cancel());
+                break;
+            }
+
+            let (file, job) = match jobs.next(on_progress) {
+                NextJob::Ready(file, job) => (file, job),
+                NextJob::Pending => continue,
+                NextJob::Done => break,
+            };
+            dispatched += 1;
+            let job = match job {
+                Ok(job) => job,
+                Err(e) => {
+                    tx.send((file, Some(Err(e)))).unwrap();
+                    continue;
+                },
+            };
+            on_progress(Progress::JobStarted { file: &file });
+            let job_pool = if job.is_small_transfer(self.batch_settings.small_file_size) {
+                &small_file_pool
+            } else if job.is_transfer() {
+                &io_pool
+            } else {
+                &pool
+            };
+
+            let guard = ResultGuard { file: Some(file), tx: tx.clone(), lost: Error::Decode };
+            let next_decoded_tx = decoded_tx.clone();
+            let cancelled = self.cancelled.clone();
+            let paused = self.control.paused.clone();
+            let activity = self.activity.clone();
+            job_pool.execute(move || {
+                wait_while_paused(&paused, &cancelled);
+                if cancelled.load(Ordering::Relaxed) {
+                    guard.send(None);
+                    return;
+                }
+                set_activity(&activity, guard.file());
+                let result = job.run_decode();
+                set_activity(&activity, None);
+                match result {
+                    // if all encoders are gone, the guard comes back with the error and reports the job as lost
+                    Ok(Stage::Decoded(decoded)) => { let _ = next_decoded_tx.send((guard, decoded)); },
+                    Ok(Stage::Finished(stats)) => guard.send(Some(Ok(stats))),
+                    Err(e) => guard.send(Some(Err(e))),
+                }
+            });
+        }
+        drop(decoded_tx);
+        // the channel closes once all jobs and encoders are done, even if some of them never reported
+        drop(tx);
+
+        let total = jobs.total;
+        for (file, result) in rx.iter().take(dispatched - results.received) {
+            results.record(file, result, total, &mut last_job_time, on_progress);
+            self.adjust_threads(&mut pool);
+        }
+        if results.received < dispatched {
+            eprintln!("Lost the results of {} jobs", dispatched - results.received);
+            for _ in results.received..dispatched {
+                results.statistics.errors.inc();
+            }
+        }
+
+        let Results { statistics: acc_stats, mut unprocessed, .. } = results;
+        if !unprocessed.is_empty() {
+            unprocessed.sort();
+            on_progress(Progress::Cancelled { done: acc_stats.total.count() as usize, total, unprocessed: &unprocessed });
+        }
+
+        // all decode jobs are done at this point, so the encoders see the queue closing
+        for encoder in encoders {
+            let _ = encoder.join();
+        }
+
+        acc_stats
+    }
+
+    /// Applies a thread count changed through the control, surplus threads exit once their current job is done
+    fn adjust_threads(&self, pool: &mut ThreadPool) {
+        let threads = self.control.threads();
+        if threads > 0 && threads != pool.max_count() {
+            pool.set_num_threads(threads);
+        }
+    }
+}
+
+impl Jobs<'_> {
+    /// The next job to run, planning files that were streamed in since the last call
+    ///
+    /// Files that are still being written are deferred and only returned once they have settled,
+    /// after all other known files.
+    fn next(&mut self, on_progress: &mut dyn FnMut(Progress)) -> NextJob {
+        let next = match self.planned.next() {
+            Some((file, job)) => NextJob::Ready(file, job),
+            None => self.receive(on_progress),
+        };
+        match next {
+            NextJob::Ready(file, job) => self.defer_unsettled(file, job, on_progress),
+            NextJob::Pending => self.next_deferred().unwrap_or(NextJob::Pending),
+            NextJob::Done if self.deferred.is_empty() => NextJob::Done,
+            NextJob::Done => self.next_deferred().unwrap_or_else(|| {
+                // nothing else to do, so avoid spinning while the deferred files are being written
+                thread::sleep(STREAM_POLL_INTERVAL);
+                NextJob::Pending
+            }),
+        }
+    }
+
+    /// Plans the next file that was streamed in
+    fn receive(&mut self, on_progress: &mut dyn FnMut(Progress)) -> NextJob {
+        let incoming = match &self.incoming {
+            Some(incoming) => incoming,
+            None => return NextJob::Done,
+        };
+        let file = match incoming.recv_timeout(STREAM_POLL_INTERVAL) {
+            Ok(file) => file,
+            Err(RecvTimeoutError::Timeout) => return NextJob::Pending,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.incoming = None;
+                return NextJob::Done;
+            },
+        };
+
+        self.total += 1;
+        on_progress(Progress::Discovered { count: 1 });
+        let job = self.batch.job(&mut self.overrides, &mut self.claimed, &file, on_progress);
+        NextJob::Ready(file, job)
+    }
+
+    /// Puts the job of `file` at the end of the queue if the file is still being written
+    fn defer_unsettled(&mut self, file: PathBuf, job: Result<Job, Error>, on_progress: &mut dyn FnMut(Progress)) -> NextJob {
+        match self.batch.batch_settings.stable_for {
+            Some(stable_for) if !is_settled(&file, stable_for) => {
+                on_progress(Progress::Deferred { file: &file });
+                let size = file.metadata().map(|m| m.len()).ok();
+                self.deferred.push_back(Deferred { file, job, size, since: Instant::now(), deferred: Instant::now() });
+                NextJob::Pending
+            },
+            _ => NextJob::Ready(file, job),
+        }
+    }
+
+    /// The first deferred job whose file has settled in the meantime
+    ///
+    /// Files that are still not settled after `BatchSettings::max_deferral` (e.g. because they keep
+    /// growing or are kept open) are returned as failed jobs.
+    fn next_deferred(&mut self) -> Option<NextJob> {
+        let stable_for = self.batch.batch_settings.stable_for?;
+        let max_deferral = self.batch.batch_settings.max_deferral;
+        if let Some(position) = self.deferred.iter().position(|d| d.deferred.elapsed() >= max_deferral) {
+            let Deferred { file, .. } = self.deferred.remove(position)?;
+            let reason = format!("still being written after {}", fmt_duration(&max_deferral));
+            let error = Error::io(&file, io::Error::other(reason));
+            return Some(NextJob::Ready(file, Err(error)));
+        }
+        let position = self.deferred.iter_mut().position(|d| d.is_settled(stable_for))?;
+        let Deferred { file, job, .. } = self.deferred.remove(position)?;
+        Some(NextJob::Ready(file, job))
+    }
+
+    /// Stops discovering new files and returns the known files that were not started yet
+    fn cancel(&mut self) -> Vec<PathBuf> {
+        self.incoming = None;
+        let mut files: Vec<_> = self.planned.by_ref().map(|(file, _)| file).collect();
+        files.extend(self.deferred.drain(..).map(|d| d.file));
+        files
+    }
+}
+
+impl Deferred {
+    /// Whether the size of the file has not changed for `stable_for` and it is no longer in use
+    ///
+    /// The size is tracked in addition to the modification time, which some file systems only
+    /// store with a precision of seconds (or even two seconds on FAT formatted cards).
+    fn is_settled(&mut self, stable_for: time::Duration) -> bool {
+        let size = self.file.metadata().map(|m| m.len()).ok();
+        if size != self.size {
+            self.size = size;
+            self.since = Instant::now();
+            return false;
+        }
+        self.since.elapsed() >= stable_for && is_settled(&self.file, stable_for)
+    }
+}
+
+impl ResultGuard {
+    fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    fn send(mut self, result: Option<Result<Statistics, Error>>) {
+        if let Some(file) = self.file.take() {
+            let _ = self.tx.send((file, result));
+        }
+    }
+}
+
+impl Drop for ResultGuard {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let error = (self.lost)(String::from("the job crashed"));
+            let _ = self.tx.send((file, Some(Err(error))));
+        }
+    }
+}
+
+impl Results {
+    /// Accounts for the outcome of a job, `None` if it was never started
+    fn record(&mut self, file: PathBuf, result: Option<Result<Statistics, Error>>, total: usize,
+              last_job_time: &mut Instant, on_progress: &mut dyn FnMut(Progress)) {
+        self.received += 1;
+        let stats = match result {
+            Some(Ok(stats)) => stats,
+            Some(Err(e)) => {
+                on_progress(Progress::JobFailed { file: &file, error: &e });
+                let mut stats = Statistics::default();
+                stats.errors.inc();
+                stats
+            },
+            None => {
+                self.unprocessed.push(file);
+                return;
+            },
+        };
+
+        let now = Instant::now();
+        self.statistics.total.record(now - *last_job_time);
+        *last_job_time = now;
+        self.statistics.extend(&stats);
+        on_progress(Progress::JobFinished { file: &file, done: self.statistics.total.count() as usize, total, statistics: &stats });
+    }
+}
+
+/// Name of the flattened output `output` of `file` according to `template`
+///
+/// `{stem}` is the original file stem, `{dir}` the name of the directory containing the input
+/// and `{path}` the input directory relative to `input_base` with `_` as separator.
+fn flattened_name(template: &str, file: &Path, input_base: &Path, output: &Path) -> OsString {
+    let relative_dir = file.parent()
+        .and_then(|p| p.strip_prefix(input_base).ok())
+        .unwrap_or(Path::new(""));
+    let mut path = OsString::new();
+    for (i, component) in relative_dir.components().enumerate() {
+        if i > 0 {
+            path.push("_");
+        }
+        path.push(component.as_os_str());
+    }
+    let dir = relative_dir.file_name().unwrap_or_default();
+
+    let mut name = expand_template(template, &[
+        ("{stem}", output.file_stem().unwrap_or_default()),
+        ("{dir}", dir),
+        ("{path}", &path),
+    ]);
+    if let Some(extension) = output.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    name
+}
+
+/// Blocks while the batch is paused, unless it gets cancelled in the meantime
+fn wait_while_paused(paused: &AtomicBool, cancelled: &AtomicBool) {
+    while paused.load(Ordering::Relaxed) && !cancelled.load(Ordering::Relaxed) {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+}
+
+/// Records the file the current thread works on (or that it is idle)
+fn set_activity(activity: &Activity, file: Option<&Path>) {
+    let current = thread::current();
+    let key = format!("{} {:?}", current.name().unwrap_or("main"), current.id());
+    let mut activity = activity.lock().unwrap();
+    match file {
+        Some(file) => { activity.insert(key, file.display().to_string()); },
+        None => { activity.remove(&key); },
+    }
+}
@@ -0,0 +1,303 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use clap::ValueEnum;
+use eframe::egui;
+use raw_to_img::*;
+
+
+/// Edge length of the thumbnails of converted images
+const THUMBNAIL_SIZE: u32 = 128;
+/// Number of thumbnails kept, older ones are dropped
+const MAX_THUMBNAILS: usize = 300;
+
+/// State shared between the UI and the thread running the batch
+#[derive(Default)]
+struct RunState {
+    running: bool,
+    done: usize,
+    total: usize,
+    errors: usize,
+    last_file: String,
+    log: Vec<String>,
+    /// Thumbnails of converted images that have not been uploaded to the GPU yet
+    thumbnails: Vec<(String, egui::ColorImage)>,
+}
+
+struct App {
+    input: String,
+    output: String,
+    /// Profiles of the config files, selecting one applies its settings
+    profiles: Vec<String>,
+    profile: Option<String>,
+    encode_type: EncodedType,
+    jpeg_quality: u8,
+    threads: usize,
+    /// Decode on the GPU, only offered by builds with the `gpu` feature
+    gpu: bool,
+    state: Arc<Mutex<RunState>>,
+    cancel: Option<Arc<AtomicBool>>,
+    thumbnails: Vec<(String, egui::TextureHandle)>,
+}
+
+
+impl Default for App {
+    fn default() -> App {
+        let mut app = App {
+            input: String::new(),
+            output: String::new(),
+            profiles: Vec::new(),
+            profile: None,
+            encode_type: EncodedType::Jpeg,
+            jpeg_quality: 90,
+            threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            gpu: false,
+            state: Arc::default(),
+            cancel: None,
+            thumbnails: Vec::new(),
+        };
+        match profile_names() {
+            Ok(profiles) => app.profiles = profiles,
+            Err(e) => app.state.lock().unwrap().log.push(e),
+        }
+        app
+    }
+}
+
+impl App {
+    /// Takes over the settings of `profile` that the window offers, reporting all others as ignored
+    fn apply_profile(&mut self, profile: &str) {
+        let settings = match profile_settings(profile) {
+            Ok(settings) => settings,
+            Err(e) => { self.state.lock().unwrap().log.push(e); return },
+        };
+
+        let mut ignored = Vec::new();
+        for (key, value) in &settings {
+            match (key.as_str(), value) {
+                ("encode_type", toml::Value::String(name)) => match <EncodedType as ValueEnum>::from_str(name, true) {
+                    Ok(encode_type) => self.encode_type = encode_type,
+                    Err(_) => ignored.push(key.as_str()),
+                },
+                ("jpeg_quality", toml::Value::Integer(quality)) => self.jpeg_quality = (*quality).clamp(1, 100) as u8,
+                ("threads", toml::Value::Integer(threads)) => self.threads = (*threads).clamp(1, 64) as usize,
+                ("gpu", toml::Value::Boolean(gpu)) if cfg!(feature = "gpu") => self.gpu = *gpu,
+                _ => ignored.push(key.as_str()),
+            }
+        }
+        if !ignored.is_empty() {
+            self.state.lock().unwrap().log.push(format!("Profile \"{}\": ignoring {}, which the window does not support",
+                profile, ignored.join(", ")));
+        }
+    }
+
+    fn start(&mut self) {
+        let input = PathBuf::from(&self.input);
+        let output = PathBuf::from(&self.output);
+        let settings = JobSettings {
+            gpu: self.gpu,
+            encoder: EncoderType::new(self.encode_type, self.jpeg_quality),
+            jpeg_quality: self.jpeg_quality,
+            // the report carries the outputs for the thumbnails
+            report: true,
+            ..JobSettings::default()
+        };
+        let batch_settings = BatchSettings { threads: self.threads, ..BatchSettings::default() };
+        let batch = Batch::new(Vec::new(), &input, &output, &settings, &batch_settings)
+            .with_stream(spawn_scan(&input, None, false, output_dirs_in(&input, &output, &settings)));
+
+        self.cancel = Some(batch.cancel_handle());
+        self.thumbnails.clear();
+        *self.state.lock().unwrap() = RunState { running: true, ..RunState::default() };
+
+        let state = self.state.clone();
+        let thumbnails = spawn_thumbnailer(self.state.clone());
+        thread::spawn(move || {
+            let statistics = batch.run(&mut |progress: Progress| {
+                let mut state = state.lock().unwrap();
+                match progress {
+                    Progress::JobFailed { file, error } => {
+                        state.errors += 1;
+                        state.log.push(format!("Error ({}): {}", file.display(), error));
+                    },
+                    Progress::JobFinished { file, done, statistics, .. } => {
+                        state.done = done;
+                        state.last_file = file.display().to_string();
+                        if statistics.decoded.count() + statistics.cached.count() > 0 {
+                            if let Some(entry) = statistics.report.last() {
+                                let _ = thumbnails.send(entry.output.clone());
+                            }
+                        }
+                    },
+                    Progress::Cancelled { done, total, .. } =>
+                        state.log.push(format!("Cancelled after {} of {} files", done, total)),
+                    Progress::Discovered { count } => state.total += count,
+                    Progress::Deferred { file } =>
+                        state.log.push(format!("Deferring {} (still being written)", file.display())),
+                    Progress::Started { .. } | Progress::JobStarted { .. } => (),
+                }
+            });
+
+            let mut state = state.lock().unwrap();
+            state.running = false;
+            state.log.push(format!("Finished: {} decoded, {} copied, {} errors",
+                statistics.decoded.count(), statistics.copied.count(), statistics.errors.count()));
+        });
+    }
+}
+
+impl eframe::App for App {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // dropping a folder onto the window fills the first empty field
+        for file in ctx.input(|i| i.raw.dropped_files.clone()) {
+            if let Some(path) = file.path {
+                let path = path.to_string_lossy().to_string();
+                if self.input.is_empty() {
+                    self.input = path;
+                } else {
+                    self.output = path;
+                }
+            }
+        }
+
+        let running = self.state.lock().unwrap().running;
+
+        let pending = std::mem::take(&mut self.state.lock().unwrap().thumbnails);
+        for (name, image) in pending {
+            let texture = ctx.load_texture(name.clone(), image, egui::TextureOptions::default());
+            self.thumbnails.push((name, texture));
+        }
+        if self.thumbnails.len() > MAX_THUMBNAILS {
+            self.thumbnails.drain(..self.thumbnails.len() - MAX_THUMBNAILS);
+        }
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Raw to Image");
+            ui.label("Drop an input folder and then an output folder onto this window");
+
+            egui::Grid::new("settings").num_columns(2).show(ui, |ui| {
+                ui.label("Input");
+                ui.text_edit_singleline(&mut self.input);
+                ui.end_row();
+
+                ui.label("Output");
+                ui.text_edit_singleline(&mut self.output);
+                ui.end_row();
+
+                ui.label("Profile");
+                let previous = self.profile.clone();
+                egui::ComboBox::from_id_salt("profile")
+                    .selected_text(self.profile.as_deref().unwrap_or("none"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.profile, None, "none");
+                        for profile in &self.profiles {
+                            ui.selectable_value(&mut self.profile, Some(profile.clone()), profile);
+                        }
+                    });
+                if self.profile != previous {
+                    if let Some(profile) = self.profile.clone() {
+                        self.apply_profile(&profile);
+                    }
+                }
+                ui.end_row();
+
+                ui.label("Format");
+                egui::ComboBox::from_id_salt("format")
+                    .selected_text(format!("{:?}", self.encode_type))
+                    .show_ui(ui, |ui| {
+                        for encode_type in [EncodedType::Jpeg, EncodedType::Png, EncodedType::Tiff, EncodedType::Qoi] {
+                            ui.selectable_value(&mut self.encode_type, encode_type, format!("{:?}", encode_type));
+                        }
+                    });
+                ui.end_row();
+
+                ui.label("JPEG quality");
+                ui.add(egui::Slider::new(&mut self.jpeg_quality, 1..=100));
+                ui.end_row();
+
+                ui.label("Threads");
+                ui.add(egui::Slider::new(&mut self.threads, 1..=64));
+                ui.end_row();
+
+                if cfg!(feature = "gpu") {
+                    ui.label("GPU");
+                    ui.checkbox(&mut self.gpu, "Decode on the GPU");
+                    ui.end_row();
+                }
+            });
+
+            ui.horizontal(|ui| {
+                let can_start = !running && !self.input.is_empty() && !self.output.is_empty();
+                if ui.add_enabled(can_start, egui::Button::new("Convert")).clicked() {
+                    self.start();
+                }
+                if ui.add_enabled(running, egui::Button::new("Cancel")).clicked() {
+                    if let Some(cancel) = &self.cancel {
+                        cancel.store(true, Ordering::Relaxed);
+                    }
+                }
+            });
+
+            let state = self.state.lock().unwrap();
+            if state.total > 0 {
+                ui.add(egui::ProgressBar::new(state.done as f32 / state.total as f32)
+                    .text(format!("{}/{} ({} errors)", state.done, state.total, state.errors)));
+                ui.label(state.last_file.as_str());
+            }
+
+            egui::ScrollArea::vertical().id_salt("log").max_height(120.0).stick_to_bottom(true).show(ui, |ui| {
+                for line in &state.log {
+                    ui.label(line.as_str());
+                }
+            });
+            drop(state);
+
+            ui.separator();
+            egui::ScrollArea::vertical().id_salt("thumbnails").stick_to_bottom(true).show(ui, |ui| {
+                ui.horizontal_wrapped(|ui| {
+                    let size = egui::vec2(THUMBNAIL_SIZE as f32, THUMBNAIL_SIZE as f32);
+                    for (name, texture) in &self.thumbnails {
+                        ui.add(egui::Image::new(texture).max_size(size)).on_hover_text(name.as_str());
+                    }
+                });
+            });
+        });
+
+        if running {
+            ctx.request_repaint();
+        }
+    }
+}
+
+/// Starts a thread turning the outputs sent to it into thumbnails for the grid
+///
+/// Loading the outputs takes a while, so it happens neither on the UI thread nor on the one running the batch.
+fn spawn_thumbnailer(state: Arc<Mutex<RunState>>) -> Sender<PathBuf> {
+    let (tx, rx) = channel::<PathBuf>();
+    thread::spawn(move || {
+        for output in rx {
+            if let Some(thumbnail) = load_thumbnail(&output) {
+                let name = output.file_name().unwrap_or_default().to_string_lossy().to_string();
+                state.lock().unwrap().thumbnails.push((name, thumbnail));
+            }
+        }
+    });
+    tx
+}
+
+fn load_thumbnail(path: &Path) -> Option<egui::ColorImage> {
+    let image = image::open(path).ok()?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgba8();
+    let size = [image.width() as usize, image.height() as usize];
+    Some(egui::ColorImage::from_rgba_unmultiplied(size, image.as_raw()))
+}
+
+fn main() -> eframe::Result<()> {
+    eframe::run_native(
+        "raw-to-img",
+        eframe::NativeOptions::default(),
+        Box::new(|_cc| Ok(Box::new(App::default()))),
+    )
+}
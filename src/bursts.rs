@@ -0,0 +1,189 @@
+use crate::*;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+
+/// Width that frames are scaled down to before measuring their sharpness
+const SHARPNESS_WIDTH: usize = 512;
+
+/// What to do with the frames of a burst
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum BurstMode {
+    /// Only process the first frame of each burst
+    First,
+    /// Only process the sharpest frame of each burst
+    Sharpest,
+    /// Process all frames, but put each burst into its own subdirectory
+    AllSubdir,
+}
+
+/// What to do with frames that are less sharp than `--reject-blurry`
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum BlurryAction {
+    /// Process them into a `rejected/` subdirectory
+    Reject,
+    /// Do not process them at all
+    Skip,
+}
+
+/// Result of grouping the files of a batch into bursts
+#[derive(Default)]
+pub struct BurstPlan {
+    /// Files that should be processed
+    pub files: Vec<PathBuf>,
+    /// Subdirectory (relative to the regular output directory) for files that belong to a burst
+    pub routes: HashMap<PathBuf, PathBuf>,
+    /// Number of bursts that were found
+    pub bursts: usize,
+    /// Number of frames that are skipped
+    pub skipped: usize,
+}
+
+
+/// Capture time of a raw file in seconds (only meaningful relative to other capture times)
+pub fn capture_time(path: &Path) -> Option<f64> {
+    let data = read_exif(path)?;
+    let field = data.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
+        .or_else(|| data.get_field(exif::Tag::DateTime, exif::In::PRIMARY))?;
+    let mut datetime = match field.value {
+        exif::Value::Ascii(ref values) => exif::DateTime::from_ascii(values.first()?).ok()?,
+        _ => return None,
+    };
+    if let Some(exif::Value::Ascii(values)) = data.get_field(exif::Tag::SubSecTimeOriginal, exif::In::PRIMARY).map(|f| &f.value) {
+        if let Some(subsec) = values.first() {
+            let _ = datetime.parse_subsec(subsec);
+        }
+    }
+
+    let days = days_from_civil(datetime.year as i64, datetime.month as i64, datetime.day as i64);
+    let seconds = ((days * 24 + datetime.hour as i64) * 60 + datetime.minute as i64) * 60 + datetime.second as i64;
+    Some(seconds as f64 + datetime.nanosecond.unwrap_or(0) as f64 / 1e9)
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Groups the raws of each directory into bursts of frames taken at most `gap` apart
+///
+/// Files without a capture time are never part of a burst.
+pub fn group_bursts(files: &[PathBuf], gap: time::Duration, detect_by_content: bool) -> Vec<Vec<PathBuf>> {
+    let mut by_dir: BTreeMap<PathBuf, Vec<(f64, PathBuf)>> = BTreeMap::new();
+    for file in files {
+        if !matches!(file_kind(file, detect_by_content), FileKind::Raw) {
+            continue;
+        }
+        if let (Some(time), Some(dir)) = (capture_time(file), file.parent()) {
+            by_dir.entry(dir.to_path_buf()).or_default().push((time, file.clone()));
+        }
+    }
+
+    let mut bursts = Vec::new();
+    for (_, mut frames) in by_dir {
+        frames.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        let mut current: Vec<PathBuf> = Vec::new();
+        let mut last_time = f64::NEG_INFINITY;
+        for (time, file) in frames {
+            if time - last_time > gap.as_secs_f64() && !current.is_empty() {
+                bursts.push(std::mem::take(&mut current));
+            }
+            current.push(file);
+            last_time = time;
+        }
+        if !current.is_empty() {
+            bursts.push(current);
+        }
+    }
+
+    bursts.retain(|burst| burst.len() > 1);
+    bursts
+}
+
+/// Decides which files of a batch to process based on the bursts among them
+pub fn plan_bursts(files: Vec<PathBuf>, gap: time::Duration, mode: BurstMode, settings: &JobSettings) -> BurstPlan {
+    let bursts = group_bursts(&files, gap, settings.detect_by_content);
+    let mut plan = BurstPlan { bursts: bursts.len(), ..BurstPlan::default() };
+    let mut skipped = HashSet::new();
+
+    for burst in bursts {
+        match mode {
+            BurstMode::First => skipped.extend(burst.into_iter().skip(1)),
+            BurstMode::Sharpest => {
+                let best = burst.iter()
+                    .map(|f| (sharpness(f, settings), f))
+                    .max_by(|a, b| a.0.total_cmp(&b.0))
+                    .map(|(_, f)| f.clone());
+                skipped.extend(burst.into_iter().filter(|f| Some(f) != best.as_ref()));
+            },
+            BurstMode::AllSubdir => {
+                let subdir = PathBuf::from(suffixed_stem(&burst[0], "_burst"));
+                for file in burst {
+                    plan.routes.insert(file, subdir.clone());
+                }
+            },
+        }
+    }
+
+    plan.skipped = skipped.len();
+    plan.files = files.into_iter().filter(|f| !skipped.contains(f)).collect();
+    plan
+}
+
+/// Variance of the Laplacian of a downscaled grayscale version of the frame (higher is sharper)
+///
+/// Uses the embedded preview if there is one and decodes the raw otherwise.
+pub fn sharpness(path: &Path, settings: &JobSettings) -> f64 {
+    let image = match embedded_preview(path) {
+        Some(preview) => preview,
+        None => match decode_with_chain(RawInput::File(path), settings.decoder, settings.gpu, &DecodeOptions::default()) {
+            Ok(decoded) => decoded,
+            Err(_) => return 0.0,
+        },
+    };
+    if image.width < 3 || image.height < 3 {
+        return 0.0;
+    }
+
+    let width = image.width.min(SHARPNESS_WIDTH);
+    let height = (image.height * width / image.width).max(3);
+    let small = pixelops::resize_rgb8(&image.data, image.width, image.height, width, height);
+    let gray: Vec<f64> = small.chunks_exact(3)
+        .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+        .collect();
+
+    let mut sum = 0.0;
+    let mut sum_sq = 0.0;
+    let mut count = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let i = y * width + x;
+            let laplacian = gray[i - width] + gray[i + width] + gray[i - 1] + gray[i + 1] - 4.0 * gray[i];
+            sum += laplacian;
+            sum_sq += laplacian * laplacian;
+            count += 1.0;
+        }
+    }
+
+    sum_sq / count - (sum / count).powi(2)
+}
+
+/// Decodes the JPEG thumbnail stored in the EXIF data of a raw file
+pub fn embedded_preview(path: &Path) -> Option<imagepipe::SRGBImage> {
+    let data = read_exif(path)?;
+    let offset = data.get_field(exif::Tag::JPEGInterchangeFormat, exif::In::THUMBNAIL)?.value.get_uint(0)? as usize;
+    let length = data.get_field(exif::Tag::JPEGInterchangeFormatLength, exif::In::THUMBNAIL)?.value.get_uint(0)? as usize;
+    let jpeg = data.buf().get(offset..offset + length)?;
+
+    let preview = image::load_from_memory(jpeg).ok()?.to_rgb8();
+    Some(imagepipe::SRGBImage {
+        width: preview.width() as usize,
+        height: preview.height() as usize,
+        data: preview.into_raw(),
+    })
+}
@@ -0,0 +1,91 @@
+use crate::*;
+
+use sha2::Digest;
+
+
+/// Directory of previously converted outputs, keyed by the content of their raw and the settings
+///
+/// Converting a raw that was already converted with the same settings restores the cached output
+/// instead of decoding the raw again.
+#[derive(Clone, Debug)]
+pub struct OutputCache {
+    dir: PathBuf,
+    /// Fingerprint of the run-wide settings that influence converted outputs
+    settings: String,
+}
+
+
+impl OutputCache {
+    /// Opens (and creates) the cache in `dir` for conversions with the given settings fingerprint
+    pub fn open(dir: &Path, settings: &str) -> Result<OutputCache, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("unable to create cache directory {:?} ({})", dir, e))?;
+        Ok(OutputCache { dir: dir.to_path_buf(), settings: settings.to_string() })
+    }
+
+    /// Cache key of converting `input` with `settings`
+    ///
+    /// Besides the run-wide fingerprint, this covers the settings that can be changed per directory.
+    pub fn key(&self, input: &Path, settings: &JobSettings) -> Result<String, Error> {
+        let digest = hash_file(input, ChecksumAlgo::Sha256).map_err(|e| Error::io(input, io::Error::other(e)))?;
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(digest.as_bytes());
+        hasher.update(self.settings.as_bytes());
+        hasher.update(format!("\nencoder={}:{}", settings.encoder.extension(), settings.jpeg_quality).as_bytes());
+        if let Some(decoder) = clap::ValueEnum::to_possible_value(&settings.decoder) {
+            hasher.update(format!("\ndecoder={}", decoder.get_name()).as_bytes());
+        }
+        if !settings.variants.is_empty() {
+            hasher.update(format!("\nvariants={:?}", settings.variants).as_bytes());
+        }
+        if !settings.adjustments.is_identity() {
+            hasher.update(format!("\nadjustments={:?}", settings.adjustments).as_bytes());
+        }
+        // annotations may contain the file name
+        if settings.annotation.is_some() {
+            hasher.update(input.file_name().unwrap_or_default().as_encoded_bytes());
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    fn path(&self, key: &str, encoder: EncoderType) -> PathBuf {
+        self.dir.join(&key[..2]).join(format!("{}.{}", key, encoder.extension()))
+    }
+
+    /// Copies the cached output for `key` to `output` (see `clone_or_copy()`), `None` if there is none
+    pub fn restore(&self, key: &str, encoder: EncoderType, output: &Path) -> Option<time::Duration> {
+        let cached = self.path(key, encoder);
+        if !cached.is_file() {
+            return None;
+        }
+        println!("Restoring {:?} from cache", output);
+        let start = Instant::now();
+        clone_or_copy(&cached, output).ok()?;
+        Some(start.elapsed())
+    }
+
+    /// Adds a freshly written `output` to the cache
+    pub fn store(&self, key: &str, encoder: EncoderType, output: &Path) -> Result<(), Error> {
+        let cached = self.path(key, encoder);
+        if cached.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = cached.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+        }
+
+        clone_or_copy(output, &cached).map_err(|e| Error::io(&cached, e))
+    }
+}
+
+/// Clones `from` to `to` if the file system supports reflinks, copies it otherwise
+///
+/// Cache entries never share their data with outputs (as hardlinks would), so editing an output
+/// in place does not change what is restored for the next conversion.
+fn clone_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    reflink(from, to).or_else(|_| {
+        let temp = temp_path(to);
+        fs::copy(from, &temp)
+            .and_then(|_| finish_temp(&temp, to))
+            .inspect_err(|_| { let _ = fs::remove_file(&temp); })
+    })
+}
@@ -0,0 +1,73 @@
+use crate::*;
+
+use std::collections::HashSet;
+
+
+/// File inside `output_base` that lists the camera files imported by previous runs
+pub const CAMERA_IMPORT_LOG: &str = ".raw-to-img-camera-imports";
+
+/// Camera files already imported into `output_base` (as `folder/name`)
+pub fn imported_camera_files(output_base: &Path) -> HashSet<String> {
+    fs::read_to_string(output_base.join(CAMERA_IMPORT_LOG))
+        .map(|content| content.lines().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// Records `files` (as `folder/name`) as imported into `output_base`
+pub fn record_camera_imports(output_base: &Path, files: &[String]) -> Result<(), String> {
+    use std::io::Write;
+
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(output_base.join(CAMERA_IMPORT_LOG))
+        .map_err(|e| e.to_string())?;
+    for file in files {
+        writeln!(log, "{}", file).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Downloads all raws from the first connected camera that are not in `imported` into `dir`
+///
+/// The folder structure of the camera is kept. Returns the downloaded files as `folder/name`.
+#[cfg(feature = "camera")]
+pub fn download_from_camera(dir: &Path, imported: &HashSet<String>) -> Result<Vec<String>, String> {
+    let context = gphoto2::Context::new().map_err(|e| e.to_string())?;
+    let camera = context.autodetect_camera().wait()
+        .map_err(|e| format!("no camera found ({})", e))?;
+    println!("Importing from {}", camera.abilities().model());
+
+    let mut downloaded = Vec::new();
+    let mut folders = vec![String::from("/")];
+    while let Some(folder) = folders.pop() {
+        let camera_fs = camera.fs();
+        for subfolder in camera_fs.list_folders(&folder).wait().map_err(|e| e.to_string())? {
+            folders.push(format!("{}/{}", folder.trim_end_matches('/'), subfolder));
+        }
+
+        for name in camera_fs.list_files(&folder).wait().map_err(|e| e.to_string())? {
+            let id = format!("{}/{}", folder.trim_end_matches('/'), name);
+            if imported.contains(&id) || !matches!(file_kind(Path::new(&name), false), FileKind::Raw) {
+                continue;
+            }
+
+            let local = dir.join(folder.trim_start_matches('/')).join(&name);
+            if let Some(parent) = local.parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            println!("Downloading {}", id);
+            match camera_fs.download_to(&folder, &name, &local).wait() {
+                Ok(_) => downloaded.push(id),
+                Err(e) => println!("Unable to download {} ({})", id, e),
+            }
+        }
+    }
+
+    Ok(downloaded)
+}
+
+#[cfg(not(feature = "camera"))]
+pub fn download_from_camera(_dir: &Path, _imported: &HashSet<String>) -> Result<Vec<String>, String> {
+    Err(String::from("This build does not support importing from cameras (enable the camera feature)"))
+}
@@ -0,0 +1,100 @@
+use crate::*;
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use sha2::Digest;
+
+
+/// Hash algorithm used for checksum manifests
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum ChecksumAlgo {
+    Sha256,
+    Sha512,
+}
+
+
+impl ChecksumAlgo {
+    /// Name of the manifest file in the output root (as written by `sha256sum` and friends)
+    pub fn manifest_name(&self) -> &'static str {
+        match self {
+            ChecksumAlgo::Sha256 => "SHA256SUMS",
+            ChecksumAlgo::Sha512 => "SHA512SUMS",
+        }
+    }
+}
+
+/// Hashes a file and returns the digest as lowercase hex
+pub fn hash_file(path: &Path, algo: ChecksumAlgo) -> Result<String, String> {
+    let mut file = fs::File::open(path).map_err(|e| e.to_string())?;
+    let digest = match algo {
+        ChecksumAlgo::Sha256 => {
+            let mut hasher = sha2::Sha256::new();
+            io::copy(&mut file, &mut hasher).map_err(|e| e.to_string())?;
+            format!("{:x}", hasher.finalize())
+        },
+        ChecksumAlgo::Sha512 => {
+            let mut hasher = sha2::Sha512::new();
+            io::copy(&mut file, &mut hasher).map_err(|e| e.to_string())?;
+            format!("{:x}", hasher.finalize())
+        },
+    };
+    Ok(digest)
+}
+
+fn read_manifest(manifest: &Path) -> Result<BTreeMap<PathBuf, String>, String> {
+    let mut entries = BTreeMap::new();
+    if !manifest.exists() {
+        return Ok(entries);
+    }
+
+    let content = fs::read_to_string(manifest).map_err(|e| e.to_string())?;
+    for (i, line) in content.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let (digest, file) = line.split_once("  ")
+            .ok_or_else(|| format!("{:?}: invalid line {}", manifest, i + 1))?;
+        entries.insert(PathBuf::from(file), digest.to_string());
+    }
+    Ok(entries)
+}
+
+/// Adds the checksums of `files` to the manifest in `output_base`
+///
+/// Entries of files that are not part of `checksums` are kept, so repeated runs into the same
+/// output tree extend the manifest.
+pub fn write_manifest(output_base: &Path, algo: ChecksumAlgo, checksums: &[(PathBuf, String)]) -> Result<(), String> {
+    let manifest = output_base.join(algo.manifest_name());
+    let mut entries = read_manifest(&manifest)?;
+    for (file, digest) in checksums {
+        let relative = file.strip_prefix(output_base).unwrap_or(file);
+        entries.insert(relative.to_path_buf(), digest.clone());
+    }
+
+    let temp = temp_path(&manifest);
+    let mut out = io::BufWriter::new(fs::File::create(&temp).map_err(|e| e.to_string())?);
+    for (file, digest) in &entries {
+        writeln!(out, "{}  {}", digest, file.to_string_lossy()).map_err(|e| e.to_string())?;
+    }
+    out.flush().map_err(|e| e.to_string())?;
+    drop(out);
+    fs::rename(&temp, &manifest).map_err(|e| e.to_string())
+}
+
+/// Re-hashes all files listed in the manifest in `output_base`
+///
+/// Returns the files that are missing or whose checksum does not match, with the reason.
+pub fn verify_manifest(output_base: &Path, algo: ChecksumAlgo) -> Result<Vec<(PathBuf, String)>, String> {
+    let manifest = output_base.join(algo.manifest_name());
+    if !manifest.exists() {
+        return Err(format!("no manifest found at {:?}", manifest));
+    }
+
+    let mut failures = Vec::new();
+    for (file, expected) in read_manifest(&manifest)? {
+        let path = output_base.join(&file);
+        match hash_file(&path, algo) {
+            Ok(digest) if digest == expected => (),
+            Ok(_) => failures.push((path, String::from("checksum mismatch"))),
+            Err(e) => failures.push((path, e)),
+        }
+    }
+    Ok(failures)
+}
@@ -0,0 +1,177 @@
+use crate::*;
+
+use std::sync::OnceLock;
+
+
+/// Linear sRGB to linear Display P3, both relative to D65
+const SRGB_TO_DISPLAY_P3: [[f32; 3]; 3] = [
+    [0.8224621, 0.1775380, 0.0000000],
+    [0.0331941, 0.9668058, 0.0000000],
+    [0.0170827, 0.0723974, 0.9105199],
+];
+
+/// Display P3 primaries adapted to the D50 profile connection space, as in Apple's profile
+const DISPLAY_P3_COLORANTS: [[f64; 3]; 3] = [
+    [0.515102, 0.241196, -0.001053],
+    [0.291965, 0.692245, 0.041885],
+    [0.157153, 0.066561, 0.784073],
+];
+
+/// Bradford adaptation from D65 to D50
+const D65_TO_D50: [f64; 9] = [
+    1.047882, 0.022918, -0.050217,
+    0.029586, 0.990478, -0.017075,
+    -0.009247, 0.015075, 0.751678,
+];
+
+const D50: [f64; 3] = [0.964203, 1.0, 0.824905];
+
+/// Parameters of the sRGB transfer function (`(a * x + b) ^ g` above `d`, `c * x` below)
+const SRGB_CURVE: [f64; 5] = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045];
+
+/// Value of the EXIF `ColorSpace` tag for anything but sRGB, whose actual space is given by the ICC profile
+const EXIF_UNCALIBRATED: u16 = 0xffff;
+
+static DISPLAY_P3_PROFILE: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Color space of converted outputs
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorSpace {
+    /// Untagged sRGB, which every viewer assumes
+    #[default]
+    Srgb,
+    /// Display P3 with an embedded ICC profile, the color space of Apple displays and Photos
+    DisplayP3,
+}
+
+
+impl ColorSpace {
+    /// Whether images in this color space can be stored by `encoder`
+    ///
+    /// Anything but sRGB needs an embedded ICC profile, which only JPEG and PNG outputs carry.
+    /// Outputs of other encoders stay sRGB.
+    pub fn is_supported_by(&self, encoder: EncoderType) -> bool {
+        match self {
+            ColorSpace::Srgb => true,
+            ColorSpace::DisplayP3 => matches!(encoder, EncoderType::JpegEncoder(_) | EncoderType::PngEncoder(..)),
+        }
+    }
+
+    /// ICC profile to embed into outputs, `None` for sRGB
+    pub fn icc_profile(&self) -> Option<&'static [u8]> {
+        match self {
+            ColorSpace::Srgb => None,
+            ColorSpace::DisplayP3 => Some(DISPLAY_P3_PROFILE.get_or_init(display_p3_profile)),
+        }
+    }
+
+    /// Value of the EXIF `ColorSpace` tag, `None` to keep the one of the raw
+    pub fn exif_value(&self) -> Option<u16> {
+        match self {
+            ColorSpace::Srgb => None,
+            ColorSpace::DisplayP3 => Some(EXIF_UNCALIBRATED),
+        }
+    }
+
+    /// Converts an sRGB image into this color space, `None` if that is sRGB
+    ///
+    /// The decoders render into sRGB, so this only changes the encoding of the colors, colors outside
+    /// of sRGB are not recovered.
+    pub fn convert(&self, image: &imagepipe::SRGBImage) -> Option<imagepipe::SRGBImage> {
+        let matrix = match self {
+            ColorSpace::Srgb => return None,
+            ColorSpace::DisplayP3 => SRGB_TO_DISPLAY_P3,
+        };
+        // both spaces share the sRGB transfer function
+        let linear = pixelops::srgb8_to_linear_table();
+        let converted: Vec<f32> = image.data.chunks_exact(3)
+            .flat_map(|pixel| {
+                let rgb = [0, 1, 2].map(|c| linear[pixel[c] as usize]);
+                matrix.map(|row| row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2])
+            })
+            .collect();
+        let data = pixelops::linear_to_srgb8(&converted, [0.0; 3], [1.0; 3]);
+        Some(imagepipe::SRGBImage { data, width: image.width, height: image.height })
+    }
+}
+
+/// Builds an ICC v4 display profile for Display P3 (matrix, parametric sRGB curves)
+fn display_p3_profile() -> Vec<u8> {
+    let curve = tag_para(&SRGB_CURVE);
+    let tags: [(&[u8; 4], Vec<u8>); 9] = [
+        (b"desc", tag_mluc("Display P3")),
+        (b"cprt", tag_mluc("No copyright, use freely")),
+        (b"wtpt", tag_xyz(D50)),
+        (b"rXYZ", tag_xyz(DISPLAY_P3_COLORANTS[0])),
+        (b"gXYZ", tag_xyz(DISPLAY_P3_COLORANTS[1])),
+        (b"bXYZ", tag_xyz(DISPLAY_P3_COLORANTS[2])),
+        (b"rTRC", curve.clone()),
+        (b"gTRC", curve.clone()),
+        (b"bTRC", curve),
+    ];
+    let chad = (b"chad", tag_sf32(&D65_TO_D50));
+
+    let mut table = Vec::new();
+    let mut data = Vec::new();
+    let tags_start = 128 + 4 + 12 * (tags.len() + 1);
+    for (signature, tag) in tags.iter().chain(std::iter::once(&chad)) {
+        table.extend_from_slice(*signature);
+        table.extend_from_slice(&((tags_start + data.len()) as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        data.extend_from_slice(tag);
+        // tags start on four byte boundaries
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+
+    let mut profile = Vec::with_capacity(tags_start + data.len());
+    profile.extend_from_slice(&((tags_start + data.len()) as u32).to_be_bytes());
+    profile.extend_from_slice(&[0; 4]); // preferred CMM
+    profile.extend_from_slice(&[4, 0x30, 0, 0]); // version 4.3
+    profile.extend_from_slice(b"mntrRGB XYZ ");
+    for value in [2022u16, 1, 1, 0, 0, 0] {
+        profile.extend_from_slice(&value.to_be_bytes());
+    }
+    profile.extend_from_slice(b"acspAPPL");
+    profile.extend_from_slice(&[0; 20]); // flags, manufacturer, model, attributes
+    profile.extend_from_slice(&[0; 4]); // perceptual rendering intent
+    profile.extend_from_slice(&xyz_number(D50));
+    profile.extend_from_slice(&[0; 48]); // creator, profile id, reserved
+    profile.extend_from_slice(&((tags.len() + 1) as u32).to_be_bytes());
+    profile.extend_from_slice(&table);
+    profile.extend_from_slice(&data);
+    profile
+}
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_number(xyz: [f64; 3]) -> Vec<u8> {
+    xyz.iter().flat_map(|v| s15_fixed16(*v)).collect()
+}
+
+fn tag_xyz(xyz: [f64; 3]) -> Vec<u8> {
+    [&b"XYZ \0\0\0\0"[..], &xyz_number(xyz)].concat()
+}
+
+fn tag_sf32(values: &[f64]) -> Vec<u8> {
+    [&b"sf32\0\0\0\0"[..], &values.iter().flat_map(|v| s15_fixed16(*v)).collect::<Vec<_>>()].concat()
+}
+
+/// Parametric curve of function type 3 (see `SRGB_CURVE`)
+fn tag_para(parameters: &[f64; 5]) -> Vec<u8> {
+    [&b"para\0\0\0\0\0\x03\0\0"[..], &parameters.iter().flat_map(|v| s15_fixed16(*v)).collect::<Vec<_>>()].concat()
+}
+
+/// Text in a single (English) localization
+fn tag_mluc(text: &str) -> Vec<u8> {
+    let text: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+    let mut tag = b"mluc\0\0\0\0".to_vec();
+    tag.extend_from_slice(&1u32.to_be_bytes()); // number of records
+    tag.extend_from_slice(&12u32.to_be_bytes()); // record size
+    tag.extend_from_slice(b"enUS");
+    tag.extend_from_slice(&(text.len() as u32).to_be_bytes());
+    tag.extend_from_slice(&28u32.to_be_bytes()); // offset of the text
+    tag.extend_from_slice(&text);
+    tag
+}
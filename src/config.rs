@@ -0,0 +1,176 @@
+use crate::*;
+
+use std::env;
+use std::ffi::OsString;
+
+
+const SYSTEM_CONFIG: &str = "/etc/raw-to-img/config.toml";
+const USER_CONFIG: &str = "raw-to-img/config.toml";
+const LOCAL_CONFIG: &str = "raw-to-img.toml";
+
+
+/// Config files in order of increasing precedence (system, user, current directory)
+pub fn config_files() -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from(SYSTEM_CONFIG)];
+
+    let user_config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    };
+    if let Some(dir) = user_config_dir {
+        files.push(dir.join(USER_CONFIG));
+    }
+
+    files.push(PathBuf::from(LOCAL_CONFIG));
+    files.into_iter().filter(|f| f.is_file()).collect()
+}
+
+/// Translates all discovered config files into command line arguments
+///
+/// Keys are named like the long options (`jpeg_quality = 80` becomes `--jpeg-quality=80`).
+/// Arguments from files with higher precedence come later, so they override earlier ones,
+/// just like the actual command line arguments which are appended after these. Flags set to
+/// `false` drop the flag from files with lower precedence.
+/// Settings of the selected profile (`[profile.<name>]`) override the top level settings of all files.
+/// If no profile is given, the `profile` key of the config files is used.
+pub fn config_args(profile: Option<&str>) -> Result<Vec<OsString>, String> {
+    let mut tables = Vec::new();
+    for file in config_files() {
+        let table = read_config(&file)?;
+        tables.push((file, table));
+    }
+
+    let default_profile = tables.iter().rev()
+        .find_map(|(_, table)| table.get("profile").and_then(|p| p.as_str()))
+        .map(|p| p.to_string());
+    let profile = profile.map(|p| p.to_string()).or(default_profile);
+
+    let mut args = Vec::new();
+    for (file, table) in &tables {
+        add_args(&mut args, table_to_args(table)
+            .map_err(|e| format!("{} (in {:?})", e, file))?);
+    }
+
+    if let Some(profile) = profile {
+        let mut found = false;
+        for (file, table) in &tables {
+            let profile_table = table.get("profile")
+                .and_then(|p| p.as_table())
+                .and_then(|p| p.get(&profile))
+                .and_then(|p| p.as_table());
+            if let Some(profile_table) = profile_table {
+                found = true;
+                add_args(&mut args, table_to_args(profile_table)
+                    .map_err(|e| format!("{} (in profile \"{}\" of {:?})", e, profile, file))?);
+            }
+        }
+
+        if !found {
+            return Err(format!("Profile \"{}\" not found in any config file", profile));
+        }
+    }
+
+    Ok(args.into_iter().map(|(_, arg)| arg).collect())
+}
+
+/// Names of the profiles defined in any of the config files, sorted and without duplicates
+pub fn profile_names() -> Result<Vec<String>, String> {
+    let mut names = Vec::new();
+    for file in config_files() {
+        if let Some(profiles) = read_config(&file)?.get("profile").and_then(|p| p.as_table()) {
+            names.extend(profiles.keys().cloned());
+        }
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Settings of all config files merged in order of precedence, with those of `profile` on top
+///
+/// This is meant for frontends that do not pass the settings through the command line parser.
+pub fn profile_settings(profile: &str) -> Result<toml::Table, String> {
+    let tables = config_files().iter()
+        .map(|file| read_config(file))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut settings = toml::Table::new();
+    for table in &tables {
+        settings.extend(table.iter()
+            .filter(|(key, value)| *key != "profile" && !value.is_table())
+            .map(|(key, value)| (key.clone(), value.clone())));
+    }
+    for table in &tables {
+        if let Some(profile_table) = table.get("profile").and_then(|p| p.get(profile)).and_then(|p| p.as_table()) {
+            settings.extend(profile_table.iter().map(|(key, value)| (key.clone(), value.clone())));
+        }
+    }
+    Ok(settings)
+}
+
+/// Finds the value of `--profile` in the raw command line arguments
+///
+/// This has to happen before the actual argument parsing, as the profile determines
+/// which arguments are passed to the parser.
+pub fn profile_from_args(args: &[OsString]) -> Option<String> {
+    let mut iter = args.iter().map(|a| a.to_string_lossy());
+    while let Some(arg) = iter.next() {
+        if arg == "--profile" {
+            return iter.next().map(|p| p.to_string());
+        } else if let Some(profile) = arg.strip_prefix("--profile=") {
+            return Some(profile.to_string());
+        }
+    }
+    None
+}
+
+fn read_config(path: &path::Path) -> Result<toml::Table, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read config {:?} ({})", path, e))?;
+    content.parse::<toml::Table>()
+        .map_err(|e| format!("Unable to parse config {:?} ({})", path, e))
+}
+
+/// Appends the arguments of a config table (see `table_to_args()`) to those of the tables before it
+fn add_args(args: &mut Vec<(String, OsString)>, table_args: Vec<(String, Option<OsString>)>) {
+    for (key, arg) in table_args {
+        match arg {
+            Some(arg) => args.push((key, arg)),
+            None => args.retain(|(k, _)| *k != key),
+        }
+    }
+}
+
+/// Arguments for the keys of a config table, `None` for flags that are turned off
+///
+/// Values are attached with `=`, as some options only accept them that way (e.g. `--quarantine=DIR`).
+fn table_to_args(table: &toml::Table) -> Result<Vec<(String, Option<OsString>)>, String> {
+    let mut args = Vec::new();
+    for (key, value) in table {
+        if key == "profile" {
+            continue;
+        }
+        let flag = format!("--{}", key.replace('_', "-"));
+        match value {
+            toml::Value::Boolean(true) => args.push((key.clone(), Some(flag.into()))),
+            toml::Value::Boolean(false) => args.push((key.clone(), None)),
+            toml::Value::Table(_) => (),
+            toml::Value::Array(values) => for value in values {
+                args.push((key.clone(), Some(value_to_arg(&flag, key, value)?)));
+            },
+            value => args.push((key.clone(), Some(value_to_arg(&flag, key, value)?))),
+        }
+    }
+    Ok(args)
+}
+
+fn value_to_arg(flag: &str, key: &str, value: &toml::Value) -> Result<OsString, String> {
+    let value = match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Integer(i) => i.to_string(),
+        toml::Value::Float(f) => f.to_string(),
+        toml::Value::Boolean(b) => b.to_string(),
+        _ => return Err(format!("Unsupported value for config key \"{}\"", key)),
+    };
+    Ok(format!("{}={}", flag, value).into())
+}
@@ -0,0 +1,74 @@
+use crate::*;
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+/// Unix socket accepting commands that steer the running batches
+///
+/// Every line sent to the socket is one command (`pause`, `resume`, `threads <n>`, `cancel` or
+/// `status`), which is answered with a single line. The socket file is removed when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+
+impl ControlSocket {
+    /// Listens on `path` from a background thread, `cancelled` is set by the `cancel` command
+    pub fn start(path: &Path, control: RunControl, cancelled: Arc<AtomicBool>) -> Result<ControlSocket, String> {
+        // a socket left behind by a previous run would make binding fail
+        if path.exists() && UnixStream::connect(path).is_err() {
+            let _ = fs::remove_file(path);
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("unable to listen on {:?} ({})", path, e))?;
+
+        std::thread::Builder::new()
+            .name(String::from("control-socket"))
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let _ = serve(stream, &control, &cancelled);
+                }
+            })
+            .map_err(|e| e.to_string())?;
+
+        Ok(ControlSocket { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve(stream: UnixStream, control: &RunControl, cancelled: &AtomicBool) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let reply = match execute(line?.trim(), control, cancelled) {
+            Ok(reply) => reply,
+            Err(e) => format!("error: {}", e),
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+fn execute(command: &str, control: &RunControl, cancelled: &AtomicBool) -> Result<String, String> {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("pause"), None) => { control.pause(); Ok(String::from("paused")) },
+        (Some("resume"), None) => { control.resume(); Ok(String::from("resumed")) },
+        (Some("cancel"), None) => { cancelled.store(true, Ordering::Relaxed); Ok(String::from("cancelled")) },
+        (Some("threads"), Some(threads)) => {
+            let threads = threads.parse().map_err(|_| format!("invalid number of threads '{}'", threads))?;
+            control.set_threads(threads)?;
+            Ok(format!("threads {}", threads))
+        },
+        (Some("status"), None) => Ok(format!("{} threads {}", if control.is_paused() { "paused" } else { "running" },
+                                             control.threads())),
+        _ => Err(format!("unknown command '{}' (expected pause, resume, threads <n>, cancel or status)", command)),
+    }
+}
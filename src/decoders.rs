@@ -0,0 +1,468 @@
+use crate::*;
+
+use std::process::Command;
+
+
+/// Linear sRGB to XYZ, relative to D65
+const SRGB_TO_XYZ: [[f64; 3]; 3] = [
+    [0.4124564, 0.3575761, 0.1804375],
+    [0.2126729, 0.7151522, 0.0721750],
+    [0.0193339, 0.1191920, 0.9503041],
+];
+
+/// Which backend to use for decoding raw files
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum DecoderChoice {
+    /// Try all available decoders in order until one succeeds
+    Auto,
+    Imagepipe,
+    /// Rawloader with the built-in demosaic algorithms
+    Rawloader,
+    /// LibRaw bindings (requires the `libraw` feature)
+    Libraw,
+    /// `dcraw_emu` subprocess (requires LibRaw's `dcraw_emu` in PATH)
+    Dcraw,
+}
+
+/// Raw data to decode, either a file or bytes that are already in memory
+///
+/// Decoding from memory needs no file system access at all, which is what the WebAssembly build
+/// relies on. Decoders that need a file (like `dcraw_emu`) reject in-memory input.
+#[derive(Copy, Clone)]
+pub enum RawInput<'a> {
+    File(&'a Path),
+    Bytes(&'a [u8]),
+}
+
+pub trait Decoder {
+    fn name(&self) -> &'static str;
+    fn decode(&self, input: RawInput, options: &DecodeOptions) -> Result<imagepipe::SRGBImage, String>;
+}
+
+pub struct ImagepipeDecoder;
+pub struct RawloaderDecoder;
+#[cfg(feature = "libraw")]
+pub struct LibrawDecoder;
+pub struct DcrawDecoder;
+
+
+impl RawInput<'_> {
+    /// Parses the raw data with rawloader
+    pub fn read_raw(&self) -> Result<rawloader::RawImage, String> {
+        let raw = match self {
+            RawInput::File(path) => rawloader::decode_file(path),
+            RawInput::Bytes(bytes) => rawloader::decode(&mut io::Cursor::new(*bytes)),
+        };
+        raw.map_err(|e| e.to_string())
+    }
+
+    /// Reads files that are no raws as regular images
+    fn read_image(&self) -> Result<imagepipe::SRGBImage, String> {
+        match self {
+            RawInput::File(path) => imagepipe::simple_decode_8bit(path, 0, 0),
+            RawInput::Bytes(bytes) => {
+                let image = image::load_from_memory(bytes).map_err(|e| e.to_string())?.to_rgb8();
+                let (width, height) = (image.width() as usize, image.height() as usize);
+                Ok(imagepipe::SRGBImage { width, height, data: image.into_raw() })
+            },
+        }
+    }
+}
+
+
+impl Decoder for ImagepipeDecoder {
+    fn name(&self) -> &'static str {
+        "imagepipe"
+    }
+
+    fn decode(&self, input: RawInput, options: &DecodeOptions) -> Result<imagepipe::SRGBImage, String> {
+        if options.demosaic.is_some() {
+            return Err(String::from("imagepipe does not support selecting the demosaic algorithm"));
+        }
+        let mut raw = match input.read_raw() {
+            Ok(raw) => raw,
+            // imagepipe reads files that are no raws as regular images
+            Err(_) if !options.corrects_mosaic() && options.fast_decode.is_none() => return input.read_image(),
+            Err(e) => return Err(e),
+        };
+        if raw.is_monochrome() {
+            return Err(String::from("monochrome raws are not supported"));
+        }
+        correct_mosaic(&mut raw, options)?;
+        // bounding the longer side in both directions works regardless of the orientation
+        let size = options.fast_decode.map(|f| raw.width.max(raw.height) / f.factor()).unwrap_or(0);
+        let mut pipeline = imagepipe::Pipeline::new_from_source(imagepipe::ImageSource::Raw(raw))?;
+        pipeline.globals.settings.maxwidth = size;
+        pipeline.globals.settings.maxheight = size;
+        pipeline.output_8bit(None)
+    }
+}
+
+impl Decoder for RawloaderDecoder {
+    fn name(&self) -> &'static str {
+        "rawloader"
+    }
+
+    fn decode(&self, input: RawInput, options: &DecodeOptions) -> Result<imagepipe::SRGBImage, String> {
+        develop_raw(input.read_raw()?, options)
+    }
+}
+
+#[cfg(feature = "libraw")]
+impl Decoder for LibrawDecoder {
+    fn name(&self) -> &'static str {
+        "libraw"
+    }
+
+    fn decode(&self, input: RawInput, options: &DecodeOptions) -> Result<imagepipe::SRGBImage, String> {
+        if options.corrects_mosaic() {
+            return Err(String::from("raw corrections are not supported by this decoder"));
+        }
+        if options.demosaic.is_some() {
+            return Err(String::from("libraw does not support selecting the demosaic algorithm"));
+        }
+
+        let buf = match input {
+            RawInput::File(path) => std::borrow::Cow::Owned(fs::read(path).map_err(|e| e.to_string())?),
+            RawInput::Bytes(bytes) => std::borrow::Cow::Borrowed(bytes),
+        };
+        let processor = libraw::Processor::new();
+        let processed = processor.process_8bit(&buf).map_err(|e| e.to_string())?;
+
+        let image = imagepipe::SRGBImage {
+            width: processed.width() as usize,
+            height: processed.height() as usize,
+            data: processed.to_vec(),
+        };
+        Ok(match options.fast_decode {
+            Some(fast_decode) => fast_decode.shrink(image, 1),
+            None => image,
+        })
+    }
+}
+
+impl Decoder for DcrawDecoder {
+    fn name(&self) -> &'static str {
+        "dcraw_emu"
+    }
+
+    fn decode(&self, input: RawInput, options: &DecodeOptions) -> Result<imagepipe::SRGBImage, String> {
+        let RawInput::File(path) = input else {
+            return Err(String::from("dcraw_emu can only decode files"));
+        };
+        if options.corrects_mosaic() {
+            return Err(String::from("raw corrections are not supported by this decoder"));
+        }
+
+        let mut command = Command::new("dcraw_emu");
+        command.args(["-w", "-Z", "-"]);
+        if let Some(demosaic) = options.demosaic {
+            command.args(["-q", &demosaic.dcraw_quality().to_string()]);
+        }
+        if options.fast_decode.is_some() {
+            // half size output, which skips demosaicing
+            command.arg("-h");
+        }
+        let output = command
+            .arg(path)
+            .output()
+            .map_err(|e| format!("unable to run dcraw_emu ({})", e))?;
+
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+
+        let image = parse_ppm(&output.stdout)?;
+        Ok(match options.fast_decode {
+            Some(fast_decode) => fast_decode.shrink(image, 2),
+            None => image,
+        })
+    }
+}
+
+/// Turns parsed raw data into an upright sRGB image, which is all the rawloader decoder does after parsing
+pub fn develop_raw(mut raw: rawloader::RawImage, options: &DecodeOptions) -> Result<imagepipe::SRGBImage, String> {
+    correct_mosaic(&mut raw, options)?;
+    let (image, reduced) = render_raw(&raw, options)?;
+    Ok(finish_raw(&raw, image, reduced, options))
+}
+
+/// Turns parsed raw data into an upright image of linear sRGB values in `0..=1`, without encoding them
+///
+/// Unlike `develop_raw()`, images are only shrunk for `fast_decode` as far as their mosaic can be binned.
+pub fn develop_raw_linear(mut raw: rawloader::RawImage, options: &DecodeOptions) -> Result<HdrImage, String> {
+    correct_mosaic(&mut raw, options)?;
+    let (linear, width, height) = render_linear(&raw, options)?;
+    let reduced = raw.width / width;
+    let [top, right, bottom, left] = raw.crops.map(|c| c / reduced.max(1));
+    let (data, width, height) = pixelops::crop_rgb8(&linear, width, height, [left, top, right, bottom]);
+    let (data, width, height) = pixelops::rotate_rgb8(&data, width, height, raw_rotation(&raw));
+    Ok(HdrImage { width, height, data })
+}
+
+/// Demosaics, white balances and color corrects raw data like the rawloader decoder, without cropping
+/// it and turning it upright yet
+///
+/// Returns the image along with the factor its size was reduced by for `fast_decode`.
+pub fn render_raw(raw: &rawloader::RawImage, options: &DecodeOptions) -> Result<(imagepipe::SRGBImage, usize), String> {
+    let (linear, width, height) = render_linear(raw, options)?;
+    let reduced = raw.width / width;
+    let data = pixelops::linear_to_srgb8(&linear, [0.0; 3], [1.0; 3]);
+    Ok((imagepipe::SRGBImage { width, height, data }, reduced))
+}
+
+/// Linear sRGB values in `0..=1` of the raw data rendered by `render_raw()`, along with their width and height
+fn render_linear(raw: &rawloader::RawImage, options: &DecodeOptions) -> Result<(Vec<f32>, usize, usize), String> {
+    let data: Vec<f32> = match raw.data {
+        rawloader::RawImageData::Integer(ref data) => data.iter().map(|v| *v as f32).collect(),
+        rawloader::RawImageData::Float(ref data) => data.clone(),
+    };
+
+    let (mut width, mut height) = (raw.width, raw.height);
+    let binned = options.fast_decode
+        .filter(|_| raw.cpp == 1 && raw.cfa.is_valid())
+        .and_then(|f| bin_mosaic(&data, width, height, &raw.cfa, f.factor()));
+    let rgb = if let Some((binned, binned_width, binned_height)) = binned {
+        (width, height) = (binned_width, binned_height);
+        binned
+    } else if raw.cpp == 3 {
+        data
+    } else if raw.cpp == 1 && raw.cfa.is_valid() {
+        options.demosaic.unwrap_or(Demosaic::Bilinear).apply(&data, width, height, &raw.cfa)?
+    } else if raw.is_monochrome() {
+        data.iter().flat_map(|v| [*v, *v, *v]).collect()
+    } else {
+        return Err(format!("unsupported number of components per pixel ({})", raw.cpp));
+    };
+
+    // monochrome sensors have no white balance and a single black and white level
+    let channel = |c: usize| if raw.is_monochrome() { 0 } else { c };
+    let wb = if raw.wb_coeffs[1] > 0.0 && !raw.wb_coeffs[0].is_nan() && !raw.is_monochrome() {
+        [raw.wb_coeffs[0] / raw.wb_coeffs[1], 1.0, raw.wb_coeffs[2] / raw.wb_coeffs[1]]
+    } else {
+        [1.0, 1.0, 1.0]
+    };
+    let black: [f32; 3] = std::array::from_fn(|c| raw.blacklevels[channel(c)] as f32);
+    let scale: [f32; 3] = std::array::from_fn(|c| wb[c] / (raw.whitelevels[channel(c)] as f32 - black[c]));
+    // clipped highlights stay white instead of turning magenta
+    let normalized = |pixel: &[f32]| -> [f32; 3] {
+        std::array::from_fn(|c| ((pixel[c] - black[c]) * scale[c]).clamp(0.0, 1.0))
+    };
+    let linear = match camera_to_srgb(raw)? {
+        Some(matrix) => rgb.chunks_exact(3)
+            .flat_map(|pixel| {
+                let camera = normalized(pixel);
+                matrix.map(|row| (row[0] * camera[0] + row[1] * camera[1] + row[2] * camera[2]).clamp(0.0, 1.0))
+            })
+            .collect(),
+        None => rgb.chunks_exact(3).flat_map(normalized).collect(),
+    };
+    Ok((linear, width, height))
+}
+
+/// Matrix converting white balanced camera colors into linear sRGB, `None` for monochrome raws
+///
+/// Built from the XYZ to camera matrix of the raw like dcraw does, with the rows normalized so that
+/// neutral colors stay neutral. Fails for cameras without a known matrix, rather than guessing their colors.
+pub fn camera_to_srgb(raw: &rawloader::RawImage) -> Result<Option<[[f32; 3]; 3]>, String> {
+    if raw.is_monochrome() {
+        return Ok(None);
+    }
+    let srgb_to_camera: [[f64; 3]; 3] = std::array::from_fn(|i| std::array::from_fn(|j| {
+        (0..3).map(|k| raw.xyz_to_cam[i][k] as f64 * SRGB_TO_XYZ[k][j]).sum()
+    }));
+    let normalized = srgb_to_camera.map(|row| {
+        let sum: f64 = row.iter().sum();
+        row.map(|v| v / sum)
+    });
+    Some(normalized)
+        .filter(|m| m.iter().flatten().all(|v| v.is_finite()))
+        .and_then(invert_matrix)
+        .map(|m| Some(m.map(|row| row.map(|v| v as f32))))
+        .ok_or_else(|| format!("no color matrix known for {} {}", raw.make, raw.model))
+}
+
+/// Crops an image rendered by `render_raw()` to the area the camera marks as valid, turns it upright
+/// and shrinks it for `fast_decode`
+pub fn finish_raw(raw: &rawloader::RawImage, image: imagepipe::SRGBImage, reduced: usize, options: &DecodeOptions) -> imagepipe::SRGBImage {
+    // rawloader lists the crops as top, right, bottom and left, in photosites
+    let [top, right, bottom, left] = raw.crops.map(|c| c / reduced.max(1));
+    let (data, width, height) = pixelops::crop_rgb8(&image.data, image.width, image.height, [left, top, right, bottom]);
+    let (data, width, height) = pixelops::rotate_rgb8(&data, width, height, raw_rotation(raw));
+
+    let image = imagepipe::SRGBImage { width, height, data };
+    match options.fast_decode {
+        Some(fast_decode) => fast_decode.shrink(image, reduced),
+        None => image,
+    }
+}
+
+/// Rotation that turns a raw upright
+fn raw_rotation(raw: &rawloader::RawImage) -> pixelops::Rotation {
+    match raw.orientation {
+        rawloader::Orientation::Rotate90 => pixelops::Rotation::Cw90,
+        rawloader::Orientation::Rotate180 => pixelops::Rotation::Cw180,
+        rawloader::Orientation::Rotate270 => pixelops::Rotation::Cw270,
+        _ => pixelops::Rotation::None,
+    }
+}
+
+/// Builds the list of decoders that will be tried in order for the given choice
+///
+/// With `gpu` set, the gpu decoder is tried first if this build supports it, unless a specific
+/// CPU decoder was chosen.
+pub fn decoder_chain(choice: DecoderChoice, gpu: bool) -> Vec<Box<dyn Decoder>> {
+    let mut chain: Vec<Box<dyn Decoder>> = Vec::new();
+    #[cfg(feature = "gpu")]
+    if gpu && choice == DecoderChoice::Auto {
+        chain.push(Box::new(GpuDecoder));
+    }
+    #[cfg(not(feature = "gpu"))]
+    let _ = gpu;
+
+    chain.extend(cpu_decoder_chain(choice));
+    chain
+}
+
+fn cpu_decoder_chain(choice: DecoderChoice) -> Vec<Box<dyn Decoder>> {
+    match choice {
+        DecoderChoice::Imagepipe => vec![Box::new(ImagepipeDecoder)],
+        DecoderChoice::Rawloader => vec![Box::new(RawloaderDecoder)],
+        #[cfg(feature = "libraw")]
+        DecoderChoice::Libraw => vec![Box::new(LibrawDecoder)],
+        #[cfg(not(feature = "libraw"))]
+        DecoderChoice::Libraw => vec![],
+        DecoderChoice::Dcraw => vec![Box::new(DcrawDecoder)],
+        DecoderChoice::Auto => {
+            let mut chain: Vec<Box<dyn Decoder>> = vec![Box::new(ImagepipeDecoder), Box::new(RawloaderDecoder)];
+            #[cfg(feature = "libraw")]
+            chain.push(Box::new(LibrawDecoder));
+            chain.push(Box::new(DcrawDecoder));
+            chain
+        },
+    }
+}
+
+/// Tries each decoder of the chain until one succeeds
+pub fn decode_with_chain(input: RawInput, choice: DecoderChoice, gpu: bool, options: &DecodeOptions) -> Result<imagepipe::SRGBImage, String> {
+    let chain = decoder_chain(choice, gpu);
+    if chain.is_empty() {
+        return Err(String::from("selected decoder is not available in this build"));
+    }
+
+    let mut errors = Vec::new();
+    for decoder in chain {
+        match decoder.decode(input, options) {
+            Ok(img) => return Ok(img),
+            Err(e) => errors.push(format!("{}: {}", decoder.name(), e)),
+        }
+    }
+
+    Err(errors.join("; "))
+}
+
+fn parse_ppm(bytes: &[u8]) -> Result<imagepipe::SRGBImage, String> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if pos < bytes.len() && bytes[pos] == b'#' {
+            while pos < bytes.len() && bytes[pos] != b'\n' {
+                pos += 1;
+            }
+            continue;
+        }
+        let start = pos;
+        while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(String::from("truncated ppm header"));
+        }
+        fields.push(String::from_utf8_lossy(&bytes[start..pos]).to_string());
+    }
+    pos += 1;
+
+    if fields[0] != "P6" || fields[3] != "255" {
+        return Err(String::from("unsupported ppm format"));
+    }
+    let width: usize = fields[1].parse().map_err(|_| String::from("invalid ppm width"))?;
+    let height: usize = fields[2].parse().map_err(|_| String::from("invalid ppm height"))?;
+
+    let data = bytes.get(pos..pos + width * height * 3)
+        .ok_or_else(|| String::from("truncated ppm data"))?
+        .to_vec();
+
+    Ok(imagepipe::SRGBImage { width, height, data })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// XYZ to linear sRGB, which makes the camera colors of `red_raw()` sRGB colors
+    const XYZ_TO_SRGB: [[f32; 3]; 4] = [
+        [3.2404542, -1.5371385, -0.4985314],
+        [-0.9692660, 1.8760108, 0.0415560],
+        [0.0556434, -0.2040259, 1.0572252],
+        [0.0, 0.0, 0.0],
+    ];
+
+    /// A 12x8 RGGB raw with saturated red photosites only, 2 photosites cropped off each edge and rotated by 90 degrees
+    fn red_raw() -> rawloader::RawImage {
+        let cfa = rawloader::CFA::new("RGGB");
+        let data = (0..8).flat_map(|row| (0..12).map(move |col| (row, col)))
+            .map(|(row, col)| if cfa.color_at(row, col) == 0 { 4095 } else { 0 })
+            .collect();
+        rawloader::RawImage {
+            make: String::from("Test"),
+            model: String::from("Bayer"),
+            clean_make: String::from("Test"),
+            clean_model: String::from("Bayer"),
+            width: 12,
+            height: 8,
+            cpp: 1,
+            wb_coeffs: [1.0, 1.0, 1.0, f32::NAN],
+            whitelevels: [4095; 4],
+            blacklevels: [0; 4],
+            xyz_to_cam: XYZ_TO_SRGB,
+            cfa,
+            crops: [2, 2, 2, 2],
+            blackareas: Vec::new(),
+            orientation: rawloader::Orientation::Rotate90,
+            data: rawloader::RawImageData::Integer(data),
+        }
+    }
+
+    fn assert_red(image: &imagepipe::SRGBImage) {
+        assert_eq!(image.data.len(), image.width * image.height * 3);
+        for pixel in image.data.chunks_exact(3) {
+            assert!(pixel[0] > 250 && pixel[1] < 5 && pixel[2] < 5, "{:?} is not red", pixel);
+        }
+    }
+
+    #[test]
+    fn fast_decode_bins_crops_and_rotates() {
+        // 12x8 binned to 6x4, cropped by one binned pixel on each side to 4x2, turned upright to 2x4
+        let half = DecodeOptions { fast_decode: Some(FastDecode::Half), ..DecodeOptions::default() };
+        let image = develop_raw(red_raw(), &half).unwrap();
+        assert_eq!((image.width, image.height), (2, 4));
+        assert_red(&image);
+
+        // 12x8 binned to 3x2, the crops of half a binned pixel are dropped
+        let quarter = DecodeOptions { fast_decode: Some(FastDecode::Quarter), ..DecodeOptions::default() };
+        let image = develop_raw(red_raw(), &quarter).unwrap();
+        assert_eq!((image.width, image.height), (2, 3));
+        assert_red(&image);
+    }
+
+    #[test]
+    fn full_decode_keeps_the_cropped_size() {
+        let image = develop_raw(red_raw(), &DecodeOptions::default()).unwrap();
+        assert_eq!((image.width, image.height), (4, 8));
+        assert_red(&image);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::*;
+
+
+/// Algorithm that interpolates the missing colors of every photosite
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Demosaic {
+    /// Copies the colors of each block of the pattern (2x2, or 3x3 on X-Trans sensors) to all of its pixels (for previews)
+    Fast,
+    /// Averages the neighbours of each color in a 3x3 window
+    Bilinear,
+    /// Aliasing minimization and zipper elimination (`dcraw_emu` with LibRaw's demosaic pack)
+    Amaze,
+    /// Linear minimum mean square error interpolation (`dcraw_emu` with LibRaw's demosaic pack)
+    Lmmse,
+}
+
+/// Reduced resolution decoding for previews
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum FastDecode {
+    /// Half the width and height
+    Half,
+    /// A quarter of the width and height
+    Quarter,
+}
+
+
+impl Demosaic {
+    /// Interpolation quality passed to `dcraw_emu -q`
+    pub fn dcraw_quality(&self) -> u8 {
+        match self {
+            Demosaic::Fast | Demosaic::Bilinear => 0,
+            Demosaic::Lmmse => 9,
+            Demosaic::Amaze => 10,
+        }
+    }
+
+    /// Demosaics single channel raw data into interleaved RGB, if the algorithm is implemented here
+    pub fn apply(&self, data: &[f32], width: usize, height: usize, cfa: &rawloader::CFA) -> Result<Vec<f32>, String> {
+        match self {
+            Demosaic::Fast => match fast_block_size(cfa) {
+                Some(block) if width >= block && height >= block => Ok(demosaic_fast(data, width, height, cfa, block)),
+                _ => Ok(demosaic_bilinear(data, width, height, cfa)),
+            },
+            // every 3x3 window contains all colors, both on Bayer and X-Trans sensors
+            Demosaic::Bilinear => Ok(demosaic_bilinear(data, width, height, cfa)),
+            Demosaic::Amaze | Demosaic::Lmmse => Err(String::from("demosaic algorithm is only supported by dcraw_emu")),
+        }
+    }
+}
+
+impl FastDecode {
+    /// Number of photosites along each side that are combined into one pixel
+    pub fn factor(&self) -> usize {
+        match self {
+            FastDecode::Half => 2,
+            FastDecode::Quarter => 4,
+        }
+    }
+
+    /// Scales an image that a decoder already reduced by `reduced` down the rest of the way
+    pub fn shrink(&self, image: imagepipe::SRGBImage, reduced: usize) -> imagepipe::SRGBImage {
+        let remaining = self.factor() / reduced.max(1);
+        if remaining <= 1 {
+            return image;
+        }
+        let width = (image.width / remaining).max(1);
+        let height = (image.height / remaining).max(1);
+        let data = pixelops::resize_rgb8(&image.data, image.width, image.height, width, height);
+        imagepipe::SRGBImage { data, width, height }
+    }
+}
+
+/// Combines blocks of `factor` x `factor` photosites into one RGB pixel, which replaces demosaicing
+///
+/// Only sensors with a 2x2 pattern are supported, as only those have every color in each block.
+/// Returns the interleaved RGB data, width and height.
+pub fn bin_mosaic(data: &[f32], width: usize, height: usize, cfa: &rawloader::CFA, factor: usize) -> Option<(Vec<f32>, usize, usize)> {
+    if cfa.width != 2 || cfa.height != 2 || factor % 2 != 0 {
+        return None;
+    }
+    let (binned_width, binned_height) = (width / factor, height / factor);
+    if binned_width == 0 || binned_height == 0 {
+        return None;
+    }
+
+    let mut out = vec![0.0; binned_width * binned_height * 3];
+    for (i, pixel) in out.chunks_exact_mut(3).enumerate() {
+        let (top, left) = (i / binned_width * factor, i % binned_width * factor);
+        let mut sums = [0.0f32; 3];
+        let mut counts = [0u32; 3];
+        for row in top..top + factor {
+            for col in left..left + factor {
+                let color = color_at(cfa, row, col);
+                sums[color] += data[row * width + col];
+                counts[color] += 1;
+            }
+        }
+        for ((value, sum), count) in pixel.iter_mut().zip(sums).zip(counts) {
+            *value = sum / count.max(1) as f32;
+        }
+    }
+    Some((out, binned_width, binned_height))
+}
+
+fn color_at(cfa: &rawloader::CFA, row: usize, col: usize) -> usize {
+    match cfa.color_at(row, col) {
+        3 => 1,
+        color => color,
+    }
+}
+
+/// Size of the smallest square blocks that contain every color of the pattern
+fn fast_block_size(cfa: &rawloader::CFA) -> Option<usize> {
+    match (cfa.width, cfa.height) {
+        (2, 2) => Some(2),
+        // X-Trans
+        (6, 6) => Some(3),
+        _ => None,
+    }
+}
+
+fn demosaic_fast(data: &[f32], width: usize, height: usize, cfa: &rawloader::CFA, block: usize) -> Vec<f32> {
+    let mut out = vec![0.0; width * height * 3];
+
+    for row in 0..height {
+        // blocks of images whose size is no multiple of the block size overlap at the last row and column
+        let top = (row / block * block).min(height - block);
+        for col in 0..width {
+            let left = (col / block * block).min(width - block);
+            let mut sums = [0.0f32; 3];
+            let mut counts = [0u32; 3];
+            for r in top..top + block {
+                for c in left..left + block {
+                    let color = color_at(cfa, r, c);
+                    sums[color] += data[r * width + c];
+                    counts[color] += 1;
+                }
+            }
+
+            let pixel = &mut out[(row * width + col) * 3..(row * width + col + 1) * 3];
+            for ((value, sum), count) in pixel.iter_mut().zip(sums).zip(counts) {
+                *value = sum / count.max(1) as f32;
+            }
+        }
+    }
+
+    out
+}
+
+fn demosaic_bilinear(data: &[f32], width: usize, height: usize, cfa: &rawloader::CFA) -> Vec<f32> {
+    let mut out = vec![0.0; width * height * 3];
+
+    for row in 0..height {
+        for col in 0..width {
+            let mut sums = [0.0f32; 3];
+            let mut counts = [0u32; 3];
+
+            for dy in -1i64..=1 {
+                for dx in -1i64..=1 {
+                    let (r, c) = (row as i64 + dy, col as i64 + dx);
+                    if r < 0 || c < 0 || r >= height as i64 || c >= width as i64 {
+                        continue;
+                    }
+                    let color = color_at(cfa, r as usize, c as usize);
+                    sums[color] += data[r as usize * width + c as usize];
+                    counts[color] += 1;
+                }
+            }
+
+            let pixel = &mut out[(row * width + col) * 3..(row * width + col + 1) * 3];
+            for ((value, sum), count) in pixel.iter_mut().zip(sums).zip(counts) {
+                if count > 0 {
+                    *value = sum / count as f32;
+                }
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Photosites whose value is the number of their color (1 red, 2 green, 3 blue)
+    fn mosaic(cfa: &rawloader::CFA, width: usize, height: usize) -> Vec<f32> {
+        (0..height).flat_map(|row| (0..width).map(move |col| (color_at(cfa, row, col) + 1) as f32)).collect()
+    }
+
+    #[test]
+    fn fast_demosaic_of_x_trans_finds_all_colors() {
+        let cfa = rawloader::CFA::new("GGRGGBGGBGGRBRGRBGGGBGGRGGRGGBRBGBRG");
+        assert_eq!(fast_block_size(&cfa), Some(3));
+
+        // 7x7 is no multiple of the block size, so the last blocks overlap
+        let rgb = Demosaic::Fast.apply(&mosaic(&cfa, 7, 7), 7, 7, &cfa).unwrap();
+        assert_eq!(rgb.len(), 7 * 7 * 3);
+        for pixel in rgb.chunks_exact(3) {
+            assert_eq!(pixel, [1.0, 2.0, 3.0]);
+        }
+    }
+
+    #[test]
+    fn fast_demosaic_of_bayer_uses_2x2_blocks() {
+        let cfa = rawloader::CFA::new("RGGB");
+        assert_eq!(fast_block_size(&cfa), Some(2));
+
+        let rgb = Demosaic::Fast.apply(&mosaic(&cfa, 5, 3), 5, 3, &cfa).unwrap();
+        for pixel in rgb.chunks_exact(3) {
+            assert_eq!(pixel, [1.0, 2.0, 3.0]);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use crate::*;
+
+
+/// Applies all configured adjustments to a freshly decoded image before it is encoded
+///
+/// Cropping happens after the corrections, so those depending on the optical center see the full frame.
+/// Overlays are added to the final image. Adjustments that depend on the input file (camera matching,
+/// presets, lens corrections and annotations) are skipped without an `input`, e.g. for images decoded
+/// from memory.
+pub fn develop(image: &mut imagepipe::SRGBImage, input: Option<&Path>, settings: &JobSettings) {
+    if let (true, Some(input)) = (settings.match_embedded, input) {
+        if let Err(e) = match_embedded(image, input) {
+            println!("Unable to match {:?} to its embedded preview: {}", input, e);
+        }
+    }
+
+    let preset = input.and_then(|i| settings.presets.as_ref()?.for_file(i));
+    if let Some(preset) = preset {
+        preset.apply(image);
+    }
+    settings.adjustments.apply(image);
+
+    if let (Some(lenses), Some(input)) = (&settings.lens_database, input) {
+        correct_lens(image, input, lenses, preset.and_then(|p| p.lens_profile.as_deref()));
+    }
+
+    if let Some(base) = settings.invert_negative {
+        invert_negative(image, base);
+    }
+
+    if !settings.tone.is_identity() {
+        settings.tone.apply(image);
+    }
+
+    settings.style.apply(image);
+    if let Some(lut) = &settings.lut {
+        lut.apply(image);
+    }
+
+    if let Some(preset) = preset {
+        preset.crop(image);
+    }
+    settings.adjustments.reframe(image);
+
+    if let (Some(template), Some(input)) = (&settings.annotation, input) {
+        annotate(image, input, template);
+    }
+
+    if let Some(watermark) = &settings.watermark {
+        watermark.apply(image);
+    }
+
+    if !settings.canvas.is_identity() {
+        settings.canvas.apply(image);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::*;
+
+
+/// Whether `input` is an http(s) URL rather than a local path
+pub fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+/// Local path below `dir` that `url` is downloaded to, mirroring the path of the URL
+pub fn download_path(url: &str, dir: &Path) -> Result<PathBuf, String> {
+    let location = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let location = location.split(['?', '#']).next().unwrap_or_default();
+    let segments: Vec<_> = location.split('/')
+        .skip(1) // host
+        .filter(|s| !s.is_empty() && *s != ".")
+        .collect();
+
+    if segments.is_empty() || segments.contains(&"..") {
+        return Err(format!("unable to derive a file name from {:?}", url));
+    }
+    Ok(segments.iter().fold(dir.to_path_buf(), |path, segment| path.join(segment)))
+}
+
+/// Reads a list of URLs, one per line (empty lines and lines starting with `#` are skipped)
+pub fn read_url_list(path: &Path) -> Result<Vec<String>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("unable to read {:?} ({})", path, e))?;
+    let urls: Vec<_> = content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+
+    match urls.iter().find(|url| !is_url(url)) {
+        Some(url) => Err(format!("{:?} is not an http(s) URL", url)),
+        None => Ok(urls),
+    }
+}
+
+/// Downloads `url` below `dir` and returns the path of the downloaded file
+#[cfg(feature = "http")]
+pub fn download(url: &str, dir: &Path) -> Result<PathBuf, String> {
+    let path = download_path(url, dir)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+
+    let start = Instant::now();
+    println!("Downloading {}", url);
+    let response = ureq::get(url).call()
+        .map_err(|e| format!("unable to download {} ({})", url, e))?;
+
+    let temp = temp_path(&path);
+    let mut file = fs::File::create(&temp).map_err(|e| e.to_string())?;
+    if let Err(e) = io::copy(&mut response.into_reader(), &mut file) {
+        let _ = fs::remove_file(&temp);
+        return Err(format!("unable to download {} ({})", url, e));
+    }
+    finish_temp(&temp, &path).map_err(|e| e.to_string())?;
+
+    println!("Downloaded {} in {}", url, fmt_duration(&start.elapsed()));
+    Ok(path)
+}
+
+#[cfg(not(feature = "http"))]
+pub fn download(url: &str, _dir: &Path) -> Result<PathBuf, String> {
+    Err(format!("unable to download {} (this build does not support http inputs, enable the http feature)", url))
+}
@@ -0,0 +1,47 @@
+use crate::*;
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::sync::Mutex;
+
+
+/// Files seen so far in a run, to process files present in several inputs only once
+///
+/// Dual-slot cameras write every frame to both cards, so merging the cards would otherwise convert
+/// each frame twice. Files count as duplicates if they have the same name and size or the same content.
+#[derive(Default)]
+pub struct DuplicateTracker {
+    seen: Mutex<SeenFiles>,
+}
+
+#[derive(Default)]
+struct SeenFiles {
+    /// (input, output) of the first file with a given name and size
+    by_name: HashMap<(OsString, u64), (PathBuf, PathBuf)>,
+    /// (input, output) of the first file with a given SHA-256
+    by_hash: HashMap<String, (PathBuf, PathBuf)>,
+}
+
+
+impl DuplicateTracker {
+    /// Registers `input` (converted to `output`) and returns the input and output of an earlier
+    /// file it duplicates, `None` if it is the first of its kind
+    pub fn claim(&self, input: &Path, output: &Path) -> Result<Option<(PathBuf, PathBuf)>, Error> {
+        let size = input.metadata().map_err(|e| Error::io(input, e))?.len();
+        let name_key = (input.file_name().unwrap_or_default().to_os_string(), size);
+        if let Some(original) = self.seen.lock().unwrap().by_name.get(&name_key) {
+            return Ok(Some(original.clone()));
+        }
+
+        // hashing takes a while, so the lock is only held for looking up and inserting
+        let hash = hash_file(input, ChecksumAlgo::Sha256).map_err(|e| Error::io(input, io::Error::other(e)))?;
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(original) = seen.by_name.get(&name_key).or_else(|| seen.by_hash.get(&hash)) {
+            return Ok(Some(original.clone()));
+        }
+        let files = (input.to_path_buf(), output.to_path_buf());
+        seen.by_name.insert(name_key, files.clone());
+        seen.by_hash.insert(hash, files);
+        Ok(None)
+    }
+}
@@ -0,0 +1,174 @@
+use crate::*;
+
+
+/// Color temperature the camera white balance is assumed to correspond to when importing temperatures
+const DAYLIGHT_KELVIN: f32 = 5500.0;
+
+/// darktable orientations that are plain rotations (see `flip` in darktable's history)
+const DARKTABLE_ROTATIONS: [(i32, pixelops::Rotation); 4] = [
+    (0, pixelops::Rotation::None),
+    (3, pixelops::Rotation::Cw180),
+    (5, pixelops::Rotation::Cw270),
+    (6, pixelops::Rotation::Cw90),
+];
+
+
+/// Reads the adjustments of `file` from the sidecar of a raw editor, `None` if there is none
+///
+/// A best-effort subset of the edits is imported: exposure, crop and rotation from RawTherapee
+/// (`IMG_1234.CR2.pp3`) and darktable (`IMG_1234.CR2.xmp`), as well as the white balance from RawTherapee.
+/// Everything else (curves, local adjustments, ...) is ignored. RawTherapee sidecars take precedence.
+pub fn import_edits(file: &Path) -> Result<Option<Adjustments>, String> {
+    let mut pp3 = file.as_os_str().to_owned();
+    pp3.push(".pp3");
+    let pp3 = PathBuf::from(pp3);
+    if pp3.is_file() {
+        let content = fs::read_to_string(&pp3).map_err(|e| format!("Unable to read {:?} ({})", pp3, e))?;
+        return Ok(Some(parse_pp3(&content)));
+    }
+
+    for sidecar in sidecar_paths(file) {
+        if let Ok(content) = fs::read_to_string(&sidecar) {
+            if content.contains("darktable:history") {
+                return Ok(Some(parse_darktable_xmp(&content)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Adjustments of a RawTherapee processing profile
+fn parse_pp3(content: &str) -> Adjustments {
+    let mut adjustments = Adjustments::default();
+    let mut section = "";
+    // the temperature is stored even if the camera white balance is used
+    let mut camera_white_balance = true;
+    let mut white_balance = (None, 1.0);
+    let mut crop = (false, [0usize; 4]);
+
+    for line in content.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name;
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        match (section, key) {
+            ("Exposure", "Compensation") => adjustments.exposure = value.parse().unwrap_or(0.0),
+            ("White Balance", "Setting") => camera_white_balance = value == "Camera",
+            ("White Balance", "Temperature") => white_balance.0 = value.parse::<f32>().ok(),
+            ("White Balance", "Green") => white_balance.1 = value.parse().unwrap_or(1.0),
+            ("Crop", "Enabled") => crop.0 = value == "true",
+            ("Crop", "X") => crop.1[0] = value.parse().unwrap_or(0),
+            ("Crop", "Y") => crop.1[1] = value.parse().unwrap_or(0),
+            ("Crop", "W") => crop.1[2] = value.parse().unwrap_or(0),
+            ("Crop", "H") => crop.1[3] = value.parse().unwrap_or(0),
+            ("Coarse Transformation", "Rotate") => adjustments.rotation = match value {
+                "90" => pixelops::Rotation::Cw90,
+                "180" => pixelops::Rotation::Cw180,
+                "270" => pixelops::Rotation::Cw270,
+                _ => pixelops::Rotation::None,
+            },
+            _ => (),
+        }
+    }
+
+    if let (false, (Some(temperature), green)) = (camera_white_balance, white_balance) {
+        adjustments.white_balance = Some(temperature_multipliers(temperature, green));
+    }
+    if crop.0 && crop.1[2] > 0 && crop.1[3] > 0 {
+        adjustments.crop = Some(Crop::Rect(crop.1));
+    }
+    adjustments
+}
+
+/// Adjustments of the enabled history items of a darktable XMP sidecar
+///
+/// Items with compressed parameters are skipped.
+fn parse_darktable_xmp(content: &str) -> Adjustments {
+    let mut adjustments = Adjustments::default();
+    let history_end: usize = attribute(content, "darktable:history_end").and_then(|e| e.parse().ok()).unwrap_or(usize::MAX);
+    let mut crop = None;
+
+    for (num, item) in content.split("<rdf:li").skip(1).enumerate() {
+        let Some(operation) = attribute(item, "darktable:operation") else {
+            continue;
+        };
+        let num = attribute(item, "darktable:num").and_then(|n| n.parse().ok()).unwrap_or(num);
+        if num >= history_end {
+            continue;
+        }
+        let enabled = attribute(item, "darktable:enabled") == Some("1");
+        let Some(params) = attribute(item, "darktable:params").and_then(decode_hex) else {
+            continue;
+        };
+
+        match operation {
+            "exposure" => adjustments.exposure = if enabled { read_f32(&params, 8).unwrap_or(0.0) } else { 0.0 },
+            "crop" if enabled => crop = (0..4).map(|i| read_f32(&params, i * 4)).collect::<Option<Vec<_>>>(),
+            "crop" => crop = None,
+            "flip" => {
+                let orientation = params.get(..4).and_then(|b| b.try_into().ok()).map(i32::from_le_bytes);
+                adjustments.rotation = DARKTABLE_ROTATIONS.iter()
+                    .find(|(o, _)| enabled && Some(*o) == orientation)
+                    .map(|(_, rotation)| *rotation)
+                    .unwrap_or_default();
+            },
+            _ => (),
+        }
+    }
+
+    // darktable crops the rotated image, the crop is applied before rotating here
+    if let Some([left, top, right, bottom]) = crop.as_deref().and_then(|c| <[f32; 4]>::try_from(c).ok()) {
+        adjustments.crop = Some(Crop::Relative(match adjustments.rotation {
+            pixelops::Rotation::None => [left, top, right, bottom],
+            pixelops::Rotation::Cw90 => [top, 1.0 - right, bottom, 1.0 - left],
+            pixelops::Rotation::Cw180 => [1.0 - right, 1.0 - bottom, 1.0 - left, 1.0 - top],
+            pixelops::Rotation::Cw270 => [1.0 - bottom, left, 1.0 - top, right],
+        }));
+    }
+    adjustments
+}
+
+/// Value of the XML attribute `name` in `element`
+fn attribute<'a>(element: &'a str, name: &str) -> Option<&'a str> {
+    let start = element.find(&format!("{}=\"", name))? + name.len() + 2;
+    let end = element[start..].find('"')?;
+    Some(&element[start..start + end])
+}
+
+/// Decodes hex encoded parameters, `None` for compressed (`gz...`) or malformed ones
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn read_f32(params: &[u8], offset: usize) -> Option<f32> {
+    Some(f32::from_le_bytes(params.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// Channel multipliers turning the camera white balance into a white balance of `kelvin`
+///
+/// The camera white balance is assumed to be daylight. `green` is RawTherapee's tint, where values
+/// above 1 make the image more magenta.
+fn temperature_multipliers(kelvin: f32, green: f32) -> [f32; 3] {
+    let reference = blackbody_rgb(DAYLIGHT_KELVIN);
+    let target = blackbody_rgb(kelvin);
+    let [r, g, b]: [f32; 3] = std::array::from_fn(|c| reference[c] / target[c].max(f32::EPSILON));
+    [r / g, 1.0 / green.max(f32::EPSILON), b / g]
+}
+
+/// Approximate linear color of a black body at `kelvin` (after Tanner Helland)
+fn blackbody_rgb(kelvin: f32) -> [f32; 3] {
+    let t = kelvin.clamp(2000.0, 40000.0) / 100.0;
+    let r = if t <= 66.0 { 255.0 } else { 329.69873 * (t - 60.0).powf(-0.13320476) };
+    let g = if t <= 66.0 { 99.4708 * t.ln() - 161.11957 } else { 288.12217 * (t - 60.0).powf(-0.075514846) };
+    let b = if t >= 66.0 { 255.0 } else if t <= 19.0 { 0.0 } else { 138.51773 * (t - 10.0).ln() - 305.04479 };
+    let linear = pixelops::srgb8_to_linear_table();
+    [r, g, b].map(|v| linear[v.clamp(0.0, 255.0).round() as usize])
+}
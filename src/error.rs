@@ -0,0 +1,80 @@
+use crate::*;
+
+use std::fmt;
+
+
+/// Reasons a job or one of its steps can fail
+#[derive(Debug)]
+pub enum Error {
+    /// A raw file could not be decoded
+    Decode(String),
+    /// Decoding took longer than the job timeout
+    Timeout(time::Duration),
+    /// An image could not be encoded or did not survive verification
+    Encode(String),
+    /// Reading or writing a file failed
+    Io { path: PathBuf, source: io::Error },
+    /// A file is not of the kind it was expected to be
+    Classification(String),
+    /// No (unused) output path could be determined for a file
+    Path(String),
+    /// Settings for a file could not be resolved
+    Config(String),
+}
+
+
+impl Error {
+    pub fn io(path: &Path, source: io::Error) -> Error {
+        Error::Io { path: path.to_path_buf(), source }
+    }
+
+    /// Short machine readable name of the kind of error
+    pub fn category(&self) -> &'static str {
+        match self {
+            Error::Decode(_) => "decode",
+            Error::Timeout(_) => "timeout",
+            Error::Encode(_) => "encode",
+            Error::Io { .. } => "io",
+            Error::Classification(_) => "classification",
+            Error::Path(_) => "path",
+            Error::Config(_) => "config",
+        }
+    }
+
+    /// Whether the error may go away when trying again, like a busy file or a hiccup of a network share
+    pub fn is_transient(&self) -> bool {
+        let Error::Io { source, .. } = self else {
+            return false;
+        };
+        if matches!(source.kind(), io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe) {
+            return true;
+        }
+        #[cfg(unix)]
+        if let Some(code) = source.raw_os_error() {
+            return [libc::EIO, libc::EBUSY, libc::ETXTBSY, libc::ESTALE, libc::EHOSTUNREACH].contains(&code);
+        }
+        false
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Timeout(timeout) => write!(f, "timed out after {}", fmt_duration(timeout)),
+            Error::Io { path, source } => write!(f, "{:?}: {}", path, source),
+            Error::Decode(e) | Error::Encode(e) | Error::Classification(e) | Error::Path(e) | Error::Config(e) =>
+                write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::*;
+
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+
+/// Emits one JSON object per line for every change of the run's state (`--progress-json`)
+///
+/// Unlike the console output, the events are meant to be parsed by wrappers and scripts. Every
+/// event has an `event` field (`run-started`, `files-discovered`, `job-started`, `job-failed`,
+/// `job-finished`, `cancelled` or `run-summary`) and a `time` in seconds since the Unix epoch.
+pub struct ProgressEvents {
+    writer: Mutex<Box<dyn Write + Send>>,
+    /// Whether the events go to stdout, where they replace the regular progress output
+    pub on_stdout: bool,
+}
+
+
+impl ProgressEvents {
+    /// Opens the target of the events, `-` for stdout or the number of an open file descriptor
+    ///
+    /// Events on stdout take it over for the rest of the process (see `take_stdout()`).
+    pub fn open(target: &str) -> Result<ProgressEvents, String> {
+        if target == "-" || target == "1" {
+            return Ok(ProgressEvents { writer: Mutex::new(take_stdout()?), on_stdout: true });
+        }
+        let fd: i32 = target.parse()
+            .map_err(|_| format!("invalid progress target {:?} (expected - or a file descriptor)", target))?;
+        Ok(ProgressEvents { writer: Mutex::new(Box::new(open_fd(fd)?)), on_stdout: false })
+    }
+
+    pub fn record(&self, progress: &Progress) {
+        self.write(&progress_event(progress));
+    }
+
+    /// Emits the final `run-summary` event
+    pub fn summary(&self, statistics: &Statistics) {
+        self.write(&summary_event(statistics));
+    }
+
+    fn write(&self, event: &str) {
+        let mut writer = self.writer.lock().unwrap();
+        let result = writeln!(writer, "{{{},\"time\":{:.3}}}", event, unix_time(SystemTime::now()))
+            .and_then(|_| writer.flush());
+        if let Err(e) = result {
+            eprintln!("Unable to write progress event ({})", e);
+        }
+    }
+}
+
+/// Fields of the event describing `progress` (without the surrounding braces)
+pub fn progress_event(progress: &Progress) -> String {
+    match progress {
+        Progress::Started { total, threads, .. } =>
+            format!("\"event\":\"run-started\",\"total\":{},\"threads\":{}", total, threads),
+        Progress::Discovered { count } =>
+            format!("\"event\":\"files-discovered\",\"count\":{}", count),
+        Progress::Deferred { file } =>
+            format!("\"event\":\"job-deferred\",\"file\":\"{}\"", json_escape(&file.to_string_lossy())),
+        Progress::JobStarted { file } =>
+            format!("\"event\":\"job-started\",\"file\":\"{}\"", json_escape(&file.to_string_lossy())),
+        Progress::JobFailed { file, error } =>
+            format!("\"event\":\"job-failed\",\"file\":\"{}\",\"error_kind\":\"{}\",\"error\":\"{}\"",
+                json_escape(&file.to_string_lossy()), error.category(), json_escape(&error.to_string())),
+        Progress::JobFinished { file, done, total, statistics } => {
+            let result = if statistics.timeouts.count() > 0 {
+                "timeout"
+            } else if statistics.errors.count() > 0 {
+                "error"
+            } else {
+                "ok"
+            };
+            format!("\"event\":\"job-finished\",\"file\":\"{}\",\"done\":{},\"total\":{},\"action\":\"{}\",\
+                     \"result\":\"{}\",\"duration\":{:.3}",
+                json_escape(&file.to_string_lossy()), done, total, statistics.job_action(), result,
+                statistics.job_time().as_secs_f64())
+        },
+        Progress::Cancelled { done, total, unprocessed } => {
+            let unprocessed: Vec<_> = unprocessed.iter()
+                .map(|f| format!("\"{}\"", json_escape(&f.to_string_lossy())))
+                .collect();
+            format!("\"event\":\"cancelled\",\"done\":{},\"total\":{},\"unprocessed\":[{}]",
+                done, total, unprocessed.join(","))
+        },
+    }
+}
+
+/// Fields of the `run-summary` event (without the surrounding braces)
+pub fn summary_event(statistics: &Statistics) -> String {
+    format!("\"event\":\"run-summary\",\"files\":{},\"converted\":{},\"cached\":{},\"copied\":{},\
+             \"moved\":{},\"ignored\":{},\"errors\":{},\"timeouts\":{},\"retried\":{},\
+             \"quarantined\":{},\"salvaged\":{},\"wall_time\":{:.3}",
+        statistics.total.count(), statistics.decoded.count(), statistics.cached.count(),
+        statistics.copied.count(), statistics.moved.count(), statistics.ignored.count(),
+        statistics.errors.count(), statistics.timeouts.count(), statistics.retried.count(),
+        statistics.quarantined.count(), statistics.salvaged.count(), statistics.wall_time.as_secs_f64())
+}
+
+/// Moves stdout to a new file descriptor for the events and points stdout at stderr
+///
+/// Everything else printed to stdout (messages of the jobs, the summary) then ends up on stderr,
+/// so stdout carries nothing but one event per line.
+#[cfg(unix)]
+fn take_stdout() -> Result<Box<dyn Write + Send>, String> {
+    use std::os::fd::FromRawFd;
+    let _ = io::stdout().flush();
+    let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if fd < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        let e = io::Error::last_os_error();
+        if fd >= 0 {
+            unsafe { libc::close(fd) };
+        }
+        return Err(format!("unable to redirect stdout for the progress events ({})", e));
+    }
+    Ok(Box::new(unsafe { fs::File::from_raw_fd(fd) }))
+}
+
+/// Without file descriptors to juggle, other messages on stdout still end up between the events
+#[cfg(not(unix))]
+fn take_stdout() -> Result<Box<dyn Write + Send>, String> {
+    Ok(Box::new(io::stdout()))
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Result<fs::File, String> {
+    use std::os::fd::FromRawFd;
+    // the descriptor is set up by the caller (e.g. `3>events.jsonl`), so it has to be open already
+    if fd < 1 || unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(format!("file descriptor {} is not open", fd));
+    }
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn open_fd(fd: i32) -> Result<fs::File, String> {
+    Err(format!("writing progress to file descriptor {} is only supported on Unix, use - for stdout", fd))
+}
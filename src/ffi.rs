@@ -0,0 +1,160 @@
+use crate::*;
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+
+/// Options of `raw_to_img_convert()`, initialized with `raw_to_img_options_default()`
+///
+/// This is the C interface of the conversion core, see `include/raw_to_img.h` for its declarations.
+#[repr(C)]
+pub struct RawToImgOptions {
+    /// 0 = JPEG, 1 = PNG, 2 = TIFF, 3 = QOI
+    pub encode_type: u32,
+    pub jpeg_quality: u8,
+    /// Number of files converted in parallel (1 converts them one after another)
+    pub threads: u32,
+    /// Rename outputs that already exist instead of skipping their inputs
+    pub rename_existing: bool,
+}
+
+/// Called after every finished file with the number of finished files, the number of files found so far,
+/// the input file and whether it failed
+pub type RawToImgProgress = Option<extern "C" fn(user_data: *mut c_void, done: usize, total: usize,
+                                                 file: *const c_char, failed: bool)>;
+
+impl Default for RawToImgOptions {
+    fn default() -> RawToImgOptions {
+        RawToImgOptions { encode_type: 0, jpeg_quality: 90, threads: 1, rename_existing: false }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+
+/// Fills `options` with the defaults of the command line tool
+///
+/// # Safety
+/// `options` must point to writable memory for a `RawToImgOptions`.
+#[no_mangle]
+pub unsafe extern "C" fn raw_to_img_options_default(options: *mut RawToImgOptions) {
+    if let Some(options) = options.as_mut() {
+        *options = RawToImgOptions::default();
+    }
+}
+
+/// Converts the file or directory `input` into `output`
+///
+/// Returns the number of files that failed (0 if all succeeded) or -1 if the conversion could not
+/// be started, in which case `raw_to_img_last_error()` describes why. `progress` may be null.
+///
+/// # Safety
+/// `input` and `output` must be null-terminated strings, `options` must be null or point to
+/// initialized options. `user_data` is passed to `progress` untouched.
+#[no_mangle]
+pub unsafe extern "C" fn raw_to_img_convert(input: *const c_char, output: *const c_char, options: *const RawToImgOptions,
+                                            progress: RawToImgProgress, user_data: *mut c_void) -> c_int {
+    let input = match path_arg(input, "input") {
+        Ok(input) => input,
+        Err(e) => return fail(e),
+    };
+    let output = match path_arg(output, "output") {
+        Ok(output) => output,
+        Err(e) => return fail(e),
+    };
+    let defaults = RawToImgOptions::default();
+    let options = options.as_ref().unwrap_or(&defaults);
+
+    match convert(&input, &output, options, progress, user_data) {
+        Ok(failed) => failed.min(c_int::MAX as u32) as c_int,
+        Err(e) => fail(e),
+    }
+}
+
+/// Message of the last error of `raw_to_img_convert()` on this thread, null if there was none
+///
+/// The string stays valid until the next call of `raw_to_img_convert()` on the same thread.
+#[no_mangle]
+pub extern "C" fn raw_to_img_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map(|e| e.as_ptr()).unwrap_or(std::ptr::null()))
+}
+
+fn convert(input: &Path, output: &Path, options: &RawToImgOptions, progress: RawToImgProgress,
+           user_data: *mut c_void) -> Result<u32, String> {
+    LAST_ERROR.with(|e| e.borrow_mut().take());
+    let encode_type = match options.encode_type {
+        0 => EncodedType::Jpeg,
+        1 => EncodedType::Png,
+        2 => EncodedType::Tiff,
+        3 => EncodedType::Qoi,
+        other => return Err(format!("unknown encode type {}", other)),
+    };
+    let settings = JobSettings {
+        encoder: EncoderType::new(encode_type, options.jpeg_quality),
+        jpeg_quality: options.jpeg_quality,
+        on_existing: if options.rename_existing { ExistingAction::Rename } else { ExistingAction::Ignore },
+        ..JobSettings::default()
+    };
+
+    let metadata = input.metadata().map_err(|e| format!("unable to read {:?} ({})", input, e))?;
+    if !metadata.is_dir() {
+        let result = Job::new(input, output, &settings).run();
+        let failed = !matches!(&result, Ok(statistics) if statistics.errors.count() == 0);
+        report_progress(progress, user_data, 1, 1, input, failed);
+        return Ok(failed as u32);
+    }
+
+    let batch_settings = BatchSettings { threads: options.threads.max(1) as usize, ..BatchSettings::default() };
+    let batch = Batch::new(Vec::new(), input, output, &settings, &batch_settings)
+        .with_stream(spawn_scan(input, None, false, output_dirs_in(input, output, &settings)));
+    let mut failed = std::collections::HashSet::new();
+    let statistics = batch.run(&mut |event: Progress| match event {
+        Progress::JobFailed { file, .. } => { failed.insert(file.to_path_buf()); },
+        Progress::JobFinished { file, done, total, statistics } => {
+            let failed = failed.contains(file) || statistics.errors.count() > 0;
+            report_progress(progress, user_data, done, total, file, failed);
+        },
+        _ => (),
+    });
+    Ok(statistics.errors.count())
+}
+
+fn report_progress(progress: RawToImgProgress, user_data: *mut c_void, done: usize, total: usize, file: &Path, failed: bool) {
+    if let Some(progress) = progress {
+        let file = path_cstring(file);
+        progress(user_data, done, total, file.as_ptr(), failed);
+    }
+}
+
+unsafe fn path_arg(path: *const c_char, name: &str) -> Result<PathBuf, String> {
+    if path.is_null() {
+        return Err(format!("{} is null", name));
+    }
+    let bytes = CStr::from_ptr(path).to_bytes();
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+    }
+    #[cfg(not(unix))]
+    std::str::from_utf8(bytes).map(PathBuf::from).map_err(|_| format!("{} is not valid UTF-8", name))
+}
+
+fn path_cstring(path: &Path) -> CString {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().into_owned().into_bytes();
+    // paths can not contain null bytes
+    CString::new(bytes).unwrap_or_default()
+}
+
+fn fail(message: String) -> c_int {
+    LAST_ERROR.with(|e| *e.borrow_mut() = CString::new(message).ok());
+    -1
+}
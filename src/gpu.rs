@@ -0,0 +1,263 @@
+use crate::*;
+
+use std::sync::OnceLock;
+use wgpu::util::DeviceExt;
+
+
+const SHADER: &str = r#"
+struct Params {
+    width: u32,
+    height: u32,
+    cfa: u32,
+    _padding: u32,
+    black: vec4<f32>,
+    white: vec4<f32>,
+    wb: vec4<f32>,
+    // rows of the camera to linear sRGB matrix
+    matrix: array<vec4<f32>, 3>,
+};
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> raw: array<u32>;
+@group(0) @binding(2) var<storage, read_write> rgb: array<u32>;
+
+fn sample(x: i32, y: i32) -> f32 {
+    let i = u32(y) * params.width + u32(x);
+    return f32((raw[i / 2u] >> ((i % 2u) * 16u)) & 0xffffu);
+}
+
+fn color_at(x: i32, y: i32) -> u32 {
+    let shift = ((u32(y) % 2u) * 2u + (u32(x) % 2u)) * 2u;
+    let color = (params.cfa >> shift) & 3u;
+    return select(color, 1u, color == 3u);
+}
+
+fn gamma(v: f32) -> f32 {
+    if (v <= 0.0031308) {
+        return v * 12.92;
+    }
+    return 1.055 * pow(v, 1.0 / 2.4) - 0.055;
+}
+
+@compute @workgroup_size(16, 16)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= params.width || id.y >= params.height) {
+        return;
+    }
+
+    var sums = vec3<f32>(0.0);
+    var counts = vec3<f32>(0.0);
+    for (var dy = -1; dy <= 1; dy++) {
+        for (var dx = -1; dx <= 1; dx++) {
+            let x = i32(id.x) + dx;
+            let y = i32(id.y) + dy;
+            if (x < 0 || y < 0 || x >= i32(params.width) || y >= i32(params.height)) {
+                continue;
+            }
+            let color = color_at(x, y);
+            sums[color] += sample(x, y);
+            counts[color] += 1.0;
+        }
+    }
+
+    var camera = vec3<f32>(0.0);
+    for (var c = 0u; c < 3u; c++) {
+        let value = sums[c] / max(counts[c], 1.0);
+        camera[c] = clamp((value - params.black[c]) / (params.white[c] - params.black[c]) * params.wb[c], 0.0, 1.0);
+    }
+
+    var packed = 0u;
+    for (var c = 0u; c < 3u; c++) {
+        let linear = clamp(dot(params.matrix[c].xyz, camera), 0.0, 1.0);
+        packed |= u32(round(gamma(linear) * 255.0)) << (c * 8u);
+    }
+    rgb[id.y * params.width + id.x] = packed;
+}
+"#;
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct Params {
+    width: u32,
+    height: u32,
+    cfa: u32,
+    _padding: u32,
+    black: [f32; 4],
+    white: [f32; 4],
+    wb: [f32; 4],
+    matrix: [[f32; 4]; 3],
+}
+
+struct GpuContext {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    max_buffer_size: u64,
+}
+
+/// Lazily initialized GPU state shared by all jobs (`None` if no usable adapter was found)
+static CONTEXT: OnceLock<Option<GpuContext>> = OnceLock::new();
+
+/// Rawloader decoding with demosaicing and color conversion on the GPU
+pub struct GpuDecoder;
+
+
+impl GpuContext {
+    fn new() -> Option<GpuContext> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        }))?;
+        let limits = adapter.limits();
+        let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+            label: Some("raw-to-img"),
+            required_features: wgpu::Features::empty(),
+            required_limits: limits.clone(),
+            memory_hints: wgpu::MemoryHints::default(),
+        }, None)).ok()?;
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("demosaic"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("demosaic"),
+            layout: None,
+            module: &module,
+            entry_point: "main",
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        let max_buffer_size = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+        Some(GpuContext { device, queue, pipeline, max_buffer_size })
+    }
+
+    fn demosaic(&self, data: &[u16], params: Params) -> Result<Vec<u8>, String> {
+        let pixels = params.width as u64 * params.height as u64;
+        if pixels * 4 > self.max_buffer_size {
+            return Err(String::from("image too large for gpu buffers"));
+        }
+
+        let mut packed: Vec<u32> = data.chunks(2)
+            .map(|c| (c[0] as u32) | ((*c.get(1).unwrap_or(&0) as u32) << 16))
+            .collect();
+        if packed.is_empty() {
+            packed.push(0);
+        }
+
+        let params_buffer = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("params"),
+            contents: bytemuck::bytes_of(&params),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let raw_buffer = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("raw"),
+            contents: bytemuck::cast_slice(&packed),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let rgb_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("rgb"),
+            size: pixels * 4,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("staging"),
+            size: pixels * 4,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("demosaic"),
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: params_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: raw_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: rgb_buffer.as_entire_binding() },
+            ],
+        });
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None, timestamp_writes: None });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(params.width.div_ceil(16), params.height.div_ceil(16), 1);
+        }
+        encoder.copy_buffer_to_buffer(&rgb_buffer, 0, &staging_buffer, 0, pixels * 4);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = staging_buffer.slice(..);
+        let (tx, rx) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| { let _ = tx.send(result); });
+        let _ = self.device.poll(wgpu::Maintain::Wait);
+        rx.recv()
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+
+        let mapped = slice.get_mapped_range();
+        let rgb = bytemuck::cast_slice::<u8, u32>(&mapped).iter()
+            .flat_map(|p| [*p as u8, (*p >> 8) as u8, (*p >> 16) as u8])
+            .collect();
+        drop(mapped);
+        staging_buffer.unmap();
+
+        Ok(rgb)
+    }
+}
+
+impl Decoder for GpuDecoder {
+    fn name(&self) -> &'static str {
+        "gpu"
+    }
+
+    fn decode(&self, input: RawInput, options: &DecodeOptions) -> Result<imagepipe::SRGBImage, String> {
+        if options.demosaic.is_some_and(|d| d != Demosaic::Bilinear) {
+            return Err(String::from("only bilinear demosaicing is supported on the gpu"));
+        }
+        let context = CONTEXT.get_or_init(GpuContext::new).as_ref()
+            .ok_or_else(|| String::from("no usable gpu adapter found"))?;
+
+        let mut raw = input.read_raw()?;
+        correct_mosaic(&mut raw, options)?;
+        let data = match raw.data {
+            rawloader::RawImageData::Integer(ref data) if raw.cpp == 1 => data,
+            _ => return Err(String::from("only single channel integer raws are supported on the gpu")),
+        };
+        if raw.cfa.width != 2 || raw.cfa.height != 2 {
+            return Err(format!("unsupported cfa pattern {} for gpu demosaic", raw.cfa.name));
+        }
+
+        let mut cfa = 0;
+        for row in 0..2 {
+            for col in 0..2 {
+                cfa |= (raw.cfa.color_at(row, col) as u32) << ((row * 2 + col) * 2);
+            }
+        }
+
+        let wb = if raw.wb_coeffs[1] > 0.0 && !raw.wb_coeffs[0].is_nan() {
+            [raw.wb_coeffs[0] / raw.wb_coeffs[1], 1.0, raw.wb_coeffs[2] / raw.wb_coeffs[1], 1.0]
+        } else {
+            [1.0; 4]
+        };
+        let params = Params {
+            width: raw.width as u32,
+            height: raw.height as u32,
+            cfa,
+            _padding: 0,
+            black: raw.blacklevels.map(|b| b as f32),
+            white: raw.whitelevels.map(|w| w as f32),
+            wb,
+            matrix: camera_to_srgb(&raw)?
+                .unwrap_or([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]])
+                .map(|[r, g, b]| [r, g, b, 0.0]),
+        };
+
+        let data = context.demosaic(data, params)?;
+        let image = imagepipe::SRGBImage { width: raw.width, height: raw.height, data };
+        Ok(finish_raw(&raw, image, 1, options))
+    }
+}
@@ -0,0 +1,161 @@
+use crate::*;
+
+
+/// Output of `merge-hdr`
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum HdrFormat {
+    /// Tonemapped image in the selected encode type
+    Tonemapped,
+    /// Linear 32 bit float OpenEXR
+    Exr,
+}
+
+/// A merged HDR image with linear RGB values
+pub struct HdrImage {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>,
+}
+
+
+/// Exposure compensation of a raw file in EV
+pub fn exposure_bias(path: &Path) -> Option<f64> {
+    let data = read_exif(path)?;
+    match data.get_field(exif::Tag::ExposureBiasValue, exif::In::PRIMARY)?.value {
+        exif::Value::SRational(ref values) => values.first().map(|r| r.to_f64()),
+        _ => None,
+    }
+}
+
+/// Groups the raws below `files` into bracketed sequences
+///
+/// A bracket is a burst (frames at most `gap` apart) whose frames do not all share the same
+/// exposure compensation.
+pub fn find_brackets(files: &[PathBuf], gap: time::Duration, detect_by_content: bool) -> Vec<Vec<(PathBuf, f64)>> {
+    group_bursts(files, gap, detect_by_content).into_iter()
+        .map(|burst| burst.into_iter()
+            .filter_map(|f| exposure_bias(&f).map(|bias| (f, bias)))
+            .collect::<Vec<_>>())
+        .filter(|frames| frames.iter().any(|(_, bias)| *bias != frames[0].1))
+        .collect()
+}
+
+/// Decodes all frames into linear values and merges them into one linear HDR image
+///
+/// Frames are always decoded with rawloader, as the other decoders only yield 8 bit sRGB data.
+/// Each pixel is the weighted average of the frames' radiance estimates, where well exposed
+/// values get the highest weight and clipped or very dark values the lowest.
+pub fn merge_hdr(frames: &[(PathBuf, f64)], settings: &JobSettings) -> Result<HdrImage, String> {
+    // weighted by how well exposed the values look, like the encoded image would show them
+    let weight = |v: f32| (1.0 - (2.0 * pixelops::linear_to_srgb(v) - 1.0).abs()).max(1e-3);
+
+    let mut merged: Option<(usize, usize, Vec<f32>, Vec<f32>)> = None;
+    for (path, bias) in frames {
+        let raw = RawInput::File(path).read_raw().map_err(|e| format!("{:?}: {}", path, e))?;
+        let frame = develop_raw_linear(raw, &settings.decode_options_for(path)).map_err(|e| format!("{:?}: {}", path, e))?;
+        let (width, height, sums, weights) = merged.get_or_insert_with(|| {
+            (frame.width, frame.height, vec![0.0; frame.data.len()], vec![0.0; frame.data.len()])
+        });
+        if frame.width != *width || frame.height != *height {
+            return Err(format!("{:?} does not have the same dimensions as the other frames", path));
+        }
+
+        let exposure = 2.0f32.powf(*bias as f32);
+        for ((value, sum), total_weight) in frame.data.iter().zip(sums.iter_mut()).zip(weights.iter_mut()) {
+            let w = weight(*value);
+            *sum += w * value / exposure;
+            *total_weight += w;
+        }
+    }
+
+    let (width, height, sums, weights) = merged.ok_or_else(|| String::from("no frames to merge"))?;
+    let data = sums.iter().zip(weights).map(|(sum, weight)| sum / weight).collect();
+    Ok(HdrImage { width, height, data })
+}
+
+/// Maps an HDR image to 8 bit sRGB with the global Reinhard operator
+pub fn tonemap(hdr: &HdrImage) -> imagepipe::SRGBImage {
+    const KEY: f32 = 0.18;
+
+    let luminance = |p: &[f32]| 0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2];
+    let pixels = (hdr.width * hdr.height).max(1) as f32;
+    let log_average = (hdr.data.chunks_exact(3)
+        .map(|p| (luminance(p) + 1e-6).ln())
+        .sum::<f32>() / pixels).exp();
+
+    let mapped: Vec<f32> = hdr.data.iter()
+        .map(|v| {
+            let scaled = v * KEY / log_average;
+            scaled / (1.0 + scaled)
+        })
+        .collect();
+
+    imagepipe::SRGBImage {
+        width: hdr.width,
+        height: hdr.height,
+        data: pixelops::linear_to_srgb8(&mapped, [0.0; 3], [1.0; 3]),
+    }
+}
+
+fn write_exr(hdr: &HdrImage, path: &Path) -> Result<(), String> {
+    let image = image::Rgb32FImage::from_raw(hdr.width as u32, hdr.height as u32, hdr.data.clone())
+        .ok_or_else(|| String::from("invalid hdr buffer"))?;
+    let temp = temp_path(path);
+    image.save_with_format(&temp, image::ImageFormat::OpenExr)
+        .map_err(|e| e.to_string())?;
+    fs::rename(&temp, path).map_err(|e| e.to_string())
+}
+
+/// Finds all brackets below `input_base` and writes one merged image per bracket to `output_base`
+///
+/// Outputs are named after the first frame of the bracket with an `_hdr` suffix.
+pub fn merge_brackets(input_base: &Path, output_base: &Path, gap: time::Duration, format: HdrFormat,
+                      settings: &JobSettings) -> Statistics {
+    let run_start = Instant::now();
+    let mut statistics = Statistics::default();
+    let files = scan_dir(input_base, None, false, true);
+    let brackets = find_brackets(&files, gap, settings.detect_by_content);
+    println!("Found {} brackets", brackets.len());
+
+    for frames in brackets {
+        let start = Instant::now();
+        let first = &frames[0].0;
+        let extension = match format {
+            HdrFormat::Tonemapped => settings.encoder.extension(),
+            HdrFormat::Exr => "exr",
+        };
+        let name = suffixed_stem(first, &format!("_hdr.{}", extension));
+        let output = match switch_base(first, input_base, output_base) {
+            Ok(output) => output.with_file_name(name),
+            Err(e) => { println!("Error ({:?}): {}", first, e); statistics.errors.inc(); continue },
+        };
+
+        println!("Merging {} frames into {:?}", frames.len(), output);
+        let result = merge_hdr(&frames, settings).and_then(|hdr| {
+            statistics.decoded.record(start.elapsed());
+            if let Some(parent) = output.parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            let encode_start = Instant::now();
+            match format {
+                HdrFormat::Tonemapped => { encode_img(&tonemap(&hdr), &output, settings.encoder, None, None, false).map_err(|e| e.to_string())?; },
+                HdrFormat::Exr => write_exr(&hdr, &output)?,
+            }
+            Ok(encode_start.elapsed())
+        });
+
+        match result {
+            Ok(encode_time) => {
+                statistics.encoded.record(encode_time);
+                statistics.total.record(start.elapsed());
+            },
+            Err(e) => {
+                println!("Error ({:?}): {}", output, e);
+                statistics.errors.inc();
+            },
+        }
+    }
+
+    statistics.wall_time = run_start.elapsed();
+    statistics
+}
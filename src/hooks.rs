@@ -0,0 +1,44 @@
+use crate::*;
+
+use std::ffi::OsString;
+use std::process::Command;
+
+
+/// User commands that are run around each job
+#[derive(Clone, Default)]
+pub struct Hooks {
+    /// Run before a file is processed
+    pub pre_file: Option<String>,
+    /// Run after a file was processed (also if processing failed)
+    pub post_file: Option<String>,
+}
+
+
+/// Runs `command` through `sh -c` with the given additional environment variables
+pub fn run_hook(command: &str, env: &[(&str, OsString)]) -> Result<(), String> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(k, v)| (*k, v.as_os_str())))
+        .status()
+        .map_err(|e| format!("unable to run {:?} ({})", command, e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{:?} failed ({})", command, status))
+    }
+}
+
+/// Environment passed to the per-file hooks (`$INPUT`, `$OUTPUT`, `$CAMERA` and `$STATUS` if known)
+pub fn file_hook_env(input: &Path, output: &Path, status: Option<&str>) -> Vec<(&'static str, OsString)> {
+    let mut env = vec![
+        ("INPUT", input.as_os_str().to_os_string()),
+        ("OUTPUT", output.as_os_str().to_os_string()),
+        ("CAMERA", ExifSummary::read(input).camera.unwrap_or_default().into()),
+    ];
+    if let Some(status) = status {
+        env.push(("STATUS", status.into()));
+    }
+    env
+}
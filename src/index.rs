@@ -0,0 +1,300 @@
+use crate::*;
+
+#[cfg(feature = "index")]
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+
+/// Database of all files processed by previous runs
+///
+/// Every run that uses the index is recorded along with each of its files, so later runs can skip
+/// files that were already processed (`--sync`) or that duplicate files processed before, and the
+/// origin of any output can be looked up with the `query` subcommand.
+pub struct Index {
+    #[cfg(feature = "index")]
+    db: Mutex<rusqlite::Connection>,
+    #[cfg(not(feature = "index"))]
+    db: std::convert::Infallible,
+    /// Run that new files are recorded in (0 until `start_run()` is called)
+    #[cfg_attr(not(feature = "index"), allow(dead_code))]
+    run: i64,
+    /// Output base and settings fingerprint of the current run, which `is_synced()` compares against
+    #[cfg_attr(not(feature = "index"), allow(dead_code))]
+    output: PathBuf,
+    #[cfg_attr(not(feature = "index"), allow(dead_code))]
+    settings: String,
+}
+
+/// A file as it was processed by a run
+#[derive(Clone, Debug, Default)]
+pub struct IndexEntry {
+    pub run: i64,
+    pub input: PathBuf,
+    pub size: u64,
+    /// Modification time of the input in seconds since the Unix epoch
+    pub mtime: f64,
+    /// SHA-256 of the input
+    pub hash: String,
+    pub camera: Option<String>,
+    /// Capture date as `YYYY-MM-DD HH:MM:SS`
+    pub taken: Option<String>,
+    /// See `Statistics::job_action()`
+    pub action: String,
+    /// `ok`, `partial`, `corrupt`, `error`, `duplicate` or `ignored`
+    pub status: String,
+    pub output: PathBuf,
+    /// Copy of the original kept by `--keep-raws`, if this run created it
+    pub original: Option<PathBuf>,
+    /// When the file was finished, in seconds since the Unix epoch
+    pub time: f64,
+}
+
+/// A run recorded in the index
+#[derive(Clone, Debug)]
+pub struct IndexRun {
+    pub id: i64,
+    /// Start of the run in seconds since the Unix epoch
+    pub started: f64,
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// Fingerprint of the options that influenced the outputs
+    pub settings: String,
+}
+
+
+impl IndexEntry {
+    /// Describes `input` before it is processed (while it is still at its original location)
+    pub fn describe(input: &Path) -> Result<IndexEntry, Error> {
+        let metadata = input.metadata().map_err(|e| Error::io(input, e))?;
+        let hash = hash_file(input, ChecksumAlgo::Sha256).map_err(|e| Error::io(input, io::Error::other(e)))?;
+        let exif = ExifSummary::read(input);
+        Ok(IndexEntry {
+            input: absolute_path(input),
+            size: metadata.len(),
+            mtime: metadata.modified().map(unix_time).unwrap_or(0.0),
+            hash,
+            camera: exif.camera,
+            taken: exif.date,
+            ..IndexEntry::default()
+        })
+    }
+}
+
+#[cfg(feature = "index")]
+impl Index {
+    /// Opens the index at `path`, creating it if it does not exist yet
+    pub fn open(path: &Path) -> Result<Index, String> {
+        let db = rusqlite::Connection::open(path)
+            .map_err(|e| format!("unable to open index {:?} ({})", path, e))?;
+        db.execute_batch("
+            CREATE TABLE IF NOT EXISTS runs (
+                id INTEGER PRIMARY KEY, started REAL, input BLOB, output BLOB, settings TEXT);
+            CREATE TABLE IF NOT EXISTS files (
+                run INTEGER, input BLOB, size INTEGER, mtime REAL, hash TEXT, camera TEXT, taken TEXT,
+                action TEXT, status TEXT, output BLOB, original BLOB, time REAL);
+            CREATE INDEX IF NOT EXISTS files_input ON files (input);
+            CREATE INDEX IF NOT EXISTS files_hash ON files (hash);
+            CREATE INDEX IF NOT EXISTS files_output ON files (output);
+        ").map_err(|e| format!("unable to initialize index {:?} ({})", path, e))?;
+        Ok(Index { db: Mutex::new(db), run: 0, output: PathBuf::new(), settings: String::new() })
+    }
+
+    /// Starts a new run converting `input` into `output` that following records belong to
+    pub fn start_run(&mut self, input: &Path, output: &Path, settings: &str) -> Result<i64, String> {
+        let db = self.db.lock().unwrap();
+        db.execute("INSERT INTO runs (started, input, output, settings) VALUES (?1, ?2, ?3, ?4)",
+                   rusqlite::params![unix_time(SystemTime::now()), path_bytes(&absolute_path(input)),
+                                     path_bytes(&absolute_path(output)), settings])
+            .map_err(|e| format!("unable to record run ({})", e))?;
+        self.run = db.last_insert_rowid();
+        self.output = absolute_path(output);
+        self.settings = settings.to_string();
+        Ok(self.run)
+    }
+
+    /// Adds a processed file to the current run
+    pub fn record(&self, entry: &IndexEntry) -> Result<(), String> {
+        self.db.lock().unwrap().execute(
+            "INSERT INTO files (run, input, size, mtime, hash, camera, taken, action, status, output, original, time)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            rusqlite::params![self.run, path_bytes(&entry.input), entry.size as i64, entry.mtime, entry.hash,
+                              entry.camera, entry.taken, entry.action, entry.status,
+                              path_bytes(&absolute_path(&entry.output)),
+                              entry.original.as_deref().map(|p| path_bytes(&absolute_path(p))),
+                              unix_time(SystemTime::now())])
+            .map(|_| ())
+            .map_err(|e| format!("unable to record {:?} in the index ({})", entry.input, e))
+    }
+
+    /// Whether `input` was processed successfully before and has not changed since (by size and modification time)
+    ///
+    /// Only runs into the same output base with the same settings fingerprint as the current run count,
+    /// and all outputs recorded for the file have to still exist according to `output_exists`.
+    pub fn is_synced(&self, input: &Path, output_exists: impl Fn(&Path) -> bool) -> bool {
+        let metadata = match input.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+        let mtime = metadata.modified().map(unix_time).unwrap_or(0.0);
+        let outputs: Result<Vec<Vec<u8>>, _> = {
+            let db = self.db.lock().unwrap();
+            let mut statement = match db.prepare(
+                    "SELECT files.output FROM files JOIN runs ON runs.id = files.run
+                     WHERE files.input = ?1 AND files.size = ?2 AND files.mtime = ?3 AND files.status = 'ok'
+                     AND runs.output = ?4 AND runs.settings = ?5") {
+                Ok(statement) => statement,
+                Err(_) => return false,
+            };
+            let outputs = statement.query_map(
+                    rusqlite::params![path_bytes(&absolute_path(input)), metadata.len() as i64, mtime,
+                                      path_bytes(&self.output), self.settings],
+                    |row| row.get(0))
+                .and_then(|rows| rows.collect());
+            outputs
+        };
+        match outputs {
+            Ok(outputs) => !outputs.is_empty() && outputs.into_iter().all(|output| output_exists(&bytes_path(output))),
+            Err(_) => false,
+        }
+    }
+
+    /// Another input with the content `hash` that was processed successfully before, along with its output
+    pub fn duplicate_of(&self, hash: &str, input: &Path) -> Option<(PathBuf, PathBuf)> {
+        self.db.lock().unwrap()
+            .query_row("SELECT input, output FROM files WHERE hash = ?1 AND input != ?2 AND status = 'ok' LIMIT 1",
+                       rusqlite::params![hash, path_bytes(&absolute_path(input))],
+                       |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?)))
+            .ok()
+            .map(|(input, output)| (bytes_path(input), bytes_path(output)))
+    }
+
+    /// Files whose input or output path contains `pattern`, or whose hash starts with it
+    pub fn query(&self, pattern: &str) -> Result<Vec<IndexEntry>, String> {
+        let like = format!("%{}%", pattern.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        self.select("WHERE input LIKE ?1 ESCAPE '\\' OR output LIKE ?1 ESCAPE '\\' OR hash LIKE ?2",
+                    rusqlite::params![like, format!("{}%", pattern)])
+    }
+
+    /// All files recorded for `run`
+    pub fn run_entries(&self, run: i64) -> Result<Vec<IndexEntry>, String> {
+        self.select("WHERE run = ?1", rusqlite::params![run])
+    }
+
+    /// Removes the record of `input` from `run` (e.g. after the run was undone for it)
+    pub fn forget(&self, run: i64, input: &Path) -> Result<(), String> {
+        self.db.lock().unwrap()
+            .execute("DELETE FROM files WHERE run = ?1 AND input = ?2", rusqlite::params![run, path_bytes(input)])
+            .map(|_| ())
+            .map_err(|e| format!("unable to remove {:?} from the index ({})", input, e))
+    }
+
+    /// All recorded runs, oldest first
+    pub fn runs(&self) -> Result<Vec<IndexRun>, String> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare("SELECT id, started, input, output, settings FROM runs ORDER BY id")
+            .map_err(|e| e.to_string())?;
+        let runs = statement.query_map([], |row| Ok(IndexRun {
+                id: row.get(0)?,
+                started: row.get(1)?,
+                input: bytes_path(row.get(2)?),
+                output: bytes_path(row.get(3)?),
+                settings: row.get(4)?,
+            }))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| e.to_string());
+        runs
+    }
+
+    fn select(&self, condition: &str, params: impl rusqlite::Params) -> Result<Vec<IndexEntry>, String> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(&format!(
+                "SELECT run, input, size, mtime, hash, camera, taken, action, status, output, original, time
+                 FROM files {} ORDER BY run, time", condition))
+            .map_err(|e| e.to_string())?;
+        let entries = statement.query_map(params, |row| Ok(IndexEntry {
+                run: row.get(0)?,
+                input: bytes_path(row.get(1)?),
+                size: row.get::<_, i64>(2)? as u64,
+                mtime: row.get(3)?,
+                hash: row.get(4)?,
+                camera: row.get(5)?,
+                taken: row.get(6)?,
+                action: row.get(7)?,
+                status: row.get(8)?,
+                output: bytes_path(row.get(9)?),
+                original: row.get::<_, Option<Vec<u8>>>(10)?.map(bytes_path),
+                time: row.get(11)?,
+            }))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| e.to_string());
+        entries
+    }
+}
+
+#[cfg(not(feature = "index"))]
+impl Index {
+    pub fn open(_path: &Path) -> Result<Index, String> {
+        Err(String::from("This build does not support the index (enable the index feature)"))
+    }
+
+    pub fn start_run(&mut self, _input: &Path, _output: &Path, _settings: &str) -> Result<i64, String> {
+        match self.db {}
+    }
+
+    pub fn record(&self, _entry: &IndexEntry) -> Result<(), String> {
+        match self.db {}
+    }
+
+    pub fn is_synced(&self, _input: &Path, _output_exists: impl Fn(&Path) -> bool) -> bool {
+        match self.db {}
+    }
+
+    pub fn duplicate_of(&self, _hash: &str, _input: &Path) -> Option<(PathBuf, PathBuf)> {
+        match self.db {}
+    }
+
+    pub fn query(&self, _pattern: &str) -> Result<Vec<IndexEntry>, String> {
+        match self.db {}
+    }
+
+    pub fn run_entries(&self, _run: i64) -> Result<Vec<IndexEntry>, String> {
+        match self.db {}
+    }
+
+    pub fn forget(&self, _run: i64, _input: &Path) -> Result<(), String> {
+        match self.db {}
+    }
+
+    pub fn runs(&self) -> Result<Vec<IndexRun>, String> {
+        match self.db {}
+    }
+}
+
+/// `path` made absolute without resolving symlinks, so it stays valid for moved files
+fn absolute_path(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Raw bytes of `path` as stored in the database, so names that are not valid UTF-8 survive
+#[cfg(all(feature = "index", unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(all(feature = "index", unix))]
+fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+// other platforms have no stable byte representation of their paths, so these are stored as UTF-8
+#[cfg(all(feature = "index", not(unix)))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(all(feature = "index", not(unix)))]
+fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
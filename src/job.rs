@@ -1,112 +1,764 @@
 use crate::*;
 
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Output directories that have already been created during this run
+static CREATED_DIRS: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+
+/// Output files that are currently being written by a job
+static IN_PROGRESS: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+
+/// Delay before the first retry of a failed step, doubled for every further retry
+const RETRY_DELAY: time::Duration = time::Duration::from_millis(500);
+
+/// Options shared by all jobs of a run
+#[derive(Clone)]
+pub struct JobSettings {
+    pub on_raw: ParsableAction,
+    pub on_file: UnparsableAction,
+    pub on_image: UnparsableAction,
+    pub on_video: UnparsableAction,
+    pub on_existing: ExistingAction,
+    /// Output subdirectories per file kind
+    pub routes: KindRoutes,
+    /// Directory below the output base that the originals of converted raws are kept in
+    pub keep_raws: Option<PathBuf>,
+    /// Directory below the output base that raws which turn out to be corrupt are put into
+    pub quarantine: Option<PathBuf>,
+    /// Decode the readable part of raws that fail to decode into an output marked with `_partial`
+    pub salvage: bool,
+    pub detect_by_content: bool,
+    pub decoder: DecoderChoice,
+    pub gpu: bool,
+    /// Corrections applied by the decoders
+    pub decode_options: DecodeOptions,
+    pub encoder: EncoderType,
+    pub jpeg_quality: u8,
+    /// Sizes and formats every raw is converted into, each into its own directory (the first one replaces `encoder`)
+    pub variants: Vec<Variant>,
+    /// Color space of the outputs, encoders that can not carry it write sRGB
+    pub color_space: ColorSpace,
+    pub paranoid: bool,
+    /// Abort decoding a file after this long
+    pub job_timeout: Option<time::Duration>,
+    /// Retry steps that fail with transient I/O errors (see `Error::is_transient()`) this many times
+    pub retries: u32,
+    /// Hash every written output file with this algorithm
+    pub checksums: Option<ChecksumAlgo>,
+    /// EXIF fields that are not copied into converted outputs
+    pub strip_metadata: Vec<MetadataField>,
+    /// Only process raws with at least this XMP star rating
+    pub min_rating: Option<i32>,
+    pub hooks: Hooks,
+    /// Per-camera-model processing tweaks
+    pub presets: Option<Arc<Presets>>,
+    /// Lens profiles used to correct distortion, vignetting and chromatic aberration
+    pub lens_database: Option<Arc<LensDatabase>>,
+    /// Invert color negatives, removing the film base sampled as given
+    pub invert_negative: Option<FilmBase>,
+    /// Match the colors and tones of converted raws to the JPEG preview embedded by the camera
+    pub match_embedded: bool,
+    /// Highlight recovery, tone curve and contrast
+    pub tone: ToneSettings,
+    pub style: Style,
+    /// Custom 3D LUT applied after the style
+    pub lut: Option<Arc<Lut3d>>,
+    /// Template of a text banner drawn onto every converted output
+    pub annotation: Option<String>,
+    /// Image stamped onto every converted output
+    pub watermark: Option<Arc<Watermark>>,
+    /// Border and padding added around every converted output
+    pub canvas: Canvas,
+    /// Exposure, white balance and crop of a single image (from its sidecar)
+    pub adjustments: Adjustments,
+    /// Take the adjustments from RawTherapee or darktable sidecars (see `import_edits()`)
+    pub import_edits: bool,
+    /// Compute brightness and color statistics of every decoded image
+    pub analyze: bool,
+    /// Collect a `ReportEntry` for every file
+    pub report: bool,
+    /// Upload outputs to a remote backend instead of keeping them locally
+    pub output_storage: Option<RemoteOutput>,
+    /// Reuse outputs of raws that were converted with the same settings before
+    pub cache: Option<Arc<OutputCache>>,
+    /// Record every processed file in this index
+    pub index: Option<Arc<Index>>,
+    /// Skip files whose content was already processed according to the index
+    pub skip_duplicates: bool,
+    /// Files seen so far in this run, set to process files present in several inputs only once
+    pub duplicates: Option<Arc<DuplicateTracker>>,
+    pub summary_template: Option<String>,
+}
+
 pub struct Job {
     input_file: PathBuf,
     output_file: PathBuf,
-    on_raw: ParsableAction,
-    on_file: UnparsableAction,
-    on_image: UnparsableAction,
-    on_existing: ExistingAction,
-    encoder: EncoderType,
+    settings: JobSettings,
     statistics: Statistics,
+    analysis: Option<ImageAnalysis>,
+    /// Where the original of a converted raw is kept
+    original_copy: Option<PathBuf>,
+    /// Where the raw is put if it can not be decoded
+    quarantine_copy: Option<PathBuf>,
+    /// Further variants of the converted raw, written along with the output of the job
+    variants: Vec<(Variant, EncoderType, PathBuf)>,
+    /// When the job started working on its file
+    started: Instant,
+    /// Key under which the converted output is added to the cache
+    cache_key: Option<String>,
+    /// Description of the input for the index, taken before the input is moved
+    index_entry: Option<IndexEntry>,
+    /// Input the file was skipped in favor of as a duplicate, along with the output of that input
+    duplicate_of: Option<(PathBuf, PathBuf)>,
+}
+
+/// Outcome of the first (decoding) half of a job
+pub enum Stage {
+    Finished(Statistics),
+    Decoded(DecodedJob),
+}
+
+/// A raw conversion job that has been decoded and still has to be encoded
+pub struct DecodedJob {
+    job: Job,
+    decoded: imagepipe::SRGBImage,
+    decode_time: time::Duration,
+    /// Whether the raw comes from a monochrome sensor
+    monochrome: bool,
 }
 
 
+/// Settings of the command line defaults, for library users that only change a few of them
+impl Default for JobSettings {
+    fn default() -> JobSettings {
+        JobSettings {
+            on_raw: ParsableAction::Parse,
+            on_file: UnparsableAction::Copy,
+            on_image: UnparsableAction::Copy,
+            on_video: UnparsableAction::Copy,
+            on_existing: ExistingAction::Ignore,
+            routes: KindRoutes::default(),
+            keep_raws: None,
+            quarantine: None,
+            salvage: false,
+            detect_by_content: false,
+            decoder: DecoderChoice::Auto,
+            gpu: false,
+            decode_options: DecodeOptions::default(),
+            encoder: EncoderType::new(EncodedType::Jpeg, 90),
+            jpeg_quality: 90,
+            variants: Vec::new(),
+            color_space: ColorSpace::Srgb,
+            paranoid: false,
+            job_timeout: None,
+            retries: 0,
+            checksums: None,
+            strip_metadata: Vec::new(),
+            min_rating: None,
+            hooks: Hooks::default(),
+            presets: None,
+            lens_database: None,
+            invert_negative: None,
+            match_embedded: false,
+            tone: ToneSettings::default(),
+            style: Style::Neutral,
+            lut: None,
+            annotation: None,
+            watermark: None,
+            canvas: Canvas::default(),
+            adjustments: Adjustments::default(),
+            import_edits: false,
+            analyze: false,
+            report: false,
+            output_storage: None,
+            cache: None,
+            index: None,
+            skip_duplicates: false,
+            duplicates: None,
+            summary_template: None,
+        }
+    }
+}
+
+impl JobSettings {
+    /// Color space of outputs written by `encoder`, sRGB if it can not carry `color_space`
+    pub fn output_color_space(&self, encoder: EncoderType) -> ColorSpace {
+        match self.color_space.is_supported_by(encoder) {
+            true => self.color_space,
+            false => ColorSpace::Srgb,
+        }
+    }
+
+    /// Decode options for `input`, including the hot photosites of the camera preset that applies to it
+    pub fn decode_options_for(&self, input: &Path) -> DecodeOptions {
+        let mut options = self.decode_options.clone();
+        if let Some(preset) = self.presets.as_ref().and_then(|p| p.for_file(input)) {
+            options.hot_photosites.extend(&preset.hot_photosites);
+        }
+        options
+    }
+}
+
 impl Job {
-    pub fn new(input_file: &Path, output_file: &Path, on_raw: ParsableAction,
-           on_file: UnparsableAction, on_image: UnparsableAction, on_existing: ExistingAction,
-           encoder: EncoderType) -> Job {
+    pub fn new(input_file: &Path, output_file: &Path, settings: &JobSettings) -> Job {
         Job {
             input_file: input_file.to_path_buf(),
             output_file: output_file.to_path_buf(),
-            on_raw, on_file, on_image, on_existing, encoder,
+            settings: settings.clone(),
             statistics: Statistics::default(),
+            analysis: None,
+            original_copy: None,
+            quarantine_copy: None,
+            variants: Vec::new(),
+            started: Instant::now(),
+            cache_key: None,
+            index_entry: None,
+            duplicate_of: None,
         }
     }
 
-    pub fn name(&self) -> String {
-        return self.input_file.to_string_lossy().to_string();
+    /// Also keeps the original raw at `path` when converting it
+    pub fn keep_original(mut self, path: &Path) -> Job {
+        self.original_copy = Some(path.to_path_buf());
+        self
     }
 
-    pub fn run(mut self) -> Result<Statistics, String> {
-        // fetch file metadata to later distinguish regular files from other files
-        let metadata = self.input_file.metadata()
-            .map_err(|s| s.to_string())?;
+    /// Puts the raw at `path` instead of converting it if it turns out to be corrupt
+    pub fn quarantine_to(mut self, path: &Path) -> Job {
+        self.quarantine_copy = Some(path.to_path_buf());
+        self
+    }
+
+    /// Also writes the given variants (with their encoder and output path) when converting the raw
+    ///
+    /// The output of the job itself is the first variant of `JobSettings::variants`.
+    pub fn with_variants(mut self, variants: Vec<(Variant, EncoderType, PathBuf)>) -> Job {
+        self.variants = variants;
+        self
+    }
+
+    pub fn input_file(&self) -> &Path {
+        &self.input_file
+    }
+
+    pub fn output_file(&self) -> &Path {
+        &self.output_file
+    }
+
+    /// Files a successful run of the job leaves in the output: the output, its variants and the kept original
+    ///
+    /// Empty for files that are ignored. Quarantined copies are not included, only failing jobs create them.
+    pub fn expected_outputs(&self) -> Vec<PathBuf> {
+        let ignored = match file_kind(&self.input_file, self.settings.detect_by_content) {
+            FileKind::Raw => self.settings.on_raw == ParsableAction::Ignore,
+            FileKind::Image => self.settings.on_image == UnparsableAction::Ignore,
+            FileKind::Video => self.settings.on_video == UnparsableAction::Ignore,
+            FileKind::Other => self.settings.on_file == UnparsableAction::Ignore,
+        };
+        if ignored {
+            return Vec::new();
+        }
+        std::iter::once(self.output_file.clone())
+            .chain(self.variants.iter().map(|(_, _, path)| path.clone()))
+            .chain(self.original_copy.clone())
+            .collect()
+    }
+
+    /// Where the raw is copied to if it turns out to be corrupt (see `quarantine_to()`)
+    pub fn quarantine_copy(&self) -> Option<&Path> {
+        self.quarantine_copy.as_deref()
+    }
+
+    /// Whether this job only copies or moves its file
+    pub fn is_transfer(&self) -> bool {
+        match file_kind(&self.input_file, self.settings.detect_by_content) {
+            FileKind::Raw => matches!(self.settings.on_raw, ParsableAction::Copy | ParsableAction::Move),
+            FileKind::Image => self.settings.on_image != UnparsableAction::Ignore,
+            FileKind::Video => self.settings.on_video != UnparsableAction::Ignore,
+            FileKind::Other => self.settings.on_file != UnparsableAction::Ignore,
+        }
+    }
+
+    /// Whether this job only copies or moves a file of at most `threshold` bytes
+    pub fn is_small_transfer(&self, threshold: u64) -> bool {
+        let size = match self.input_file.metadata() {
+            Ok(meta) if meta.is_file() => meta.len(),
+            _ => return false,
+        };
+
+        size <= threshold && self.is_transfer()
+    }
+
+    /// Whether this is a raw file rated lower than `min_rating` (unrated files count as 0 stars)
+    fn below_min_rating(&self) -> bool {
+        match self.settings.min_rating {
+            Some(min_rating) => matches!(file_kind(&self.input_file, self.settings.detect_by_content), FileKind::Raw)
+                && xmp_rating(&self.input_file).unwrap_or(0) < min_rating,
+            None => false,
+        }
+    }
+
+    /// Marks the output as complete, records the checksums of the `written` outputs, adds the report
+    /// and index entries and runs the post-file hook
+    ///
+    /// `written` starts with the output of the job, followed by the variants that were written along with it.
+    fn finish_output(&mut self, written: &[PathBuf]) {
+        unmark_in_progress(&self.output_file);
+        self.statistics.record_extension(&self.input_file, self.started.elapsed());
+        let variants = written.get(1..).unwrap_or_default();
 
-        // create parent directory if necessary
-        if self.output_file.parent().is_some() && !self.output_file.parent().unwrap().exists() {
-            fs::create_dir_all((self.output_file.parent()).unwrap()).map_err(|s| s.to_string())?;
+        if let Some(algo) = self.settings.checksums {
+            for output in written {
+                match hash_file(output, algo) {
+                    Ok(digest) => self.statistics.checksums.push((output.clone(), digest)),
+                    Err(e) => {
+                        println!("Unable to hash {:?}: {}", output, e);
+                        self.statistics.errors.inc();
+                    },
+                }
+            }
         }
 
+        if let Some(remote) = &self.settings.output_storage {
+            for output in written {
+                if let Err(e) = remote.upload(output) {
+                    println!("Error ({}): {}", self.input_file.display(), e);
+                    self.statistics.errors.inc();
+                }
+            }
+        }
+
+        let status = if self.statistics.salvaged.count() > 0 {
+            "partial"
+        } else if self.statistics.quarantined.count() > 0 {
+            "corrupt"
+        } else if self.statistics.errors.count() > 0 {
+            "error"
+        } else if self.duplicate_of.is_some() {
+            "duplicate"
+        } else if !written.is_empty() {
+            "ok"
+        } else {
+            "ignored"
+        };
+
+        let output = match (status, &self.quarantine_copy, &self.duplicate_of) {
+            ("corrupt", Some(quarantined), _) => quarantined.clone(),
+            ("duplicate", _, Some((_, original_output))) => original_output.clone(),
+            _ => self.output_file.clone(),
+        };
+
+        // files that were skipped early on are described here, while they are still in place
+        let entry = match (&self.settings.index, self.index_entry.take()) {
+            (Some(_), None) => IndexEntry::describe(&self.input_file).ok(),
+            (_, entry) => entry,
+        };
+        if let (Some(index), Some(mut entry)) = (&self.settings.index, entry) {
+            entry.action = self.statistics.job_action().to_string();
+            entry.status = status.to_string();
+            entry.output = output.clone();
+            let variant_entries: Vec<_> = variants.iter()
+                .map(|path| IndexEntry { output: path.clone(), original: None, ..entry.clone() })
+                .collect();
+            for entry in std::iter::once(&entry).chain(&variant_entries) {
+                if let Err(e) = index.record(entry) {
+                    println!("Error ({}): {}", self.input_file.display(), e);
+                }
+            }
+        }
+
+        if self.settings.report {
+            self.statistics.report.push(ReportEntry {
+                input: self.input_file.clone(),
+                output,
+                status,
+                analysis: self.analysis.take(),
+                duplicate_of: self.duplicate_of.as_ref().map(|(original, _)| original.clone()),
+            });
+            for path in variants {
+                self.statistics.report.push(ReportEntry {
+                    input: self.input_file.clone(),
+                    output: path.clone(),
+                    status,
+                    analysis: None,
+                    duplicate_of: None,
+                });
+            }
+        }
+
+        if let Some(command) = &self.settings.hooks.post_file {
+            let mut env = file_hook_env(&self.input_file, &self.output_file, Some(status));
+            if let Ok(joined) = std::env::join_paths(variants) {
+                env.push(("VARIANTS", joined));
+            }
+            if let Err(e) = run_hook(command, &env) {
+                println!("Error ({}): {}", self.input_file.display(), e);
+            }
+        }
+    }
+
+    /// Continues with the partial image salvaged from a corrupt raw, which goes into an output marked with `_partial`
+    ///
+    /// The partial image never ends up in the cache, so converting the raw again once it was replaced works as usual.
+    fn into_salvaged(mut self, decoded: imagepipe::SRGBImage, decode_time: time::Duration, monochrome: bool) -> DecodedJob {
+        self.statistics.salvaged.inc();
+        self.cache_key = None;
+        unmark_in_progress(&self.output_file);
+        self.output_file = partial_path(&self.output_file);
+        for (_, _, path) in &mut self.variants {
+            *path = partial_path(path);
+        }
+        mark_in_progress(&self.output_file);
+        DecodedJob { job: self, decoded, decode_time, monochrome }
+    }
+
+    /// Restores the output and all variants from the cache, `None` unless all of them were cached
+    fn restore_cached(&self, cache: &OutputCache, key: &str) -> Option<time::Duration> {
+        if let Some(parent) = self.output_file.parent() {
+            ensure_dir(parent).ok()?;
+        }
+        let mut time = cache.restore(key, self.settings.encoder, &self.output_file)?;
+        for (variant, encoder, path) in &self.variants {
+            if let Some(parent) = path.parent() {
+                ensure_dir(parent).ok()?;
+            }
+            time += cache.restore(&variant_key(key, variant), *encoder, path)?;
+        }
+        Some(time)
+    }
+
+    /// Records a copy of the output file that took `time`, along with its size in MiB for the throughput
+    fn record_copy(&mut self, time: time::Duration) {
+        let size = self.output_file.metadata().map(|m| m.len()).unwrap_or(0);
+        self.statistics.copied.record_volume(time, size as f64 / (1024.0 * 1024.0));
+    }
+
+    /// Handles a file that is not converted according to `action`
+    fn transfer(&mut self, action: UnparsableAction) {
+        let (input, output) = (self.input_file.as_path(), self.output_file.as_path());
+        let transfer: fn(&Path, &Path) -> Result<time::Duration, Error> = match action {
+            UnparsableAction::Ignore => {
+                self.statistics.ignored.inc();
+                return;
+            },
+            UnparsableAction::Copy => copy,
+            UnparsableAction::Move => move_file,
+            UnparsableAction::Hardlink => link_or_copy,
+            UnparsableAction::Reflink => reflink_or_copy,
+        };
+        let moved = action == UnparsableAction::Move;
+        let transferred = retry_transient(self.settings.retries, &mut self.statistics.retried, || {
+            if let Some(parent) = output.parent() {
+                ensure_dir(parent)?;
+            }
+            transfer(input, output)
+        });
+        match (transferred, moved) {
+            (Ok(mtime), true) => self.statistics.moved.record(mtime),
+            (Ok(ctime), false) => self.record_copy(ctime),
+            (Err(_), _) => self.statistics.errors.inc(),
+        }
+    }
+
+    pub fn run(self) -> Result<Statistics, Error> {
+        match self.run_decode()? {
+            Stage::Finished(statistics) => Ok(statistics),
+            Stage::Decoded(decoded) => Ok(decoded.run_encode()),
+        }
+    }
+
+    /// Runs the job up to and including decoding
+    ///
+    /// Jobs that do not need to be encoded are completed right away.
+    pub fn run_decode(mut self) -> Result<Stage, Error> {
+        self.started = Instant::now();
+        let retries = self.settings.retries;
+        // fetch file metadata to later distinguish regular files from other files
+        let metadata = retry_transient(retries, &mut self.statistics.retried,
+            || self.input_file.metadata().map_err(|e| Error::io(&self.input_file, e)))?;
+
         if metadata.is_file() {
-            if self.output_file.exists() {
-                match self.on_existing {
+            if self.below_min_rating() {
+                self.statistics.ignored.inc();
+                self.finish_output(&[]);
+                return Ok(Stage::Finished(self.statistics));
+            }
+            if let Some(duplicates) = self.settings.duplicates.clone() {
+                if let Some((original, original_output)) = duplicates.claim(&self.input_file, &self.output_file)? {
+                    println!("Skipping {:?}, it duplicates {:?}", self.input_file, original);
+                    self.statistics.ignored.inc();
+                    self.duplicate_of = Some((original, original_output));
+                    self.finish_output(&[]);
+                    return Ok(Stage::Finished(self.statistics));
+                }
+            }
+
+            let exists = match &self.settings.output_storage {
+                Some(remote) => remote.exists(&self.output_file),
+                None => self.output_file.exists(),
+            };
+            if exists {
+                match self.settings.on_existing {
                     ExistingAction::Rename => {
                         self.statistics.errors.inc();
-                        return Err(format!("Could not find unused path for {}", self.output_file.to_string_lossy()));
+                        return Err(Error::Path(format!("Could not find unused path for {}", self.output_file.display())));
                     },
                     ExistingAction::Ignore => {
                         self.statistics.ignored.inc();
-                        return Ok(self.statistics);
+                        self.finish_output(&[]);
+                        return Ok(Stage::Finished(self.statistics));
                     }
                 }
             }
+            if let Some(index) = self.settings.index.clone() {
+                match IndexEntry::describe(&self.input_file) {
+                    Ok(entry) => {
+                        let duplicate = match self.settings.skip_duplicates {
+                            true => index.duplicate_of(&entry.hash, &self.input_file),
+                            false => None,
+                        };
+                        self.index_entry = Some(entry);
+                        if let Some(duplicate) = duplicate {
+                            println!("Skipping {:?}, it duplicates {:?}", self.input_file, duplicate.0);
+                            self.statistics.ignored.inc();
+                            self.duplicate_of = Some(duplicate);
+                            self.finish_output(&[]);
+                            return Ok(Stage::Finished(self.statistics));
+                        }
+                    },
+                    Err(e) => println!("Error ({}): {}", self.input_file.display(), e),
+                }
+            }
+            mark_in_progress(&self.output_file);
 
-            match file_kind(&self.input_file) {
-                FileKind::Raw => match self.on_raw {
+            if let Some(command) = &self.settings.hooks.pre_file {
+                if let Err(e) = run_hook(command, &file_hook_env(&self.input_file, &self.output_file, None)) {
+                    println!("Error ({}): {}", self.input_file.display(), e);
+                }
+            }
+
+            match file_kind(&self.input_file, self.settings.detect_by_content) {
+                FileKind::Raw => match self.settings.on_raw {
                     ParsableAction::Ignore => self.statistics.ignored.inc(),
-                    ParsableAction::Parse =>
-                        match recode(self.input_file.as_path(), self.output_file.as_path(), self.encoder) {
-                            Some((dtime, etime)) => {
-                                self.statistics.decoded.record(dtime);
-                                self.statistics.encoded.record(etime);
+                    ParsableAction::Parse => {
+                        if let Some(original_copy) = &self.original_copy {
+                            if !original_copy.exists() {
+                                let kept = match original_copy.parent() {
+                                    Some(parent) => ensure_dir(parent).is_ok(),
+                                    None => true,
+                                } && retry_transient(retries, &mut self.statistics.retried,
+                                    || link_or_copy(&self.input_file, original_copy)).is_ok();
+                                if !kept {
+                                    self.statistics.errors.inc();
+                                } else if let Some(entry) = &mut self.index_entry {
+                                    entry.original = Some(original_copy.clone());
+                                }
+                            }
+                        }
+
+                        if let Some(cache) = self.settings.cache.clone() {
+                            match cache.key(&self.input_file, &self.settings) {
+                                Ok(key) => match self.restore_cached(&cache, &key) {
+                                    Some(time) => {
+                                        self.statistics.cached.record(time);
+                                        let written: Vec<_> = std::iter::once(self.output_file.clone())
+                                            .chain(self.variants.iter().map(|(_, _, path)| path.clone()))
+                                            .collect();
+                                        self.finish_output(&written);
+                                        return Ok(Stage::Finished(self.statistics));
+                                    },
+                                    None => self.cache_key = Some(key),
+                                },
+                                Err(e) => println!("Error ({}): {}", self.input_file.display(), e),
+                            }
+                        }
+
+                        let start = Instant::now();
+                        let decoded = retry_transient(retries, &mut self.statistics.retried,
+                            || decode_step(self.input_file.as_path(), &self.settings));
+                        match decoded {
+                            Ok((decoded, decode_time, monochrome)) => {
+                                self.statistics.decoded.record_volume(decode_time, megapixels(&decoded));
+                                if self.settings.analyze {
+                                    let analysis = analyze_image(&decoded);
+                                    analysis.print(&self.input_file);
+                                    self.analysis = Some(analysis);
+                                }
+                                return Ok(Stage::Decoded(DecodedJob { job: self, decoded, decode_time, monochrome }));
                             },
-                            None => self.statistics.errors.inc(),
-                        },
-                    ParsableAction::Copy =>
-                        match copy(self.input_file.as_path(), self.output_file.as_path()) {
-                            Some(ctime) => self.statistics.copied.record(ctime),
-                            None => self.statistics.errors.inc(),
-                        },
-                    ParsableAction::Move =>
-                        match move_file(self.input_file.as_path(), self.output_file.as_path()) {
-                            Some(mtime) => self.statistics.moved.record(mtime),
-                            None => self.statistics.errors.inc(),
-                        },
-                },
-                FileKind::Image => match self.on_image {
-                    UnparsableAction::Ignore => self.statistics.ignored.inc(),
-                    UnparsableAction::Copy =>
-                        match copy(self.input_file.as_path(), self.output_file.as_path()) {
-                            Some(ctime) => self.statistics.copied.record(ctime),
-                            None => self.statistics.errors.inc(),
-                        },
-                    UnparsableAction::Move =>
-                        match move_file(self.input_file.as_path(), self.output_file.as_path()) {
-                            Some(mtime) => self.statistics.moved.record(mtime),
-                            None => self.statistics.errors.inc(),
-                        },
-                },
-                FileKind::Other => match self.on_file {
-                    UnparsableAction::Ignore => self.statistics.ignored.inc(),
-                    UnparsableAction::Copy =>
-                        match copy(self.input_file.as_path(), self.output_file.as_path()) {
-                            Some(ctime) => self.statistics.copied.record(ctime),
-                            None => self.statistics.errors.inc(),
-                        },
-                    UnparsableAction::Move =>
-                        match move_file(self.input_file.as_path(), self.output_file.as_path()) {
-                            Some(mtime) => self.statistics.moved.record(mtime),
-                            None => self.statistics.errors.inc(),
-                        },
+                            Err(e) => {
+                                if self.settings.job_timeout.is_some_and(|t| start.elapsed() >= t) {
+                                    self.statistics.timeouts.inc();
+                                } else if let (Error::Decode(_), Some(quarantine)) = (&e, &self.quarantine_copy) {
+                                    match quarantine_raw(&self.input_file, quarantine) {
+                                        Ok(()) => self.statistics.quarantined.inc(),
+                                        Err(e) => println!("Error ({}): {}", self.input_file.display(), e),
+                                    }
+                                }
+                                self.statistics.errors.inc();
+                                if let (Error::Decode(_), true) = (&e, self.settings.salvage) {
+                                    if let Ok((decoded, decode_time, monochrome)) = salvage_step(&self.input_file, &self.settings) {
+                                        return Ok(Stage::Decoded(self.into_salvaged(decoded, decode_time, monochrome)));
+                                    }
+                                }
+                            },
+                        }
+                    },
+                    ParsableAction::Copy => self.transfer(UnparsableAction::Copy),
+                    ParsableAction::Move => self.transfer(UnparsableAction::Move),
                 },
+                FileKind::Image => self.transfer(self.settings.on_image),
+                FileKind::Video => self.transfer(self.settings.on_video),
+                FileKind::Other => self.transfer(self.settings.on_file),
             }
+            let written = match self.statistics.copied.count() + self.statistics.moved.count() > 0 {
+                true => vec![self.output_file.clone()],
+                false => Vec::new(),
+            };
+            self.finish_output(&written);
         } else {
             self.statistics.ignored.inc();
         }
 
-        Ok(self.statistics)
+        Ok(Stage::Finished(self.statistics))
     }
 }
 
+impl DecodedJob {
+    pub fn input_file(&self) -> &Path {
+        self.job.input_file()
+    }
 
+    pub fn run_encode(mut self) -> Statistics {
+        let job = &mut self.job;
+        let resized = job.settings.variants.first().and_then(|v| v.resized(&self.decoded));
+        let image = resized.as_ref().unwrap_or(&self.decoded);
+        let encoded = retry_transient(job.settings.retries, &mut job.statistics.retried, || {
+            if let Some(parent) = job.output_file.parent() {
+                ensure_dir(parent)?;
+            }
+            encode_step(image, self.decode_time, self.monochrome, &job.input_file, &job.output_file, &job.settings)
+        });
+        let mut written = Vec::new();
+        match encoded {
+            Ok(encode_time) => {
+                job.statistics.encoded.record_volume(encode_time, megapixels(image));
+                written.push(job.output_file.clone());
+            },
+            Err(_) => job.statistics.errors.inc(),
+        }
+        if let (false, Some(cache), Some(key)) = (written.is_empty(), &job.settings.cache, &job.cache_key) {
+            if let Err(e) = cache.store(key, job.settings.encoder, &job.output_file) {
+                println!("Unable to add {:?} to the cache: {}", job.output_file, e);
+            }
+        }
+
+        // variants of an output that failed would be left behind without it
+        let variants = match written.is_empty() {
+            true => &[][..],
+            false => &job.variants[..],
+        };
+        for (variant, encoder, path) in variants {
+            let settings = JobSettings { encoder: *encoder, ..job.settings.clone() };
+            let resized = variant.resized(&self.decoded);
+            let image = resized.as_ref().unwrap_or(&self.decoded);
+            mark_in_progress(path);
+            let encoded = retry_transient(settings.retries, &mut job.statistics.retried, || {
+                if let Some(parent) = path.parent() {
+                    ensure_dir(parent)?;
+                }
+                encode_step(image, self.decode_time, self.monochrome, &job.input_file, path, &settings)
+            });
+            unmark_in_progress(path);
+            match encoded {
+                Ok(encode_time) => {
+                    job.statistics.encoded.record_volume(encode_time, megapixels(image));
+                    written.push(path.clone());
+                },
+                Err(_) => {
+                    job.statistics.errors.inc();
+                    continue;
+                },
+            }
+            if let (Some(cache), Some(key)) = (&settings.cache, &job.cache_key) {
+                if let Err(e) = cache.store(&variant_key(key, variant), *encoder, path) {
+                    println!("Unable to add {:?} to the cache: {}", path, e);
+                }
+            }
+        }
+
+        job.finish_output(&written);
+        self.job.statistics
+    }
+}
+
+/// Runs `step` again while it fails with a transient error (see `Error::is_transient()`) and `retries`
+/// are left, waiting twice as long before every further attempt
+///
+/// Each retry is counted in `retried`.
+pub fn retry_transient<T>(retries: u32, retried: &mut StatisticsItem, mut step: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+    let mut delay = RETRY_DELAY;
+    for _ in 0..retries {
+        match step() {
+            Err(e) if e.is_transient() => {
+                println!("Retrying in {} ({})", fmt_duration(&delay), e);
+                retried.inc();
+                std::thread::sleep(delay);
+                delay *= 2;
+            },
+            result => return result,
+        }
+    }
+    step()
+}
+
+/// Cache key of a variant of the output cached under `key`
+fn variant_key(key: &str, variant: &Variant) -> String {
+    format!("{}-{}", key, variant.name)
+}
+
+/// Name of the output a partially salvaged raw is written to (`IMG_1234_partial.jpg`)
+fn partial_path(output: &Path) -> PathBuf {
+    let mut name = suffixed_stem(output, "_partial");
+    if let Some(extension) = output.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    output.with_file_name(name)
+}
+
+fn megapixels(image: &imagepipe::SRGBImage) -> f64 {
+    (image.width * image.height) as f64 / 1e6
+}
+
+/// Creates `dir` and its parents unless a previous job already did so
+fn ensure_dir(dir: &Path) -> Result<(), Error> {
+    let cache = CREATED_DIRS.get_or_init(Default::default);
+    if cache.lock().unwrap().contains(dir) {
+        return Ok(());
+    }
+
+    fs::create_dir_all(dir).map_err(|e| Error::io(dir, e))?;
+    cache.lock().unwrap().insert(dir.to_path_buf());
+    Ok(())
+}
+
+fn mark_in_progress(output: &Path) {
+    IN_PROGRESS.get_or_init(Default::default).lock().unwrap().insert(output.to_path_buf());
+}
+
+fn unmark_in_progress(output: &Path) {
+    IN_PROGRESS.get_or_init(Default::default).lock().unwrap().remove(output);
+}
+
+/// Deletes the temporary output files of all jobs that are still running and returns their paths
+///
+/// Meant to be called right before the process exits while jobs are still in flight.
+pub fn remove_partial_outputs() -> Vec<PathBuf> {
+    let mut in_progress = IN_PROGRESS.get_or_init(Default::default).lock().unwrap();
+    in_progress.drain()
+        .map(|output| temp_path(&output))
+        .filter(|temp| fs::remove_file(temp).is_ok())
+        .collect()
+}
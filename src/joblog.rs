@@ -0,0 +1,76 @@
+use crate::*;
+
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::time::SystemTime;
+
+
+/// Appends one JSON record per job to a log file, independent of what is printed to the console
+pub struct JobLog {
+    path: PathBuf,
+    writer: BufWriter<fs::File>,
+    /// Errors reported for jobs that have not finished yet, keyed by file
+    errors: HashMap<PathBuf, (&'static str, String)>,
+}
+
+
+impl JobLog {
+    /// Opens `path` for appending, so consecutive runs and batches share one log
+    pub fn open(path: &Path) -> Result<JobLog, String> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format!("unable to open log file {:?} ({})", path, e))?;
+        Ok(JobLog { path: path.to_path_buf(), writer: BufWriter::new(file), errors: HashMap::new() })
+    }
+
+    pub fn record(&mut self, progress: &Progress) {
+        let result = match progress {
+            Progress::Started { .. } | Progress::Discovered { .. } | Progress::Deferred { .. } | Progress::JobStarted { .. } => Ok(()),
+            Progress::JobFailed { file, error } => {
+                self.errors.insert(file.to_path_buf(), (error.category(), error.to_string()));
+                Ok(())
+            },
+            Progress::JobFinished { file, statistics, .. } => {
+                let error = self.errors.remove(*file);
+                self.write_job(&file.to_string_lossy(), statistics, error)
+            },
+            Progress::Cancelled { unprocessed, .. } => unprocessed.iter()
+                .try_for_each(|file| self.write_cancelled(&file.to_string_lossy())),
+        };
+        if let Err(e) = result.and_then(|_| self.writer.flush()) {
+            eprintln!("Unable to write to log file {:?} ({})", self.path, e);
+        }
+    }
+
+    fn write_job(&mut self, name: &str, statistics: &Statistics, error: Option<(&'static str, String)>) -> io::Result<()> {
+        let end = unix_time(SystemTime::now());
+        let duration = statistics.job_time();
+        let result = if statistics.timeouts.count() > 0 {
+            "timeout"
+        } else if statistics.errors.count() > 0 || error.is_some() {
+            "error"
+        } else {
+            "ok"
+        };
+        let error = match error {
+            Some((category, message)) => format!(",\"error_kind\":\"{}\",\"error\":\"{}\"", category, json_escape(&message)),
+            None => String::new(),
+        };
+
+        writeln!(self.writer, "{{\"file\":\"{}\",\"start\":{:.3},\"end\":{:.3},\"action\":\"{}\",\"result\":\"{}\"{},\
+                               \"durations\":{{\"total\":{:.3},\"decode\":{:.3},\"encode\":{:.3},\"copy\":{:.3},\"move\":{:.3}}}}}",
+            json_escape(name), end - duration.as_secs_f64(), end, statistics.job_action(), result, error,
+            duration.as_secs_f64(), statistics.decoded.time_total().as_secs_f64(),
+            statistics.encoded.time_total().as_secs_f64(), statistics.copied.time_total().as_secs_f64(),
+            statistics.moved.time_total().as_secs_f64())
+    }
+
+    fn write_cancelled(&mut self, name: &str) -> io::Result<()> {
+        writeln!(self.writer, "{{\"file\":\"{}\",\"end\":{:.3},\"action\":\"none\",\"result\":\"cancelled\"}}",
+            json_escape(name), unix_time(SystemTime::now()))
+    }
+}
+
+/// Seconds since the Unix epoch
+pub(crate) fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+}
@@ -0,0 +1,235 @@
+use crate::*;
+
+
+/// Directories searched for the lensfun database if none is given
+const LENSFUN_DIRS: &[&str] = &["/usr/share/lensfun/version_1", "/usr/local/share/lensfun/version_1"];
+
+/// Calibration data of a single lens from the lensfun database
+#[derive(Clone, Debug, Default)]
+pub struct LensProfile {
+    pub model: String,
+    /// `ptlens` distortion coefficients `a`, `b` and `c` per focal length
+    pub distortion: Vec<(f32, [f32; 3])>,
+    /// `pa` vignetting coefficients `k1`, `k2` and `k3` per focal length (at the widest calibrated aperture)
+    pub vignetting: Vec<(f32, [f32; 3])>,
+    /// `linear` lateral chromatic aberration factors for red and blue per focal length
+    pub tca: Vec<(f32, [f32; 2])>,
+}
+
+/// Lens profiles read from lensfun XML files
+#[derive(Clone, Debug, Default)]
+pub struct LensDatabase {
+    lenses: Vec<LensProfile>,
+}
+
+
+impl LensDatabase {
+    /// Reads all XML files in `dir`, or in the default lensfun locations if `dir` is not given
+    pub fn load(dir: Option<&Path>) -> Result<LensDatabase, String> {
+        let dir = match dir {
+            Some(dir) => dir.to_path_buf(),
+            None => LENSFUN_DIRS.iter()
+                .map(PathBuf::from)
+                .find(|d| d.is_dir())
+                .ok_or_else(|| String::from("No lensfun database found, please specify one with --lens-db"))?,
+        };
+
+        let mut lenses = Vec::new();
+        let entries = fs::read_dir(&dir).map_err(|e| format!("Unable to read {:?} ({})", dir, e))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|e| e == "xml") {
+                let content = fs::read_to_string(&path).map_err(|e| format!("Unable to read {:?} ({})", path, e))?;
+                lenses.extend(parse_lenses(&content));
+            }
+        }
+        Ok(LensDatabase { lenses })
+    }
+
+    /// Finds the profile of a lens by name, ignoring case, spaces and punctuation
+    pub fn find(&self, name: &str) -> Option<&LensProfile> {
+        let wanted = normalize_name(name);
+        if wanted.is_empty() {
+            return None;
+        }
+        self.lenses.iter().find(|l| normalize_name(&l.model) == wanted)
+            .or_else(|| self.lenses.iter().find(|l| {
+                let model = normalize_name(&l.model);
+                model.contains(&wanted) || wanted.contains(&model)
+            }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.lenses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lenses.is_empty()
+    }
+}
+
+impl LensProfile {
+    /// Corrects vignetting, distortion and lateral chromatic aberration at `focal` mm
+    pub fn correct(&self, image: &mut imagepipe::SRGBImage, focal: f32) {
+        if let Some(k) = nearest(&self.vignetting, focal) {
+            correct_vignetting(image, k);
+        }
+
+        let distortion = nearest(&self.distortion, focal);
+        let tca = nearest(&self.tca, focal);
+        if distortion.is_some() || tca.is_some() {
+            remap(image, distortion.unwrap_or([0.0; 3]), tca.unwrap_or([1.0, 1.0]));
+        }
+    }
+}
+
+/// Looks up the lens of a raw file (falling back to `hint`) and corrects the image with its profile
+pub fn correct_lens(image: &mut imagepipe::SRGBImage, input: &Path, database: &LensDatabase, hint: Option<&str>) {
+    let exif = read_exif(input);
+    let field = |tag| exif.as_ref()?.get_field(tag, exif::In::PRIMARY).cloned();
+
+    let lens = field(exif::Tag::LensModel)
+        .map(|f| f.display_value().to_string().trim_matches('"').trim().to_string())
+        .and_then(|name| database.find(&name))
+        .or_else(|| database.find(hint?));
+    let focal = field(exif::Tag::FocalLength)
+        .and_then(|f| match f.value {
+            exif::Value::Rational(ref values) => values.first().map(|r| r.to_f64() as f32),
+            _ => None,
+        });
+
+    match (lens, focal) {
+        (Some(lens), Some(focal)) => lens.correct(image, focal),
+        (None, _) => println!("No lens profile found for {:?}", input),
+        (_, None) => println!("No focal length found for {:?}, skipping lens correction", input),
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Coefficients of the calibration closest to `focal`
+fn nearest<const N: usize>(calibrations: &[(f32, [f32; N])], focal: f32) -> Option<[f32; N]> {
+    calibrations.iter()
+        .min_by(|a, b| (a.0 - focal).abs().total_cmp(&(b.0 - focal).abs()))
+        .map(|(_, coefficients)| *coefficients)
+}
+
+/// Brightens the corners according to the `pa` model (`1 + k1 r² + k2 r⁴ + k3 r⁶`, r relative to the half diagonal)
+fn correct_vignetting(image: &mut imagepipe::SRGBImage, [k1, k2, k3]: [f32; 3]) {
+    let (cx, cy) = (image.width as f32 / 2.0, image.height as f32 / 2.0);
+    let half_diagonal = (cx * cx + cy * cy).sqrt();
+
+    // the gain only depends on the radius, so it is tabulated
+    const STEPS: usize = 1024;
+    let luts: Vec<[u8; 256]> = (0..=STEPS)
+        .map(|i| {
+            let r2 = (i as f32 / STEPS as f32).powi(2);
+            let gain = 1.0 / (1.0 + k1 * r2 + k2 * r2 * r2 + k3 * r2 * r2 * r2).max(0.1);
+            pixelops::linear_curve_lut(|v| v * gain)
+        })
+        .collect();
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+            let r = ((dx * dx + dy * dy).sqrt() / half_diagonal).min(1.0);
+            let lut = &luts[(r * STEPS as f32) as usize];
+            let i = (y * image.width + x) * 3;
+            for value in &mut image.data[i..i + 3] {
+                *value = lut[*value as usize];
+            }
+        }
+    }
+}
+
+/// Resamples the image undoing `ptlens` distortion and scaling red and blue by the `tca` factors
+///
+/// Radii are relative to half of the shorter image side, as in lensfun.
+fn remap(image: &mut imagepipe::SRGBImage, [a, b, c]: [f32; 3], [kr, kb]: [f32; 2]) {
+    let (width, height) = (image.width, image.height);
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let norm = cx.min(cy);
+    let source = image.data.clone();
+
+    let sample = |x: f32, y: f32, channel: usize| -> u8 {
+        let x = x.clamp(0.0, (width - 1) as f32);
+        let y = y.clamp(0.0, (height - 1) as f32);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+        let at = |x: usize, y: usize| source[(y * width + x) * 3 + channel] as f32;
+        let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+        let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+        (top * (1.0 - fy) + bottom * fy).round() as u8
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            let (dx, dy) = ((x as f32 - cx) / norm, (y as f32 - cy) / norm);
+            let r = (dx * dx + dy * dy).sqrt();
+            let scale = a * r * r * r + b * r * r + c * r + 1.0 - a - b - c;
+
+            let i = (y * width + x) * 3;
+            for (channel, factor) in [(0, kr), (1, 1.0), (2, kb)] {
+                let s = scale * factor;
+                image.data[i + channel] = sample(cx + dx * s * norm, cy + dy * s * norm, channel);
+            }
+        }
+    }
+}
+
+/// Extracts the lens profiles from a lensfun XML file
+///
+/// Only the elements needed for the supported models are read, everything else is skipped.
+fn parse_lenses(xml: &str) -> Vec<LensProfile> {
+    let mut lenses = Vec::new();
+    for block in xml.split("<lens>").skip(1) {
+        let block = block.split("</lens>").next().unwrap_or_default();
+        let model = match block.split("<model>").nth(1).and_then(|m| m.split("</model>").next()) {
+            Some(model) => model.trim().to_string(),
+            None => continue,
+        };
+
+        let mut lens = LensProfile { model, ..LensProfile::default() };
+        for tag in block.split('<').skip(1).map(|t| t.split('>').next().unwrap_or_default()) {
+            let number = |name: &str| attribute(tag, name).and_then(|v| v.parse::<f32>().ok());
+            let focal = match number("focal") {
+                Some(focal) => focal,
+                None => continue,
+            };
+
+            if tag.starts_with("distortion") && attribute_is(tag, "model", "ptlens") {
+                lens.distortion.push((focal, [
+                    number("a").unwrap_or(0.0), number("b").unwrap_or(0.0), number("c").unwrap_or(0.0),
+                ]));
+            } else if tag.starts_with("vignetting") && attribute_is(tag, "model", "pa") {
+                // keep the calibration at the widest aperture and closest distance per focal length
+                if !lens.vignetting.iter().any(|(f, _)| *f == focal) {
+                    lens.vignetting.push((focal, [
+                        number("k1").unwrap_or(0.0), number("k2").unwrap_or(0.0), number("k3").unwrap_or(0.0),
+                    ]));
+                }
+            } else if tag.starts_with("tca") && attribute_is(tag, "model", "linear") {
+                lens.tca.push((focal, [number("kr").unwrap_or(1.0), number("kb").unwrap_or(1.0)]));
+            }
+        }
+        lenses.push(lens);
+    }
+    lenses
+}
+
+/// Value of the XML attribute `name` inside the tag `tag`
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let pattern = format!(" {}=\"", name);
+    let start = tag.find(&pattern)? + pattern.len();
+    tag[start..].split('"').next()
+}
+
+fn attribute_is(tag: &str, name: &str, value: &str) -> bool {
+    attribute(tag, name) == Some(value)
+}
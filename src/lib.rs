@@ -0,0 +1,817 @@
+use std::{fs, path, io, time};
+
+use image::ColorType;
+use image::ImageEncoder;
+use std::time::Instant;
+use std::path::*;
+
+extern crate imagepipe;
+extern crate rawloader;
+
+pub use adjustments::*;
+pub use archive::*;
+pub use batch::*;
+pub use bursts::*;
+pub use cache::*;
+pub use camera::*;
+pub use checksums::*;
+pub use colorspace::*;
+pub use config::*;
+#[cfg(unix)]
+pub use control::*;
+pub use decoders::*;
+pub use demosaic::*;
+pub use develop::*;
+pub use download::*;
+pub use duplicates::*;
+pub use edits::*;
+pub use error::*;
+pub use events::*;
+#[cfg(feature = "ffi")]
+pub use ffi::*;
+#[cfg(feature = "gpu")]
+pub use gpu::*;
+pub use hdr::*;
+pub use hooks::*;
+pub use index::*;
+pub use job::*;
+pub use joblog::*;
+pub use lens::*;
+pub use matching::*;
+pub use metadata::*;
+pub use metrics::*;
+pub use mosaic::*;
+pub use negative::*;
+pub use overlay::*;
+pub use overrides::*;
+pub use paths::*;
+pub use presets::*;
+pub use quarantine::*;
+pub use report::*;
+pub use routing::*;
+pub use salvage::*;
+pub use scan::*;
+pub use sequences::*;
+pub use serve::*;
+pub use sftp::*;
+pub use sniff::*;
+pub use space::*;
+pub use statistics::*;
+pub use storage::*;
+pub use styles::*;
+pub use tone::*;
+pub use transfer::*;
+pub use undo::*;
+#[cfg(feature = "tui")]
+pub use tui::*;
+pub use status::*;
+pub use variants::*;
+pub use verify::*;
+#[cfg(feature = "wasm")]
+pub use wasm::*;
+pub use webhook::*;
+pub use xmp::*;
+
+mod adjustments;
+mod archive;
+mod batch;
+mod bursts;
+mod cache;
+mod camera;
+mod checksums;
+mod colorspace;
+mod config;
+#[cfg(unix)]
+mod control;
+mod decoders;
+mod demosaic;
+mod develop;
+mod download;
+mod duplicates;
+mod edits;
+mod error;
+mod events;
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(feature = "gpu")]
+mod gpu;
+mod hdr;
+mod hooks;
+mod index;
+mod job;
+mod joblog;
+mod lens;
+mod matching;
+mod metadata;
+mod metrics;
+mod mosaic;
+mod negative;
+mod overlay;
+mod overrides;
+mod paths;
+mod presets;
+mod quarantine;
+mod report;
+mod routing;
+mod salvage;
+mod scan;
+pub mod pixelops;
+mod sequences;
+mod serve;
+mod sftp;
+mod sniff;
+mod space;
+mod statistics;
+mod storage;
+mod styles;
+mod tone;
+mod transfer;
+mod undo;
+#[cfg(feature = "tui")]
+mod tui;
+mod status;
+mod variants;
+mod verify;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod webhook;
+mod xmp;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum UnparsableAction {
+    Copy, Move, Ignore,
+    /// Hardlink the file into the output (copying it if input and output are on different file systems)
+    Hardlink,
+    /// Share the data of the file with its copy on file systems that support it (btrfs, XFS, ...),
+    /// copying it otherwise
+    Reflink,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum ParsableAction {
+    Copy, Move, Ignore, Parse,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum ExistingAction {
+    Rename, Ignore,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum EncodedType {
+    Jpeg, Png, Tiff, Qoi
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Raw, Image, Video, Other,
+}
+
+#[derive(Copy, Clone)]
+pub enum EncoderType {
+    JpegEncoder(u8),
+    PngEncoder(image::codecs::png::CompressionType, image::codecs::png::FilterType),
+    TiffEncoder,
+    QoiEncoder
+}
+
+impl EncoderType {
+    pub fn new(encode_type: EncodedType, jpeg_quality: u8) -> EncoderType {
+        match encode_type {
+            EncodedType::Jpeg => EncoderType::JpegEncoder(jpeg_quality),
+            EncodedType::Png => EncoderType::PngEncoder(image::codecs::png::CompressionType::Default,
+                                                       image::codecs::png::FilterType::Adaptive),
+            EncodedType::Tiff => EncoderType::TiffEncoder,
+            EncodedType::Qoi => EncoderType::QoiEncoder,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            EncoderType::JpegEncoder(_) => "jpg",
+            EncoderType::PngEncoder(_, _) => "png",
+            EncoderType::TiffEncoder => "tiff",
+            EncoderType::QoiEncoder => "qoi",
+        }
+    }
+}
+
+pub const RAW_EXTENSIONS: [&str; 3] = [
+    "arw", "cr2", "raw",
+];
+
+pub const IMG_EXTENSIONS: [&str; 5] = [
+    "jpg", "jpeg", "png", "tiff", "tif",
+];
+
+pub const VIDEO_EXTENSIONS: [&str; 8] = [
+    "mp4", "mov", "m4v", "mts", "m2ts", "avi", "3gp", "mxf",
+];
+
+
+pub fn raw_info_short(raw_path: &path::Path) {
+    let from_time = Instant::now();
+    let image = match rawloader::decode_file(raw_path) {
+        Ok(val) => val,
+        Err(_e) => return,
+    };
+    let duration = from_time.elapsed();
+
+    println!("File: {:?}", raw_path);
+    println!("\tSize: {}x{}", image.width, image.height);
+    println!("\tTaken with \"{}\"", image.model);
+    println!("\tDecoded metadata in {} ms", duration.as_millis());
+}
+
+pub fn fmt_duration(duration: &time::Duration) -> String {
+    let millis = duration.as_millis() % 1000;
+    let secs = duration.as_secs() % 60;
+    let mins = duration.as_secs() / 60;
+
+    let mut string = String::new();
+
+    if mins > 0 {
+        string.push_str(format!("{}m ", mins).as_str());
+    }
+    if secs > 0 {
+        string.push_str(format!("{}s ", secs).as_str());
+    }
+    string.push_str(format!("{}ms", millis).as_str());
+
+    string
+}
+
+pub fn fmt_bytes(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        return format!("{:.2} KiB", (bytes as f64) / 1024.0);
+    } else if bytes < 1024 * 1024 * 1024 {
+        return format!("{:.2} MiB", (bytes as f64) / (1024.0 * 1024.0));
+    } else {
+        return format!("{:.2} GiB", (bytes as f64) / (1024.0 * 1024.0 * 1024.0));
+    }
+
+}
+
+pub fn decode_raw(path: &path::Path, decoder: DecoderChoice, gpu: bool, options: &DecodeOptions) -> Result<(imagepipe::SRGBImage, time::Duration), Error> {
+    let start_decode = Instant::now();
+    // a file that can not be opened is an I/O error (which may be transient), not an undecodable one
+    fs::File::open(path).map_err(|e| Error::io(path, e))?;
+    let decoded = match decode_with_chain(RawInput::File(path), decoder, gpu, options) {
+        Ok(img) => img,
+        Err(e) => return Err(Error::Decode(e)),
+    };
+
+    Ok((decoded, start_decode.elapsed()))
+}
+
+/// Decodes raw data that is already in memory, without any file system access
+///
+/// Unlike `decode_raw()`, the decode time is not measured, as there is no clock on every
+/// platform (e.g. in the browser).
+pub fn decode_raw_bytes(data: &[u8], decoder: DecoderChoice, gpu: bool, options: &DecodeOptions) -> Result<imagepipe::SRGBImage, Error> {
+    decode_with_chain(RawInput::Bytes(data), decoder, gpu, options).map_err(Error::Decode)
+}
+
+/// Like `decode_raw()`, but gives up after `timeout`
+///
+/// Decoding runs on a separate thread, which can not be stopped from the outside. A decoder that
+/// hangs keeps its thread busy in the background, but no longer blocks the calling job.
+pub fn decode_raw_timeout(path: &path::Path, decoder: DecoderChoice, gpu: bool, options: &DecodeOptions,
+                          timeout: time::Duration) -> Result<(imagepipe::SRGBImage, time::Duration), Error> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let owned_path = path.to_path_buf();
+    let options = options.clone();
+    std::thread::spawn(move || {
+        let _ = tx.send(decode_raw(&owned_path, decoder, gpu, &options));
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Err(Error::Timeout(timeout)),
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => Err(Error::Decode(String::from("decoder thread panicked"))),
+    }
+}
+
+/// Temporary file that output is written to before it is renamed to `path`
+///
+/// The temporary file lives next to `path` (so renaming it is atomic) and is hidden by a leading dot.
+pub fn temp_path(path: &path::Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Moves a finished temporary file into place, deleting it if that fails
+fn finish_temp(temp: &path::Path, path: &path::Path) -> io::Result<()> {
+    fs::rename(temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(temp);
+    })
+}
+
+/// Encodes `decoded` into `path`, embedding `exif` and the `icc` profile if the format supports them
+///
+/// The image is written to a temporary file first, so `path` never contains a truncated image.
+/// With `gray`, the image is stored as grayscale (see `is_grayscale()`).
+pub fn encode_img(decoded: &imagepipe::SRGBImage, path: &path::Path, encoder_type: EncoderType,
+                  exif: Option<&[u8]>, icc: Option<&[u8]>, gray: bool) -> Result<time::Duration, Error> {
+    let start_encode = Instant::now();
+    let encoded = encode_bytes(decoded, encoder_type, exif, icc, gray)?;
+    let temp = write_temp(path, &encoded)?;
+    finish_temp(&temp, path).map_err(|e| Error::io(path, e))?;
+    Ok(start_encode.elapsed())
+}
+
+/// Writes `encoded` to the temporary file of `path` (see `temp_path()`), returning the temporary file
+fn write_temp(path: &path::Path, encoded: &[u8]) -> Result<PathBuf, Error> {
+    let temp = temp_path(path);
+    let write_result = fs::File::create(&temp)
+        .and_then(|mut file| {
+            io::Write::write_all(&mut file, encoded)?;
+            file.sync_all()
+        });
+    if let Err(e) = write_result {
+        let _ = fs::remove_file(&temp);
+        return Err(Error::io(path, e));
+    }
+    Ok(temp)
+}
+
+/// Encodes `decoded` in memory like `encode_img()`, returning the encoded file
+pub fn encode_bytes(decoded: &imagepipe::SRGBImage, encoder_type: EncoderType, exif: Option<&[u8]>,
+                    icc: Option<&[u8]>, gray: bool) -> Result<Vec<u8>, Error> {
+    // QOI only supports color images
+    let gray = gray && !matches!(encoder_type, EncoderType::QoiEncoder);
+    let (data, color_type) = if gray {
+        (std::borrow::Cow::Owned(pixelops::gray_from_rgb8(&decoded.data)), ColorType::L8)
+    } else {
+        (std::borrow::Cow::Borrowed(&decoded.data[..]), ColorType::Rgb8)
+    };
+    // RGB profiles do not apply to grayscale images, whose gray looks the same in every color space
+    let icc = icc.filter(|_| !gray).map(<[u8]>::to_vec);
+
+    let mut buffer = io::Cursor::new(Vec::new());
+    let encode_result = match encoder_type {
+        EncoderType::JpegEncoder(quality) => {
+            let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
+            if let Some(icc) = icc {
+                encoder.set_icc_profile(icc).map_err(|e| Error::Encode(e.to_string()))?;
+            }
+            encoder.write_image(&data, decoded.width as u32, decoded.height as u32, color_type.into())
+        },
+        EncoderType::PngEncoder(compression, filter) => {
+            let mut encoder = image::codecs::png::PngEncoder::new_with_quality(&mut buffer, compression, filter);
+            if let Some(icc) = icc {
+                encoder.set_icc_profile(icc).map_err(|e| Error::Encode(e.to_string()))?;
+            }
+            encoder.write_image(&data, decoded.width as u32, decoded.height as u32, color_type.into())
+        },
+        EncoderType::TiffEncoder
+            => image::codecs::tiff::TiffEncoder::new(&mut buffer)
+                .write_image(&data, decoded.width as u32, decoded.height as u32, color_type.into()),
+        EncoderType::QoiEncoder
+            => image::codecs::qoi::QoiEncoder::new(&mut buffer)
+                .write_image(&decoded.data, decoded.width as u32, decoded.height as u32, ColorType::Rgb8.into()),
+
+    };
+    encode_result.map_err(|e| Error::Encode(e.to_string()))?;
+
+    let encoded = buffer.into_inner();
+    Ok(match exif {
+        Some(exif) => embed_exif(encoded, exif, encoder_type),
+        None => encoded,
+    })
+}
+
+/// Whether the developed `image` is stored as grayscale
+///
+/// Only images of monochrome sensors and black and white styles are, as long as no later step (like
+/// a colored watermark) added colors. Neutral images of color raws keep their color profile.
+pub fn is_grayscale(image: &imagepipe::SRGBImage, monochrome: bool, settings: &JobSettings) -> bool {
+    (monochrome || settings.style == Style::HighContrastBw) && pixelops::is_gray_rgb8(&image.data)
+}
+
+/// Whether a freshly decoded (not yet developed) raw comes from a monochrome sensor
+///
+/// Only monochrome sensors decode to exactly neutral pixels, for demosaiced color raws the check
+/// ends at one of the first pixels.
+fn is_monochrome(decoded: &imagepipe::SRGBImage) -> bool {
+    pixelops::is_gray_rgb8(&decoded.data)
+}
+
+/// Averages each channel over a coarse grid, yielding a fingerprint that survives lossy encoding
+fn downsampled_checksum(data: &[u8], width: usize, height: usize) -> Vec<f32> {
+    const GRID: usize = 8;
+    let mut sums = vec![0.0f64; GRID * GRID * 3];
+    let mut counts = vec![0u64; GRID * GRID];
+
+    for y in 0..height {
+        for x in 0..width {
+            let cell = (y * GRID / height) * GRID + (x * GRID / width);
+            for c in 0..3 {
+                sums[cell * 3 + c] += data[(y * width + x) * 3 + c] as f64;
+            }
+            counts[cell] += 1;
+        }
+    }
+
+    sums.iter().enumerate()
+        .map(|(i, sum)| (sum / counts[i / 3].max(1) as f64) as f32)
+        .collect()
+}
+
+/// Rereads the image encoded into `path` and compares it with `decoded`
+///
+/// The format is taken from the content, so this also works for temporary files (see `temp_path()`).
+pub fn verify_encoded(decoded: &imagepipe::SRGBImage, path: &path::Path, encoder_type: EncoderType) -> Result<(), Error> {
+    let reread = image::ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| Error::io(path, e))?
+        .decode()
+        .map_err(|e| Error::Encode(e.to_string()))?;
+    compare_reread(decoded, reread, encoder_type)
+}
+
+/// Like `verify_encoded()`, for an image encoded in memory
+pub fn verify_encoded_bytes(decoded: &imagepipe::SRGBImage, encoded: &[u8], encoder_type: EncoderType) -> Result<(), Error> {
+    let reread = image::load_from_memory(encoded).map_err(|e| Error::Encode(e.to_string()))?;
+    compare_reread(decoded, reread, encoder_type)
+}
+
+fn compare_reread(decoded: &imagepipe::SRGBImage, reread: image::DynamicImage, encoder_type: EncoderType) -> Result<(), Error> {
+    let reread = reread.to_rgb8();
+    if reread.width() as usize != decoded.width || reread.height() as usize != decoded.height {
+        return Err(Error::Encode(format!("dimensions changed from {}x{} to {}x{}", decoded.width, decoded.height,
+                                         reread.width(), reread.height())));
+    }
+
+    let tolerance = match encoder_type {
+        EncoderType::JpegEncoder(_) => 8.0,
+        _ => 0.5,
+    };
+    let expected = downsampled_checksum(&decoded.data, decoded.width, decoded.height);
+    let actual = downsampled_checksum(reread.as_raw(), decoded.width, decoded.height);
+    let deviation = expected.iter().zip(actual.iter())
+        .map(|(e, a)| (e - a).abs())
+        .fold(0.0, f32::max);
+
+    if deviation > tolerance {
+        Err(Error::Encode(format!("checksum deviates by {:.2} (tolerance {:.2})", deviation, tolerance)))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn output_path(input: &Path, input_base: &Path, output_base: &Path,
+               settings: &JobSettings) -> Result<std::path::PathBuf, Error> {
+    let output_with_extension = target_path(input, input_base, output_base, settings)?;
+
+    if output_with_extension.exists() && settings.on_existing == ExistingAction::Rename {
+        unused_path(&output_with_extension, |p| p.exists())
+    } else {
+        Ok(output_with_extension)
+    }
+}
+
+/// Output path of `input` before resolving conflicts with existing files
+fn target_path(input: &Path, input_base: &Path, output_base: &Path,
+               settings: &JobSettings) -> Result<std::path::PathBuf, Error> {
+    let kind = file_kind(input, settings.detect_by_content);
+    let mut base = output_base.to_path_buf();
+    // converted raws go into the directory of their first variant, the others are derived from it
+    if let (FileKind::Raw, ParsableAction::Parse, Some(variant)) = (kind, settings.on_raw, settings.variants.first()) {
+        base.push(&variant.name);
+    }
+    if let Some(dir) = settings.routes.get(kind) {
+        base.push(dir);
+    }
+    let output_with_base = switch_base(input, input_base, &base)?;
+
+    Ok(match kind {
+        FileKind::Raw => match (settings.on_raw, settings.variants.first()) {
+            (ParsableAction::Parse, Some(variant)) => output_with_base.with_extension(variant.encoder(settings).extension()),
+            (ParsableAction::Parse, None) => output_with_base.with_extension(settings.encoder.extension()),
+            _ => output_with_base,
+        }
+        _ => output_with_base,
+    })
+}
+
+fn switch_base(path: &path::Path, old_base: &path::Path, new_base: &path::Path) -> Result<path::PathBuf, Error> {
+    match path.strip_prefix(old_base) {
+        Ok(stripped) => Ok(new_base.join(portable_path(stripped))),
+        Err(_e) => Err(Error::Path(format!("{:?} is not inside {:?}", path, old_base))),
+    }
+}
+
+/// Finds the first `name_N.ext` next to `orig_path` for which `taken` returns false
+pub fn unused_path(orig_path: &path::Path, taken: impl Fn(&path::Path) -> bool) -> Result<path::PathBuf, Error> {
+    let (parent, name) = match (orig_path.parent(), orig_path.file_stem()) {
+        (Some(parent), Some(name)) => (parent, name),
+        _ => return Err(Error::Path(format!("Could not find unused path for {:?}, it will be ignored", orig_path))),
+    };
+    let new_path = |i| {
+        let mut extended_name = name.to_os_string();
+        extended_name.push(format!("_{}", i));
+        if let Some(extension) = orig_path.extension() {
+            extended_name.push(".");
+            extended_name.push(extension);
+        }
+        parent.join(extended_name)
+    };
+
+    let mut i = 1;
+    while taken(&new_path(i)) {
+        i += 1;
+    }
+
+    Ok(new_path(i))
+}
+
+pub fn file_kind(path: &path::Path, by_content: bool) -> FileKind {
+    if by_content {
+        if let Some(kind) = sniff_kind(path) {
+            return kind;
+        }
+    }
+
+    extension_kind(path)
+}
+
+fn extension_kind(path: &path::Path) -> FileKind {
+    if has_extension(path, &RAW_EXTENSIONS) {
+        FileKind::Raw
+    } else if has_extension(path, &IMG_EXTENSIONS) {
+        FileKind::Image
+    } else if has_extension(path, &VIDEO_EXTENSIONS) {
+        FileKind::Video
+    } else {
+        FileKind::Other
+    }
+}
+
+pub fn recode(input_path: &path::Path, output_path: &path::Path, settings: &JobSettings) -> Option<(time::Duration, time::Duration)> {
+    let (decoded, decode_time, monochrome) = decode_step(input_path, settings).ok()?;
+    let encode_time = encode_step(&decoded, decode_time, monochrome, input_path, output_path, settings).ok()?;
+    Some((decode_time, encode_time))
+}
+
+/// Converts a raw file that is already in memory, returning the encoded image
+///
+/// Decoding, development, metadata and verification follow `settings` like a job does, without any
+/// file system access. Settings that depend on the input file (camera matching, presets, lens
+/// corrections and annotations) and the job timeout do not apply.
+pub fn convert_bytes(raw: &[u8], settings: &JobSettings) -> Result<Vec<u8>, Error> {
+    let mut decoded = decode_raw_bytes(raw, settings.decoder, settings.gpu, &settings.decode_options)?;
+    let monochrome = is_monochrome(&decoded);
+    develop(&mut decoded, None, settings);
+    let color_space = settings.output_color_space(settings.encoder);
+    if let Some(converted) = color_space.convert(&decoded) {
+        decoded = converted;
+    }
+    let gray = is_grayscale(&decoded, monochrome, settings);
+
+    let exif = if settings.strip_metadata.contains(&MetadataField::All) {
+        None
+    } else {
+        read_exif_bytes(raw).and_then(|data| exif_blob_from(&data, &settings.strip_metadata, color_space))
+    };
+    let encoded = encode_bytes(&decoded, settings.encoder, exif.as_deref(), color_space.icc_profile(), gray)?;
+    if settings.paranoid {
+        verify_encoded_bytes(&decoded, &encoded, settings.encoder)?;
+    }
+    Ok(encoded)
+}
+
+/// First half of `recode()`, decoding the raw file
+///
+/// Returns the developed image, the decode time and whether the raw comes from a monochrome sensor.
+pub fn decode_step(input_path: &path::Path, settings: &JobSettings) -> Result<(imagepipe::SRGBImage, time::Duration, bool), Error> {
+    let verbose = settings.summary_template.is_none();
+
+    if verbose { println!("Decoding {:?}", input_path) }
+    let options = settings.decode_options_for(input_path);
+    let result = match settings.job_timeout {
+        Some(timeout) => decode_raw_timeout(input_path, settings.decoder, settings.gpu, &options, timeout),
+        None => decode_raw(input_path, settings.decoder, settings.gpu, &options),
+    };
+    let (mut decoded, decode_time) = match result {
+        Ok((decoded, decode_time)) => (decoded, decode_time),
+        Err(e) => { println!("Unable to decode {:?}: {}", input_path, e); return Err(e) },
+    };
+    if settings.quarantine.is_some() {
+        if let Err(e) = check_raw_size(input_path, &decoded) {
+            println!("Unable to decode {:?}: {}", input_path, e);
+            return Err(Error::Decode(e));
+        }
+    }
+    if verbose { println!("Decoded {:?} in {}", input_path, fmt_duration(&decode_time)) }
+
+    let monochrome = is_monochrome(&decoded);
+    develop(&mut decoded, Some(input_path), settings);
+
+    Ok((decoded, decode_time, monochrome))
+}
+
+/// Decodes the readable part of a raw that failed to decode (see `salvage_raw()`) and develops it like `decode_step()`
+pub fn salvage_step(input_path: &path::Path, settings: &JobSettings) -> Result<(imagepipe::SRGBImage, time::Duration, bool), Error> {
+    println!("Salvaging {:?}", input_path);
+    let start = Instant::now();
+    let (mut decoded, recovered) = match salvage_raw(input_path, &settings.decode_options_for(input_path)) {
+        Ok(salvaged) => salvaged,
+        Err(e) => { println!("Unable to salvage {:?}: {}", input_path, e); return Err(Error::Decode(e)) },
+    };
+    println!("Salvaged {:.0}% of {:?}", recovered * 100.0, input_path);
+
+    let monochrome = is_monochrome(&decoded);
+    develop(&mut decoded, Some(input_path), settings);
+
+    Ok((decoded, start.elapsed(), monochrome))
+}
+
+/// Second half of `recode()`, encoding (and optionally verifying) the decoded image
+pub fn encode_step(decoded: &imagepipe::SRGBImage, decode_time: time::Duration, monochrome: bool, input_path: &path::Path,
+                   output_path: &path::Path, settings: &JobSettings) -> Result<time::Duration, Error> {
+    let verbose = settings.summary_template.is_none();
+
+    if verbose { println!("Encoding {:?}", output_path) }
+    let color_space = settings.output_color_space(settings.encoder);
+    let converted = color_space.convert(decoded);
+    let decoded = converted.as_ref().unwrap_or(decoded);
+    let exif = if settings.strip_metadata.contains(&MetadataField::All) {
+        None
+    } else {
+        exif_blob(input_path, &settings.strip_metadata, color_space)
+    };
+    let start_encode = Instant::now();
+    let gray = is_grayscale(decoded, monochrome, settings);
+    let temp = match encode_bytes(decoded, settings.encoder, exif.as_deref(), color_space.icc_profile(), gray)
+            .and_then(|encoded| write_temp(output_path, &encoded)) {
+        Ok(temp) => temp,
+        Err(e) => { println!("Unable to encode {:?}: {}", output_path, e); return Err(e) },
+    };
+    let encode_time = start_encode.elapsed();
+    if verbose { println!("Encoded {:?} in {}", output_path, fmt_duration(&encode_time)) }
+
+    // the temporary file is verified, so a broken output never takes the place of the output
+    if settings.paranoid {
+        if let Err(e) = verify_encoded(decoded, &temp, settings.encoder) {
+            let _ = fs::remove_file(&temp);
+            println!("Verification of {:?} failed: {}", output_path, e);
+            return Err(e);
+        }
+        if verbose { println!("Verified {:?}", output_path) }
+    }
+    if let Err(e) = finish_temp(&temp, output_path) {
+        println!("Unable to encode {:?}: {}", output_path, e);
+        return Err(Error::io(output_path, e));
+    }
+
+    if let Some(template) = &settings.summary_template {
+        println!("{}", format_summary(template, input_path, output_path, &(decode_time + encode_time)));
+    }
+
+    Ok(encode_time)
+}
+
+/// Copies `input_path` to `output_path` through a temporary file
+pub fn copy(input_path: &path::Path, output_path: &path::Path) -> Result<time::Duration, Error> {
+    if input_path == output_path {
+        return Err(same_file(input_path));
+    }
+
+    let start_time = time::Instant::now();
+
+    println!("Copying {:?} to {:?}", input_path, output_path);
+    let temp = temp_path(output_path);
+    let bytes = match copy_file(input_path, &temp) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            println!("Unable to copy {:?}: {:?}", output_path, e);
+            return Err(Error::io(output_path, e));
+        },
+    };
+    if let Err(e) = finish_temp(&temp, output_path) {
+        println!("Unable to copy {:?}: {:?}", output_path, e);
+        return Err(Error::io(output_path, e));
+    }
+
+    let time = start_time.elapsed();
+    println!("Copied {} to {:?} in {}", fmt_bytes(bytes), output_path, fmt_duration(&time));
+    Ok(time)
+}
+
+/// Hardlinks `input_path` to `output_path`, falling back to a copy if that is not possible (e.g. across file systems)
+pub fn link_or_copy(input_path: &path::Path, output_path: &path::Path) -> Result<time::Duration, Error> {
+    let start_time = time::Instant::now();
+    if input_path != output_path && fs::hard_link(input_path, output_path).is_ok() {
+        let time = start_time.elapsed();
+        println!("Linked {:?} to {:?} in {}", input_path, output_path, fmt_duration(&time));
+        return Ok(time);
+    }
+    copy(input_path, output_path)
+}
+
+/// Clones `input_path` to `output_path` without duplicating its data, falling back to a copy if the
+/// file system does not support that
+pub fn reflink_or_copy(input_path: &path::Path, output_path: &path::Path) -> Result<time::Duration, Error> {
+    let start_time = time::Instant::now();
+    if input_path == output_path {
+        return Err(same_file(input_path));
+    }
+    match reflink(input_path, output_path) {
+        Ok(()) => {
+            let time = start_time.elapsed();
+            println!("Reflinked {:?} to {:?} in {}", input_path, output_path, fmt_duration(&time));
+            Ok(time)
+        },
+        Err(e) => {
+            println!("Unable to reflink {:?} ({}), copying instead", input_path, e);
+            copy(input_path, output_path)
+        },
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(input_path: &path::Path, output_path: &path::Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    /// `_IOW(0x94, 9, int)`
+    const FICLONE: u64 = 0x40049409;
+
+    let source = fs::File::open(input_path)?;
+    let temp = temp_path(output_path);
+    let target = fs::File::create(&temp)?;
+    let result = unsafe { libc::ioctl(target.as_raw_fd(), FICLONE as _, source.as_raw_fd()) };
+    let error = io::Error::last_os_error();
+    drop(target);
+    if result != 0 {
+        let _ = fs::remove_file(&temp);
+        return Err(error);
+    }
+    finish_temp(&temp, output_path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_input_path: &path::Path, _output_path: &path::Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "reflinks are only supported on Linux"))
+}
+
+/// Moves `input_path` to `output_path` (renaming is atomic, so no temporary file is needed)
+pub fn move_file(input_path: &path::Path, output_path: &path::Path) -> Result<time::Duration, Error> {
+    if input_path == output_path {
+        return Err(same_file(input_path));
+    }
+
+    let start_time = time::Instant::now();
+
+    println!("Moving {:?} to {:?}", input_path, output_path);
+    if let Err(e) = fs::rename(input_path, output_path) {
+        println!("Unable to move {:?}: {:?}", output_path, e);
+        return Err(Error::io(output_path, e));
+    }
+
+    let time = start_time.elapsed();
+    println!("Moved {:?} to {:?} in {}", input_path, output_path, fmt_duration(&time));
+    Ok(time)
+}
+
+/// Error of transferring a file onto itself
+fn same_file(path: &path::Path) -> Error {
+    Error::Path(format!("{:?} is both input and output", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray_image() -> imagepipe::SRGBImage {
+        let data = (0..16 * 16).flat_map(|i| [i as u8; 3]).collect();
+        imagepipe::SRGBImage { data, width: 16, height: 16 }
+    }
+
+    fn encoded_color(gray: bool) -> image::ColorType {
+        let encoded = encode_bytes(&gray_image(), EncoderType::new(EncodedType::Png, 90), None, None, gray).unwrap();
+        image::load_from_memory(&encoded).unwrap().color()
+    }
+
+    #[test]
+    fn monochrome_images_are_stored_as_grayscale() {
+        let settings = JobSettings::default();
+        assert!(is_monochrome(&gray_image()));
+        assert!(is_grayscale(&gray_image(), true, &settings));
+        assert_eq!(encoded_color(true), image::ColorType::L8);
+    }
+
+    #[test]
+    fn neutral_images_of_color_raws_stay_rgb() {
+        let settings = JobSettings::default();
+        assert!(!is_grayscale(&gray_image(), false, &settings));
+        assert_eq!(encoded_color(false), image::ColorType::Rgb8);
+
+        let bw = JobSettings { style: Style::HighContrastBw, ..JobSettings::default() };
+        assert!(is_grayscale(&gray_image(), false, &bw));
+    }
+}
@@ -1,33 +1,60 @@
-use std::{fs, path, io, time};
-use threadpool::ThreadPool;
-use std::sync::mpsc::channel;
-
-use image::ColorType;
-use image::ImageEncoder;
-use clap::Parser;
-use std::time::Instant;
+use std::{thread, time};
+use std::collections::HashSet;
 use std::path::*;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Instant;
 
-extern crate imagepipe;
-extern crate rawloader;
-
-use job::*;
-use statistics::*;
+use clap::{CommandFactory, Parser, Subcommand};
+use raw_to_img::*;
 
-mod job;
-mod statistics;
+/// Webhook that is told about runs failing before they get to convert anything (see `exit_with_error()`)
+static FAILURE_WEBHOOK: OnceLock<Arc<Webhook>> = OnceLock::new();
 
 /// Converts raw image files produced by cameras into image files
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, args_override_self = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// File or directory to parse
-    #[clap()]
-    filename: std::path::PathBuf,
-
-    /// Output file or directory (must not exist yet)
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Files or directories to parse, an http(s) URL of a single file, an sftp:// URL of a file or directory
+    /// or a zip or tar archive
+    ///
+    /// Several local inputs can be given at once, they are combined in the output according to --input-layout.
+    #[clap(required_unless_present_any = ["files_from", "from_camera"])]
+    inputs: Vec<std::path::PathBuf>,
+
+    /// How several inputs are combined in the output directory
+    #[clap(long, value_enum, default_value_t = InputLayout::Subdirs)]
+    input_layout: InputLayout,
+
+    /// Download and convert the http(s) URLs listed in this file (one per line)
+    #[clap(long, value_name = "FILE", conflicts_with = "inputs", requires = "output")]
+    files_from: Option<std::path::PathBuf>,
+
+    /// Import and convert new raws from a USB-connected camera
+    #[clap(long, conflicts_with_all = ["inputs", "files_from"], requires = "output")]
+    from_camera: bool,
+
+    /// Output file or directory (must not exist yet unless --merge is given), or a remote location like s3://bucket/prefix or sftp://user@host/path
+    ///
+    /// Without an output, converted files are written next to their sources.
     #[clap(short, long)]
-    output: std::path::PathBuf,
+    output: Option<std::path::PathBuf>,
+
+    /// Write converted files next to their sources (the default without --output)
+    #[clap(long, conflicts_with = "output")]
+    in_place: bool,
+
+    /// Write all outputs into this archive (.zip, .tar or .tar.zst) instead of a directory
+    #[clap(long, value_name = "ARCHIVE", conflicts_with_all = ["output", "in_place"])]
+    output_archive: Option<std::path::PathBuf>,
+
+    /// Allow converting into an existing output directory (conflicting files are handled according to --existing)
+    #[clap(long)]
+    merge: bool,
 
     /// How to handle raw image files
     #[clap(short, long, value_enum, value_parser, default_value_t = ParsableAction::Parse)]
@@ -38,7 +65,32 @@ struct Args {
     #[clap(short, long, value_enum, value_parser, default_value_t = UnparsableAction::Copy)]
     images: UnparsableAction,
 
-    /// How to handle files other than raw or parsed images
+    /// How to handle video files (MP4, MOV, AVCHD, ...)
+    #[clap(long, value_enum, value_parser, default_value_t = UnparsableAction::Copy)]
+    videos: UnparsableAction,
+
+    /// Put videos into this directory below the output directory (keeping their relative path)
+    #[clap(long, value_name = "DIR")]
+    video_dir: Option<PathBuf>,
+
+    /// Also keep the originals of converted raws in this directory below the output directory (hardlinked if possible)
+    #[clap(long, value_name = "DIR")]
+    keep_raws: Option<PathBuf>,
+
+    /// Put raws that can not be decoded or are truncated into this directory below the output directory
+    /// instead of only reporting an error
+    #[clap(long, value_name = "DIR", num_args = 0..=1, require_equals = true, default_missing_value = "corrupt")]
+    quarantine: Option<PathBuf>,
+
+    /// Decode whatever part of a corrupt raw is readable (the rest is filled with gray) into an output marked with _partial
+    #[clap(long)]
+    salvage: bool,
+
+    /// Put each kind of file into its own directory below the output directory (kinds: raw, image, video, other)
+    #[clap(long, value_name = "KIND=DIR,...", value_parser = parse_routes)]
+    route: Option<KindRoutes>,
+
+    /// How to handle files other than raws, parsed images or videos
     #[clap(short, long, value_enum, value_parser, default_value_t = UnparsableAction::Copy)]
     files: UnparsableAction,
 
@@ -50,412 +102,1632 @@ struct Args {
     #[clap(short('n'), long, value_enum, value_parser, default_value_t = EncodedType::Jpeg)]
     encode_type: EncodedType,
 
+    /// Which decoder to use for raw files
+    #[clap(short, long, value_enum, value_parser, default_value_t = DecoderChoice::Auto)]
+    decoder: DecoderChoice,
+
+    /// Demosaic on the GPU if possible, falling back to the CPU decoders
+    #[clap(long)]
+    gpu: bool,
+
+    /// Demosaic algorithm (by default the one of the decoder), decoders that do not support it are skipped
+    #[clap(long, value_enum)]
+    demosaic: Option<Demosaic>,
+
+    /// Decode at reduced resolution by combining neighbouring photosites instead of demosaicing (for previews)
+    #[clap(long, value_enum, conflicts_with = "demosaic")]
+    fast_decode: Option<FastDecode>,
+
     /// Quality setting for jpeg encoding
     #[clap(long, default_value_t = 90)]
     jpeg_quality: u8,
 
+    /// Convert every raw into a named variant with the given size of the longer side (or orig), JPEG quality and
+    /// format, each into its own directory below the output directory (repeatable, e.g. web=2048,q80)
+    #[clap(long = "variant", value_name = "NAME=SPEC", value_parser = parse_variant)]
+    variants: Vec<Variant>,
+
+    /// Color space of the outputs, display-p3 embeds an ICC profile (JPEG and PNG only, other formats stay sRGB)
+    #[clap(long, value_enum, default_value_t = ColorSpace::Srgb)]
+    color_space: ColorSpace,
+
+    /// Profile from the config files to apply
+    #[clap(long)]
+    profile: Option<String>,
+
     /// Number of threads to run in parallel
     #[clap(short, long, default_value_t = 1)]
     threads: usize,
 
-}
+    /// Number of threads encoding decoded raws (only used with more than one thread)
+    #[clap(long, default_value_t = 2)]
+    encode_threads: usize,
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
-pub enum UnparsableAction {
-    Copy, Move, Ignore,
-}
+    /// Maximum number of decoded images waiting for an encoder thread
+    #[clap(long, default_value_t = 4)]
+    encode_queue: usize,
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
-pub enum ParsableAction {
-    Copy, Move, Ignore, Parse,
-}
+    /// Number of threads copying or moving large files (only used with more than one thread)
+    #[clap(long, default_value_t = 2)]
+    io_threads: usize,
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
-pub enum ExistingAction {
-    Rename, Ignore,
-}
+    /// Number of threads copying or moving small files (only used with more than one thread)
+    #[clap(long, default_value_t = 4)]
+    small_file_threads: usize,
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
-pub enum EncodedType {
-    Jpeg, Png, Tiff, Qoi
-}
+    /// Files of at most this size are copied or moved by the small-file threads
+    #[clap(long, default_value_t = 1024, value_name = "KIB")]
+    small_file_size: u64,
+
+    /// Limit the throughput of copies to this many MiB per second (combined over all threads)
+    #[clap(long, value_name = "MIB/S")]
+    io_limit: Option<f64>,
+
+    /// Read back every copy and compare its hash against the source before counting it as successful
+    #[clap(long)]
+    verify_copies: bool,
+
+    /// Move files to the trash instead of deleting them (when undoing a run)
+    #[clap(long)]
+    use_trash: bool,
+
+    /// Abort before starting if the outputs would leave less than this much free space on the output file system
+    #[clap(long, value_name = "MIB")]
+    min_free_space: Option<u64>,
+
+    /// Keep converted outputs in this directory and reuse them when the same raw is converted with the same settings again
+    #[clap(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Record every processed file in this index database (requires the index feature)
+    #[clap(long, value_name = "PATH")]
+    index: Option<PathBuf>,
+
+    /// Skip files that the index lists as processed successfully and unchanged since
+    #[clap(long, requires = "index")]
+    sync: bool,
+
+    /// Skip files whose content the index lists as processed before under a different path
+    #[clap(long, requires = "index")]
+    skip_duplicates: bool,
+
+    /// Process files present in several inputs (same name and size or same content) only once
+    #[clap(long)]
+    merge_duplicates: bool,
+
+    /// Descend at most this many levels of subdirectories
+    #[clap(long, value_name = "DEPTH")]
+    max_depth: Option<usize>,
+
+    /// Put all outputs directly into the output directory instead of mirroring the input tree
+    #[clap(long)]
+    flatten: bool,
+
+    /// Rename outputs whose names collide when flattening ({stem}, {dir} and {path} are replaced)
+    #[clap(long, value_name = "TEMPLATE", requires = "flatten")]
+    flatten_template: Option<String>,
+
+    /// Only process files directly inside the input directory
+    #[clap(long, conflicts_with = "max_depth")]
+    no_recurse: bool,
+
+    /// Also descend into hidden directories (starting with a dot)
+    #[clap(long)]
+    follow_hidden: bool,
+
+    /// Detect raw files by their content instead of only by their extension
+    #[clap(long)]
+    detect_by_content: bool,
+
+    /// Re-decode every encoded output and compare it against the decoded raw
+    #[clap(long)]
+    paranoid: bool,
+
+    /// Only process raws with at least this star rating (read from XMP sidecars or embedded XMP)
+    #[clap(long, value_name = "STARS", allow_negative_numbers = true)]
+    min_rating: Option<i32>,
+
+    /// Process the files in chunks of this many files, printing statistics after each chunk
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    batch_size: Option<u64>,
+
+    /// Order in which files are processed (directory order by default)
+    #[clap(long, value_enum, conflicts_with = "newest_first")]
+    order: Option<JobOrder>,
+
+    /// Process the most recently modified files first
+    #[clap(long)]
+    newest_first: bool,
+
+    /// Treat raws taken at most this many seconds apart as a burst
+    #[clap(long, value_name = "SECONDS")]
+    group_bursts: Option<f64>,
+
+    /// How to handle bursts found with --group-bursts
+    #[clap(long, value_enum, default_value_t = BurstMode::First)]
+    bursts: BurstMode,
+
+    /// Reject raws whose sharpness (variance of the Laplacian of a downscaled preview) is below this value
+    #[clap(long, value_name = "THRESHOLD")]
+    reject_blurry: Option<f64>,
+
+    /// What to do with frames rejected by --reject-blurry
+    #[clap(long, value_enum, default_value_t = BlurryAction::Reject, requires = "reject_blurry")]
+    blurry: BlurryAction,
+
+    /// Put focus bracketed sequences into per-stack directories with sequence-numbered names
+    #[clap(long)]
+    detect_focus_stacks: bool,
+
+    /// Maximum time between two frames of a focus stack in seconds
+    #[clap(long, default_value_t = 2.0, value_name = "SECONDS")]
+    focus_stack_gap: f64,
+
+    /// Put sequences of raws shot with identical exposure in quick succession into per-panorama directories
+    #[clap(long)]
+    detect_panoramas: bool,
+
+    /// Maximum time between two frames of a panorama in seconds
+    #[clap(long, default_value_t = 5.0, value_name = "SECONDS")]
+    panorama_gap: f64,
+
+    /// Command to run for every panorama directory after converting (the directory is appended as argument)
+    #[clap(long, value_name = "COMMAND", requires = "detect_panoramas")]
+    stitch_command: Option<String>,
+
+    /// Presets file with processing tweaks per camera model (in addition to presets.toml in the config directories)
+    #[clap(long, value_name = "FILE")]
+    presets: Option<PathBuf>,
+
+    /// Apply exposure, white balance, crop and rotation from RawTherapee (.pp3) or darktable (.xmp) sidecars
+    #[clap(long)]
+    import_edits: bool,
+
+    /// Invert camera scans of color negatives into positives
+    #[clap(long)]
+    invert_negative: bool,
+
+    /// Where to take the color of the unexposed film from (auto or x,y in the decoded image)
+    #[clap(long, default_value = "auto", value_name = "auto|X,Y", value_parser = parse_film_base, requires = "invert_negative")]
+    film_base: FilmBase,
+
+    /// Stretch the black and white point of every image to the full range
+    #[clap(long)]
+    auto_levels: bool,
+
+    /// Subtract the thermal noise recorded in this raw (taken with the lens cap on) from every raw before demosaicing
+    #[clap(long, value_name = "RAW")]
+    dark_frame: Option<PathBuf>,
+
+    /// Divide out vignetting and sensor nonuniformity recorded in this evenly lit raw before demosaicing
+    #[clap(long, value_name = "RAW")]
+    flat_field: Option<PathBuf>,
+
+    /// Detect hot and dead photosites and interpolate them before demosaicing
+    #[clap(long)]
+    fix_hot_pixels: bool,
+
+    /// Turn partially clipped highlights into neutral tones instead of color casts
+    #[clap(long)]
+    highlight_recovery: bool,
+
+    /// Curve mapping the decoded values to output tones
+    #[clap(long, value_enum, default_value_t = ToneCurve::Linear)]
+    tone_curve: ToneCurve,
+
+    /// Contrast adjustment from -1 (flat) to 1 (strong), larger values are clamped
+    #[clap(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    contrast: f32,
+
+    /// Correct distortion, vignetting and chromatic aberration using lensfun profiles
+    #[clap(long)]
+    lens_correction: bool,
+
+    /// Directory containing the lensfun database (defaults to the system installation)
+    #[clap(long, value_name = "DIR", requires = "lens_correction")]
+    lens_db: Option<PathBuf>,
+
+    /// Remove GPS data from the EXIF data copied into converted files
+    #[clap(long)]
+    strip_gps: bool,
+
+    /// Remove these EXIF fields from converted files
+    #[clap(long, value_enum, value_delimiter = ',', value_name = "FIELDS")]
+    strip_metadata: Vec<MetadataField>,
+
+    /// Command to run before each file ($INPUT, $OUTPUT and $CAMERA are set)
+    #[clap(long, value_name = "COMMAND")]
+    pre_file_cmd: Option<String>,
+
+    /// Command to run after each file ($INPUT, $OUTPUT, $CAMERA and $STATUS are set)
+    #[clap(long, value_name = "COMMAND")]
+    post_file_cmd: Option<String>,
+
+    /// Command to run once all files are processed ($INPUT, $OUTPUT, $STATUS, $FILES and $ERRORS are set)
+    #[clap(long, value_name = "COMMAND")]
+    post_run_cmd: Option<String>,
+
+    /// Serve the progress of the run as JSON over HTTP on this address (e.g. 0.0.0.0:8080)
+    #[clap(long, value_name = "ADDR")]
+    serve_status: Option<String>,
+
+    /// Write the statistics in the Prometheus text format to this file (updated after every batch in watch mode)
+    #[clap(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Append a JSON record for every processed file to this file, regardless of what is printed
+    #[clap(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Emit a JSON object per line for every change of the run's state on stdout (-) or an open file descriptor
+    #[clap(long, value_name = "FD", num_args = 0..=1, require_equals = true, default_missing_value = "-")]
+    progress_json: Option<String>,
+
+    /// Show the progress in an interactive terminal interface that can pause and cancel the run
+    #[clap(long)]
+    tui: bool,
+
+    /// Accept commands on this Unix socket to pause, resume or cancel the run and change its number of threads
+    #[clap(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// Show a desktop notification with a summary once all files are processed
+    #[clap(long)]
+    notify: bool,
+
+    /// POST the JSON summary of the run to this URL once all files are processed (e.g. a Slack webhook)
+    #[clap(long, value_name = "URL")]
+    webhook: Option<String>,
+
+    /// Include the failed files and their errors in the --webhook summary
+    #[clap(long, requires = "webhook")]
+    webhook_failures: bool,
+
+    /// Match colors and tones to the JPEG preview the camera embedded into the raw, so outputs look like the camera's JPEGs
+    #[clap(long)]
+    match_embedded: bool,
+
+    /// Look of the converted images
+    #[clap(long, value_enum, default_value_t = Style::Neutral)]
+    style: Style,
+
+    /// Apply this 3D LUT (.cube file) after the style
+    #[clap(long, value_name = "FILE")]
+    lut: Option<PathBuf>,
+
+    /// Draw a text banner onto every converted output (placeholders: {filename}, {date}, {exposure}, {camera}, {iso}, {shutter}, {aperture})
+    #[clap(long, value_name = "TEMPLATE")]
+    annotate: Option<String>,
+
+    /// Stamp this image (e.g. a PNG logo) onto every converted output
+    #[clap(long, value_name = "IMAGE")]
+    watermark: Option<PathBuf>,
+
+    /// Corner (or center) to place the watermark in
+    #[clap(long, value_enum, default_value_t = OverlayPosition::BottomRight, requires = "watermark")]
+    watermark_position: OverlayPosition,
+
+    /// Opacity of the watermark from 0 to 1
+    #[clap(long, default_value_t = 0.5, requires = "watermark")]
+    watermark_opacity: f32,
+
+    /// Width of the watermark relative to the image width
+    #[clap(long, default_value_t = 0.2, requires = "watermark")]
+    watermark_scale: f32,
+
+    /// Add a border of this many pixels around every converted output
+    #[clap(long, default_value_t = 0, value_name = "PIXELS")]
+    border: usize,
+
+    /// Scale outputs down to fit and pad them to exactly this size (e.g. 1920x1080)
+    #[clap(long, value_name = "WxH", value_parser = parse_size)]
+    pad_to: Option<(usize, usize)>,
+
+    /// Color of the border and padding (#rrggbb, black or white)
+    #[clap(long, default_value = "black", value_name = "COLOR", value_parser = parse_color)]
+    pad_color: [u8; 3],
+
+    /// Compute mean luminance, clipped highlights and shadows and the white balance of every image
+    #[clap(long)]
+    analyze: bool,
+
+    /// Write the outcome (and analysis) of every file to this JSON or CSV (by extension) file
+    #[clap(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Write a manifest with checksums of all written files to the output directory
+    #[clap(long, value_enum, value_name = "ALGO")]
+    checksums: Option<ChecksumAlgo>,
+
+    /// Give up decoding a file after this many seconds and count it as an error
+    #[clap(long, value_name = "SECONDS")]
+    job_timeout: Option<u64>,
+
+    /// Retry steps failing with transient I/O errors (busy files, network share hiccups) this many times,
+    /// waiting 0.5s before the first retry and twice as long before every further one
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// Print a one-line summary per converted file instead of the verbose progress output
+    ///
+    /// Supported placeholders: {name}, {camera}, {iso}, {shutter}, {aperture}, {size}, {time}
+    #[clap(long, value_name = "TEMPLATE", num_args = 0..=1, default_missing_value = DEFAULT_SUMMARY_TEMPLATE)]
+    summary: Option<String>,
+
+    /// Keep watching the input directory and process new files every SECONDS
+    #[clap(short, long, value_name = "SECONDS")]
+    watch: Option<u64>,
+
+    /// Only start files whose size did not change for SECONDS, putting files that are still being written last
+    #[clap(long, value_name = "SECONDS")]
+    stable_for: Option<u64>,
+
+    /// Give up on files that are still being written after SECONDS (with --stable-for), counting them as errors
+    #[clap(long, value_name = "SECONDS", default_value_t = 600)]
+    max_deferral: u64,
 
-pub enum FileKind {
-    Raw, Image, Other,
 }
 
-#[derive(Copy, Clone)]
-pub enum EncoderType {
-    JpegEncoder(u8),
-    PngEncoder(image::codecs::png::CompressionType, image::codecs::png::FilterType),
-    TiffEncoder,
-    QoiEncoder
+/// How several inputs share the output directory
+#[derive(Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum InputLayout {
+    /// Put each input directory into its own subdirectory named after it (files go directly into the output)
+    Subdirs,
+    /// Mirror all inputs directly into the output, handling files present in several inputs according to --existing
+    Merge,
 }
 
-const RAW_EXTENSIONS: [&str; 3] = [
-    "arw", "cr2", "raw",
-];
+#[derive(Subcommand)]
+enum Command {
+    /// Compare an input tree against a converted output tree
+    ///
+    /// Reports inputs without output, outputs without input and outputs that are empty or can not
+    /// be decoded. Conversion options given before the subcommand are used to determine the
+    /// expected outputs.
+    Verify {
+        /// Directory that was converted
+        input: PathBuf,
+
+        /// Directory the input was converted into
+        output: PathBuf,
+    },
+    /// Merge bracketed exposures into HDR images
+    ///
+    /// Brackets are raws taken at most --gap seconds apart with differing exposure compensation.
+    MergeHdr {
+        /// Directory containing the brackets
+        input: PathBuf,
+
+        /// Directory to write the merged images to
+        output: PathBuf,
+
+        /// Maximum time between two frames of a bracket in seconds
+        #[clap(long, default_value_t = 2.0)]
+        gap: f64,
+
+        /// Whether to write tonemapped images (in the selected encode type) or linear EXR files
+        #[clap(long, value_enum, default_value_t = HdrFormat::Tonemapped)]
+        format: HdrFormat,
+    },
+    /// Re-validate the files listed in a checksum manifest
+    VerifyChecksums {
+        /// Directory containing the manifest
+        dir: PathBuf,
+
+        /// Algorithm of the manifest to check
+        #[clap(long, value_enum, default_value_t = ChecksumAlgo::Sha256)]
+        algo: ChecksumAlgo,
+    },
+    /// Run an HTTP service converting raw files
+    ///
+    /// `POST /convert` converts the raw file sent as request body, `GET /convert?path=...`
+    /// converts a file below --root. Both respond with the converted image. Conversion options
+    /// given before the subcommand apply to all requests.
+    Serve {
+        /// Address to listen on
+        #[clap(default_value = "127.0.0.1:8080")]
+        addr: String,
+
+        /// Number of requests converted concurrently
+        #[clap(long, default_value_t = 2)]
+        workers: usize,
+
+        /// Number of requests waiting for a worker before further requests are rejected
+        #[clap(long, default_value_t = 16)]
+        queue: usize,
+
+        /// Directory on a shared volume that path requests may read from
+        #[clap(long)]
+        root: Option<PathBuf>,
+    },
+    /// Look up files in the index given with --index
+    ///
+    /// Lists every recorded file whose input or output path contains PATTERN or whose hash starts
+    /// with it, e.g. to find the raw a converted image came from.
+    Query {
+        /// Part of an input or output path, or the beginning of a hash
+        pattern: String,
+    },
+    /// Reverse a run recorded in the index given with --index
+    ///
+    /// Deletes the outputs the run created and moves files it moved back to their original location.
+    /// Files that were changed since, or whose original location is taken again, are left alone and reported.
+    Undo {
+        /// Number of the run (as shown by the query subcommand), the last run by default
+        run: Option<i64>,
+
+        /// Only report what would be reverted
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Print a completion script for a shell
+    ///
+    /// For example `raw-to-img completions bash > /usr/share/bash-completion/completions/raw-to-img`.
+    Completions {
+        /// Shell to generate the completions for
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print a man page in roff format
+    Manpage,
+}
 
-const IMG_EXTENSIONS: [&str; 4] = [
-    "jpg", "jpeg", "png", "tiff",
-];
+impl Args {
+    fn strip_metadata(&self) -> Vec<MetadataField> {
+        let mut fields = self.strip_metadata.clone();
+        if self.strip_gps {
+            fields.push(MetadataField::Gps);
+        }
+        fields
+    }
 
+    fn max_depth(&self) -> Option<usize> {
+        if self.no_recurse {
+            Some(0)
+        } else {
+            self.max_depth
+        }
+    }
 
-fn recurse(dirname: &mut path::PathBuf) -> Vec<path::PathBuf> {
-    let mut file_list = Vec::new();
-    for entry in fs::read_dir(dirname).unwrap() {
-        let entry = entry.unwrap();
-        let meta = entry.metadata().unwrap();
-        let path = entry.path();
+    /// Routes given with --route, with --video-dir as shorthand for the video route
+    fn routes(&self) -> KindRoutes {
+        let mut routes = self.route.clone().unwrap_or_default();
+        if routes.video.is_none() {
+            routes.video = self.video_dir.clone();
+        }
+        routes
+    }
 
-        file_list.push(path);
-        if meta.is_dir() {
-            let mut subfiles = recurse(&mut file_list.pop().unwrap());
-            file_list.append(&mut subfiles);
+    fn batch_settings(&self) -> BatchSettings {
+        BatchSettings {
+            threads: self.threads,
+            encode_threads: self.encode_threads,
+            encode_queue: self.encode_queue,
+            io_threads: self.io_threads,
+            small_file_threads: self.small_file_threads,
+            small_file_size: self.small_file_size * 1024,
+            stable_for: self.stable_for.map(time::Duration::from_secs),
+            max_deferral: time::Duration::from_secs(self.max_deferral),
         }
     }
-    file_list
 }
 
-fn raw_info_short(raw_path: &path::Path) {
-    let from_time = Instant::now();
-    let image = match rawloader::decode_file(raw_path) {
-        Ok(val) => val,
-        Err(_e) => return,
-    };
-    let duration = from_time.elapsed();
-
-    println!("File: {:?}", raw_path);
-    println!("\tSize: {}x{}", image.width, image.height);
-    println!("\tTaken with \"{}\"", image.model);
-    println!("\tDecoded metadata in {} ms", duration.as_millis());
+fn print_progress(progress: Progress) {
+    match progress {
+        Progress::Started { threads: 1, .. } => println!("Running in single job mode"),
+        Progress::Started { threads, encode_threads, io_threads, small_file_threads, .. } => {
+            println!("Starting new thread pool running {} threads in parallel", threads);
+            println!("Starting {} encoder threads", encode_threads);
+            println!("Starting new thread pool running {} threads for copying and moving files", io_threads);
+            println!("Starting new thread pool running {} threads for small files", small_file_threads);
+        },
+        Progress::Discovered { .. } | Progress::JobStarted { .. } => (),
+        Progress::Deferred { file } => println!("Deferring {} (still being written)", file.display()),
+        Progress::JobFailed { file, error } => println!("Error ({}): {}", file.display(), error),
+        Progress::JobFinished { file, done, total, .. } => println!("Finished job {} ({}/{})", file.display(), done, total),
+        Progress::Cancelled { done, total, unprocessed } => {
+            println!("Cancelled after {} of {} jobs", done, total);
+            println!("Unprocessed files (run again to resume):");
+            for file in unprocessed {
+                println!("  {}", file.display());
+            }
+        },
+    }
 }
 
-fn fmt_duration(duration: &time::Duration) -> String {
-    let millis = duration.as_millis() % 1000;
-    let secs = duration.as_secs() % 60;
-    let mins = duration.as_secs() / 60;
+/// Interfaces following and steering the batches besides the console output
+#[derive(Default)]
+struct Monitors {
+    /// Shared by all batches, so pausing and thread changes carry over in watch mode
+    control: RunControl,
+    status: Option<StatusServer>,
+    #[cfg(unix)]
+    control_socket: Option<ControlSocket>,
+    #[cfg(feature = "tui")]
+    tui: Option<Tui>,
+    events: Option<Arc<ProgressEvents>>,
+    webhook: Option<Arc<Webhook>>,
+}
 
-    let mut string = String::new();
+impl Monitors {
+    /// Whether the terminal interface shows the progress or stdout carries JSON events, so it must not be printed
+    #[cfg(feature = "tui")]
+    fn interactive(&self) -> bool {
+        self.tui.is_some() || self.events.as_ref().is_some_and(|e| e.on_stdout)
+    }
 
-    if mins > 0 {
-        string.push_str(format!("{}m ", mins).as_str());
+    #[cfg(not(feature = "tui"))]
+    fn interactive(&self) -> bool {
+        self.events.as_ref().is_some_and(|e| e.on_stdout)
     }
-    if secs > 0 {
-        string.push_str(format!("{}s ", secs).as_str());
+
+    fn watch_batch(&self, batch: Batch) -> Batch {
+        if let Some(status) = &self.status {
+            status.watch_batch(&batch);
+        }
+        #[cfg(feature = "tui")]
+        if let Some(tui) = &self.tui {
+            tui.watch_batch(&batch);
+        }
+        batch.with_control(self.control.clone())
     }
-    string.push_str(format!("{}ms", millis).as_str());
 
-    string
+    fn update(&self, progress: &Progress) {
+        if let Some(events) = &self.events {
+            events.record(progress);
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.record(progress);
+        }
+        if let Some(status) = &self.status {
+            status.update(progress);
+        }
+        #[cfg(feature = "tui")]
+        if let Some(tui) = &self.tui {
+            tui.update(progress);
+        }
+    }
 }
 
-fn fmt_bytes(bytes: u64) -> String {
-    if bytes < 1024 {
-        format!("{} B", bytes)
-    } else if bytes < 1024 * 1024 {
-        return format!("{:.2} KiB", (bytes as f64) / 1024.0);
-    } else {
-        return format!("{:.2} MiB", (bytes as f64) / (1024.0 * 1024.0));
+/// Applies the flatten, burst, sequence and order options to a batch
+///
+/// Returns the batch along with the output directories of the panoramas found.
+fn arrange_batch(mut batch: Batch, args: &Args) -> (Batch, Vec<PathBuf>) {
+    if args.flatten {
+        batch = batch.flattened(match &args.flatten_template {
+            Some(template) => FlattenNames::Template(template.clone()),
+            None => FlattenNames::Suffix,
+        });
     }
 
-}
+    if let Some(gap) = args.group_bursts {
+        let (grouped, bursts, skipped) = batch.group_bursts(time::Duration::from_secs_f64(gap.max(0.0)), args.bursts);
+        println!("Found {} bursts, skipping {} frames", bursts, skipped);
+        batch = grouped;
+    }
 
-fn decode_raw(path: &path::Path) -> Result<(imagepipe::SRGBImage, time::Duration), String> {
-    let start_decode = Instant::now();
-    let decoded = match imagepipe::simple_decode_8bit(path, 0, 0) {
-        Ok(img) => img,
-        Err(e) => return Err(e),
-    };
+    if args.detect_focus_stacks || args.detect_panoramas {
+        batch.finish_scan();
+    }
 
-    Ok((decoded, start_decode.elapsed()))
-}
+    if args.detect_focus_stacks {
+        let gap = time::Duration::from_secs_f64(args.focus_stack_gap.max(0.0));
+        let stacks = find_focus_stacks(batch.files(), gap, args.detect_by_content);
+        println!("Found {} focus stacks", stacks.len());
+        batch.route_groups(&stacks, "stack", true);
+    }
 
-fn encode_img(decoded: imagepipe::SRGBImage, path: &path::Path, encoder_type: EncoderType) -> Result<time::Duration, String> {
-    let start_encode = Instant::now();
+    let mut panorama_dirs = Vec::new();
+    if args.detect_panoramas {
+        let gap = time::Duration::from_secs_f64(args.panorama_gap.max(0.0));
+        let candidates: Vec<PathBuf> = batch.files().iter()
+            .filter(|f| !batch.is_routed(f))
+            .cloned()
+            .collect();
+        let panoramas = find_panoramas(&candidates, gap, args.detect_by_content);
+        println!("Found {} panoramas", panoramas.len());
+        panorama_dirs = batch.route_groups(&panoramas, "pano", false);
+    }
 
-    let output_file = match fs::File::create(path) {
-        Ok(val) => val,
-        Err(e) => return Err(e.to_string()),
-    };
-    let bufwriter = io::BufWriter::new(output_file);
-
-    let encode_result = match encoder_type {
-        EncoderType::JpegEncoder(quality)
-            => image::codecs::jpeg::JpegEncoder::new_with_quality(bufwriter, quality)
-                .write_image(&decoded.data, decoded.width as u32, decoded.height as u32, ColorType::Rgb8.into()),
-        EncoderType::PngEncoder(compression, filter)
-            => image::codecs::png::PngEncoder::new_with_quality(bufwriter, compression, filter)
-                .write_image(&decoded.data, decoded.width as u32, decoded.height as u32, ColorType::Rgb8.into()),
-        EncoderType::TiffEncoder
-            => image::codecs::tiff::TiffEncoder::new(bufwriter)
-                .write_image(&decoded.data, decoded.width as u32, decoded.height as u32, ColorType::Rgb8.into()),
-        EncoderType::QoiEncoder
-            => image::codecs::qoi::QoiEncoder::new(bufwriter)
-                .write_image(&decoded.data, decoded.width as u32, decoded.height as u32, ColorType::Rgb8.into()),
+    if let Some(threshold) = args.reject_blurry {
+        let rejected = batch.reject_blurry(threshold, args.blurry);
+        println!("Rejected {} blurry frames", rejected);
+    }
 
+    // the order decides which of several colliding files keeps the plain output name
+    if args.newest_first {
+        batch = batch.ordered(JobOrder::Mtime, true);
+    } else if let Some(order) = args.order {
+        batch = batch.ordered(order, false);
+    }
+
+    (batch, panorama_dirs)
+}
+
+/// Arranges a batch (see `arrange_batch()`), runs it and then runs the sequence hooks
+fn run_batch(batch: Batch, args: &Args, monitors: &Monitors) -> Statistics {
+    let (batch, panorama_dirs) = arrange_batch(batch, args);
+
+    let chunks = match args.batch_size {
+        Some(size) => batch.into_chunks(size as usize),
+        None => vec![batch],
     };
+    let nchunks = chunks.len();
+    let mut log = args.log_file.as_ref().and_then(|path| match JobLog::open(path) {
+        Ok(log) => Some(log),
+        Err(e) => { eprintln!("{}", e); None },
+    });
+    let mut statistics = Statistics::default();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let chunk = monitors.watch_batch(chunk);
+        let chunk_statistics = chunk.run(&mut |progress: Progress| {
+            monitors.update(&progress);
+            if let Some(log) = &mut log {
+                log.record(&progress);
+            }
+            if !monitors.interactive() {
+                print_progress(progress);
+            }
+        });
+        statistics.extend(&chunk_statistics);
+
+        if nchunks > 1 {
+            if !monitors.interactive() {
+                println!();
+                println!("Chunk {}/{}:", i + 1, nchunks);
+                chunk_statistics.print_summary(args.threads.try_into().unwrap());
+                println!();
+            }
+            // keep the report up to date, so it is usable even if the run is aborted
+            if let Some(report) = &args.report {
+                if let Err(e) = write_report(report, &statistics.report) {
+                    eprintln!("Unable to write report ({})", e);
+                }
+            }
+        }
+    }
 
-    match encode_result {
-        Ok(()) => Ok(start_encode.elapsed()),
-        Err(e) => Err(e.to_string()),
+    if let Some(command) = &args.stitch_command {
+        for dir in panorama_dirs {
+            if let Err(e) = run_sequence_hook(command, &dir) {
+                println!("Error ({:?}): {}", dir, e);
+            }
+        }
     }
+
+    statistics
 }
 
-fn output_path(input: &Path, input_base: &Path, output_base: &Path, extension: &str,
-               on_raw: ParsableAction, on_existing: ExistingAction) -> Result<std::path::PathBuf, String> {
-    let output_with_base = switch_base(input, input_base, output_base)?;
+/// Shows a desktop notification summarizing the run
+#[cfg(feature = "notify")]
+fn notify(statistics: &Statistics, elapsed: time::Duration) {
+    let body = format!("{} files in {}, {} errors", statistics.total.count(),
+        fmt_duration(&elapsed), statistics.errors.count());
+    let result = notify_rust::Notification::new()
+        .summary("raw-to-img finished")
+        .body(&body)
+        .show();
+    if let Err(e) = result {
+        eprintln!("Unable to show notification ({})", e);
+    }
+}
 
-    let decode_pathbuf = output_with_base.with_extension(extension);
-    let output_with_extension = match file_kind(input) {
-        FileKind::Raw => match on_raw {
-            ParsableAction::Parse => decode_pathbuf.as_path(),
-            _ => output_with_base.as_path(),
+/// Stops new jobs on the first Ctrl-C and aborts on the second one
+fn install_interrupt_handler() -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    let result = ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::Relaxed) {
+            for file in remove_partial_outputs() {
+                println!("Removed partial output {:?}", file);
+            }
+            std::process::exit(130);
         }
-        _ => output_with_base.as_path(),
-    };
+        println!("Interrupted, finishing running jobs (press Ctrl-C again to abort)");
+    });
+    if let Err(e) = result {
+        eprintln!("Unable to install Ctrl-C handler ({})", e);
+    }
 
+    interrupted
+}
 
-    if output_with_extension.exists() && on_existing == ExistingAction::Rename {
-        unused_path(output_with_extension)
-            .map_err(|e| format!("Could not find unused path for {:?} ({}), it will be ignored", output_with_extension, e))
-    } else {
-        Ok(output_with_extension.to_path_buf())
+fn watch(input_base: &Path, output_base: &Path, settings: &JobSettings, args: &Args,
+         interval: u64, interrupted: &Arc<AtomicBool>, monitors: &Monitors) -> Statistics {
+    println!("Watching {:?} for new files every {}s", input_base, interval);
+
+    let seen = Arc::new(Mutex::new(HashSet::new()));
+    let mut lifetime = Statistics::default();
+    loop {
+        let scan = spawn_scan(input_base, args.max_depth(), args.follow_hidden,
+                              output_dirs_in(input_base, output_base, settings));
+        let files = new_files(scan, seen.clone());
+
+        // the batch starts with the first new file while the rest of the tree is still being scanned
+        if let Ok(first) = files.recv() {
+            let mut batch = Batch::new(vec![first], input_base, output_base, settings, &args.batch_settings())
+                .with_stream(files)
+                .with_cancel_handle(interrupted.clone());
+            if let (true, Some(index)) = (args.sync, &settings.index) {
+                println!("Skipping {} files that are already in the index", batch.skip_synced(index));
+            }
+            let stats = run_batch(batch, args, monitors);
+            if let Some(algo) = args.checksums {
+                if let Err(e) = write_manifest(output_base, algo, &stats.checksums) {
+                    eprintln!("Unable to write checksum manifest ({})", e);
+                }
+            }
+            // watching runs indefinitely, so only the aggregates of earlier batches are kept, along with
+            // the checksums and report entries of the latest one
+            lifetime.compact();
+            lifetime.extend(&stats);
+            if let Some(report) = &args.report {
+                if let Err(e) = write_report(report, &lifetime.report) {
+                    eprintln!("Unable to write report ({})", e);
+                }
+            }
+            if let Some(path) = &args.metrics_file {
+                if let Err(e) = write_metrics(path, &lifetime) {
+                    eprintln!("Unable to write metrics ({})", e);
+                }
+            }
+
+            if !monitors.interactive() {
+                println!();
+                lifetime.print_windows();
+                println!();
+                println!("Lifetime:");
+                lifetime.print_summary(args.threads.try_into().unwrap());
+            }
+        }
+
+        // sleep in short steps to react to interrupts quickly
+        let wake = Instant::now() + time::Duration::from_secs(interval);
+        while Instant::now() < wake && !interrupted.load(Ordering::Relaxed) {
+            thread::sleep(time::Duration::from_millis(200));
+        }
+        if interrupted.load(Ordering::Relaxed) {
+            return lifetime;
+        }
     }
 }
 
-fn switch_base(path: &path::Path, old_base: &path::Path, new_base: &path::Path) -> Result<path::PathBuf, String> {
-    match path.strip_prefix(old_base) {
-        Ok(stripped) => Ok(new_base.join(stripped)),
-        Err(_e) => Err(String::from("unable to switch base")),
+/// Rejects combinations of options that do not work with several inputs
+fn check_multiple_inputs(args: &Args) -> Result<(), String> {
+    if args.output.is_none() && args.output_archive.is_none() {
+        return Err(String::from("Converting several inputs requires --output or --output-archive"));
+    }
+    if args.watch.is_some() {
+        return Err(String::from("--watch only supports a single input"));
+    }
+    if let Some(url) = args.inputs.iter().find(|i| is_url(&i.to_string_lossy()) || is_sftp_url(&i.to_string_lossy())) {
+        return Err(format!("{:?} is not a local path, remote inputs can only be converted one at a time", url));
+    }
+    match args.inputs.iter().find(|i| archive_format(i).is_some()) {
+        Some(archive) => Err(format!("{:?} is an archive, archives can only be converted one at a time", archive)),
+        None => Ok(()),
     }
 }
 
-fn unused_path(orig_path: &path::Path) -> Result<path::PathBuf, String> {
-    let parent = match orig_path.parent() {
-        Some(parent) => parent,
-        None => return Err(String::from("Unable to find unused path")),
-    };
-    let name = match orig_path.file_stem() {
-        Some(stem) => match stem.to_str() {
-            Some(string) => string,
-            None => return Err(String::from("Unable to find unused path")),
-        },
-        None => return Err(String::from("Unable to find unused path")),
-    };
-    let extension = match orig_path.extension() {
-        Some(extension) => match extension.to_str() {
-            Some(string) => string,
-            None => return Err(String::from("Unable to find unused path")),
-        },
-        None => "",
-    };
+/// Input and output base of each of several inputs converted into `output`
+///
+/// Input files always go directly into the output. With `InputLayout::Subdirs`, each input directory
+/// gets a subdirectory named after it, with a numbered suffix if several inputs share the same name.
+fn input_outputs(inputs: &[PathBuf], output: &Path, layout: InputLayout) -> Vec<(PathBuf, PathBuf)> {
+    let mut taken = HashSet::new();
+    inputs.iter()
+        .map(|input| {
+            let input = long_path(input);
+            if layout == InputLayout::Merge || !input.is_dir() {
+                return (input, output.to_path_buf());
+            }
+            let name = std::path::absolute(&input).ok()
+                .and_then(|p| p.file_name().map(|n| n.to_os_string()))
+                .unwrap_or_else(|| "input".into());
+            let mut subdir = output.join(&name);
+            if !taken.insert(subdir.clone()) {
+                subdir = unused_path(&subdir, |p| taken.contains(p)).unwrap_or(subdir);
+                taken.insert(subdir.clone());
+            }
+            (input, subdir)
+        })
+        .collect()
+}
 
-    let extended_name = | i | format!("{}_{}.{}", name, i, extension);
-    let new_path = | i | parent.join(path::Path::new(&extended_name(i)));
+/// Deepest directory containing all of `paths`
+fn common_ancestor(paths: &[PathBuf]) -> PathBuf {
+    let absolute: Vec<PathBuf> = paths.iter()
+        .map(|p| std::path::absolute(p).unwrap_or_else(|_| p.clone()))
+        .collect();
+    let mut ancestor = absolute[0].parent().map(Path::to_path_buf).unwrap_or_default();
+    while !absolute.iter().all(|p| p.starts_with(&ancestor)) {
+        match ancestor.parent() {
+            Some(parent) => ancestor = parent.to_path_buf(),
+            None => break,
+        }
+    }
+    ancestor
+}
 
-    let mut i = 1;
-    while new_path(i).exists() {
-        i += 1;
+/// Value of `INPUT` for the post-run hook (several inputs are joined like `PATH`)
+fn input_env(args: &Args) -> std::ffi::OsString {
+    if args.inputs.len() > 1 {
+        if let Ok(joined) = std::env::join_paths(&args.inputs) {
+            return joined;
+        }
     }
+    args.inputs.first().cloned()
+        .or(args.files_from.clone())
+        .or(args.from_camera.then(|| PathBuf::from("camera")))
+        .unwrap_or_default()
+        .into_os_string()
+}
 
-    Ok(new_path(i))
+/// Passes on the files from `scanned` that are not in `seen` yet, adding them to it
+fn new_files(scanned: Receiver<PathBuf>, seen: Arc<Mutex<HashSet<PathBuf>>>) -> Receiver<PathBuf> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut found = HashSet::new();
+        for file in scanned {
+            found.insert(file.clone());
+            if seen.lock().unwrap().insert(file.clone()) && tx.send(file).is_err() {
+                return;
+            }
+        }
+        // forget files that left the input, so `seen` does not grow beyond the input tree
+        seen.lock().unwrap().retain(|file| found.contains(file));
+    });
+    rx
 }
 
-fn file_kind(path: &path::Path) -> FileKind {
-    return match path.extension() {
-        Some(extension) => match extension.to_str() {
-            Some(ext) => {
-                if RAW_EXTENSIONS.iter().any(|e| e.to_lowercase() == ext.to_lowercase()) {
-                    FileKind::Raw
-                } else if IMG_EXTENSIONS.iter().any(|e| e.to_lowercase() == ext.to_lowercase()) {
-                    FileKind::Image
-                } else {
-                    FileKind::Other
-                }
-            },
-            None => FileKind::Other,
-        },
-        None => FileKind::Other,
+/// Scans `input` in the background for a batch converting it into `output_base`
+///
+/// Without `--min-free-space`, the batch starts right away and the free space is checked while the
+/// files arrive, warning once their outputs would no longer fit (see `check_free_space()`).
+fn scan_input(input: &Path, output_base: &Path, settings: &JobSettings, args: &Args) -> Receiver<PathBuf> {
+    let scanned = spawn_scan(input, args.max_depth(), args.follow_hidden, output_dirs_in(input, output_base, settings));
+    let free = match (args.min_free_space, free_space(output_base)) {
+        (None, Some(free)) => free,
+        _ => return scanned,
     };
+
+    let (tx, rx) = channel();
+    let (output_base, settings) = (output_base.to_path_buf(), settings.clone());
+    thread::spawn(move || {
+        let mut estimate = 0u64;
+        for file in scanned {
+            let size = estimate_output_size(std::slice::from_ref(&file), &output_base, &settings);
+            if estimate <= free && estimate.saturating_add(size) > free {
+                eprintln!("Warning: the outputs will probably not fit into {:?} ({} free)", output_base, fmt_bytes(free));
+            }
+            estimate = estimate.saturating_add(size);
+            if tx.send(file).is_err() {
+                break;
+            }
+        }
+    });
+    rx
 }
 
-fn recode(input_path: &path::Path, output_path: &path::Path, encoder: EncoderType) -> Option<(time::Duration, time::Duration)> {
-    println!("Decoding {:?}", input_path);
-    let (decoded, decode_time) = match decode_raw(input_path) {
-        Ok((decoded, decode_time)) => (decoded, decode_time),
-        Err(e) => { println!("Unable to decode {:?}: {:?}", input_path, e); return None },
+/// Compares the estimated size of the outputs with the free space of the output file system
+///
+/// Exits if the outputs would leave less than `--min-free-space` free, only warns if that is not given.
+fn check_free_space(files: &[PathBuf], output_base: &Path, settings: &JobSettings, args: &Args) {
+    let free = match free_space(output_base) {
+        Some(free) => free,
+        None => return,
     };
-    println!("Decoded {:?} in {}", input_path, fmt_duration(&decode_time));
+    let estimate = estimate_output_size(files, output_base, settings);
+    let reserve = args.min_free_space.unwrap_or(0) * 1024 * 1024;
+    println!("Outputs will take up about {} ({} free)", fmt_bytes(estimate), fmt_bytes(free));
+
+    if estimate.saturating_add(reserve) > free {
+        match args.min_free_space {
+            Some(_) => {
+                exit_with_error(format!("Not enough free space in {:?}: about {} needed, {} free, {} to be kept free",
+                                        output_base, fmt_bytes(estimate), fmt_bytes(free), fmt_bytes(reserve)));
+            },
+            None => eprintln!("Warning: the outputs will probably not fit into {:?}", output_base),
+        }
+    }
+}
 
-    println!("Encoding {:?}", output_path);
-    let encode_time = match encode_img(decoded, output_path, encoder) {
-        Ok(encode_time) => encode_time,
-        Err(e) => { println!("Unable to encode {:?}: {:?}", output_path, e); return None },
+/// Loads the presets files and the one given with `--presets`, exiting on errors
+fn load_presets(args: &Args) -> Option<Arc<Presets>> {
+    let mut presets = match Presets::load() {
+        Ok(presets) => presets,
+        Err(e) => exit_with_error(e),
     };
-    println!("Encoded {:?} in {}", output_path, fmt_duration(&encode_time));
+    if let Some(file) = &args.presets {
+        match Presets::read(file) {
+            Ok(extra) => presets.extend(extra),
+            Err(e) => exit_with_error(e),
+        }
+    }
 
-    Some((decode_time, encode_time))
+    (!presets.is_empty()).then(|| Arc::new(presets))
 }
 
-fn copy(input_path: &path::Path, output_path: &path::Path) -> Option<time::Duration> {
-    if input_path == output_path {
-        return None;
+/// Decodes the dark frame if one is given, exiting on errors
+fn load_dark_frame(args: &Args) -> Option<Arc<DarkFrame>> {
+    let path = args.dark_frame.as_ref()?;
+    match DarkFrame::load(path) {
+        Ok(dark_frame) => Some(Arc::new(dark_frame)),
+        Err(e) => exit_with_error(e),
     }
+}
 
-    let start_time = time::Instant::now();
-
-    println!("Copying {:?} to {:?}", input_path, output_path);
-    let bytes = match fs::copy(input_path, output_path) {
-        Ok(bytes) => bytes,
-        Err(e) => { println!("Unable to copy {:?}: {:?}", output_path, e); return None },
-    };
+/// Decodes the flat frame if one is given, exiting on errors
+fn load_flat_field(args: &Args) -> Option<Arc<FlatField>> {
+    let path = args.flat_field.as_ref()?;
+    match FlatField::load(path) {
+        Ok(flat_field) => Some(Arc::new(flat_field)),
+        Err(e) => exit_with_error(e),
+    }
+}
 
-    let time = start_time.elapsed();
-    println!("Copied {} to {:?} in {}", fmt_bytes(bytes), output_path, fmt_duration(&time));
-    Some(time)
+/// Loads the 3D LUT if one is given, exiting on errors
+fn load_lut(args: &Args) -> Option<Arc<Lut3d>> {
+    let path = args.lut.as_ref()?;
+    match Lut3d::load(path) {
+        Ok(lut) => Some(Arc::new(lut)),
+        Err(e) => exit_with_error(e),
+    }
 }
 
-fn move_file(input_path: &path::Path, output_path: &path::Path) -> Option<time::Duration> {
-    if input_path == output_path {
-        return None;
+/// Loads the watermark image if one is given, exiting on errors
+fn load_watermark(args: &Args) -> Option<Arc<Watermark>> {
+    let path = args.watermark.as_ref()?;
+    match Watermark::load(path, args.watermark_position, args.watermark_opacity, args.watermark_scale) {
+        Ok(watermark) => Some(Arc::new(watermark)),
+        Err(e) => exit_with_error(e),
     }
+}
 
-    let start_time = time::Instant::now();
+/// Name of a value of a command line enum
+fn value_name<T: clap::ValueEnum>(value: &T) -> String {
+    value.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+}
 
-    println!("Moving {:?} to {:?}", input_path, output_path);
-    match fs::rename(input_path, output_path) {
-        Ok(()) => (),
-        Err(e) => { println!("Unable to move {:?}: {:?}", output_path, e); return None },
-    };
+/// Fingerprint of the options that influence converted outputs, for keying the cache and the index
+///
+/// Files the outputs depend on (dark frames, LUTs, ...) are included by their hash, so the cache
+/// is invalidated when they change. The encoder and decoder are added per file by `OutputCache::key()`,
+/// as they can be overridden per directory.
+fn settings_fingerprint(args: &Args, presets: Option<&Presets>) -> String {
+    let hash = |path: &Option<PathBuf>| path.as_ref()
+        .map(|p| hash_file(p, ChecksumAlgo::Sha256).unwrap_or_default())
+        .unwrap_or_default();
+    let strip: Vec<_> = args.strip_metadata().iter().map(value_name).collect();
+
+    [
+        format!("version={}", env!("CARGO_PKG_VERSION")),
+        format!("gpu={}", args.gpu),
+        format!("demosaic={:?}", args.demosaic),
+        format!("fast_decode={:?}", args.fast_decode),
+        format!("fix_hot_pixels={}", args.fix_hot_pixels),
+        format!("dark_frame={}", hash(&args.dark_frame)),
+        format!("flat_field={}", hash(&args.flat_field)),
+        format!("presets={:?}", presets),
+        format!("lens_correction={} {:?}", args.lens_correction, args.lens_db),
+        format!("invert_negative={} {:?}", args.invert_negative, args.film_base),
+        format!("tone={} {} {:?} {}", args.auto_levels, args.highlight_recovery, args.tone_curve, args.contrast),
+        format!("match_embedded={}", args.match_embedded),
+        format!("style={:?}", args.style),
+        format!("lut={}", hash(&args.lut)),
+        format!("annotate={:?}", args.annotate),
+        format!("watermark={} {:?} {} {}", hash(&args.watermark), args.watermark_position,
+                args.watermark_opacity, args.watermark_scale),
+        format!("canvas={} {:?} {:?}", args.border, args.pad_to, args.pad_color),
+        format!("strip_metadata={}", strip.join(",")),
+        format!("color_space={:?}", args.color_space),
+    ].join("\n")
+}
 
-    let time = start_time.elapsed();
-    println!("Moved {:?} to {:?} in {}", input_path, output_path, fmt_duration(&time));
-    Some(time)
-}
-
-fn process_files(files: &Vec<PathBuf>, input_base: &Path, output_base: &Path,
-                          extension: &str, encoder: EncoderType, args: &Args) -> Statistics {
-    println!("Running in single job mode");
-
-    let mut acc_stats = Statistics::default();
-    let mut last_job_time = Instant::now();
-    for file in files {
-        let output_file = output_path(file, input_base, output_base, extension, args.raws, args.existing).unwrap();
-        let job = Job::new(file, &output_file, args.raws, args.files, args.images, args.existing, encoder);
-        let name = job.name();
-
-        let stats = match job.run() {
-            Ok(stats) => stats,
-            Err(e) => {
-                println!("Error ({}): {}", name, e);
-                let mut stats = Statistics::default();
-                stats.errors.inc();
-                stats
-            },
-        };
+/// Fingerprint of the options recorded with runs in the index, which `--sync` compares
+///
+/// Unlike the cache, which keys every output by its encoder, the index compares whole runs,
+/// so the formats and the decoder are part of it.
+fn index_fingerprint(args: &Args, presets: Option<&Presets>) -> String {
+    [
+        settings_fingerprint(args, presets),
+        format!("encode_type={} {}", value_name(&args.encode_type), args.jpeg_quality),
+        format!("variants={:?}", args.variants),
+        format!("decoder={}", value_name(&args.decoder)),
+    ].join("\n")
+}
 
-        let now = Instant::now();
-        acc_stats.total.record(now - last_job_time);
-        last_job_time = now;
-        acc_stats.extend(&stats);
+/// Warns about output formats that can not carry the selected color space
+fn check_color_space(settings: &JobSettings) {
+    let encoders = std::iter::once(settings.encoder)
+        .chain(settings.variants.iter().map(|v| v.encoder(settings)));
+    let mut unsupported: Vec<_> = encoders
+        .filter(|e| !settings.color_space.is_supported_by(*e))
+        .map(|e| e.extension())
+        .collect();
+    unsupported.sort();
+    unsupported.dedup();
+    if !unsupported.is_empty() {
+        eprintln!("Warning: {} outputs can not carry the {} profile and are written as sRGB",
+            unsupported.join(", "), value_name(&settings.color_space));
+    }
+}
 
-        println!("Finished job {} ({}/{})", name, acc_stats.total.count(), files.len());
+/// Opens the output cache if one is given, exiting on errors
+fn open_cache(args: &Args, presets: Option<&Presets>) -> Option<Arc<OutputCache>> {
+    let dir = args.cache_dir.as_ref()?;
+    match OutputCache::open(dir, &settings_fingerprint(args, presets)) {
+        Ok(cache) => Some(Arc::new(cache)),
+        Err(e) => exit_with_error(e),
     }
+}
 
-    acc_stats
+/// Opens the index if one is given, exiting on errors
+fn open_index(args: &Args) -> Option<Index> {
+    let path = args.index.as_ref()?;
+    match Index::open(path) {
+        Ok(index) => Some(index),
+        Err(e) => exit_with_error(e),
+    }
 }
 
-fn process_files_parallel(files: &Vec<PathBuf>, input_base: &Path, output_base: &Path,
-                          extension: &str, encoder: EncoderType, args: &Args) -> Statistics {
-    println!("Starting new thread pool running {} threads in parallel", args.threads);
+/// Prints the files of the index matching `pattern`
+fn query_index(index: &Index, pattern: &str) -> Result<usize, String> {
+    let entries = index.query(pattern)?;
+    for entry in &entries {
+        println!("{:?} -> {:?}", entry.input, entry.output);
+        println!("\trun {}, {} ({}), {}, taken {} with {}", entry.run, entry.action, entry.status,
+                 &entry.hash[..entry.hash.len().min(16)], entry.taken.as_deref().unwrap_or("?"),
+                 entry.camera.as_deref().unwrap_or("unknown camera"));
+    }
+    Ok(entries.len())
+}
 
-    let mut last_job_time = time::Instant::now();
-    let pool = ThreadPool::new(args.threads);
-    let (tx, rx) = channel();
+/// Loads the lensfun database if lens correction is enabled, exiting on errors
+fn load_lens_database(args: &Args) -> Option<Arc<LensDatabase>> {
+    if !args.lens_correction {
+        return None;
+    }
 
-    for file in files {
-        let output_file = output_path(file, input_base, output_base, extension, args.raws, args.existing).unwrap();
-        let job = Job::new(file, &output_file, args.raws, args.files, args.images, args.existing, encoder);
-
-        let next_tx = tx.clone();
-        pool.execute(move || {
-            let name = job.name();
-            let stats = job.run();
-            match stats {
-                Ok(stats) => next_tx.send((name, stats)).unwrap(),
-                Err(e) => {
-                    println!("Error ({}): {}", name, e);
-                    let mut stats = Statistics::default();
-                    stats.errors.inc();
-                    next_tx.send((name, stats)).unwrap();
-                },
-            }
-        });
+    match LensDatabase::load(args.lens_db.as_deref()) {
+        Ok(database) => {
+            println!("Loaded {} lens profiles", database.len());
+            Some(Arc::new(database))
+        },
+        Err(e) => exit_with_error(e),
     }
+}
 
-    // pool.join();
-    let mut acc_stats = Statistics::default();
-    rx.iter().take(files.len()).fold(&mut acc_stats, |acc, (name, stats)| {
-        let now = Instant::now();
-        acc.total.record(now - last_job_time);
-        last_job_time = now;
-        println!("Finished job {} ({}/{})", name, acc.total.count(), files.len());
-        acc.extend(&stats)
-    });
-    acc_stats
+/// Prints `error` and exits, posting the failure to the `--webhook` first if one is set up
+fn exit_with_error(error: impl std::fmt::Display) -> ! {
+    eprintln!("{}", error);
+    if let Some(webhook) = FAILURE_WEBHOOK.get() {
+        if let Err(e) = webhook.send_failure(&error.to_string()) {
+            eprintln!("{}", e);
+        }
+    }
+    std::process::exit(1)
 }
 
 fn main() {
-    let args = Args::parse();
+    let cli_args: Vec<_> = std::env::args_os().collect();
+    let profile = profile_from_args(&cli_args[1..]);
+    let config_args = match config_args(profile.as_deref()) {
+        Ok(config_args) => config_args,
+        Err(e) => exit_with_error(e),
+    };
+    let args = Args::parse_from(cli_args[..1].iter().cloned().chain(config_args).chain(cli_args[1..].iter().cloned()));
     let mut statistics = Statistics::default();
+    let run_start = Instant::now();
 
-    let encoder = match args.encode_type {
-        EncodedType::Jpeg => EncoderType::JpegEncoder(args.jpeg_quality),
-        EncodedType::Png => EncoderType::PngEncoder(image::codecs::png::CompressionType::Default,
-                                                   image::codecs::png::FilterType::Adaptive),
-        EncodedType::Tiff => EncoderType::TiffEncoder,
-        EncodedType::Qoi => EncoderType::QoiEncoder,
+    match &args.command {
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(*shell, &mut Args::command(), "raw-to-img", &mut std::io::stdout());
+            return;
+        },
+        Some(Command::Manpage) => {
+            if let Err(e) = clap_mangen::Man::new(Args::command()).render(&mut std::io::stdout()) {
+                eprintln!("Unable to write man page ({})", e);
+                std::process::exit(1);
+            }
+            return;
+        },
+        _ => (),
+    }
+
+    let webhook = match args.webhook.as_deref().map(|url| Webhook::new(url, args.webhook_failures)).transpose() {
+        Ok(webhook) => webhook.map(Arc::new),
+        Err(e) => { eprintln!("{}", e); std::process::exit(1) },
     };
-    let extension = match args.encode_type {
-        EncodedType::Jpeg => "jpg",
-        EncodedType::Png => "png",
-        EncodedType::Tiff => "tiff",
-        EncodedType::Qoi => "qoi",
+    // configuration errors from here on are reported to the webhook as well
+    if let Some(webhook) = &webhook {
+        let _ = FAILURE_WEBHOOK.set(webhook.clone());
+    }
+
+    if args.notify && !cfg!(feature = "notify") {
+        eprintln!("This build does not support desktop notifications");
+    }
+    if args.control_socket.is_some() && !cfg!(unix) {
+        eprintln!("Control sockets are only supported on Unix");
+    }
+    if args.tui && !cfg!(feature = "tui") {
+        eprintln!("This build does not support the terminal interface");
+    }
+    if args.gpu && !cfg!(feature = "gpu") {
+        eprintln!("This build does not support GPU decoding, falling back to CPU decoders");
+    } else if args.gpu && args.decoder != DecoderChoice::Auto {
+        eprintln!("Warning: --gpu has no effect with --decoder, which always uses the selected decoder");
+    }
+    if args.demosaic.is_some() && matches!(args.decoder, DecoderChoice::Imagepipe | DecoderChoice::Libraw) {
+        exit_with_error(format!("The {} decoder does not support --demosaic", value_name(&args.decoder)));
+    }
+    if let Some(io_limit) = args.io_limit {
+        set_io_limit((io_limit * 1024.0 * 1024.0) as u64);
+    }
+    set_verify_copies(args.verify_copies);
+    set_use_trash(args.use_trash);
+
+    let encoder = EncoderType::new(args.encode_type, args.jpeg_quality);
+    let presets = load_presets(&args);
+    let cache = open_cache(&args, presets.as_deref());
+    let mut index = open_index(&args);
+    let settings = JobSettings {
+        on_raw: args.raws,
+        on_file: args.files,
+        on_image: args.images,
+        on_video: args.videos,
+        on_existing: args.existing,
+        routes: args.routes(),
+        keep_raws: args.keep_raws.clone(),
+        quarantine: args.quarantine.clone(),
+        salvage: args.salvage,
+        detect_by_content: args.detect_by_content,
+        decoder: args.decoder,
+        gpu: args.gpu,
+        decode_options: DecodeOptions {
+            fix_hot_pixels: args.fix_hot_pixels,
+            hot_photosites: Vec::new(),
+            dark_frame: load_dark_frame(&args),
+            flat_field: load_flat_field(&args),
+            demosaic: args.demosaic,
+            fast_decode: args.fast_decode,
+        },
+        encoder,
+        jpeg_quality: args.jpeg_quality,
+        variants: args.variants.clone(),
+        color_space: args.color_space,
+        paranoid: args.paranoid,
+        job_timeout: args.job_timeout.map(time::Duration::from_secs),
+        retries: args.retries,
+        checksums: args.checksums,
+        strip_metadata: args.strip_metadata(),
+        min_rating: args.min_rating,
+        hooks: Hooks {
+            pre_file: args.pre_file_cmd.clone(),
+            post_file: args.post_file_cmd.clone(),
+        },
+        presets,
+        lens_database: load_lens_database(&args),
+        invert_negative: args.invert_negative.then_some(args.film_base),
+        match_embedded: args.match_embedded,
+        tone: ToneSettings {
+            auto_levels: args.auto_levels,
+            highlight_recovery: args.highlight_recovery,
+            curve: args.tone_curve,
+            contrast: args.contrast,
+        },
+        style: args.style,
+        lut: load_lut(&args),
+        annotation: args.annotate.clone(),
+        watermark: load_watermark(&args),
+        canvas: Canvas {
+            border: args.border,
+            pad_to: args.pad_to,
+            color: args.pad_color,
+        },
+        adjustments: Adjustments::default(),
+        import_edits: args.import_edits,
+        analyze: args.analyze,
+        report: args.report.is_some(),
+        output_storage: None,
+        cache,
+        index: None,
+        skip_duplicates: args.skip_duplicates,
+        duplicates: args.merge_duplicates.then(|| Arc::new(DuplicateTracker::default())),
+        summary_template: args.summary.clone(),
     };
+    check_color_space(&settings);
+
+    match &args.command {
+        Some(Command::Verify { input, output }) => {
+            let scanned = spawn_scan(input, args.max_depth(), args.follow_hidden, output_dirs_in(input, output, &settings));
+            let batch = Batch::new(Vec::new(), input, output, &settings, &args.batch_settings())
+                .with_stream(scanned)
+                .for_verification();
+            let (batch, _) = arrange_batch(batch, &args);
+            let report = verify_trees(batch, output);
+            report.print();
+            std::process::exit(if report.is_ok() { 0 } else { 1 });
+        },
+        Some(Command::MergeHdr { input, output, gap, format }) => {
+            let gap = time::Duration::from_secs_f64(gap.max(0.0));
+            let statistics = merge_brackets(input, output, gap, *format, &settings);
+            println!();
+            println!("DONE");
+            println!();
+            statistics.print_summary(1);
+            std::process::exit(if statistics.errors.count() == 0 { 0 } else { 1 });
+        },
+        Some(Command::VerifyChecksums { dir, algo }) => match verify_manifest(dir, *algo) {
+            Ok(failures) => {
+                for (file, reason) in &failures {
+                    println!("{:?}: {}", file, reason);
+                }
+                println!("{} files failed verification", failures.len());
+                std::process::exit(if failures.is_empty() { 0 } else { 1 });
+            },
+            Err(e) => exit_with_error(e),
+        },
+        Some(Command::Serve { addr, workers, queue, root }) => {
+            let serve_settings = ServeSettings {
+                addr: addr.clone(),
+                workers: *workers,
+                queue: *queue,
+                root: root.clone(),
+            };
+            if let Err(e) = serve(&serve_settings, &settings) {
+                exit_with_error(e);
+            }
+            return;
+        },
+        Some(Command::Query { pattern }) => {
+            let index = match &index {
+                Some(index) => index,
+                None => exit_with_error("The query subcommand requires --index"),
+            };
+            match query_index(index, pattern) {
+                Ok(0) => { println!("No files matching {:?}", pattern); std::process::exit(1) },
+                Ok(_) => return,
+                Err(e) => exit_with_error(e),
+            }
+        },
+        Some(Command::Undo { run, dry_run }) => {
+            let index = match &index {
+                Some(index) => index,
+                None => exit_with_error("The undo subcommand requires --index"),
+            };
+            let run = match run {
+                Some(run) => Ok(*run),
+                None => index.runs().and_then(|runs| runs.last().map(|r| r.id)
+                    .ok_or_else(|| String::from("no runs in the index"))),
+            };
+            match run.and_then(|run| undo_run(index, run, *dry_run)) {
+                Ok(report) => {
+                    report.print();
+                    std::process::exit(if report.is_ok() { 0 } else { 1 });
+                },
+                Err(e) => exit_with_error(e),
+            }
+        },
+        Some(Command::Completions { .. }) | Some(Command::Manpage) | None => (),
+    }
 
+    let events = match args.progress_json.as_deref().map(ProgressEvents::open).transpose() {
+        Ok(events) => events.map(Arc::new),
+        Err(e) => exit_with_error(e),
+    };
 
-    if args.filename.as_path().metadata().expect("unable to get file attributes").is_dir() {
-        let files = recurse(&mut args.filename.clone());
-        let input_base = args.filename.clone();
-        let output_base = args.output.clone();
+    // remote inputs are downloaded first and removed again after the run
+    let download_dir = std::env::temp_dir().join(format!("raw-to-img-download-{}", std::process::id()));
+    let mut camera_imports = Vec::new();
+    let multiple_inputs = args.inputs.len() > 1;
+    if multiple_inputs {
+        if let Err(e) = check_multiple_inputs(&args) {
+            exit_with_error(e);
+        }
+    }
+    let filename = match (&args.files_from, args.inputs.as_slice()) {
+        _ if args.from_camera => {
+            let imported = imported_camera_files(args.output.as_deref().unwrap_or(Path::new(".")));
+            match download_from_camera(&download_dir, &imported) {
+                Ok(downloaded) => camera_imports = downloaded,
+                Err(e) => exit_with_error(e),
+            }
+            download_dir.clone()
+        },
+        (Some(list), _) => {
+            let urls = match read_url_list(list) {
+                Ok(urls) => urls,
+                Err(e) => exit_with_error(e),
+            };
+            if let Err(e) = std::fs::create_dir_all(&download_dir) {
+                exit_with_error(format!("Unable to create {:?} ({})", download_dir, e));
+            }
+            for url in &urls {
+                if let Err(e) = download(url, &download_dir) {
+                    println!("Error: {}", e);
+                    statistics.errors.inc();
+                }
+            }
+            download_dir.clone()
+        },
+        (None, [filename]) if is_sftp_url(&filename.to_string_lossy()) =>
+            match sftp_download(&filename.to_string_lossy(), &download_dir) {
+                Ok(path) => path,
+                Err(e) => exit_with_error(e),
+            },
+        (None, [filename]) if is_url(&filename.to_string_lossy()) =>
+            match download(&filename.to_string_lossy(), &download_dir) {
+                Ok(path) => path,
+                Err(e) => exit_with_error(e),
+            },
+        (None, [filename]) if archive_format(filename).is_some() && filename.is_file() =>
+            match extract_archive(filename, &download_dir) {
+                Ok(path) => path,
+                Err(e) => exit_with_error(e),
+            },
+        (None, [filename]) => filename.clone(),
+        // several inputs are converted one after another, the run is recorded for the directory containing all of them
+        (None, inputs) if multiple_inputs => common_ancestor(inputs),
+        // clap requires one of them unless a subcommand is given
+        (None, _) => unreachable!(),
+    };
+    let input_is_dir = multiple_inputs || filename.as_path().metadata().expect("unable to get file attributes").is_dir();
+    // archives are converted into a directory named after them
+    let requested_output = args.output.clone().or_else(|| match args.inputs.as_slice() {
+        [archive] if archive_format(archive).is_some() && filename.starts_with(&download_dir) =>
+            Some(archive.with_extension("")),
+        _ => None,
+    });
+    let in_place = args.in_place || (requested_output.is_none() && args.output_archive.is_none());
+    if in_place && filename.starts_with(&download_dir) {
+        exit_with_error("Converting in place requires a local input, please specify an output");
+    }
+    let mut output = match &requested_output {
+        Some(output) if output.exists() && !args.merge && (input_is_dir || output.is_dir()) => {
+            exit_with_error(format!("Output {:?} already exists, use --merge to convert into it", output));
+        },
+        Some(output) => output.clone(),
+        None if input_is_dir => filename.clone(),
+        None => filename.with_extension(settings.encoder.extension()),
+    };
+    // other files already are where they belong
+    let settings = match in_place {
+        true => JobSettings { on_file: UnparsableAction::Ignore, on_image: UnparsableAction::Ignore,
+                              on_video: UnparsableAction::Ignore, ..settings },
+        false => settings,
+    };
 
-        if args.threads > 1 {
-            statistics = process_files_parallel(&files, &input_base, &output_base, extension, encoder, &args);
+    // remote and archive outputs are written to a staging directory and uploaded file by file
+    let opened = match &args.output_archive {
+        Some(archive) => Some(RemoteOutput::archive(archive)),
+        None if is_remote(&output) => {
+            let url = if input_is_dir { output.as_path() } else { output.parent().unwrap_or(&output) };
+            Some(RemoteOutput::open(&url.to_string_lossy()))
+        },
+        None => None,
+    };
+    let remote = if let Some(opened) = opened {
+        match opened {
+            Ok(remote) => {
+                output = match input_is_dir {
+                    true => remote.staging.clone(),
+                    false => remote.staging.join(output.file_name().unwrap_or_default()),
+                };
+                Some(remote)
+            },
+            Err(e) => exit_with_error(e),
+        }
+    } else {
+        None
+    };
+    // files are only recorded by the jobs of directory conversions
+    if let (true, Some(index)) = (input_is_dir, &mut index) {
+        if let Err(e) = index.start_run(&filename, &output, &index_fingerprint(&args, settings.presets.as_deref())) {
+            exit_with_error(e);
+        }
+    }
+    let settings = JobSettings {
+        output_storage: remote.clone(),
+        index: index.filter(|_| input_is_dir).map(Arc::new),
+        ..settings
+    };
+
+    if input_is_dir {
+        // trees on Windows can be deeper than the classic path length limit
+        let input_base = long_path(&filename);
+        let output_base = long_path(&output);
+
+        let interrupted = install_interrupt_handler();
+        let mut monitors = Monitors { events: events.clone(), webhook: webhook.clone(), ..Monitors::default() };
+        monitors.status = match &args.serve_status {
+            Some(addr) => match StatusServer::start(addr) {
+                Ok(status) => { println!("Serving status on http://{}", addr); Some(status) },
+                Err(e) => { eprintln!("{}", e); None },
+            },
+            None => None,
+        };
+        #[cfg(unix)]
+        if let Some(path) = &args.control_socket {
+            monitors.control_socket = match ControlSocket::start(path, monitors.control.clone(), interrupted.clone()) {
+                Ok(socket) => { println!("Accepting commands on {:?}", path); Some(socket) },
+                Err(e) => { eprintln!("{}", e); None },
+            };
+        }
+        #[cfg(feature = "tui")]
+        if args.tui {
+            monitors.tui = match Tui::start(monitors.control.clone(), interrupted.clone()) {
+                Ok(tui) => Some(tui),
+                Err(e) => { eprintln!("{}", e); None },
+            };
+        }
+
+        if multiple_inputs {
+            for (input, output_base) in input_outputs(&args.inputs, &output_base, args.input_layout) {
+                println!("Converting {:?} into {:?}", input, output_base);
+                let mut batch = match input.is_dir() {
+                    true => Batch::new(Vec::new(), &input, &output_base, &settings, &args.batch_settings())
+                        .with_stream(scan_input(&input, &output_base, &settings, &args)),
+                    false => Batch::new(vec![input.clone()], input.parent().unwrap_or(Path::new(".")), &output_base,
+                                        &settings, &args.batch_settings()),
+                }.with_cancel_handle(interrupted.clone());
+                if let (true, Some(index)) = (args.sync, &settings.index) {
+                    println!("Skipping {} files that are already in the index", batch.skip_synced(index));
+                }
+                if args.min_free_space.is_some() {
+                    batch.finish_scan();
+                    check_free_space(batch.files(), &output_base, &settings, &args);
+                }
+                statistics.extend(&run_batch(batch, &args, &monitors));
+                if interrupted.load(Ordering::Relaxed) {
+                    break;
+                }
+            }
+        } else if let Some(interval) = args.watch {
+            statistics = watch(&input_base, &output_base, &settings, &args, interval, &interrupted, &monitors);
         } else {
-            statistics = process_files(&files, &input_base, &output_base, extension, encoder, &args);
+            // jobs start while the input is still being scanned, unless an option needs all files up front
+            let mut batch = Batch::new(Vec::new(), &input_base, &output_base, &settings, &args.batch_settings())
+                .with_stream(scan_input(&input_base, &output_base, &settings, &args))
+                .with_cancel_handle(interrupted);
+            if let (true, Some(index)) = (args.sync, &settings.index) {
+                println!("Skipping {} files that are already in the index", batch.skip_synced(index));
+            }
+            if args.min_free_space.is_some() {
+                batch.finish_scan();
+                check_free_space(batch.files(), &output_base, &settings, &args);
+            }
+            statistics = run_batch(batch, &args, &monitors);
         }
 
     } else {
         let starting = Instant::now();
-        raw_info_short(args.filename.as_path());
-        match recode(args.filename.as_path(), &args.output, encoder) {
+        if args.summary.is_none() {
+            raw_info_short(filename.as_path());
+        }
+        let mut analysis = None;
+        let retried = &mut statistics.retried;
+        let recoded = retry_transient(settings.retries, retried, || decode_step(filename.as_path(), &settings))
+            .and_then(|(decoded, dtime, monochrome)| {
+                if settings.analyze {
+                    let result = analyze_image(&decoded);
+                    result.print(&filename);
+                    analysis = Some(result);
+                }
+                let etime = retry_transient(settings.retries, retried,
+                    || encode_step(&decoded, dtime, monochrome, filename.as_path(), &output, &settings))?;
+                Ok((dtime, etime))
+            })
+            .ok();
+        if settings.report {
+            let status = if recoded.is_some() { "ok" } else { "error" };
+            statistics.report.push(ReportEntry { input: filename.clone(), output: output.clone(), status, analysis, duplicate_of: None });
+        }
+        match recoded {
             Some((dtime, etime)) => {
                 let ending = Instant::now();
                 statistics.total.record(ending - starting);
+                statistics.wall_time = ending - starting;
                 statistics.decoded.record(dtime);
                 statistics.encoded.record(etime);
+                if let Some(algo) = args.checksums {
+                    match hash_file(&output, algo) {
+                        Ok(digest) => statistics.checksums.push((output.clone(), digest)),
+                        Err(e) => println!("Unable to hash {:?}: {}", output, e),
+                    }
+                }
             },
             None => statistics.errors.inc(),
         };
+        if let (true, Some(remote)) = (output.exists(), &remote) {
+            if let Err(e) = remote.upload(&output) {
+                eprintln!("{}", e);
+                statistics.errors.inc();
+            }
+        }
+    }
+
+    #[cfg(feature = "notify")]
+    if args.notify {
+        notify(&statistics, run_start.elapsed());
+    }
+    #[cfg(not(feature = "notify"))]
+    let _ = run_start;
+
+    if let Some(algo) = args.checksums {
+        let manifest_root = if output.is_dir() { output.as_path() } else { output.parent().unwrap_or(Path::new(".")) };
+        match write_manifest(manifest_root, algo, &statistics.checksums) {
+            Ok(()) => println!("Wrote {:?}", manifest_root.join(algo.manifest_name())),
+            Err(e) => eprintln!("Unable to write checksum manifest ({})", e),
+        }
+        if let Some(remote) = &remote {
+            if let Err(e) = remote.upload(&manifest_root.join(algo.manifest_name())) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+    // the post-run command gets to see the complete output
+    if let Some(remote) = &remote {
+        if let Err(e) = remote.finish() {
+            eprintln!("{}", e);
+            statistics.errors.inc();
+        }
+    }
+
+    if let Some(command) = &args.post_run_cmd {
+        let status = if statistics.errors.count() > 0 { "error" } else { "ok" };
+        let env = [
+            ("INPUT", input_env(&args)),
+            ("OUTPUT", args.output.clone().or_else(|| args.output_archive.clone()).unwrap_or_else(|| output.clone()).into_os_string()),
+            ("STATUS", status.into()),
+            ("FILES", statistics.total.count().to_string().into()),
+            ("ERRORS", statistics.errors.count().to_string().into()),
+        ];
+        if let Err(e) = run_hook(command, &env) {
+            eprintln!("{}", e);
+        }
+    }
+    if let Some(webhook) = &webhook {
+        if let Err(e) = webhook.send(&statistics) {
+            eprintln!("{}", e);
+        }
     }
 
+    if let Some(report) = &args.report {
+        match write_report(report, &statistics.report) {
+            Ok(()) => println!("Wrote {:?}", report),
+            Err(e) => eprintln!("Unable to write report ({})", e),
+        }
+    }
+    if let Some(path) = &args.metrics_file {
+        if let Err(e) = write_metrics(path, &statistics) {
+            eprintln!("Unable to write metrics ({})", e);
+        }
+    }
+    if let Some(remote) = &remote {
+        remote.cleanup();
+    }
+    // remote outputs have no place to remember imports, so every run imports all files
+    if !camera_imports.is_empty() && statistics.errors.count() == 0 && remote.is_none() {
+        if let Err(e) = record_camera_imports(&output, &camera_imports) {
+            eprintln!("Unable to record imported camera files ({})", e);
+        }
+    }
+    let _ = std::fs::remove_dir_all(&download_dir);
+
+    if let Some(events) = &events {
+        events.summary(&statistics);
+        if events.on_stdout {
+            return;
+        }
+    }
     if statistics.total.count() > 0 || statistics.errors.count() > 0 {
         println!();
         println!("DONE");
         println!();
 
-        statistics.print_nthreads(args.threads.try_into().unwrap());
+        statistics.print_summary(args.threads.try_into().unwrap());
     } else {
-        println!("Found no files to process in {:?}", args.filename);
+        println!("Found no files to process in {:?}", filename);
     }
 }
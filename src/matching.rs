@@ -0,0 +1,182 @@
+use crate::*;
+
+
+/// Longer side that the render and the camera preview are compared at (small enough to hide misalignment)
+const MATCH_SIZE: usize = 96;
+
+/// Minimum number of usable pixels for a reliable estimate
+const MIN_SAMPLES: usize = 500;
+
+/// Values close to black or white in either image carry no color information
+const CLIPPED: std::ops::RangeInclusive<u8> = 6..=249;
+
+/// Radius of the box filter smoothing the estimated tone curves
+const CURVE_SMOOTHING: usize = 4;
+
+
+/// Makes a decoded raw look like the camera's own JPEG, using the preview embedded in the raw as reference
+///
+/// The render and the preview are compared pixel by pixel at a small size. A color matrix (in linear
+/// light) and per channel tone curves mapping the render to the preview are estimated from this and
+/// applied to the whole image. Fails if the raw has no usable preview.
+pub fn match_embedded(image: &mut imagepipe::SRGBImage, input: &Path) -> Result<(), String> {
+    let preview = oriented_preview(input).ok_or_else(|| String::from("no embedded preview found"))?;
+    let (render, reference) = aligned_samples(image, &preview);
+
+    let linear = pixelops::srgb8_to_linear_table();
+    let usable: Vec<usize> = (0..render.len() / 3)
+        .filter(|i| (0..3).all(|c| CLIPPED.contains(&render[i * 3 + c]) && CLIPPED.contains(&reference[i * 3 + c])))
+        .collect();
+    if usable.len() < MIN_SAMPLES {
+        return Err(format!("only {} usable pixels in the embedded preview", usable.len()));
+    }
+
+    let pixel = |data: &[u8], i: usize| [0, 1, 2].map(|c| linear[data[i * 3 + c] as usize]);
+    let matrix = fit_matrix(usable.iter().map(|&i| (pixel(&render, i), pixel(&reference, i))))
+        .ok_or_else(|| String::from("the embedded preview does not match the image"))?;
+
+    // tone curves are estimated on the render after the color matrix, so they only correct what is left
+    let matched = apply_matrix(&render, &matrix);
+    let curves: [[u8; 256]; 3] = std::array::from_fn(|c| {
+        fit_curve(usable.iter().map(|&i| (matched[i * 3 + c], reference[i * 3 + c])))
+    });
+
+    image.data = apply_matrix(&image.data, &matrix);
+    for pixel in image.data.chunks_exact_mut(3) {
+        for (value, curve) in pixel.iter_mut().zip(&curves) {
+            *value = curve[*value as usize];
+        }
+    }
+    Ok(())
+}
+
+/// Embedded preview of `input`, rotated like the decoded image
+///
+/// Decoders apply the EXIF orientation to the image, but the preview is stored as it left the sensor.
+fn oriented_preview(input: &Path) -> Option<imagepipe::SRGBImage> {
+    let preview = embedded_preview(input)?;
+    let orientation = read_exif(input)
+        .and_then(|exif| exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0));
+    let rotation = match orientation {
+        Some(3) => pixelops::Rotation::Cw180,
+        Some(6) => pixelops::Rotation::Cw90,
+        Some(8) => pixelops::Rotation::Cw270,
+        _ => return Some(preview),
+    };
+    let (data, width, height) = pixelops::rotate_rgb8(&preview.data, preview.width, preview.height, rotation);
+    Some(imagepipe::SRGBImage { data, width, height })
+}
+
+/// Scales the image and the preview down to the same small size
+///
+/// Previews with a different aspect ratio (e.g. 4:3 thumbnails of 3:2 sensors) are letterboxed, only the
+/// centered part matching the image is used.
+fn aligned_samples(image: &imagepipe::SRGBImage, preview: &imagepipe::SRGBImage) -> (Vec<u8>, Vec<u8>) {
+    let aspect = image.width as f64 / image.height.max(1) as f64;
+    let (content_width, content_height) = match preview.width as f64 / preview.height.max(1) as f64 {
+        a if a > aspect => (((preview.height as f64 * aspect).round() as usize).max(1), preview.height),
+        _ => (preview.width, ((preview.width as f64 / aspect).round() as usize).max(1)),
+    };
+    let margin_x = (preview.width - content_width.min(preview.width)) / 2;
+    let margin_y = (preview.height - content_height.min(preview.height)) / 2;
+    let (content, content_width, content_height) = pixelops::crop_rgb8(&preview.data, preview.width, preview.height,
+        [margin_x, margin_y, margin_x, margin_y]);
+
+    let scale = MATCH_SIZE as f64 / content_width.max(content_height) as f64;
+    let width = ((content_width as f64 * scale.min(1.0)).round() as usize).max(1);
+    let height = ((content_height as f64 * scale.min(1.0)).round() as usize).max(1);
+    (pixelops::resize_rgb8(&image.data, image.width, image.height, width, height),
+     pixelops::resize_rgb8(&content, content_width, content_height, width, height))
+}
+
+/// Least squares fit of the 3x3 matrix mapping linear render colors to linear reference colors
+fn fit_matrix(samples: impl Iterator<Item = ([f32; 3], [f32; 3])>) -> Option<[[f32; 3]; 3]> {
+    // normal equations: (A^T A) M^T = A^T B
+    let mut ata = [[0f64; 3]; 3];
+    let mut atb = [[0f64; 3]; 3];
+    for (render, reference) in samples {
+        let (render, reference) = (render.map(f64::from), reference.map(f64::from));
+        for ((ata_row, atb_row), r) in ata.iter_mut().zip(&mut atb).zip(render) {
+            for (j, (a, b)) in ata_row.iter_mut().zip(atb_row.iter_mut()).enumerate() {
+                *a += r * render[j];
+                *b += r * reference[j];
+            }
+        }
+    }
+
+    let inverse = invert_matrix(ata)?;
+    Some(std::array::from_fn(|row| std::array::from_fn(|col| {
+        (0..3).map(|k| inverse[col][k] * atb[k][row]).sum::<f64>() as f32
+    })))
+}
+
+/// Inverse of a 3x3 matrix, `None` if it is singular
+pub(crate) fn invert_matrix(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r: usize, c: usize| {
+        let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+        let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let determinant: f64 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+    Some(std::array::from_fn(|r| std::array::from_fn(|c| cofactor(c, r) / determinant)))
+}
+
+/// Applies a color matrix in linear light to 8 bit sRGB data
+fn apply_matrix(data: &[u8], matrix: &[[f32; 3]; 3]) -> Vec<u8> {
+    let linear = pixelops::srgb8_to_linear_table();
+    let transformed: Vec<f32> = data.chunks_exact(3)
+        .flat_map(|pixel| {
+            let rgb = [0, 1, 2].map(|c| linear[pixel[c] as usize]);
+            matrix.map(|row| row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2])
+        })
+        .collect();
+    pixelops::linear_to_srgb8(&transformed, [0.0; 3], [1.0; 3])
+}
+
+/// Monotonic tone curve mapping render values to the average reference value they correspond to
+///
+/// Values without samples are interpolated, values outside the sampled range keep the offset of
+/// the closest sampled value.
+fn fit_curve(samples: impl Iterator<Item = (u8, u8)>) -> [u8; 256] {
+    let mut sums = [0f64; 256];
+    let mut counts = [0usize; 256];
+    for (render, reference) in samples {
+        sums[render as usize] += reference as f64;
+        counts[render as usize] += 1;
+    }
+
+    let known: Vec<(usize, f64)> = (0..256)
+        .filter(|&v| counts[v] > 0)
+        .map(|v| (v, sums[v] / counts[v] as f64))
+        .collect();
+    let (Some(&first), Some(&last)) = (known.first(), known.last()) else {
+        return std::array::from_fn(|v| v as u8);
+    };
+
+    let mut curve = [0f64; 256];
+    for (v, value) in curve.iter_mut().enumerate() {
+        *value = match known.partition_point(|&(k, _)| k < v) {
+            0 => v as f64 + first.1 - first.0 as f64,
+            n if n == known.len() => v as f64 + last.1 - last.0 as f64,
+            n => {
+                let ((x0, y0), (x1, y1)) = (known[n - 1], known[n]);
+                y0 + (y1 - y0) * (v - x0) as f64 / (x1 - x0) as f64
+            },
+        };
+    }
+
+    let smoothed: Vec<f64> = (0..256)
+        .map(|v| {
+            let window = &curve[v.saturating_sub(CURVE_SMOOTHING)..(v + CURVE_SMOOTHING + 1).min(256)];
+            window.iter().sum::<f64>() / window.len() as f64
+        })
+        .collect();
+    let mut max = 0.0f64;
+    std::array::from_fn(|v| {
+        max = max.max(smoothed[v]);
+        max.round().clamp(0.0, 255.0) as u8
+    })
+}
@@ -0,0 +1,236 @@
+use crate::*;
+
+
+pub const DEFAULT_SUMMARY_TEMPLATE: &str = "{name} | {camera} | ISO {iso} {shutter} f/{aperture} | {size} | {time}";
+
+/// Groups of EXIF fields that can be removed from converted outputs
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum MetadataField {
+    /// All GPS fields
+    Gps,
+    /// Body and lens serial numbers and the unique image id
+    Serial,
+    /// Camera owner and artist name
+    Owner,
+    /// All EXIF data
+    All,
+}
+
+/// Tags describing the layout of the raw file rather than the picture, which must not be copied
+const STRUCTURAL_TAGS: [exif::Tag; 19] = [
+    exif::Tag::ImageWidth, exif::Tag::ImageLength, exif::Tag::BitsPerSample, exif::Tag::Compression,
+    exif::Tag::PhotometricInterpretation, exif::Tag::StripOffsets, exif::Tag::SamplesPerPixel,
+    exif::Tag::RowsPerStrip, exif::Tag::StripByteCounts, exif::Tag::PlanarConfiguration,
+    exif::Tag::JPEGInterchangeFormat, exif::Tag::JPEGInterchangeFormatLength, exif::Tag::ExifIFDPointer,
+    exif::Tag::GPSInfoIFDPointer, exif::Tag::InteropIFDPointer, exif::Tag::MakerNote,
+    exif::Tag::PixelXDimension, exif::Tag::PixelYDimension,
+    // outputs are already rotated by the decoders
+    exif::Tag::Orientation,
+];
+
+/// Shooting parameters read from the EXIF data of a raw file
+#[derive(Default)]
+pub struct ExifSummary {
+    pub camera: Option<String>,
+    pub iso: Option<u32>,
+    pub shutter: Option<String>,
+    pub aperture: Option<f64>,
+    /// Capture date and time as `YYYY-MM-DD HH:MM:SS`
+    pub date: Option<String>,
+}
+
+
+impl ExifSummary {
+    /// Reads the summary from a file, leaving fields empty that can not be determined
+    pub fn read(path: &path::Path) -> ExifSummary {
+        let data = match read_exif(path) {
+            Some(data) => data,
+            None => return ExifSummary::default(),
+        };
+        let field = |tag| data.get_field(tag, exif::In::PRIMARY);
+
+        let camera = field(exif::Tag::Model)
+            .map(|f| f.display_value().to_string().trim_matches('"').trim().to_string());
+        let iso = field(exif::Tag::PhotographicSensitivity)
+            .and_then(|f| f.value.get_uint(0));
+        let shutter = field(exif::Tag::ExposureTime)
+            .and_then(|f| first_rational(&f.value))
+            .map(|(num, denom)| if num > 0 && num < denom {
+                format!("1/{}", (denom as f64 / num as f64).round())
+            } else {
+                format!("{}s", num as f64 / denom.max(1) as f64)
+            });
+        let aperture = field(exif::Tag::FNumber)
+            .and_then(|f| first_rational(&f.value))
+            .map(|(num, denom)| num as f64 / denom.max(1) as f64);
+
+        let date = field(exif::Tag::DateTimeOriginal)
+            .or_else(|| field(exif::Tag::DateTime))
+            .map(|f| f.display_value().to_string());
+
+        ExifSummary { camera, iso, shutter, aperture, date }
+    }
+}
+
+/// Reads the EXIF data of a raw or image file
+pub fn read_exif(path: &path::Path) -> Option<exif::Exif> {
+    let file = fs::File::open(path).ok()?;
+    let mut reader = io::BufReader::new(file);
+    exif::Reader::new().read_from_container(&mut reader).ok()
+}
+
+/// Reads the EXIF data of a raw or image file that is already in memory
+pub fn read_exif_bytes(data: &[u8]) -> Option<exif::Exif> {
+    exif::Reader::new().read_from_container(&mut io::Cursor::new(data)).ok()
+}
+
+fn first_rational(value: &exif::Value) -> Option<(u32, u32)> {
+    match value {
+        exif::Value::Rational(values) => values.first().map(|r| (r.num, r.denom)),
+        _ => None,
+    }
+}
+
+/// Renders a one-line job summary from a template
+///
+/// Supported placeholders: `{name}`, `{camera}`, `{iso}`, `{shutter}`, `{aperture}`, `{size}`, `{time}`.
+pub fn format_summary(template: &str, input: &path::Path, output: &path::Path, time: &time::Duration) -> String {
+    let summary = ExifSummary::read(input);
+    let unknown = || String::from("?");
+    let name = input.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(unknown);
+    let size = output.metadata()
+        .map(|m| fmt_bytes(m.len()))
+        .unwrap_or_else(|_| unknown());
+
+    template
+        .replace("{name}", &name)
+        .replace("{camera}", &summary.camera.unwrap_or_else(unknown))
+        .replace("{iso}", &summary.iso.map(|i| i.to_string()).unwrap_or_else(unknown))
+        .replace("{shutter}", &summary.shutter.unwrap_or_else(unknown))
+        .replace("{aperture}", &summary.aperture.map(|a| format!("{:.1}", a)).unwrap_or_else(unknown))
+        .replace("{size}", &size)
+        .replace("{time}", &fmt_duration(time))
+}
+
+fn is_stripped(tag: exif::Tag, strip: &[MetadataField]) -> bool {
+    strip.iter().any(|field| match field {
+        MetadataField::Gps => tag.context() == exif::Context::Gps,
+        MetadataField::Serial =>
+            [exif::Tag::BodySerialNumber, exif::Tag::LensSerialNumber, exif::Tag::ImageUniqueID].contains(&tag),
+        MetadataField::Owner => [exif::Tag::CameraOwnerName, exif::Tag::Artist].contains(&tag),
+        MetadataField::All => true,
+    })
+}
+
+/// Serializes the EXIF data of `input` (without the fields in `strip`) as a TIFF structure
+///
+/// The `ColorSpace` tag is replaced by the one of `color_space` unless that is sRGB.
+///
+/// Only documented tags of the primary image are kept, as vendor specific tags and maker notes
+/// often contain offsets into the raw file.
+pub fn exif_blob(input: &path::Path, strip: &[MetadataField], color_space: ColorSpace) -> Option<Vec<u8>> {
+    exif_blob_from(&read_exif(input)?, strip, color_space)
+}
+
+/// Like `exif_blob()`, for EXIF data that has already been read
+pub fn exif_blob_from(data: &exif::Exif, strip: &[MetadataField], color_space: ColorSpace) -> Option<Vec<u8>> {
+    // the tag copied from the raw describes the camera's own JPEGs, not the color space of the output
+    let color_space_field = color_space.exif_value().map(|value| exif::Field {
+        tag: exif::Tag::ColorSpace,
+        ifd_num: exif::In::PRIMARY,
+        value: exif::Value::Short(vec![value]),
+    });
+    let fields: Vec<&exif::Field> = data.fields()
+        .filter(|f| f.ifd_num == exif::In::PRIMARY)
+        .filter(|f| f.tag.description().is_some() && !STRUCTURAL_TAGS.contains(&f.tag))
+        .filter(|f| !matches!(f.value, exif::Value::Unknown(..)))
+        .filter(|f| !is_stripped(f.tag, strip))
+        .filter(|f| color_space_field.is_none() || f.tag != exif::Tag::ColorSpace)
+        .chain(color_space_field.as_ref())
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut blob = io::Cursor::new(Vec::new());
+    writer.write(&mut blob, data.little_endian()).ok()?;
+    Some(blob.into_inner())
+}
+
+/// Embeds a TIFF structured EXIF blob into an encoded image
+///
+/// Supported for JPEG (APP1 segment) and PNG (eXIf chunk), other formats are returned unchanged.
+pub fn embed_exif(encoded: Vec<u8>, exif: &[u8], encoder_type: EncoderType) -> Vec<u8> {
+    match encoder_type {
+        EncoderType::JpegEncoder(_) => embed_exif_jpeg(encoded, exif),
+        EncoderType::PngEncoder(..) => embed_exif_png(encoded, exif),
+        EncoderType::TiffEncoder | EncoderType::QoiEncoder => encoded,
+    }
+}
+
+fn embed_exif_jpeg(encoded: Vec<u8>, exif: &[u8]) -> Vec<u8> {
+    const HEADER: &[u8] = b"Exif\0\0";
+    let length = 2 + HEADER.len() + exif.len();
+    if !encoded.starts_with(&[0xff, 0xd8]) || length > u16::MAX as usize {
+        return encoded;
+    }
+
+    // keep a JFIF segment in front, as some readers expect it directly after the SOI marker
+    let mut pos = 2;
+    if encoded.get(2..4) == Some(&[0xff, 0xe0]) {
+        if let Some(len) = encoded.get(4..6) {
+            pos = 4 + u16::from_be_bytes([len[0], len[1]]) as usize;
+        }
+    }
+    if pos > encoded.len() {
+        return encoded;
+    }
+
+    let mut out = Vec::with_capacity(encoded.len() + length + 2);
+    out.extend_from_slice(&encoded[..pos]);
+    out.extend_from_slice(&[0xff, 0xe1]);
+    out.extend_from_slice(&(length as u16).to_be_bytes());
+    out.extend_from_slice(HEADER);
+    out.extend_from_slice(exif);
+    out.extend_from_slice(&encoded[pos..]);
+    out
+}
+
+fn embed_exif_png(encoded: Vec<u8>, exif: &[u8]) -> Vec<u8> {
+    // signature (8 bytes) followed by the IHDR chunk (25 bytes)
+    const IHDR_END: usize = 33;
+    if encoded.get(12..16) != Some(b"IHDR") || encoded.len() < IHDR_END {
+        return encoded;
+    }
+
+    let mut chunk = Vec::with_capacity(exif.len() + 12);
+    chunk.extend_from_slice(&(exif.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(b"eXIf");
+    chunk.extend_from_slice(exif);
+    let crc = crc32(&chunk[4..]);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+
+    let mut out = Vec::with_capacity(encoded.len() + chunk.len());
+    out.extend_from_slice(&encoded[..IHDR_END]);
+    out.extend_from_slice(&chunk);
+    out.extend_from_slice(&encoded[IHDR_END..]);
+    out
+}
+
+/// CRC-32 as used by PNG chunks
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,74 @@
+use crate::*;
+
+use std::fmt::Write;
+
+
+/// Upper bounds of the histogram buckets for processing times in seconds
+pub(crate) const TIME_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+
+/// Renders the statistics in the Prometheus text exposition format
+pub fn prometheus_metrics(statistics: &Statistics) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP raw_to_img_files_total Files processed, by outcome.");
+    let _ = writeln!(out, "# TYPE raw_to_img_files_total counter");
+    let outcomes = [
+        ("decoded", &statistics.decoded), ("encoded", &statistics.encoded), ("copied", &statistics.copied),
+        ("moved", &statistics.moved), ("cached", &statistics.cached), ("ignored", &statistics.ignored),
+        ("error", &statistics.errors), ("timeout", &statistics.timeouts), ("quarantined", &statistics.quarantined),
+        ("salvaged", &statistics.salvaged),
+    ];
+    for (outcome, item) in outcomes {
+        let _ = writeln!(out, "raw_to_img_files_total{{outcome=\"{}\"}} {}", outcome, item.count());
+    }
+
+    counter(&mut out, "raw_to_img_retries_total", "Retries of steps that failed with transient errors.",
+            statistics.retried.count() as f64);
+    counter(&mut out, "raw_to_img_copied_bytes_total", "Bytes copied.", statistics.copied.volume() * 1024.0 * 1024.0);
+    counter(&mut out, "raw_to_img_decoded_megapixels_total", "Megapixels decoded.", statistics.decoded.volume());
+    counter(&mut out, "raw_to_img_wall_seconds_total", "Elapsed real time of all runs.", statistics.wall_time.as_secs_f64());
+    counter(&mut out, "raw_to_img_busy_seconds_total", "Time spent working on files, summed up over all threads.",
+            statistics.busy_time().as_secs_f64());
+    counter(&mut out, "raw_to_img_cpu_seconds_total", "CPU time used by the process during the runs.",
+            statistics.cpu_time.as_secs_f64());
+
+    let histograms = [
+        ("raw_to_img_decode_duration_seconds", "Time to decode a raw.", &statistics.decoded),
+        ("raw_to_img_encode_duration_seconds", "Time to encode a decoded raw.", &statistics.encoded),
+        ("raw_to_img_copy_duration_seconds", "Time to copy a file.", &statistics.copied),
+        ("raw_to_img_move_duration_seconds", "Time to move a file.", &statistics.moved),
+    ];
+    for (name, help, item) in histograms {
+        histogram(&mut out, name, help, item);
+    }
+
+    out
+}
+
+/// Writes the metrics of `statistics` to `path`, replacing the previous file atomically
+///
+/// Meant for the textfile collector of the Prometheus node exporter.
+pub fn write_metrics(path: &Path, statistics: &Statistics) -> Result<(), String> {
+    let temp = temp_path(path);
+    fs::write(&temp, prometheus_metrics(statistics)).map_err(|e| e.to_string())?;
+    fs::rename(&temp, path).map_err(|e| e.to_string())
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, item: &StatisticsItem) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    let (buckets, count) = item.histogram();
+    for (bound, bucket) in TIME_BUCKETS.iter().zip(buckets) {
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(out, "{}_sum {}", name, item.time_total().as_secs_f64());
+    let _ = writeln!(out, "{}_count {}", name, count);
+}
@@ -0,0 +1,284 @@
+use crate::*;
+
+use std::sync::Arc;
+
+
+/// Radius of the window searched for photosites of the same color
+const NEIGHBOUR_RADIUS: i64 = 2;
+/// Number of photosites in the window besides the center
+const MAX_NEIGHBOURS: usize = ((2 * NEIGHBOUR_RADIUS + 1) * (2 * NEIGHBOUR_RADIUS + 1) - 1) as usize;
+/// A photosite is hot if it is this many times brighter than its brightest same-colored neighbour ...
+const HOT_FACTOR: f32 = 2.0;
+/// ... and exceeds it by at least this fraction of the sensor range
+const HOT_MARGIN: f32 = 0.03;
+/// A photosite is dead if it is this many times darker than its darkest same-colored neighbour ...
+const DEAD_FACTOR: f32 = 4.0;
+/// ... and falls below it by at least this fraction of the sensor range
+const DEAD_MARGIN: f32 = 0.1;
+/// Largest gain applied by a flat field, so dark spots of the flat frame do not blow out
+const MAX_FLAT_GAIN: f32 = 8.0;
+
+/// Options that change how decoders turn the raw data into an image
+///
+/// Corrections of the raw mosaic are applied before demosaicing, so only decoders that have access to
+/// the undemosaiced data (imagepipe, rawloader and the gpu decoder) support them.
+#[derive(Clone, Debug, Default)]
+pub struct DecodeOptions {
+    /// Detect hot and dead photosites and interpolate them from their neighbours
+    pub fix_hot_pixels: bool,
+    /// Known defective photosites as `(x, y)` in sensor coordinates
+    pub hot_photosites: Vec<(usize, usize)>,
+    /// Thermal noise subtracted from every raw
+    pub dark_frame: Option<Arc<DarkFrame>>,
+    /// Vignetting and sensor nonuniformity divided out of every raw
+    pub flat_field: Option<Arc<FlatField>>,
+    /// Demosaic algorithm, or `None` to leave it to the decoder
+    pub demosaic: Option<Demosaic>,
+    /// Decode at reduced resolution
+    pub fast_decode: Option<FastDecode>,
+}
+
+/// Raw data of an exposure with the lens cap on, recording the thermal noise of the sensor
+#[derive(Clone, Debug)]
+pub struct DarkFrame {
+    width: usize,
+    height: usize,
+    /// Signal above the black level of every photosite
+    noise: Vec<f32>,
+}
+
+/// Per-photosite gains computed from an evenly lit reference frame
+#[derive(Clone, Debug)]
+pub struct FlatField {
+    width: usize,
+    height: usize,
+    gains: Vec<f32>,
+}
+
+
+impl DecodeOptions {
+    /// Whether any correction has to be applied to the raw mosaic
+    pub fn corrects_mosaic(&self) -> bool {
+        self.fix_hot_pixels || !self.hot_photosites.is_empty() || self.dark_frame.is_some()
+            || self.flat_field.is_some()
+    }
+}
+
+impl DarkFrame {
+    /// Decodes a dark frame, which has to be taken with the same camera and settings as the images it is applied to
+    pub fn load(path: &Path) -> Result<DarkFrame, String> {
+        let mut raw = rawloader::decode_file(path)
+            .map_err(|e| format!("Unable to decode dark frame {:?} ({})", path, e))?;
+        if raw.cpp != 1 {
+            return Err(format!("Dark frame {:?} is not a single channel raw", path));
+        }
+
+        let (width, height) = (raw.width, raw.height);
+        let black: [f32; 4] = std::array::from_fn(|c| raw.blacklevels[c] as f32);
+        let cfa = raw.cfa.clone();
+        let mut noise = Vec::new();
+        with_samples(&mut raw, |data| {
+            noise = data.iter().enumerate()
+                .map(|(i, v)| (v - black[cfa_color(&cfa, i / width, i % width)]).max(0.0))
+                .collect();
+        });
+        Ok(DarkFrame { width, height, noise })
+    }
+
+    /// Subtracts the noise from the samples of a raw with the same dimensions
+    fn subtract(&self, data: &mut [f32], width: usize, height: usize) -> Result<(), String> {
+        if (width, height) != (self.width, self.height) {
+            return Err(format!("dark frame is {}x{}, but the raw is {}x{}", self.width, self.height, width, height));
+        }
+        for (value, noise) in data.iter_mut().zip(&self.noise) {
+            *value = (*value - noise).max(0.0);
+        }
+        Ok(())
+    }
+}
+
+impl FlatField {
+    /// Decodes a flat frame (an evenly lit, unfocused exposure) and derives the gains that even it out
+    ///
+    /// The frame is smoothed over neighbouring photosites of the same color first, so its noise is not
+    /// transferred to the corrected images.
+    pub fn load(path: &Path) -> Result<FlatField, String> {
+        let mut raw = rawloader::decode_file(path)
+            .map_err(|e| format!("Unable to decode flat frame {:?} ({})", path, e))?;
+        if raw.cpp != 1 {
+            return Err(format!("Flat frame {:?} is not a single channel raw", path));
+        }
+
+        let (width, height) = (raw.width, raw.height);
+        let black: [f32; 4] = std::array::from_fn(|c| raw.blacklevels[c] as f32);
+        let cfa = raw.cfa.clone();
+        let neighbours = neighbour_offsets(&cfa);
+        let mut gains = Vec::new();
+        with_samples(&mut raw, |data| {
+            let color = |i: usize| cfa_color(&cfa, i / width, i % width);
+            let smoothed: Vec<f32> = (0..data.len())
+                .map(|i| {
+                    let mut buffer = [0.0; MAX_NEIGHBOURS];
+                    let values = neighbour_values(data, width, height, i / width, i % width, &neighbours, &mut buffer);
+                    let sum: f32 = values.iter().sum::<f32>() + data[i];
+                    (sum / (values.len() + 1) as f32 - black[color(i)]).max(0.0)
+                })
+                .collect();
+
+            let mut sums = [0.0f64; 3];
+            let mut counts = [0usize; 3];
+            for (i, value) in smoothed.iter().enumerate() {
+                sums[color(i)] += *value as f64;
+                counts[color(i)] += 1;
+            }
+            let means: [f32; 3] = std::array::from_fn(|c| (sums[c] / counts[c].max(1) as f64) as f32);
+
+            gains = smoothed.iter().enumerate()
+                .map(|(i, value)| (means[color(i)] / value.max(f32::EPSILON)).min(MAX_FLAT_GAIN))
+                .collect();
+        });
+        Ok(FlatField { width, height, gains })
+    }
+
+    /// Multiplies the signal above the black level of a raw with the same dimensions by the gains
+    fn apply(&self, data: &mut [f32], width: usize, height: usize, black: [f32; 4], cfa: &rawloader::CFA) -> Result<(), String> {
+        if (width, height) != (self.width, self.height) {
+            return Err(format!("flat frame is {}x{}, but the raw is {}x{}", self.width, self.height, width, height));
+        }
+        for (i, (value, gain)) in data.iter_mut().zip(&self.gains).enumerate() {
+            let black = black[cfa_color(cfa, i / width, i % width)];
+            *value = black + (*value - black).max(0.0) * gain;
+        }
+        Ok(())
+    }
+}
+
+/// Applies the corrections of `options` to the undemosaiced data of a raw image
+///
+/// The dark frame is subtracted first, as it already removes most hot pixels of long exposures.
+/// The flat field is applied last, so it does not amplify the defects.
+pub fn correct_mosaic(raw: &mut rawloader::RawImage, options: &DecodeOptions) -> Result<(), String> {
+    if !options.corrects_mosaic() {
+        return Ok(());
+    }
+    if raw.cpp != 1 {
+        return Err(String::from("raw corrections are only supported for single channel raws"));
+    }
+
+    let neighbours = neighbour_offsets(&raw.cfa);
+    let (width, height) = (raw.width, raw.height);
+    let black: [f32; 4] = std::array::from_fn(|c| raw.blacklevels[c] as f32);
+    let range: [f32; 4] = std::array::from_fn(|c| (raw.whitelevels[c] as f32 - black[c]).max(1.0));
+    let cfa = raw.cfa.clone();
+
+    let mut result = Ok(());
+    with_samples(raw, |data| {
+        if let Some(dark_frame) = &options.dark_frame {
+            result = dark_frame.subtract(data, width, height);
+        }
+
+        let mut repairs = Vec::new();
+        if options.fix_hot_pixels {
+            for row in 0..height {
+                for col in 0..width {
+                    let mut buffer = [0.0; MAX_NEIGHBOURS];
+                    let values = neighbour_values(data, width, height, row, col, &neighbours, &mut buffer);
+                    let color = cfa_color(&cfa, row, col);
+                    let value = data[row * width + col] - black[color];
+                    let brightest = values.iter().fold(f32::MIN, |a, b| a.max(*b)) - black[color];
+                    let darkest = values.iter().fold(f32::MAX, |a, b| a.min(*b)) - black[color];
+                    let hot = value > brightest * HOT_FACTOR && value - brightest > range[color] * HOT_MARGIN;
+                    let dead = value * DEAD_FACTOR < darkest && darkest - value > range[color] * DEAD_MARGIN;
+                    if !values.is_empty() && (hot || dead) {
+                        repairs.push((row * width + col, median(values)));
+                    }
+                }
+            }
+        }
+        for &(x, y) in options.hot_photosites.iter().filter(|(x, y)| *x < width && *y < height) {
+            let mut buffer = [0.0; MAX_NEIGHBOURS];
+            let values = neighbour_values(data, width, height, y, x, &neighbours, &mut buffer);
+            if !values.is_empty() {
+                repairs.push((y * width + x, median(values)));
+            }
+        }
+
+        for (i, value) in repairs {
+            data[i] = value;
+        }
+
+        if let (Ok(()), Some(flat_field)) = (&result, &options.flat_field) {
+            result = flat_field.apply(data, width, height, black, &cfa);
+        }
+    });
+    result
+}
+
+/// Runs `f` on the samples of the raw image as floats and stores the result in the original format
+pub fn with_samples(raw: &mut rawloader::RawImage, f: impl FnOnce(&mut [f32])) {
+    match raw.data {
+        rawloader::RawImageData::Integer(ref mut data) => {
+            let mut samples: Vec<f32> = data.iter().map(|v| *v as f32).collect();
+            f(&mut samples);
+            for (value, sample) in data.iter_mut().zip(samples) {
+                *value = sample.round().clamp(0.0, u16::MAX as f32) as u16;
+            }
+        },
+        rawloader::RawImageData::Float(ref mut data) => f(data),
+    }
+}
+
+/// Color of a photosite, counting the second green of RGBG patterns as green
+///
+/// All photosites of monochrome sensors (without a valid pattern) have color 0.
+fn cfa_color(cfa: &rawloader::CFA, row: usize, col: usize) -> usize {
+    if !cfa.is_valid() {
+        return 0;
+    }
+    match cfa.color_at(row, col) {
+        3 => 1,
+        color => color,
+    }
+}
+
+/// Offsets of the photosites with the same color within `NEIGHBOUR_RADIUS`, for every position of the pattern
+fn neighbour_offsets(cfa: &rawloader::CFA) -> Vec<Vec<Vec<(i64, i64)>>> {
+    let (pattern_width, pattern_height) = if cfa.is_valid() { (cfa.width, cfa.height) } else { (1, 1) };
+    // shift by whole patterns, so rows and columns stay positive
+    let shift = |v: usize, size: usize, d: i64| (v as i64 + d + (size as i64) * NEIGHBOUR_RADIUS) as usize;
+
+    (0..pattern_height).map(|row| (0..pattern_width).map(|col| {
+        let color = if cfa.is_valid() { cfa_color(cfa, row, col) } else { 0 };
+        let mut offsets = Vec::new();
+        for dy in -NEIGHBOUR_RADIUS..=NEIGHBOUR_RADIUS {
+            for dx in -NEIGHBOUR_RADIUS..=NEIGHBOUR_RADIUS {
+                let same = !cfa.is_valid()
+                    || cfa_color(cfa, shift(row, pattern_height, dy), shift(col, pattern_width, dx)) == color;
+                if (dy, dx) != (0, 0) && same {
+                    offsets.push((dy, dx));
+                }
+            }
+        }
+        offsets
+    }).collect()).collect()
+}
+
+/// Collects the values of the same-colored neighbours of a photosite into `buffer`
+fn neighbour_values<'a>(data: &[f32], width: usize, height: usize, row: usize, col: usize,
+                        neighbours: &[Vec<Vec<(i64, i64)>>], buffer: &'a mut [f32; MAX_NEIGHBOURS]) -> &'a mut [f32] {
+    let offsets = &neighbours[row % neighbours.len()][col % neighbours[0].len()];
+    let mut count = 0;
+    for (dy, dx) in offsets {
+        let (r, c) = (row as i64 + dy, col as i64 + dx);
+        if r >= 0 && c >= 0 && (r as usize) < height && (c as usize) < width {
+            buffer[count] = data[r as usize * width + c as usize];
+            count += 1;
+        }
+    }
+    &mut buffer[..count]
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(f32::total_cmp);
+    values[values.len() / 2]
+}
@@ -0,0 +1,105 @@
+use crate::*;
+
+
+/// Radius of the window averaged when sampling the film base at a position
+const BASE_SAMPLE_RADIUS: usize = 4;
+/// Percentile of the brightest values taken as film base with `FilmBase::Auto`
+const AUTO_BASE_PERCENTILE: f32 = 0.998;
+/// Fraction of the darkest values of the negative whose density is clipped to white
+const WHITE_CLIP: f32 = 0.005;
+/// Contrast of the positive, in decades of linear output range
+const DENSITY_RANGE: f32 = 2.0;
+
+/// Where the color of the unexposed film (the orange mask of color negatives) is taken from
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FilmBase {
+    /// Estimate it from the brightest areas of the scan (the film rebate or the gaps between frames)
+    Auto,
+    /// Sample it at a position of the decoded image
+    At(usize, usize),
+}
+
+
+/// Parses `auto` or `x,y`
+pub fn parse_film_base(s: &str) -> Result<FilmBase, String> {
+    if s.eq_ignore_ascii_case("auto") {
+        return Ok(FilmBase::Auto);
+    }
+    let (x, y) = s.split_once(',')
+        .ok_or_else(|| format!("invalid film base {:?} (expected auto or x,y)", s))?;
+    let parse = |v: &str| v.trim().parse()
+        .map_err(|_| format!("invalid film base {:?} (expected auto or x,y)", s));
+    Ok(FilmBase::At(parse(x)?, parse(y)?))
+}
+
+/// Turns a camera scan of a color negative into a positive
+///
+/// Every channel is divided by the film base and converted to a density, which removes the orange mask.
+/// The densities are then scaled per channel, so the densest areas of all channels become white.
+pub fn invert_negative(image: &mut imagepipe::SRGBImage, base: FilmBase) {
+    let linear = pixelops::srgb8_to_linear_table();
+    let base = match base {
+        FilmBase::Auto => std::array::from_fn(|c| percentile(image.data.iter().skip(c).step_by(3).copied(), AUTO_BASE_PERCENTILE)),
+        FilmBase::At(x, y) => sample(image, x, y),
+    }.map(|v| linear[v as usize].max(1.0 / 255.0));
+
+    // densities of all 8 bit values per channel, relative to the film base
+    let densities: [[f32; 256]; 3] = std::array::from_fn(|c| std::array::from_fn(|v| {
+        (base[c] / linear[v].max(1.0 / 4096.0)).log10().max(0.0)
+    }));
+    let white: [f32; 3] = std::array::from_fn(|c| {
+        let densest = percentile(image.data.iter().skip(c).step_by(3).copied(), WHITE_CLIP);
+        densities[c][densest as usize].max(f32::EPSILON)
+    });
+
+    let scale = 10f32.powf(DENSITY_RANGE) - 1.0;
+    let luts: [[u8; 256]; 3] = std::array::from_fn(|c| std::array::from_fn(|v| {
+        let normalized = (densities[c][v] / white[c]).min(1.0);
+        let positive = (10f32.powf(normalized * DENSITY_RANGE) - 1.0) / scale;
+        (pixelops::linear_to_srgb(positive) * 255.0).round() as u8
+    }));
+
+    for pixel in image.data.chunks_exact_mut(3) {
+        for (value, lut) in pixel.iter_mut().zip(&luts) {
+            *value = lut[*value as usize];
+        }
+    }
+}
+
+/// Average color in a small window around `(x, y)`
+fn sample(image: &imagepipe::SRGBImage, x: usize, y: usize) -> [u8; 3] {
+    let x = x.min(image.width.saturating_sub(1));
+    let y = y.min(image.height.saturating_sub(1));
+    let mut sums = [0u32; 3];
+    let mut count = 0;
+    for sy in y.saturating_sub(BASE_SAMPLE_RADIUS)..(y + BASE_SAMPLE_RADIUS + 1).min(image.height) {
+        for sx in x.saturating_sub(BASE_SAMPLE_RADIUS)..(x + BASE_SAMPLE_RADIUS + 1).min(image.width) {
+            let i = (sy * image.width + sx) * 3;
+            for (sum, value) in sums.iter_mut().zip(&image.data[i..i + 3]) {
+                *sum += *value as u32;
+            }
+            count += 1;
+        }
+    }
+    sums.map(|s| (s / count.max(1)) as u8)
+}
+
+/// Value below which the given fraction of `values` lies
+fn percentile(values: impl Iterator<Item = u8>, fraction: f32) -> u8 {
+    let mut histogram = [0usize; 256];
+    let mut total = 0;
+    for value in values {
+        histogram[value as usize] += 1;
+        total += 1;
+    }
+
+    let target = (total as f32 * fraction) as usize;
+    let mut seen = 0;
+    for (value, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen > target {
+            return value as u8;
+        }
+    }
+    255
+}
@@ -0,0 +1,227 @@
+use crate::*;
+
+use ab_glyph::{Font, FontRef, PxScale, ScaleFont};
+
+/// Font used for annotations
+static FONT: &[u8] = include_bytes!("../assets/DejaVuSans.ttf");
+
+/// Distance of overlays from the image border relative to the shorter image side
+const MARGIN: f32 = 0.02;
+/// Height of annotation text relative to the shorter image side
+const TEXT_SIZE: f32 = 0.03;
+/// Opacity of the banner behind annotations
+const BANNER_OPACITY: f32 = 0.6;
+
+/// Where an overlay is placed on the image
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+/// Border and fixed canvas size of the outputs
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Canvas {
+    /// Width of a border around the image in pixels
+    pub border: usize,
+    /// Canvas size that images are scaled down to fit in and padded to
+    pub pad_to: Option<(usize, usize)>,
+    /// Color of the border and the padding
+    pub color: [u8; 3],
+}
+
+/// Logo or other image that is stamped onto every output
+#[derive(Clone)]
+pub struct Watermark {
+    image: image::RgbaImage,
+    pub position: OverlayPosition,
+    /// Opacity between 0 (invisible) and 1 (as stored in the watermark file)
+    pub opacity: f32,
+    /// Width of the watermark relative to the width of the image
+    pub scale: f32,
+}
+
+
+impl OverlayPosition {
+    /// Top left corner of an overlay of the given size on an image of the given size
+    pub fn place(&self, width: usize, height: usize, overlay_width: usize, overlay_height: usize) -> (usize, usize) {
+        let margin = (width.min(height) as f32 * MARGIN) as usize;
+        let left = margin;
+        let right = width.saturating_sub(overlay_width + margin);
+        let top = margin;
+        let bottom = height.saturating_sub(overlay_height + margin);
+        match self {
+            OverlayPosition::TopLeft => (left, top),
+            OverlayPosition::TopRight => (right, top),
+            OverlayPosition::BottomLeft => (left, bottom),
+            OverlayPosition::BottomRight => (right, bottom),
+            OverlayPosition::Center => (width.saturating_sub(overlay_width) / 2, height.saturating_sub(overlay_height) / 2),
+        }
+    }
+}
+
+impl Canvas {
+    /// Whether the canvas leaves images unchanged
+    pub fn is_identity(&self) -> bool {
+        self.border == 0 && self.pad_to.is_none()
+    }
+
+    /// Adds the border, then scales the image down to fit the canvas and centers it on the canvas
+    pub fn apply(&self, image: &mut imagepipe::SRGBImage) {
+        if self.border > 0 {
+            let (data, width, height) = pixelops::pad_rgb8(&image.data, image.width, image.height,
+                [self.border; 4], self.color);
+            *image = imagepipe::SRGBImage { data, width, height };
+        }
+
+        if let Some((canvas_width, canvas_height)) = self.pad_to {
+            if image.width > canvas_width || image.height > canvas_height {
+                let factor = (canvas_width as f64 / image.width as f64).min(canvas_height as f64 / image.height as f64);
+                let width = ((image.width as f64 * factor).round() as usize).clamp(1, canvas_width);
+                let height = ((image.height as f64 * factor).round() as usize).clamp(1, canvas_height);
+                let data = pixelops::resize_rgb8(&image.data, image.width, image.height, width, height);
+                *image = imagepipe::SRGBImage { data, width, height };
+            }
+
+            let (horizontal, vertical) = (canvas_width - image.width, canvas_height - image.height);
+            let padding = [horizontal / 2, vertical / 2, horizontal - horizontal / 2, vertical - vertical / 2];
+            let (data, width, height) = pixelops::pad_rgb8(&image.data, image.width, image.height, padding, self.color);
+            *image = imagepipe::SRGBImage { data, width, height };
+        }
+    }
+}
+
+/// Parses a canvas size like `1920x1080`
+pub fn parse_size(s: &str) -> Result<(usize, usize), String> {
+    let (width, height) = s.split_once(['x', 'X'])
+        .ok_or_else(|| format!("invalid size {:?} (expected WIDTHxHEIGHT)", s))?;
+    let parse = |v: &str| v.trim().parse::<usize>().ok().filter(|v| *v > 0)
+        .ok_or_else(|| format!("invalid size {:?} (expected WIDTHxHEIGHT)", s));
+    Ok((parse(width)?, parse(height)?))
+}
+
+/// Parses a color given as `#rrggbb` (the `#` is optional), `black` or `white`
+pub fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    match s.to_lowercase().as_str() {
+        "black" => return Ok([0, 0, 0]),
+        "white" => return Ok([255, 255, 255]),
+        _ => (),
+    }
+
+    let hex = s.trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("invalid color {:?} (expected #rrggbb, black or white)", s));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16)
+        .map_err(|_| format!("invalid color {:?} (expected #rrggbb, black or white)", s));
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+impl Watermark {
+    /// Reads the watermark image (any format supported by `image`, transparency is respected)
+    pub fn load(path: &Path, position: OverlayPosition, opacity: f32, scale: f32) -> Result<Watermark, String> {
+        let image = image::open(path)
+            .map_err(|e| format!("Unable to read watermark {:?} ({})", path, e))?
+            .to_rgba8();
+        Ok(Watermark { image, position, opacity: opacity.clamp(0.0, 1.0), scale: scale.clamp(0.0, 1.0) })
+    }
+
+    /// Composites the watermark onto the image
+    pub fn apply(&self, image: &mut imagepipe::SRGBImage) {
+        let width = ((image.width as f32 * self.scale).round() as u32).max(1);
+        let height = ((self.image.height() as u64 * width as u64 / self.image.width().max(1) as u64) as u32).max(1);
+        let scaled = image::imageops::resize(&self.image, width, height, image::imageops::FilterType::Triangle);
+        let (x, y) = self.position.place(image.width, image.height, width as usize, height as usize);
+        blend_rgba(image, &scaled, x, y, self.opacity);
+    }
+}
+
+/// Alpha blends `overlay` onto the image with its top left corner at `(x, y)`, clipping it at the image border
+pub fn blend_rgba(image: &mut imagepipe::SRGBImage, overlay: &image::RgbaImage, x: usize, y: usize, opacity: f32) {
+    for (ox, oy, pixel) in overlay.enumerate_pixels() {
+        let (px, py) = (x + ox as usize, y + oy as usize);
+        if px >= image.width || py >= image.height {
+            continue;
+        }
+
+        let alpha = pixel[3] as f32 / 255.0 * opacity;
+        let i = (py * image.width + px) * 3;
+        for (value, overlay_value) in image.data[i..i + 3].iter_mut().zip(&pixel.0[..3]) {
+            *value = (*value as f32 * (1.0 - alpha) + *overlay_value as f32 * alpha).round() as u8;
+        }
+    }
+}
+
+/// Renders `template` for the raw file `input` and draws it onto a banner along the bottom edge
+///
+/// See `format_annotation()` for the supported placeholders.
+pub fn annotate(image: &mut imagepipe::SRGBImage, input: &Path, template: &str) {
+    let text = format_annotation(template, input);
+    let font = FontRef::try_from_slice(FONT).expect("bundled font is valid");
+    let size = (image.width.min(image.height) as f32 * TEXT_SIZE).max(8.0);
+    let scaled = font.as_scaled(PxScale::from(size));
+
+    // banner with half a line of padding above and below the text
+    let banner_height = ((scaled.height() * 2.0).ceil() as usize).min(image.height);
+    let banner_top = image.height - banner_height;
+    let banner = image::RgbaImage::from_pixel(image.width as u32, banner_height as u32, image::Rgba([0, 0, 0, 255]));
+    blend_rgba(image, &banner, 0, banner_top, BANNER_OPACITY);
+
+    let mut caret = ab_glyph::point(size / 2.0, banner_top as f32 + size / 2.0 + scaled.ascent());
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            caret.x += scaled.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(scaled.scale(), caret);
+        caret.x += scaled.h_advance(id);
+        previous = Some(id);
+
+        let outline = match font.outline_glyph(glyph) {
+            Some(outline) => outline,
+            None => continue,
+        };
+        let bounds = outline.px_bounds();
+        outline.draw(|gx, gy, coverage| {
+            let (x, y) = (bounds.min.x as i64 + gx as i64, bounds.min.y as i64 + gy as i64);
+            if x < 0 || y < 0 || x as usize >= image.width || y as usize >= image.height {
+                return;
+            }
+            let i = (y as usize * image.width + x as usize) * 3;
+            for value in &mut image.data[i..i + 3] {
+                *value = (*value as f32 + (255.0 - *value as f32) * coverage.clamp(0.0, 1.0)).round() as u8;
+            }
+        });
+    }
+}
+
+/// Renders an annotation template
+///
+/// Supported placeholders: `{filename}`, `{date}`, `{exposure}` (shutter, aperture and ISO),
+/// `{camera}`, `{iso}`, `{shutter}`, `{aperture}`.
+pub fn format_annotation(template: &str, input: &Path) -> String {
+    let summary = ExifSummary::read(input);
+    let unknown = || String::from("?");
+    let filename = input.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(unknown);
+    let shutter = summary.shutter.clone();
+    let aperture = summary.aperture.map(|a| format!("f/{:.1}", a));
+    let iso = summary.iso.map(|i| format!("ISO {}", i));
+    let exposure: Vec<_> = [shutter, aperture, iso].into_iter().flatten().collect();
+    let exposure = if exposure.is_empty() { unknown() } else { exposure.join(" ") };
+
+    template
+        .replace("{filename}", &filename)
+        .replace("{date}", &summary.date.unwrap_or_else(unknown))
+        .replace("{exposure}", &exposure)
+        .replace("{camera}", &summary.camera.unwrap_or_else(unknown))
+        .replace("{iso}", &summary.iso.map(|i| i.to_string()).unwrap_or_else(unknown))
+        .replace("{shutter}", &summary.shutter.unwrap_or_else(unknown))
+        .replace("{aperture}", &summary.aperture.map(|a| format!("{:.1}", a)).unwrap_or_else(unknown))
+}
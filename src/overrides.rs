@@ -0,0 +1,190 @@
+use crate::*;
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+
+
+/// Name of the files inside the input tree that override settings for their subtree
+pub const OVERRIDE_FILE: &str = ".raw-to-img.toml";
+
+/// Suffix of the sidecars that override settings for a single file (e.g. `IMG_1234.ARW.rti.toml`)
+pub const SIDECAR_SUFFIX: &str = ".rti.toml";
+
+
+/// Resolves the effective settings for each file from `.raw-to-img.toml` files in the input tree
+///
+/// Override files apply to the directory they are in and all its subdirectories.
+/// Files deeper in the tree take precedence over those closer to the input base.
+/// Edits imported from raw editors (see `import_edits()`) come next, a sidecar next to a file
+/// (see `sidecar_path()`) takes precedence over all of them.
+pub struct OverrideResolver {
+    input_base: PathBuf,
+    cache: HashMap<PathBuf, Option<toml::Table>>,
+}
+
+
+impl OverrideResolver {
+    pub fn new(input_base: &Path) -> OverrideResolver {
+        OverrideResolver {
+            input_base: input_base.to_path_buf(),
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn resolve(&mut self, file: &Path, settings: &JobSettings) -> Result<JobSettings, String> {
+        let mut resolved = settings.clone();
+
+        if let Some(relative) = file.parent().and_then(|p| p.strip_prefix(&self.input_base).ok()) {
+            let mut dir = self.input_base.clone();
+            let mut dirs = vec![dir.clone()];
+            for component in relative.components() {
+                dir.push(component);
+                dirs.push(dir.clone());
+            }
+
+            for dir in dirs {
+                if let Some(table) = self.lookup(&dir)? {
+                    apply_overrides(&mut resolved, table)
+                        .map_err(|e| format!("{} (in {:?})", e, dir.join(OVERRIDE_FILE)))?;
+                }
+            }
+        }
+
+        if resolved.import_edits {
+            if let Some(adjustments) = import_edits(file)? {
+                resolved.adjustments = adjustments;
+            }
+        }
+
+        // sidecars are read every time, as each of them only applies to a single file
+        let sidecar = sidecar_path(file);
+        if sidecar.is_file() {
+            apply_overrides(&mut resolved, &read_table(&sidecar)?)
+                .map_err(|e| format!("{} (in {:?})", e, sidecar))?;
+        }
+
+        Ok(resolved)
+    }
+
+    fn lookup(&mut self, dir: &Path) -> Result<Option<&toml::Table>, String> {
+        if !self.cache.contains_key(dir) {
+            let path = dir.join(OVERRIDE_FILE);
+            let table = if path.is_file() {
+                Some(read_table(&path)?)
+            } else {
+                None
+            };
+            self.cache.insert(dir.to_path_buf(), table);
+        }
+
+        Ok(self.cache[dir].as_ref())
+    }
+}
+
+/// Sidecar with the overrides of `file` alone, named like the file followed by `.rti.toml`
+pub fn sidecar_path(file: &Path) -> PathBuf {
+    let mut name = file.file_name().unwrap_or_default().to_os_string();
+    name.push(SIDECAR_SUFFIX);
+    file.with_file_name(name)
+}
+
+/// Whether a file name belongs to a sidecar, which is read along with its file instead of being processed
+pub fn is_sidecar(name: &OsStr) -> bool {
+    name.as_encoded_bytes().ends_with(SIDECAR_SUFFIX.as_bytes())
+}
+
+fn read_table(path: &Path) -> Result<toml::Table, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {:?} ({})", path, e))?;
+    content.parse::<toml::Table>()
+        .map_err(|e| format!("Unable to parse {:?} ({})", path, e))
+}
+
+fn apply_overrides(settings: &mut JobSettings, table: &toml::Table) -> Result<(), String> {
+    let mut encode_type = None;
+
+    for (key, value) in table {
+        match key.as_str() {
+            "raws" => settings.on_raw = parse_enum(key, value)?,
+            "images" => settings.on_image = parse_enum(key, value)?,
+            "videos" => settings.on_video = parse_enum(key, value)?,
+            "files" => settings.on_file = parse_enum(key, value)?,
+            "existing" => settings.on_existing = parse_enum(key, value)?,
+            "decoder" => settings.decoder = parse_enum(key, value)?,
+            "encode_type" => encode_type = Some(parse_enum(key, value)?),
+            "jpeg_quality" => settings.jpeg_quality = value.as_integer()
+                .and_then(|q| u8::try_from(q).ok())
+                .ok_or_else(|| format!("Invalid value for \"{}\"", key))?,
+            "min_rating" => settings.min_rating = Some(value.as_integer()
+                .and_then(|r| i32::try_from(r).ok())
+                .ok_or_else(|| format!("Invalid value for \"{}\"", key))?),
+            "paranoid" => settings.paranoid = parse_bool(key, value)?,
+            "detect_by_content" => settings.detect_by_content = parse_bool(key, value)?,
+            "exposure" => settings.adjustments.exposure = parse_float(key, value)?,
+            "white_balance" => {
+                let multipliers = parse_floats(key, value)?;
+                settings.adjustments.white_balance = Some(multipliers.try_into()
+                    .map_err(|_| String::from("\"white_balance\" needs three values (red, green, blue)"))?);
+            },
+            "crop" => {
+                let crop = parse_sizes(key, value)?;
+                settings.adjustments.crop = Some(Crop::Margins(crop.try_into()
+                    .map_err(|_| String::from("\"crop\" needs four values (left, top, right, bottom)"))?));
+            },
+            "rotate" => settings.adjustments.rotation = match value.as_integer() {
+                Some(0) => pixelops::Rotation::None,
+                Some(90) => pixelops::Rotation::Cw90,
+                Some(180) => pixelops::Rotation::Cw180,
+                Some(270) => pixelops::Rotation::Cw270,
+                _ => return Err(format!("Invalid value for \"{}\" (expected 0, 90, 180 or 270)", key)),
+            },
+            _ => return Err(format!("Unknown override \"{}\"", key)),
+        }
+    }
+
+    if let Some(encode_type) = encode_type {
+        settings.encoder = EncoderType::new(encode_type, settings.jpeg_quality);
+    } else if let EncoderType::JpegEncoder(_) = settings.encoder {
+        settings.encoder = EncoderType::JpegEncoder(settings.jpeg_quality);
+    }
+
+    Ok(())
+}
+
+fn parse_enum<T: clap::ValueEnum>(key: &str, value: &toml::Value) -> Result<T, String> {
+    let string = value.as_str()
+        .ok_or_else(|| format!("Expected a string for \"{}\"", key))?;
+    T::from_str(string, true)
+        .map_err(|_| format!("Invalid value \"{}\" for \"{}\"", string, key))
+}
+
+fn parse_bool(key: &str, value: &toml::Value) -> Result<bool, String> {
+    value.as_bool()
+        .ok_or_else(|| format!("Expected a boolean for \"{}\"", key))
+}
+
+fn parse_float(key: &str, value: &toml::Value) -> Result<f32, String> {
+    match value {
+        toml::Value::Float(f) => Ok(*f as f32),
+        toml::Value::Integer(i) => Ok(*i as f32),
+        _ => Err(format!("Expected a number for \"{}\"", key)),
+    }
+}
+
+fn parse_floats(key: &str, value: &toml::Value) -> Result<Vec<f32>, String> {
+    value.as_array()
+        .ok_or_else(|| format!("Expected a list of numbers for \"{}\"", key))?
+        .iter()
+        .map(|v| parse_float(key, v))
+        .collect()
+}
+
+fn parse_sizes(key: &str, value: &toml::Value) -> Result<Vec<usize>, String> {
+    value.as_array()
+        .ok_or_else(|| format!("Expected a list of numbers for \"{}\"", key))?
+        .iter()
+        .map(|v| v.as_integer()
+            .and_then(|i| usize::try_from(i).ok())
+            .ok_or_else(|| format!("Invalid value for \"{}\"", key)))
+        .collect()
+}
@@ -0,0 +1,133 @@
+use crate::*;
+
+use std::ffi::{OsStr, OsString};
+
+
+/// Device names that Windows does not allow as file names, regardless of their extension
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul",
+    "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+
+/// Whether the extension of `path` is one of `extensions` (ignoring ASCII case)
+///
+/// Works on the raw bytes of the name, so files whose names are not valid UTF-8 are still recognized.
+pub fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    match path.extension() {
+        Some(extension) => extensions.iter()
+            .any(|e| extension.as_encoded_bytes().eq_ignore_ascii_case(e.as_bytes())),
+        None => false,
+    }
+}
+
+/// File stem of `path` followed by `suffix`, keeping names that are not valid UTF-8 intact
+pub fn suffixed_stem(path: &Path, suffix: &str) -> OsString {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    name
+}
+
+/// Replaces the `{placeholders}` of `template` with the given values without converting them to strings
+pub fn expand_template(template: &str, values: &[(&str, &OsStr)]) -> OsString {
+    let mut expanded = OsString::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let value = values.iter()
+            .find(|(placeholder, _)| rest[start..].starts_with(placeholder));
+        match value {
+            Some((placeholder, value)) => {
+                expanded.push(&rest[..start]);
+                expanded.push(value);
+                rest = &rest[start + placeholder.len()..];
+            },
+            None => {
+                expanded.push(&rest[..=start]);
+                rest = &rest[start + 1..];
+            },
+        }
+    }
+    expanded.push(rest);
+    expanded
+}
+
+/// Whether Windows refuses `name` as a file name (`CON`, `aux.jpg`, `name.` ...)
+pub fn is_reserved_name(name: &OsStr) -> bool {
+    let bytes = name.as_encoded_bytes();
+    let stem = bytes.split(|b| *b == b'.').next().unwrap_or_default();
+    let stem = stem.trim_ascii_end();
+    RESERVED_NAMES.iter().any(|r| stem.eq_ignore_ascii_case(r.as_bytes()))
+        || bytes.ends_with(b".") || bytes.ends_with(b" ")
+}
+
+/// `name` changed so Windows accepts it, by appending `_` to reserved device names and
+/// replacing trailing dots and spaces
+pub fn portable_name(name: &OsStr) -> OsString {
+    if !is_reserved_name(name) {
+        return name.to_os_string();
+    }
+
+    let bytes = name.as_encoded_bytes();
+    let trimmed = bytes.trim_ascii_end().strip_suffix(b".").unwrap_or(bytes.trim_ascii_end());
+    let (stem, rest) = match trimmed.iter().position(|b| *b == b'.') {
+        Some(dot) => trimmed.split_at(dot),
+        None => (trimmed, &b""[..]),
+    };
+    let mut portable = Vec::with_capacity(bytes.len() + 1);
+    portable.extend_from_slice(stem);
+    portable.push(b'_');
+    portable.extend_from_slice(rest);
+    // only ASCII bytes were removed or added, so the result is still valid for the platform
+    unsafe { OsString::from_encoded_bytes_unchecked(portable) }
+}
+
+/// `relative` with every component made acceptable for the platform (see `portable_name()`)
+///
+/// Only changes anything on Windows, other platforms accept all names the input tree may contain.
+pub fn portable_path(relative: &Path) -> PathBuf {
+    if !cfg!(windows) {
+        return relative.to_path_buf();
+    }
+    relative.components()
+        .map(|c| match c {
+            Component::Normal(name) => portable_name(name),
+            other => other.as_os_str().to_os_string(),
+        })
+        .collect()
+}
+
+/// `path` in a form whose descendants may exceed the classic 260 character limit on Windows
+///
+/// On Windows, the path is made absolute and prefixed with `\\?\` (or `\\?\UNC\` for network
+/// shares), other platforms have no such limit and get the path unchanged.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> PathBuf {
+    let text = path.as_os_str().as_encoded_bytes();
+    if text.starts_with(br"\\?\") {
+        return path.to_path_buf();
+    }
+    let absolute = match std::path::absolute(path) {
+        Ok(absolute) => absolute,
+        Err(_) => return path.to_path_buf(),
+    };
+
+    let mut prefixed = OsString::new();
+    match absolute.as_os_str().as_encoded_bytes().strip_prefix(br"\\") {
+        Some(share) => {
+            prefixed.push(r"\\?\UNC\");
+            // the prefix is ASCII, so the rest is still valid for the platform
+            prefixed.push(unsafe { OsStr::from_encoded_bytes_unchecked(share) });
+        },
+        None => {
+            prefixed.push(r"\\?\");
+            prefixed.push(absolute.as_os_str());
+        },
+    }
+    PathBuf::from(prefixed)
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
@@ -0,0 +1,348 @@
+//! Pixel operations applied after demosaicing
+//!
+//! The sRGB conversion and the resize have a vectorized path that is selected at runtime if the CPU
+//! supports it, and a portable scalar fallback producing the same results. The other operations are
+//! scalar, rotations are bound by memory accesses and only work in cache friendly tiles.
+
+use std::sync::OnceLock;
+
+
+/// Resolution of the linear to sRGB lookup table
+const LUT_SIZE: usize = 1 << 14;
+
+static SRGB_LUT: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Quarter turns for `rotate_rgb8`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    None, Cw90, Cw180, Cw270,
+}
+
+
+fn srgb_lut() -> &'static [u8] {
+    SRGB_LUT.get_or_init(|| (0..LUT_SIZE)
+        .map(|i| (linear_to_srgb(i as f32 / (LUT_SIZE - 1) as f32) * 255.0).round() as u8)
+        .collect())
+}
+
+/// Applies the sRGB gamma curve to a linear value in `0..=1`
+pub fn linear_to_srgb(v: f32) -> f32 {
+    let v = v.clamp(0.0, 1.0);
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn has_avx2() -> bool {
+    is_x86_feature_detected!("avx2")
+}
+
+/// Converts interleaved linear RGB values into 8 bit sRGB
+///
+/// Each channel is normalized as `(v - black[c]) * scale[c]` and clamped to `0..=1` before the
+/// gamma curve is applied.
+pub fn linear_to_srgb8(data: &[f32], black: [f32; 3], scale: [f32; 3]) -> Vec<u8> {
+    let mut indices = vec![0u16; data.len()];
+
+    #[cfg(target_arch = "x86_64")]
+    let done = if has_avx2() {
+        unsafe { normalize_avx2(data, black, scale, &mut indices) }
+    } else {
+        0
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let done = 0;
+
+    normalize_scalar(data, black, scale, &mut indices, done);
+
+    let lut = srgb_lut();
+    indices.iter().map(|i| lut[*i as usize]).collect()
+}
+
+fn normalize_scalar(data: &[f32], black: [f32; 3], scale: [f32; 3], out: &mut [u16], start: usize) {
+    // same operations in the same order as the vectorized path, so both produce the same indices
+    let max = (LUT_SIZE - 1) as f32;
+    let scale = scale.map(|s| s * max);
+    for (i, (value, index)) in data.iter().zip(out.iter_mut()).enumerate().skip(start) {
+        let c = i % 3;
+        *index = ((value - black[c]) * scale[c]).clamp(0.0, max).round() as u16;
+    }
+}
+
+/// Normalizes all complete blocks of 8 pixels and returns the number of values processed
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn normalize_avx2(data: &[f32], black: [f32; 3], scale: [f32; 3], out: &mut [u16]) -> usize {
+    use std::arch::x86_64::*;
+
+    // 24 values (8 pixels) span three vectors, each with its own repeating channel layout
+    let max = (LUT_SIZE - 1) as f32;
+    let lanes = |j: usize, values: [f32; 3]| {
+        let lanes: [f32; 8] = std::array::from_fn(|lane| values[(j * 8 + lane) % 3]);
+        _mm256_loadu_ps(lanes.as_ptr())
+    };
+    let blacks = [lanes(0, black), lanes(1, black), lanes(2, black)];
+    let scale = scale.map(|s| s * max);
+    let scales = [lanes(0, scale), lanes(1, scale), lanes(2, scale)];
+
+    let zero = _mm256_setzero_ps();
+    let half = _mm256_set1_ps(0.5);
+    let one = _mm256_set1_ps(1.0);
+    let upper = _mm256_set1_ps(max);
+    let blocks = data.len() / 24;
+    let mut tmp = [0i32; 8];
+    for i in 0..blocks {
+        for (j, (black, scale)) in blacks.iter().zip(scales.iter()).enumerate() {
+            let offset = i * 24 + j * 8;
+            let v = _mm256_loadu_ps(data.as_ptr().add(offset));
+            let n = _mm256_mul_ps(_mm256_sub_ps(v, *black), *scale);
+            let n = _mm256_min_ps(_mm256_max_ps(n, zero), upper);
+            // round half away from zero like `f32::round` (the conversion rounds half to even), n is
+            // not negative and its fractional part is exact
+            let whole = _mm256_round_ps::<{ _MM_FROUND_TO_ZERO | _MM_FROUND_NO_EXC }>(n);
+            let up = _mm256_and_ps(_mm256_cmp_ps::<_CMP_GE_OQ>(_mm256_sub_ps(n, whole), half), one);
+            let n = _mm256_add_ps(whole, up);
+            _mm256_storeu_si256(tmp.as_mut_ptr() as *mut __m256i, _mm256_cvttps_epi32(n));
+            for (index, value) in out[offset..offset + 8].iter_mut().zip(tmp) {
+                *index = value as u16;
+            }
+        }
+    }
+
+    blocks * 24
+}
+
+/// Downscales an interleaved RGB image with a box filter
+pub fn resize_rgb8(data: &[u8], width: usize, height: usize, new_width: usize, new_height: usize) -> Vec<u8> {
+    #[cfg(target_arch = "x86_64")]
+    let simd = has_avx2();
+    #[cfg(not(target_arch = "x86_64"))]
+    let simd = false;
+
+    let mut out = vec![0u8; new_width * new_height * 3];
+    let mut column_sums = vec![0.0f32; width * 3];
+
+    // horizontal source range for every target column
+    let spans: Vec<(usize, usize)> = (0..new_width)
+        .map(|x| (x * width / new_width, ((x + 1) * width / new_width).max(x * width / new_width + 1).min(width)))
+        .collect();
+
+    for y in 0..new_height {
+        let y_start = y * height / new_height;
+        let y_end = ((y + 1) * height / new_height).max(y_start + 1).min(height);
+
+        // sum up the source rows first, this touches every source value once and is vectorized
+        column_sums.iter_mut().for_each(|s| *s = 0.0);
+        for src_y in y_start..y_end {
+            add_row(&mut column_sums, &data[src_y * width * 3..(src_y + 1) * width * 3], simd);
+        }
+
+        let out_row = &mut out[y * new_width * 3..(y + 1) * new_width * 3];
+        for (pixel, &(x_start, x_end)) in out_row.chunks_exact_mut(3).zip(&spans) {
+            let mut sums = [0.0f32; 3];
+            for column in column_sums[x_start * 3..x_end * 3].chunks_exact(3) {
+                sums[0] += column[0];
+                sums[1] += column[1];
+                sums[2] += column[2];
+            }
+            let count = (x_end.saturating_sub(x_start) * y_end.saturating_sub(y_start)).max(1) as f32;
+            for (value, sum) in pixel.iter_mut().zip(sums) {
+                *value = (sum / count).round() as u8;
+            }
+        }
+    }
+
+    out
+}
+
+/// Adds 8 bit values to `sums`
+fn add_row(sums: &mut [f32], row: &[u8], simd: bool) {
+    #[cfg(target_arch = "x86_64")]
+    let done = if simd {
+        unsafe { add_row_avx2(sums, row) }
+    } else {
+        0
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let done = {
+        let _ = simd;
+        0
+    };
+
+    add_row_scalar(sums, row, done);
+}
+
+fn add_row_scalar(sums: &mut [f32], row: &[u8], start: usize) {
+    for (sum, value) in sums.iter_mut().zip(row).skip(start) {
+        *sum += *value as f32;
+    }
+}
+
+/// Adds all complete blocks of 8 values and returns the number of values processed
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn add_row_avx2(sums: &mut [f32], row: &[u8]) -> usize {
+    use std::arch::x86_64::*;
+
+    let blocks = sums.len().min(row.len()) / 8;
+    for i in 0..blocks {
+        let bytes = _mm_loadl_epi64(row.as_ptr().add(i * 8) as *const __m128i);
+        let values = _mm256_cvtepi32_ps(_mm256_cvtepu8_epi32(bytes));
+        let sum = sums.as_mut_ptr().add(i * 8);
+        _mm256_storeu_ps(sum, _mm256_add_ps(_mm256_loadu_ps(sum), values));
+    }
+
+    blocks * 8
+}
+
+/// Rotates an interleaved RGB image (8 bit or float) clockwise, returning the new buffer, width and height
+pub fn rotate_rgb8<T: Copy + Default>(data: &[T], width: usize, height: usize, rotation: Rotation) -> (Vec<T>, usize, usize) {
+    const TILE: usize = 64;
+
+    let (new_width, new_height) = match rotation {
+        Rotation::None | Rotation::Cw180 => (width, height),
+        Rotation::Cw90 | Rotation::Cw270 => (height, width),
+    };
+    if rotation == Rotation::None {
+        return (data.to_vec(), width, height);
+    }
+
+    // work in tiles to keep both source and destination accesses cache friendly
+    let mut out = vec![T::default(); data.len()];
+    for tile_y in (0..height).step_by(TILE) {
+        for tile_x in (0..width).step_by(TILE) {
+            for y in tile_y..(tile_y + TILE).min(height) {
+                for x in tile_x..(tile_x + TILE).min(width) {
+                    let (nx, ny) = match rotation {
+                        Rotation::Cw90 => (height - 1 - y, x),
+                        Rotation::Cw180 => (width - 1 - x, height - 1 - y),
+                        Rotation::Cw270 => (y, width - 1 - x),
+                        Rotation::None => (x, y),
+                    };
+                    let src = (y * width + x) * 3;
+                    let dst = (ny * new_width + nx) * 3;
+                    out[dst..dst + 3].copy_from_slice(&data[src..src + 3]);
+                }
+            }
+        }
+    }
+
+    (out, new_width, new_height)
+}
+
+/// Removes `left`, `top`, `right` and `bottom` pixels from the edges of an interleaved RGB image (8 bit or float)
+///
+/// Returns the new buffer, width and height. Crops larger than the image leave a single pixel.
+pub fn crop_rgb8<T: Copy>(data: &[T], width: usize, height: usize, crop: [usize; 4]) -> (Vec<T>, usize, usize) {
+    let [left, top, right, bottom] = crop;
+    let left = left.min(width.saturating_sub(1));
+    let top = top.min(height.saturating_sub(1));
+    let new_width = width.saturating_sub(left + right).max(1);
+    let new_height = height.saturating_sub(top + bottom).max(1);
+
+    let mut out = Vec::with_capacity(new_width * new_height * 3);
+    for y in top..top + new_height {
+        let start = (y * width + left) * 3;
+        out.extend_from_slice(&data[start..start + new_width * 3]);
+    }
+
+    (out, new_width, new_height)
+}
+
+/// Adds `left`, `top`, `right` and `bottom` pixels of `color` around an interleaved RGB image
+///
+/// Returns the new buffer, width and height.
+pub fn pad_rgb8(data: &[u8], width: usize, height: usize, padding: [usize; 4], color: [u8; 3]) -> (Vec<u8>, usize, usize) {
+    let [left, top, right, bottom] = padding;
+    let new_width = width + left + right;
+    let new_height = height + top + bottom;
+
+    let mut out: Vec<u8> = color.iter().copied().cycle().take(new_width * new_height * 3).collect();
+    for y in 0..height {
+        let start = ((y + top) * new_width + left) * 3;
+        out[start..start + width * 3].copy_from_slice(&data[y * width * 3..(y + 1) * width * 3]);
+    }
+
+    (out, new_width, new_height)
+}
+
+/// Linear value of every 8 bit sRGB value
+pub fn srgb8_to_linear_table() -> [f32; 256] {
+    std::array::from_fn(|i| {
+        let v = i as f32 / 255.0;
+        if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    })
+}
+
+/// Blends a gamma encoded value towards (`strength > 0`) or away from (`strength < 0`) a smoothstep curve
+pub fn s_curve(v: f32, strength: f32) -> f32 {
+    let smooth = v * v * (3.0 - 2.0 * v);
+    (v + strength * (smooth - v)).clamp(0.0, 1.0)
+}
+
+/// Builds a lookup table applying `curve` to linear values of 8 bit sRGB data
+pub fn linear_curve_lut(curve: impl Fn(f32) -> f32) -> [u8; 256] {
+    let linear = srgb8_to_linear_table();
+    let lut = srgb_lut();
+    std::array::from_fn(|i| {
+        let v = curve(linear[i]).clamp(0.0, 1.0);
+        lut[(v * (LUT_SIZE - 1) as f32).round() as usize]
+    })
+}
+
+/// Maps every channel value through `lut`
+pub fn apply_lut_rgb8(data: &mut [u8], lut: &[u8; 256]) {
+    for value in data.iter_mut() {
+        *value = lut[*value as usize];
+    }
+}
+
+/// Replaces the given pixels by the average of their direct neighbours that are not listed as well
+pub fn repair_pixels_rgb8(data: &mut [u8], width: usize, height: usize, pixels: &[(usize, usize)]) {
+    let defective: std::collections::HashSet<_> = pixels.iter().copied().collect();
+    for &(x, y) in pixels {
+        if x >= width || y >= height {
+            continue;
+        }
+
+        let mut sums = [0u32; 3];
+        let mut count = 0;
+        for (dx, dy) in [(-1i64, 0i64), (1, 0), (0, -1), (0, 1)] {
+            let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+            if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64
+                || defective.contains(&(nx as usize, ny as usize)) {
+                continue;
+            }
+            let i = (ny as usize * width + nx as usize) * 3;
+            for (sum, value) in sums.iter_mut().zip(&data[i..i + 3]) {
+                *sum += *value as u32;
+            }
+            count += 1;
+        }
+
+        if count > 0 {
+            let i = (y * width + x) * 3;
+            for (value, sum) in data[i..i + 3].iter_mut().zip(sums) {
+                *value = (sum / count) as u8;
+            }
+        }
+    }
+}
+
+/// Whether all pixels of an interleaved RGB image are neutral gray
+pub fn is_gray_rgb8(data: &[u8]) -> bool {
+    data.chunks_exact(3).all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2])
+}
+
+/// Keeps one channel of a neutral gray interleaved RGB image
+pub fn gray_from_rgb8(data: &[u8]) -> Vec<u8> {
+    data.iter().step_by(3).copied().collect()
+}
@@ -0,0 +1,171 @@
+use crate::*;
+
+use std::env;
+
+
+const SYSTEM_PRESETS: &str = "/etc/raw-to-img/presets.toml";
+const USER_PRESETS: &str = "raw-to-img/presets.toml";
+
+
+/// Processing tweaks for a camera model
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Preset {
+    /// Pixels removed from the left, top, right and bottom edge
+    pub crop: Option<[usize; 4]>,
+    /// Known hot pixels as `(x, y)` in output image coordinates (before cropping)
+    pub hot_pixels: Vec<(usize, usize)>,
+    /// Known hot photosites as `(x, y)` in sensor coordinates, repaired before demosaicing
+    pub hot_photosites: Vec<(usize, usize)>,
+    /// Exposure compensation in EV applied to every image
+    pub baseline_exposure: f32,
+    /// Lens profile to use when the lens can not be identified from the EXIF data
+    pub lens_profile: Option<String>,
+}
+
+/// Presets keyed by camera model
+#[derive(Clone, Debug, Default)]
+pub struct Presets {
+    models: Vec<(String, Preset)>,
+}
+
+
+impl Presets {
+    /// Reads the presets from the system and user presets files, later files override earlier ones
+    pub fn load() -> Result<Presets, String> {
+        let mut files = vec![PathBuf::from(SYSTEM_PRESETS)];
+        let user_config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+            _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+        };
+        if let Some(dir) = user_config_dir {
+            files.push(dir.join(USER_PRESETS));
+        }
+
+        let mut presets = Presets::default();
+        for file in files.iter().filter(|f| f.is_file()) {
+            presets.extend(Presets::read(file)?);
+        }
+        Ok(presets)
+    }
+
+    /// Reads a presets file with one table per camera model
+    ///
+    /// ```toml
+    /// ["X-T3"]
+    /// crop = [8, 8, 8, 8]
+    /// hot_pixels = [[1204, 877]]
+    /// hot_photosites = [[2410, 1760]]
+    /// baseline_exposure = 0.3
+    /// lens_profile = "Fujifilm XF 18-55mm"
+    /// ```
+    pub fn read(path: &Path) -> Result<Presets, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {:?} ({})", path, e))?;
+        let table: toml::Table = content.parse()
+            .map_err(|e| format!("Unable to parse {:?} ({})", path, e))?;
+
+        let mut models = Vec::new();
+        for (model, value) in table {
+            let preset_table = value.as_table()
+                .ok_or_else(|| format!("Preset \"{}\" in {:?} is not a table", model, path))?;
+            let preset = parse_preset(preset_table)
+                .map_err(|e| format!("{} (in preset \"{}\" of {:?})", e, model, path))?;
+            models.push((model, preset));
+        }
+        Ok(Presets { models })
+    }
+
+    /// Adds the presets of `other`, replacing presets for the same models
+    pub fn extend(&mut self, other: Presets) {
+        for (model, preset) in other.models {
+            self.models.retain(|(m, _)| !m.eq_ignore_ascii_case(&model));
+            self.models.push((model, preset));
+        }
+    }
+
+    /// Preset for a camera model (compared case-insensitively)
+    pub fn for_model(&self, model: &str) -> Option<&Preset> {
+        self.models.iter()
+            .find(|(m, _)| m.eq_ignore_ascii_case(model.trim()))
+            .map(|(_, preset)| preset)
+    }
+
+    /// Preset for the camera a raw file was taken with
+    pub fn for_file(&self, path: &Path) -> Option<&Preset> {
+        if self.models.is_empty() {
+            return None;
+        }
+        self.for_model(&ExifSummary::read(path).camera?)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+}
+
+impl Preset {
+    /// Repairs hot pixels and applies the baseline exposure of the preset
+    pub fn apply(&self, image: &mut imagepipe::SRGBImage) {
+        if !self.hot_pixels.is_empty() {
+            pixelops::repair_pixels_rgb8(&mut image.data, image.width, image.height, &self.hot_pixels);
+        }
+        if self.baseline_exposure != 0.0 {
+            let gain = 2f32.powf(self.baseline_exposure);
+            pixelops::apply_lut_rgb8(&mut image.data, &pixelops::linear_curve_lut(|v| v * gain));
+        }
+    }
+
+    /// Crops the image if the preset defines a crop
+    pub fn crop(&self, image: &mut imagepipe::SRGBImage) {
+        if let Some(crop) = self.crop {
+            let (data, width, height) = pixelops::crop_rgb8(&image.data, image.width, image.height, crop);
+            *image = imagepipe::SRGBImage { data, width, height };
+        }
+    }
+}
+
+fn parse_preset(table: &toml::Table) -> Result<Preset, String> {
+    let mut preset = Preset::default();
+    for (key, value) in table {
+        match key.as_str() {
+            "crop" => {
+                let crop = parse_numbers(key, value)?;
+                preset.crop = Some(crop.try_into()
+                    .map_err(|_| String::from("\"crop\" needs four values (left, top, right, bottom)"))?);
+            },
+            "hot_pixels" => preset.hot_pixels = parse_positions(key, value)?,
+            "hot_photosites" => preset.hot_photosites = parse_positions(key, value)?,
+            "baseline_exposure" => preset.baseline_exposure = match value {
+                toml::Value::Float(f) => *f as f32,
+                toml::Value::Integer(i) => *i as f32,
+                _ => return Err(String::from("\"baseline_exposure\" must be a number")),
+            },
+            "lens_profile" => preset.lens_profile = Some(value.as_str()
+                .ok_or_else(|| String::from("\"lens_profile\" must be a string"))?
+                .to_string()),
+            _ => return Err(format!("Unsupported preset key \"{}\"", key)),
+        }
+    }
+    Ok(preset)
+}
+
+fn parse_positions(key: &str, value: &toml::Value) -> Result<Vec<(usize, usize)>, String> {
+    value.as_array()
+        .ok_or_else(|| format!("\"{}\" must be a list of [x, y] pairs", key))?
+        .iter()
+        .map(|pixel| match parse_numbers(key, pixel)?.as_slice() {
+            [x, y] => Ok((*x, *y)),
+            _ => Err(format!("\"{}\" must be a list of [x, y] pairs", key)),
+        })
+        .collect()
+}
+
+fn parse_numbers(key: &str, value: &toml::Value) -> Result<Vec<usize>, String> {
+    value.as_array()
+        .ok_or_else(|| format!("\"{}\" must be a list of numbers", key))?
+        .iter()
+        .map(|v| v.as_integer()
+            .and_then(|i| usize::try_from(i).ok())
+            .ok_or_else(|| format!("\"{}\" must be a list of non-negative numbers", key)))
+        .collect()
+}
@@ -0,0 +1,40 @@
+use crate::*;
+
+
+/// Smallest plausible file size per decoded pixel, raws below it are considered truncated
+///
+/// Even heavily compressed raws take up several times as much, while a raw that was cut off
+/// while copying it from the card usually falls far below.
+const MIN_BYTES_PER_PIXEL: f64 = 0.1;
+
+
+/// Checks that the size of the raw `path` fits the size of its decoded `image`
+///
+/// Some decoders fill the missing data of truncated raws with black instead of failing, so raws that
+/// are much smaller than their number of pixels suggests are reported as corrupt.
+pub fn check_raw_size(path: &Path, image: &imagepipe::SRGBImage) -> Result<(), String> {
+    let Ok(metadata) = path.metadata() else {
+        return Ok(());
+    };
+    let pixels = image.width * image.height;
+    if (metadata.len() as f64) < pixels as f64 * MIN_BYTES_PER_PIXEL {
+        return Err(format!("only {} for {}x{} pixels, the file is probably truncated",
+            fmt_bytes(metadata.len()), image.width, image.height));
+    }
+    Ok(())
+}
+
+/// Puts the corrupt raw `input` into quarantine at `path` (hardlinked if possible)
+///
+/// The input itself is left in place, so it can still be compared against the original on the card.
+pub fn quarantine_raw(input: &Path, path: &Path) -> Result<(), Error> {
+    if path.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+    }
+    link_or_copy(input, path)?;
+    println!("Quarantined {:?} as {:?}", input, path);
+    Ok(())
+}
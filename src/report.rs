@@ -0,0 +1,142 @@
+use crate::*;
+
+use std::io::Write;
+
+
+/// Channel values at or above this count as clipped highlights
+const HIGHLIGHT_CLIP: u8 = 254;
+/// Channel values at or below this count as clipped shadows
+const SHADOW_CLIP: u8 = 1;
+/// Relative difference between the channel averages from which on an image counts as tinted
+const CAST_THRESHOLD: f32 = 0.08;
+
+/// Brightness and color statistics of a decoded image
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageAnalysis {
+    /// Mean luminance of the gamma encoded image between 0 and 1
+    pub mean_luminance: f32,
+    /// Percentage of pixels with at least one clipped channel
+    pub clipped_highlights: f32,
+    /// Percentage of pixels with all channels at black
+    pub clipped_shadows: f32,
+    /// Dominant color cast (`neutral`, `warm`, `cool`, `green` or `magenta`)
+    pub white_balance: &'static str,
+}
+
+/// Outcome of a single file for the report written with `--report`
+#[derive(Clone, Debug)]
+pub struct ReportEntry {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// `ok`, `error`, `ignored`, `duplicate`, `corrupt` or `partial` (salvaged from a corrupt raw)
+    pub status: &'static str,
+    pub analysis: Option<ImageAnalysis>,
+    /// Input that a `duplicate` was skipped in favor of (its output is the one of that input)
+    pub duplicate_of: Option<PathBuf>,
+}
+
+
+impl ImageAnalysis {
+    pub fn print(&self, input: &Path) {
+        println!("Analyzed {:?}: mean luminance {:.2}, {:.2}% clipped highlights, {:.2}% clipped shadows, {} white balance",
+            input, self.mean_luminance, self.clipped_highlights, self.clipped_shadows, self.white_balance);
+    }
+}
+
+/// Computes the luminance, clipping and white balance statistics of an image
+pub fn analyze_image(image: &imagepipe::SRGBImage) -> ImageAnalysis {
+    let mut luminance = 0.0f64;
+    let mut sums = [0.0f64; 3];
+    let mut highlights = 0usize;
+    let mut shadows = 0usize;
+
+    for pixel in image.data.chunks_exact(3) {
+        luminance += 0.2126 * pixel[0] as f64 + 0.7152 * pixel[1] as f64 + 0.0722 * pixel[2] as f64;
+        for (sum, value) in sums.iter_mut().zip(pixel) {
+            *sum += *value as f64;
+        }
+        if pixel.iter().any(|v| *v >= HIGHLIGHT_CLIP) {
+            highlights += 1;
+        }
+        if pixel.iter().all(|v| *v <= SHADOW_CLIP) {
+            shadows += 1;
+        }
+    }
+
+    let pixels = (image.data.len() / 3).max(1) as f64;
+    ImageAnalysis {
+        mean_luminance: (luminance / pixels / 255.0) as f32,
+        clipped_highlights: (highlights as f64 / pixels * 100.0) as f32,
+        clipped_shadows: (shadows as f64 / pixels * 100.0) as f32,
+        white_balance: color_cast(sums),
+    }
+}
+
+/// Classifies the dominant color cast from the channel sums
+fn color_cast([r, g, b]: [f64; 3]) -> &'static str {
+    let mean = ((r + g + b) / 3.0).max(1.0);
+    let warmth = ((r - b) / mean) as f32;
+    let tint = ((g - (r + b) / 2.0) / mean) as f32;
+
+    if warmth.abs() < CAST_THRESHOLD && tint.abs() < CAST_THRESHOLD {
+        "neutral"
+    } else if warmth.abs() >= tint.abs() {
+        if warmth > 0.0 { "warm" } else { "cool" }
+    } else if tint > 0.0 {
+        "green"
+    } else {
+        "magenta"
+    }
+}
+
+/// Writes the report as CSV if `path` ends in `.csv` and as JSON otherwise
+pub fn write_report(path: &Path, entries: &[ReportEntry]) -> Result<(), String> {
+    let csv = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("csv"));
+    let temp = temp_path(path);
+    let mut out = io::BufWriter::new(fs::File::create(&temp).map_err(|e| e.to_string())?);
+
+    if csv {
+        writeln!(out, "input,output,status,mean_luminance,clipped_highlights,clipped_shadows,white_balance,duplicate_of")
+            .map_err(|e| e.to_string())?;
+        for entry in entries {
+            let analysis = match &entry.analysis {
+                Some(a) => format!("{:.4},{:.4},{:.4},{}", a.mean_luminance, a.clipped_highlights,
+                    a.clipped_shadows, a.white_balance),
+                None => String::from(",,,"),
+            };
+            let duplicate_of = entry.duplicate_of.as_deref().map(|p| csv_escape(&p.to_string_lossy())).unwrap_or_default();
+            writeln!(out, "{},{},{},{},{}", csv_escape(&entry.input.to_string_lossy()),
+                csv_escape(&entry.output.to_string_lossy()), entry.status, analysis, duplicate_of)
+                .map_err(|e| e.to_string())?;
+        }
+    } else {
+        let items: Vec<_> = entries.iter().map(|entry| {
+            let analysis = match &entry.analysis {
+                Some(a) => format!(",\"analysis\":{{\"mean_luminance\":{:.4},\"clipped_highlights\":{:.4},\
+                                   \"clipped_shadows\":{:.4},\"white_balance\":\"{}\"}}",
+                    a.mean_luminance, a.clipped_highlights, a.clipped_shadows, a.white_balance),
+                None => String::new(),
+            };
+            let duplicate_of = match &entry.duplicate_of {
+                Some(original) => format!(",\"duplicate_of\":\"{}\"", json_escape(&original.to_string_lossy())),
+                None => String::new(),
+            };
+            format!("{{\"input\":\"{}\",\"output\":\"{}\",\"status\":\"{}\"{}{}}}",
+                json_escape(&entry.input.to_string_lossy()), json_escape(&entry.output.to_string_lossy()),
+                entry.status, analysis, duplicate_of)
+        }).collect();
+        writeln!(out, "[\n  {}\n]", items.join(",\n  ")).map_err(|e| e.to_string())?;
+    }
+
+    out.flush().map_err(|e| e.to_string())?;
+    drop(out);
+    fs::rename(&temp, path).map_err(|e| e.to_string())
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
@@ -0,0 +1,50 @@
+use crate::*;
+
+
+/// Directories below the output base that the files of each kind are put into
+///
+/// Files keep their path relative to the input base inside these directories.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KindRoutes {
+    /// Converted (or copied) raws
+    pub raw: Option<PathBuf>,
+    pub image: Option<PathBuf>,
+    pub video: Option<PathBuf>,
+    pub other: Option<PathBuf>,
+}
+
+
+impl KindRoutes {
+    /// Directory for files of the given kind, `None` if they are put directly into the output base
+    pub fn get(&self, kind: FileKind) -> Option<&Path> {
+        match kind {
+            FileKind::Raw => self.raw.as_deref(),
+            FileKind::Image => self.image.as_deref(),
+            FileKind::Video => self.video.as_deref(),
+            FileKind::Other => self.other.as_deref(),
+        }
+    }
+}
+
+/// Parses routes like `raw=raws/,image=jpeg/,video=video/,other=misc/`
+pub fn parse_routes(s: &str) -> Result<KindRoutes, String> {
+    let mut routes = KindRoutes::default();
+    for route in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let (kind, dir) = route.split_once('=')
+            .ok_or_else(|| format!("invalid route {:?} (expected KIND=DIR)", route))?;
+        let dir = PathBuf::from(dir.trim());
+        if dir.as_os_str().is_empty() || dir.is_absolute()
+                || dir.components().any(|c| matches!(c, Component::ParentDir)) {
+            return Err(format!("invalid route {:?} (the directory has to be relative to the output)", route));
+        }
+        let slot = match kind.trim().to_lowercase().as_str() {
+            "raw" | "raws" => &mut routes.raw,
+            "image" | "images" => &mut routes.image,
+            "video" | "videos" => &mut routes.video,
+            "other" | "others" | "file" | "files" => &mut routes.other,
+            _ => return Err(format!("invalid route {:?} (kind has to be raw, image, video or other)", route)),
+        };
+        *slot = Some(dir);
+    }
+    Ok(routes)
+}
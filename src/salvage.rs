@@ -0,0 +1,68 @@
+use crate::*;
+
+
+/// Sizes (relative to the file) that a damaged raw is padded to when trying to decode it
+const PADDED_SIZES: [usize; 4] = [2, 4, 8, 16];
+
+/// Value of the parts of a salvaged raw that could not be read
+const FILL_GRAY: u8 = 128;
+
+
+/// Decodes whatever part of a truncated or damaged raw is readable, filling the rest with gray
+///
+/// The missing data is replaced by padding and the raw is decoded twice, once padded with zeros and
+/// once with ones. Rows that come out the same both times only depend on data that was actually read,
+/// everything from the first differing row on is filled with gray. Returns the image along with the
+/// fraction of rows that could be recovered.
+pub fn salvage_raw(path: &Path, options: &DecodeOptions) -> Result<(imagepipe::SRGBImage, f64), String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    let (mut raw, padded_size) = PADDED_SIZES.iter()
+        .find_map(|factor| Some((padded_raw(&data, data.len() * factor, 0x00).ok()?, data.len() * factor)))
+        .ok_or_else(|| String::from("no readable image data found"))?;
+
+    let valid_rows = match padded_raw(&data, padded_size, 0xff) {
+        Ok(other) => matching_rows(&raw, &other),
+        // decoders may reject the second padding, in which case only rows of zeros can be told apart
+        Err(_) => raw.height - trailing_zero_rows(&raw),
+    };
+    if valid_rows == 0 {
+        return Err(String::from("no readable image data found"));
+    }
+
+    correct_mosaic(&mut raw, options)?;
+    let (mut image, reduced) = render_raw(&raw, options)?;
+    let first_missing = valid_rows * image.height / raw.height.max(1);
+    image.data[(first_missing * image.width * 3).min(image.data.len())..].fill(FILL_GRAY);
+    Ok((finish_raw(&raw, image, reduced, options), valid_rows as f64 / raw.height.max(1) as f64))
+}
+
+/// Reads `data` with rawloader after padding it to `size` bytes of `byte`
+fn padded_raw(data: &[u8], size: usize, byte: u8) -> Result<rawloader::RawImage, String> {
+    let mut padded = data.to_vec();
+    padded.resize(size, byte);
+    RawInput::Bytes(&padded).read_raw()
+}
+
+/// Number of leading rows that are the same in both decodes of a raw
+fn matching_rows(a: &rawloader::RawImage, b: &rawloader::RawImage) -> usize {
+    if (a.width, a.height, a.cpp) != (b.width, b.height, b.cpp) {
+        return 0;
+    }
+    let row = (a.width * a.cpp).max(1);
+    match (&a.data, &b.data) {
+        (rawloader::RawImageData::Integer(a), rawloader::RawImageData::Integer(b)) =>
+            a.chunks(row).zip(b.chunks(row)).take_while(|(a, b)| a == b).count(),
+        (rawloader::RawImageData::Float(a), rawloader::RawImageData::Float(b)) =>
+            a.chunks(row).zip(b.chunks(row)).take_while(|(a, b)| a == b).count(),
+        _ => 0,
+    }
+}
+
+/// Number of rows at the end of a raw whose values are all zero
+fn trailing_zero_rows(raw: &rawloader::RawImage) -> usize {
+    let row = (raw.width * raw.cpp).max(1);
+    match &raw.data {
+        rawloader::RawImageData::Integer(data) => data.chunks(row).rev().take_while(|r| r.iter().all(|v| *v == 0)).count(),
+        rawloader::RawImageData::Float(data) => data.chunks(row).rev().take_while(|r| r.iter().all(|v| *v == 0.0)).count(),
+    }
+}
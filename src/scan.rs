@@ -0,0 +1,243 @@
+use crate::*;
+
+use std::io::{IsTerminal, Write};
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+
+
+/// Minimum time between two updates of the progress line
+const PROGRESS_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Upper bound for the number of directories read at once (more rarely helps, even on network shares)
+const MAX_SCAN_THREADS: usize = 16;
+
+/// "scanning… N files found" line on stderr, only shown if stderr is a terminal
+pub struct ScanProgress {
+    /// Time the line was last updated, `None` if no progress is shown
+    last: Option<Mutex<Option<Instant>>>,
+}
+
+/// Shared state of the threads scanning a tree
+struct Walk<'a> {
+    /// Directories waiting to be read (with their remaining depth) and the number of directories being read
+    queue: Mutex<(Vec<(PathBuf, Option<usize>)>, usize)>,
+    changed: Condvar,
+    found: AtomicUsize,
+    progress: ScanProgress,
+    follow_hidden: bool,
+    /// Absolute paths of directories that are not descended into
+    exclude: Vec<PathBuf>,
+    /// Receives the files of each directory, returns `false` to stop the scan
+    on_files: &'a (dyn Fn(Vec<PathBuf>) -> bool + Sync),
+}
+
+
+/// All files below `dir` in sorted order, descending at most `max_depth` directories
+///
+/// Directories are read by several threads at once, which makes a big difference on network
+/// shares. Entries that cannot be read (e.g. because of missing permissions) are reported and
+/// skipped instead of aborting the scan. With `show_progress`, the number of files found so far
+/// is shown while scanning (see `ScanProgress`).
+pub fn scan_dir(dir: &Path, max_depth: Option<usize>, follow_hidden: bool, show_progress: bool) -> Vec<PathBuf> {
+    let files = Mutex::new(Vec::new());
+    walk(dir, max_depth, follow_hidden, show_progress, &[], &|mut found| {
+        files.lock().unwrap().append(&mut found);
+        true
+    });
+
+    let mut files = files.into_inner().unwrap();
+    files.sort();
+    files
+}
+
+/// Scans `dir` like `scan_dir()` in the background, passing on files as soon as they are found
+///
+/// The files arrive in no particular order. The scan stops early once the receiver is dropped.
+/// Directories in `exclude` are skipped, which keeps outputs that are written while the scan is
+/// still running from coming back as inputs (see `output_dirs_in()`).
+pub fn spawn_scan(dir: &Path, max_depth: Option<usize>, follow_hidden: bool, exclude: Vec<PathBuf>) -> Receiver<PathBuf> {
+    let (tx, rx) = channel();
+    let dir = dir.to_path_buf();
+    std::thread::Builder::new().name(String::from("scan")).spawn(move || {
+        walk(&dir, max_depth, follow_hidden, false, &exclude, &|found| found.into_iter().all(|f| tx.send(f).is_ok()));
+    }).expect("unable to spawn scan thread");
+    rx
+}
+
+/// Directories inside `input_base` that a batch converting it to `output_base` writes into
+///
+/// If the output lies inside the input, that is the whole output. When converting in place, only the
+/// directories of variants, kind routes, kept originals and quarantined raws are separate from the inputs.
+pub fn output_dirs_in(input_base: &Path, output_base: &Path, settings: &JobSettings) -> Vec<PathBuf> {
+    let (Ok(input), Ok(output)) = (std::path::absolute(input_base), std::path::absolute(output_base)) else {
+        return Vec::new();
+    };
+    if input != output {
+        return if output.starts_with(&input) { vec![output] } else { Vec::new() };
+    }
+
+    let routes = &settings.routes;
+    settings.variants.iter().map(|v| PathBuf::from(&v.name))
+        .chain([&routes.raw, &routes.image, &routes.video, &routes.other].into_iter().flatten().cloned())
+        .chain(settings.keep_raws.iter().chain(&settings.quarantine).cloned())
+        .map(|dir| output.join(dir))
+        .collect()
+}
+
+/// Whether `file` looks completely written, i.e. it was not modified for `stable_for` and no other
+/// process holds it open
+///
+/// Files whose metadata can not be read count as settled, so converting them reports the actual error.
+pub fn is_settled(file: &Path, stable_for: time::Duration) -> bool {
+    let modified = file.metadata().and_then(|m| m.modified()).ok();
+    let unchanged = modified
+        .and_then(|m| time::SystemTime::now().duration_since(m).ok())
+        .map_or(true, |age| age >= stable_for);
+    unchanged && !is_locked(file)
+}
+
+/// Whether another process has `file` open, which is only detectable on Windows
+#[cfg(windows)]
+fn is_locked(file: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    // opening without sharing fails as long as anyone else (e.g. a card reader tool) has the file open
+    fs::OpenOptions::new().read(true).share_mode(0).open(file)
+        .is_err_and(|e| e.raw_os_error() == Some(ERROR_SHARING_VIOLATION))
+}
+
+#[cfg(not(windows))]
+fn is_locked(_file: &Path) -> bool {
+    false
+}
+
+fn walk(dir: &Path, max_depth: Option<usize>, follow_hidden: bool, show_progress: bool, exclude: &[PathBuf],
+        on_files: &(dyn Fn(Vec<PathBuf>) -> bool + Sync)) {
+    let walk = Walk {
+        queue: Mutex::new((vec![(dir.to_path_buf(), max_depth)], 0)),
+        changed: Condvar::new(),
+        found: AtomicUsize::new(0),
+        progress: ScanProgress::new(show_progress),
+        follow_hidden,
+        exclude: exclude.iter().filter_map(|dir| std::path::absolute(dir).ok()).collect(),
+        on_files,
+    };
+
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4).min(MAX_SCAN_THREADS);
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| walk.run());
+        }
+    });
+    walk.progress.clear();
+}
+
+impl ScanProgress {
+    pub fn new(enabled: bool) -> ScanProgress {
+        ScanProgress { last: (enabled && io::stderr().is_terminal()).then(|| Mutex::new(None)) }
+    }
+
+    pub fn update(&self, found: usize) {
+        let Some(last) = &self.last else { return };
+        let mut last = last.lock().unwrap();
+        if last.is_some_and(|l| l.elapsed() < PROGRESS_INTERVAL) {
+            return;
+        }
+        *last = Some(Instant::now());
+        eprint!("\rscanning… {} files found", found);
+        let _ = io::stderr().flush();
+    }
+
+    /// Removes the progress line (e.g. before printing something else)
+    pub fn clear(&self) {
+        if let Some(last) = &self.last {
+            if last.lock().unwrap().is_some() {
+                eprint!("\r\x1b[K");
+            }
+        }
+    }
+}
+
+impl Walk<'_> {
+    fn run(&self) {
+        while let Some((dir, depth)) = self.next_dir() {
+            let more = self.read_dir(&dir, depth);
+
+            let mut queue = self.queue.lock().unwrap();
+            if !more {
+                queue.0.clear();
+            }
+            queue.1 -= 1;
+            if queue.0.is_empty() && queue.1 == 0 {
+                self.changed.notify_all();
+            }
+        }
+    }
+
+    /// Waits for the next directory to read, `None` once the whole tree has been read
+    fn next_dir(&self) -> Option<(PathBuf, Option<usize>)> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(next) = queue.0.pop() {
+                queue.1 += 1;
+                return Some(next);
+            }
+            if queue.1 == 0 {
+                return None;
+            }
+            queue = self.changed.wait(queue).unwrap();
+        }
+    }
+
+    /// Reads a single directory, returns `false` if the scan should stop
+    fn read_dir(&self, dir: &Path, depth: Option<usize>) -> bool {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => { self.skip(dir, e); return true },
+        };
+
+        let mut files = Vec::new();
+        let mut subdirs = Vec::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => { self.skip(dir, e); continue },
+            };
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => { self.skip(&entry.path(), e); continue },
+            };
+            let hidden = entry.file_name().as_encoded_bytes().starts_with(b".");
+
+            if entry.file_name() == OVERRIDE_FILE || is_sidecar(&entry.file_name()) {
+                continue;
+            }
+
+            if file_type.is_dir() {
+                if depth != Some(0) && (!hidden || self.follow_hidden) && !self.is_excluded(&entry.path()) {
+                    subdirs.push((entry.path(), depth.map(|d| d - 1)));
+                }
+            } else {
+                files.push(entry.path());
+            }
+        }
+
+        if !subdirs.is_empty() {
+            self.queue.lock().unwrap().0.append(&mut subdirs);
+            self.changed.notify_all();
+        }
+        let found = self.found.fetch_add(files.len(), Ordering::Relaxed) + files.len();
+        self.progress.update(found);
+        files.is_empty() || (self.on_files)(files)
+    }
+
+    fn is_excluded(&self, dir: &Path) -> bool {
+        !self.exclude.is_empty() && std::path::absolute(dir).is_ok_and(|dir| self.exclude.contains(&dir))
+    }
+
+    fn skip(&self, path: &Path, error: io::Error) {
+        self.progress.clear();
+        eprintln!("Skipping {:?} ({})", path, error);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::*;
+
+use std::process::Command;
+
+
+/// Exposure parameters that stay fixed across the frames of a panorama
+fn exposure_settings(path: &Path) -> Option<[String; 4]> {
+    let data = read_exif(path)?;
+    let field = |tag| data.get_field(tag, exif::In::PRIMARY)
+        .map(|f| f.display_value().to_string())
+        .unwrap_or_default();
+    Some([
+        field(exif::Tag::ExposureTime),
+        field(exif::Tag::FNumber),
+        field(exif::Tag::PhotographicSensitivity),
+        field(exif::Tag::ExposureBiasValue),
+    ])
+}
+
+/// Finds panorama sequences: raws taken at most `gap` apart with identical exposure settings
+pub fn find_panoramas(files: &[PathBuf], gap: time::Duration, detect_by_content: bool) -> Vec<Vec<PathBuf>> {
+    let mut panoramas = Vec::new();
+    for burst in group_bursts(files, gap, detect_by_content) {
+        let mut current: Vec<PathBuf> = Vec::new();
+        let mut current_settings = None;
+        for file in burst {
+            let settings = exposure_settings(&file);
+            if settings != current_settings && !current.is_empty() {
+                panoramas.push(std::mem::take(&mut current));
+            }
+            current.push(file);
+            current_settings = settings;
+        }
+        panoramas.push(current);
+    }
+
+    panoramas.retain(|panorama| panorama.len() > 1);
+    panoramas
+}
+
+/// Focus distance of a raw file in meters
+///
+/// Only the standard `SubjectDistance` tag is read, vendor specific maker notes are not supported.
+pub fn focus_distance(path: &Path) -> Option<f64> {
+    let data = read_exif(path)?;
+    match data.get_field(exif::Tag::SubjectDistance, exif::In::PRIMARY)?.value {
+        exif::Value::Rational(ref values) => values.first().map(|r| r.to_f64()),
+        _ => None,
+    }
+}
+
+/// Finds focus stacks: raws taken at most `gap` apart with identical exposure and increasing focus distance
+pub fn find_focus_stacks(files: &[PathBuf], gap: time::Duration, detect_by_content: bool) -> Vec<Vec<PathBuf>> {
+    let mut stacks = Vec::new();
+    for burst in group_bursts(files, gap, detect_by_content) {
+        let mut current: Vec<PathBuf> = Vec::new();
+        let mut last: Option<([String; 4], f64)> = None;
+        for file in burst {
+            let frame = exposure_settings(&file).zip(focus_distance(&file));
+            let continues = match (&last, &frame) {
+                (Some((last_settings, last_distance)), Some((settings, distance))) =>
+                    settings == last_settings && distance > last_distance,
+                _ => false,
+            };
+            if !continues && !current.is_empty() {
+                stacks.push(std::mem::take(&mut current));
+            }
+            if frame.is_some() {
+                current.push(file);
+            }
+            last = frame;
+        }
+        stacks.push(current);
+    }
+
+    stacks.retain(|stack| stack.len() > 1);
+    stacks
+}
+
+/// Runs `command` for a finished sequence directory, which is appended as last argument
+pub fn run_sequence_hook(command: &str, dir: &Path) -> Result<(), String> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", command))
+        .arg("sh")
+        .arg(dir)
+        .status()
+        .map_err(|e| format!("unable to run {:?} ({})", command, e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{:?} failed for {:?} ({})", command, dir, status))
+    }
+}
@@ -0,0 +1,265 @@
+use crate::*;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, TrySendError};
+use std::sync::{Arc, Mutex};
+
+
+/// Largest accepted upload
+const MAX_UPLOAD: usize = 1024 * 1024 * 1024;
+
+/// Largest accepted request line and headers
+const MAX_HEAD: u64 = 64 * 1024;
+
+/// Time a client may stay silent while sending its request or taking the response
+const CLIENT_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// Counter for unique temporary file names
+static REQUEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Options of the conversion service
+#[derive(Clone)]
+pub struct ServeSettings {
+    /// Address to listen on
+    pub addr: String,
+    /// Number of requests that are converted concurrently
+    pub workers: usize,
+    /// Number of requests that may wait for a worker before new ones are rejected
+    pub queue: usize,
+    /// Directory that `GET /convert?path=...` requests may read from (path requests are disabled if unset)
+    pub root: Option<PathBuf>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+
+impl Response {
+    fn error(status: &'static str, message: &str) -> Response {
+        Response { status, content_type: "text/plain", body: format!("{}\n", message).into_bytes() }
+    }
+}
+
+/// Runs the HTTP conversion service until the process is terminated
+///
+/// `POST /convert?name=IMG_0001.CR2` converts the raw file sent as request body,
+/// `GET /convert?path=dir/IMG_0001.CR2` converts a file below the configured root.
+/// Both respond with the converted image.
+pub fn serve(serve_settings: &ServeSettings, settings: &JobSettings) -> Result<(), String> {
+    let listener = TcpListener::bind(&serve_settings.addr)
+        .map_err(|e| format!("unable to listen on {} ({})", serve_settings.addr, e))?;
+    let (tx, rx) = sync_channel::<TcpStream>(serve_settings.queue);
+    let rx = Arc::new(Mutex::new(rx));
+
+    // requests are converted as raws regardless of their name
+    let settings = JobSettings {
+        on_raw: ParsableAction::Parse,
+        detect_by_content: true,
+        ..settings.clone()
+    };
+
+    for i in 0..serve_settings.workers.max(1) {
+        let rx = rx.clone();
+        let settings = settings.clone();
+        let root = serve_settings.root.clone();
+        std::thread::Builder::new()
+            .name(format!("serve-{}", i))
+            .spawn(move || loop {
+                let stream = match rx.lock().unwrap().recv() {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                if let Err(e) = handle(stream, &settings, root.as_deref()) {
+                    println!("Error: {}", e);
+                }
+            })
+            .map_err(|e| e.to_string())?;
+    }
+
+    println!("Serving conversions on http://{}", serve_settings.addr);
+    for stream in listener.incoming().flatten() {
+        match tx.try_send(stream) {
+            Ok(()) => (),
+            Err(TrySendError::Full(stream)) =>
+                { let _ = write_response(stream, Response::error("503 Service Unavailable", "too many requests")); },
+            Err(TrySendError::Disconnected(_)) => return Err(String::from("all workers stopped")),
+        }
+    }
+
+    Ok(())
+}
+
+fn handle(stream: TcpStream, settings: &JobSettings, root: Option<&Path>) -> Result<(), String> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+
+    let (method, target, content_length) = match read_head(&mut reader) {
+        Ok(head) => head,
+        Err(response) => return write_response(stream, response).map_err(|e| e.to_string()),
+    };
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let param = |key: &str| query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| percent_decode(v));
+
+    let response = match (method.as_str(), path) {
+        ("POST", "/convert") if content_length > MAX_UPLOAD =>
+            Response::error("413 Payload Too Large", "upload too large"),
+        ("POST", "/convert") => {
+            let mut body = vec![0u8; content_length];
+            match reader.read_exact(&mut body) {
+                Ok(()) => convert_upload(&body, param("name"), settings),
+                Err(e) => request_error(&e),
+            }
+        },
+        ("GET", "/convert") => match (root, param("path")) {
+            (Some(root), Some(path)) => convert_path(root, &path, settings),
+            (None, _) => Response::error("403 Forbidden", "converting paths is disabled"),
+            (_, None) => Response::error("400 Bad Request", "missing path parameter"),
+        },
+        _ => Response::error("404 Not Found", "use POST /convert or GET /convert?path=..."),
+    };
+
+    write_response(stream, response).map_err(|e| e.to_string())
+}
+
+/// Reads the request line and headers, returning the method, target and content length
+///
+/// Requests whose head is too large, malformed or not sent in time are answered with the returned error.
+fn read_head(reader: &mut BufReader<TcpStream>) -> Result<(String, String, usize), Response> {
+    let mut head = reader.take(MAX_HEAD);
+    let mut read_line = || {
+        let mut line = String::new();
+        match head.read_line(&mut line) {
+            Ok(_) if line.ends_with('\n') => Ok(line),
+            Ok(_) => Err(Response::error("400 Bad Request", "request head too large or incomplete")),
+            Err(e) => Err(request_error(&e)),
+        }
+    };
+
+    let request_line = read_line()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        let line = read_line()?;
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()
+                    .map_err(|_| Response::error("400 Bad Request", "invalid content length"))?;
+            }
+        }
+    }
+    Ok((method, target, content_length))
+}
+
+/// Response to a request that could not be read
+fn request_error(error: &io::Error) -> Response {
+    match error.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut =>
+            Response::error("408 Request Timeout", "timed out waiting for the request"),
+        _ => Response::error("400 Bad Request", &format!("unable to read the request ({})", error)),
+    }
+}
+
+fn convert_upload(body: &[u8], name: Option<String>, settings: &JobSettings) -> Response {
+    let extension = name.as_deref()
+        .and_then(|n| Path::new(n).extension())
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_else(|| String::from("raw"));
+    let input = temp_file_path(&extension);
+    if let Err(e) = fs::write(&input, body) {
+        return Response::error("500 Internal Server Error", &e.to_string());
+    }
+
+    let response = convert_file(&input, settings);
+    let _ = fs::remove_file(&input);
+    response
+}
+
+fn convert_path(root: &Path, path: &str, settings: &JobSettings) -> Response {
+    let resolved = root.join(path.trim_start_matches('/'));
+    match (resolved.canonicalize(), root.canonicalize()) {
+        (Ok(file), Ok(root)) if file.starts_with(&root) => convert_file(&file, settings),
+        (Ok(_), Ok(_)) => Response::error("403 Forbidden", "path outside of root"),
+        _ => Response::error("404 Not Found", "no such file"),
+    }
+}
+
+/// Converts a single raw file through a regular `Job` and returns the encoded image
+fn convert_file(input: &Path, settings: &JobSettings) -> Response {
+    if !matches!(file_kind(input, true), FileKind::Raw) {
+        return Response::error("415 Unsupported Media Type", "not a raw file");
+    }
+
+    let output = temp_file_path(settings.encoder.extension());
+    let result = Job::new(input, &output, settings).run();
+    let body = fs::read(&output);
+    let _ = fs::remove_file(&output);
+
+    match (result, body) {
+        (Ok(statistics), Ok(body)) if statistics.errors.count() == 0 => Response {
+            status: "200 OK",
+            content_type: content_type(settings.encoder),
+            body,
+        },
+        (Err(e), _) => Response::error("500 Internal Server Error", &e.to_string()),
+        _ => Response::error("422 Unprocessable Entity", "unable to convert file"),
+    }
+}
+
+fn temp_file_path(extension: &str) -> PathBuf {
+    let n = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("raw-to-img-{}-{}.{}", std::process::id(), n, extension))
+}
+
+fn content_type(encoder: EncoderType) -> &'static str {
+    match encoder {
+        EncoderType::JpegEncoder(_) => "image/jpeg",
+        EncoderType::PngEncoder(..) => "image/png",
+        EncoderType::TiffEncoder => "image/tiff",
+        EncoderType::QoiEncoder => "image/qoi",
+    }
+}
+
+fn write_response(mut stream: TcpStream, response: Response) -> io::Result<()> {
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status, response.content_type, response.body.len())?;
+    stream.write_all(&response.body)
+}
+
+/// Decodes `%XX` escapes and `+` in a query parameter
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 3 <= bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(byte) => { decoded.push(byte); i += 3; continue },
+                    None => decoded.push(b'%'),
+                }
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
@@ -0,0 +1,160 @@
+use crate::*;
+
+#[cfg(feature = "sftp")]
+use std::io::Write;
+#[cfg(feature = "sftp")]
+use std::sync::Mutex;
+
+
+/// Parsed `sftp://user@host:port/path` location
+pub struct SftpUrl {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub path: PathBuf,
+}
+
+
+impl SftpUrl {
+    pub fn parse(url: &str) -> Result<SftpUrl, String> {
+        let location = url.strip_prefix("sftp://")
+            .ok_or_else(|| format!("{:?} is not an sftp:// url", url))?;
+        let (authority, path) = location.split_once('/').unwrap_or((location, ""));
+        let (user, host) = match authority.split_once('@') {
+            Some((user, host)) => (user.to_string(), host),
+            None => (std::env::var("USER").unwrap_or_default(), authority),
+        };
+        let (host, port) = match host.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("invalid port in {:?}", url))?),
+            None => (host, 22),
+        };
+
+        Ok(SftpUrl { user, host: host.to_string(), port, path: PathBuf::from(format!("/{}", path)) })
+    }
+}
+
+/// Whether `input` is an sftp:// URL rather than a local path
+pub fn is_sftp_url(input: &str) -> bool {
+    input.starts_with("sftp://")
+}
+
+/// Opens an SFTP session, authenticating via the SSH agent or `$SFTP_PASSWORD`
+#[cfg(feature = "sftp")]
+fn connect(url: &SftpUrl) -> Result<ssh2::Sftp, String> {
+    let tcp = std::net::TcpStream::connect((url.host.as_str(), url.port))
+        .map_err(|e| format!("unable to connect to {} ({})", url.host, e))?;
+    let mut session = ssh2::Session::new().map_err(|e| e.to_string())?;
+    session.set_tcp_stream(tcp);
+    session.handshake().map_err(|e| format!("ssh handshake with {} failed ({})", url.host, e))?;
+
+    if session.userauth_agent(&url.user).is_err() {
+        if let Ok(password) = std::env::var("SFTP_PASSWORD") {
+            session.userauth_password(&url.user, &password)
+                .map_err(|e| format!("authentication at {} failed ({})", url.host, e))?;
+        }
+    }
+    if !session.authenticated() {
+        return Err(format!("unable to authenticate as {} at {}", url.user, url.host));
+    }
+
+    session.sftp().map_err(|e| e.to_string())
+}
+
+/// Downloads the file or tree at `url` into `dir`
+///
+/// Returns the downloaded file, or `dir` itself if `url` points to a directory.
+#[cfg(feature = "sftp")]
+pub fn sftp_download(url: &str, dir: &Path) -> Result<PathBuf, String> {
+    let parsed = SftpUrl::parse(url)?;
+    let sftp = connect(&parsed)?;
+    let stat = sftp.stat(&parsed.path).map_err(|e| format!("unable to access {} ({})", url, e))?;
+
+    if stat.is_dir() {
+        download_tree(&sftp, &parsed.path, dir)?;
+        Ok(dir.to_path_buf())
+    } else {
+        let local = dir.join(parsed.path.file_name().unwrap_or_default());
+        download_file(&sftp, &parsed.path, &local)?;
+        Ok(local)
+    }
+}
+
+#[cfg(not(feature = "sftp"))]
+pub fn sftp_download(url: &str, _dir: &Path) -> Result<PathBuf, String> {
+    Err(format!("unable to download {} (this build does not support sftp, enable the sftp feature)", url))
+}
+
+#[cfg(feature = "sftp")]
+fn download_tree(sftp: &ssh2::Sftp, remote: &Path, local: &Path) -> Result<(), String> {
+    fs::create_dir_all(local).map_err(|e| e.to_string())?;
+    for (entry, stat) in sftp.readdir(remote).map_err(|e| format!("unable to list {:?} ({})", remote, e))? {
+        let name = entry.file_name().unwrap_or_default();
+        if stat.is_dir() {
+            download_tree(sftp, &entry, &local.join(name))?;
+        } else if stat.is_file() {
+            download_file(sftp, &entry, &local.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sftp")]
+fn download_file(sftp: &ssh2::Sftp, remote: &Path, local: &Path) -> Result<(), String> {
+    println!("Downloading {:?}", remote);
+    let mut source = sftp.open(remote).map_err(|e| format!("unable to open {:?} ({})", remote, e))?;
+    let temp = temp_path(local);
+    let mut file = fs::File::create(&temp).map_err(|e| e.to_string())?;
+    if let Err(e) = io::copy(&mut source, &mut file) {
+        let _ = fs::remove_file(&temp);
+        return Err(format!("unable to download {:?} ({})", remote, e));
+    }
+    finish_temp(&temp, local).map_err(|e| e.to_string())
+}
+
+/// Directory on an SFTP server
+#[cfg(feature = "sftp")]
+pub struct SftpStorage {
+    sftp: Mutex<ssh2::Sftp>,
+    host: String,
+    base: PathBuf,
+}
+
+#[cfg(feature = "sftp")]
+impl SftpStorage {
+    pub fn new(url: &str) -> Result<SftpStorage, String> {
+        let parsed = SftpUrl::parse(url)?;
+        let sftp = connect(&parsed)?;
+        Ok(SftpStorage { sftp: Mutex::new(sftp), host: parsed.host, base: parsed.path })
+    }
+}
+
+#[cfg(feature = "sftp")]
+impl Storage for SftpStorage {
+    fn exists(&self, key: &str) -> Result<bool, String> {
+        Ok(self.sftp.lock().unwrap().stat(&self.base.join(key)).is_ok())
+    }
+
+    fn put(&self, path: &Path, key: &str) -> Result<(), String> {
+        let target = self.base.join(key);
+        let temp = temp_path(&target);
+        let sftp = self.sftp.lock().unwrap();
+
+        // create missing parents, existing ones make mkdir fail harmlessly
+        let mut dir = PathBuf::new();
+        for component in target.parent().unwrap_or(&self.base).components() {
+            dir.push(component);
+            let _ = sftp.mkdir(&dir, 0o755);
+        }
+
+        let content = fs::read(path).map_err(|e| e.to_string())?;
+        let mut file = sftp.create(&temp).map_err(|e| format!("unable to create {} ({})", self.describe(key), e))?;
+        file.write_all(&content).map_err(|e| format!("unable to upload {} ({})", self.describe(key), e))?;
+        drop(file);
+        sftp.rename(&temp, &target, Some(ssh2::RenameFlags::OVERWRITE | ssh2::RenameFlags::ATOMIC))
+            .map_err(|e| format!("unable to upload {} ({})", self.describe(key), e))
+    }
+
+    fn describe(&self, key: &str) -> String {
+        format!("sftp://{}{}", self.host, self.base.join(key).to_string_lossy())
+    }
+}
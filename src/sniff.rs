@@ -0,0 +1,66 @@
+use crate::*;
+
+use std::io::Read;
+
+
+/// Magic bytes of raw formats that can be told apart from regular images by their header
+const RAW_SIGNATURES: [(usize, &[u8]); 8] = [
+    (8, b"CR"),                 // Canon CR2 (TIFF based)
+    (4, b"ftypcrx "),           // Canon CR3 (ISO base media)
+    (0, b"FUJIFILMCCD-RAW"),    // Fujifilm RAF
+    (0, b"IIRO"),               // Olympus ORF
+    (0, b"IIRS"),               // Olympus ORF
+    (0, b"MMOR"),               // Olympus ORF
+    (0, b"IIU\0"),              // Panasonic RW2
+    (0, b"FOVb"),               // Sigma X3F
+];
+
+const IMG_SIGNATURES: [(usize, &[u8]); 3] = [
+    (0, b"\xff\xd8\xff"),       // JPEG
+    (0, b"\x89PNG\r\n\x1a\n"),  // PNG
+    (0, b"qoif"),               // QOI
+];
+
+const VIDEO_SIGNATURES: [(usize, &[u8]); 4] = [
+    (4, b"ftyp"),               // MP4, MOV, 3GP (ISO base media, checked after CR3)
+    (4, b"moov"),               // QuickTime
+    (4, b"mdat"),               // QuickTime
+    (8, b"AVI "),               // AVI (RIFF)
+];
+
+const TIFF_SIGNATURES: [&[u8]; 2] = [
+    b"II*\0",
+    b"MM\0*",
+];
+
+
+/// Classifies a file by looking at its first few bytes
+///
+/// Returns `None` if the header is not recognized, so callers can fall back to the extension.
+/// Most raw formats are TIFF containers, so a plain TIFF header is only considered a regular
+/// image if the extension says so.
+pub fn sniff_kind(path: &path::Path) -> Option<FileKind> {
+    let mut header = [0u8; 16];
+    let mut file = fs::File::open(path).ok()?;
+    let len = file.read(&mut header).ok()?;
+    let header = &header[..len];
+
+    if RAW_SIGNATURES.iter().any(|(offset, magic)| has_signature(header, *offset, magic)) {
+        Some(FileKind::Raw)
+    } else if IMG_SIGNATURES.iter().any(|(offset, magic)| has_signature(header, *offset, magic)) {
+        Some(FileKind::Image)
+    } else if VIDEO_SIGNATURES.iter().any(|(offset, magic)| has_signature(header, *offset, magic)) {
+        Some(FileKind::Video)
+    } else if TIFF_SIGNATURES.iter().any(|magic| header.starts_with(magic)) {
+        match extension_kind(path) {
+            FileKind::Image => Some(FileKind::Image),
+            _ => Some(FileKind::Raw),
+        }
+    } else {
+        None
+    }
+}
+
+fn has_signature(header: &[u8], offset: usize, magic: &[u8]) -> bool {
+    header.get(offset..offset + magic.len()) == Some(magic)
+}
@@ -0,0 +1,85 @@
+use crate::*;
+
+
+/// Rough size of a converted output relative to the size of its raw file, per encoder
+///
+/// Raws store 12-14 bits per photosite, while outputs store 24 bits per pixel, so uncompressed
+/// formats grow to about twice to three times the size of the raw.
+fn encode_ratio(encoder: EncoderType) -> f64 {
+    match encoder {
+        EncoderType::JpegEncoder(_) => 0.5,
+        EncoderType::PngEncoder(_, _) => 1.8,
+        EncoderType::TiffEncoder => 3.0,
+        EncoderType::QoiEncoder => 2.0,
+    }
+}
+
+/// Estimates the number of bytes the outputs of `files` will take up in `output_base`
+///
+/// Copies count with the size of their input, conversions with a heuristic depending on the encoder.
+/// Moves, and links within the same file system, take no additional space.
+pub fn estimate_output_size(files: &[PathBuf], output_base: &Path, settings: &JobSettings) -> u64 {
+    let local = |path: &Path| same_device(path, output_base);
+    let transfer = |action: UnparsableAction, path: &Path, size: u64| match action {
+        UnparsableAction::Copy => size,
+        UnparsableAction::Hardlink | UnparsableAction::Reflink if !local(path) => size,
+        _ => 0,
+    };
+
+    files.iter()
+        .filter_map(|file| Some((file, file.metadata().ok().filter(|m| m.is_file())?.len())))
+        .map(|(file, size)| match file_kind(file, settings.detect_by_content) {
+            FileKind::Raw => match settings.on_raw {
+                ParsableAction::Parse => {
+                    let kept = match settings.keep_raws {
+                        Some(_) if !local(file) => size,
+                        _ => 0,
+                    };
+                    (size as f64 * encode_ratio(settings.encoder)) as u64 + kept
+                },
+                ParsableAction::Copy => size,
+                _ => 0,
+            },
+            FileKind::Image => transfer(settings.on_image, file, size),
+            FileKind::Video => transfer(settings.on_video, file, size),
+            FileKind::Other => transfer(settings.on_file, file, size),
+        })
+        .sum()
+}
+
+/// Bytes available to unprivileged users on the file system containing `path`
+///
+/// If `path` does not exist yet, its closest existing ancestor is checked.
+/// Returns `None` if the free space can not be determined.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let existing = if existing.as_os_str().is_empty() { Path::new(".") } else { existing };
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Whether `a` and the closest existing ancestor of `b` are on the same file system
+#[cfg(unix)]
+fn same_device(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let device = |path: &Path| path.ancestors().find_map(|p| p.metadata().ok()).map(|m| m.dev());
+    matches!((device(a), device(b)), (Some(a), Some(b)) if a == b)
+}
+
+#[cfg(not(unix))]
+fn same_device(_a: &Path, _b: &Path) -> bool {
+    false
+}
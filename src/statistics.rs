@@ -1,10 +1,35 @@
 use crate::*;
 
+use std::collections::BTreeMap;
+
+const WINDOWS: [(&str, time::Duration); 2] = [
+    ("hour", time::Duration::from_secs(60 * 60)),
+    ("day", time::Duration::from_secs(24 * 60 * 60)),
+];
+
+/// Number of most recent times `StatisticsItem::compact()` keeps for the percentiles
+const KEPT_TIMES: usize = 1000;
 
 #[derive(Default)]
 pub struct StatisticsItem {
     count: u32,
     times: Vec<time::Duration>,
+    /// Times dropped by `compact()`, only kept in aggregate
+    folded: FoldedTimes,
+    stamps: Vec<Instant>,
+    /// Amount of work done in the recorded times (bytes copied, megapixels decoded, ...)
+    volume: f64,
+}
+
+/// Sum, extremes and histogram of times that are no longer kept individually
+#[derive(Clone, Copy, Default)]
+struct FoldedTimes {
+    count: usize,
+    total: time::Duration,
+    min: Option<time::Duration>,
+    max: time::Duration,
+    /// Number of times up to each bound of `TIME_BUCKETS`
+    buckets: [usize; TIME_BUCKETS.len()],
 }
 
 #[derive(Default)]
@@ -13,70 +38,334 @@ pub struct Statistics {
     pub decoded: StatisticsItem,
     pub copied: StatisticsItem,
     pub moved: StatisticsItem,
+    /// Raws whose output was restored from the cache instead of converting them
+    pub cached: StatisticsItem,
     pub ignored: StatisticsItem,
     pub errors: StatisticsItem,
+    /// Jobs that exceeded the job timeout (also counted as errors)
+    pub timeouts: StatisticsItem,
+    /// Retries of steps that failed with a transient error
+    pub retried: StatisticsItem,
+    /// Corrupt raws put into the quarantine directory (also counted as errors)
+    pub quarantined: StatisticsItem,
+    /// Corrupt raws that were partially decoded (also counted as errors)
+    pub salvaged: StatisticsItem,
     pub total: StatisticsItem,
+    /// Checksums of the written output files (only collected if enabled in `JobSettings`)
+    pub checksums: Vec<(PathBuf, String)>,
+    /// Per-file outcomes (only collected if enabled in `JobSettings`)
+    pub report: Vec<ReportEntry>,
+    /// Processing times of the files by lowercase extension
+    pub extensions: BTreeMap<String, StatisticsItem>,
+    /// Elapsed real time of the run
+    pub wall_time: time::Duration,
+    /// CPU time the process spent during the run (user and system, zero if unknown)
+    pub cpu_time: time::Duration,
 }
 
 
 impl StatisticsItem {
     pub fn record(&mut self, time: time::Duration) {
         self.times.push(time);
+        self.stamps.push(Instant::now());
         self.count += 1;
     }
 
+    /// Like `record()`, for a file whose processing involved `volume` units of work
+    pub fn record_volume(&mut self, time: time::Duration, volume: f64) {
+        self.record(time);
+        self.volume += volume;
+    }
+
     pub fn inc(&mut self) {
+        self.stamps.push(Instant::now());
         self.count += 1;
     }
 
+    /// Number of recorded files that finished within the last `window`
+    pub fn count_since(&self, window: time::Duration) -> usize {
+        self.stamps.iter().filter(|s| s.elapsed() <= window).count()
+    }
+
+    /// Forget timestamps that are older than the largest rolling window
+    pub fn prune(&mut self, max_age: time::Duration) {
+        self.stamps.retain(|s| s.elapsed() <= max_age);
+    }
+
+    /// Folds all but the most recent times into totals, so the memory used stays bounded
+    ///
+    /// Totals, averages, extremes and the histogram still cover all times, percentiles only the recent ones.
+    pub fn compact(&mut self) {
+        let dropped = self.times.len().saturating_sub(KEPT_TIMES);
+        for time in self.times.drain(..dropped) {
+            self.folded.add(time);
+        }
+    }
+
     pub fn count(&self) -> u32 {
         self.count
     }
 
+    /// Times that are still kept individually (see `compact()`)
+    pub fn times(&self) -> &[time::Duration] {
+        &self.times
+    }
+
+    /// Number of times up to each bound of `TIME_BUCKETS` and the total number of times
+    pub fn histogram(&self) -> ([usize; TIME_BUCKETS.len()], usize) {
+        let mut buckets = self.folded.buckets;
+        for (bucket, bound) in buckets.iter_mut().zip(TIME_BUCKETS) {
+            *bucket += self.times.iter().filter(|t| t.as_secs_f64() <= bound).count();
+        }
+        (buckets, self.times.len() + self.folded.count)
+    }
+
+    /// Total amount of work recorded with `record_volume()`
+    pub fn volume(&self) -> f64 {
+        self.volume
+    }
+
     pub fn time_total(&self) -> time::Duration {
-        self.times.iter().sum()
+        self.times.iter().sum::<time::Duration>() + self.folded.total
     }
 
     pub fn time_avg(&self) -> time::Duration {
-        if !self.times.is_empty() {
-            return self.times.iter().sum::<time::Duration>() / (self.times.len() as u32);
-        } else {
-            time::Duration::default()
+        match self.times.len() + self.folded.count {
+            0 => time::Duration::default(),
+            count => self.time_total() / count as u32,
         }
     }
 
+    /// Time below which the given fraction of the recorded times lies (nearest rank)
+    ///
+    /// Only the times that are still kept individually count (see `compact()`).
+    pub fn time_percentile(&self, fraction: f64) -> time::Duration {
+        let mut times = self.times.clone();
+        times.sort();
+        let rank = ((times.len() as f64 * fraction).ceil() as usize).clamp(1, times.len().max(1));
+        times.get(rank - 1).copied().unwrap_or_default()
+    }
+
+    pub fn time_min(&self) -> time::Duration {
+        self.times.iter().copied().chain(self.folded.min).min().unwrap_or_default()
+    }
+
+    pub fn time_max(&self) -> time::Duration {
+        self.times.iter().copied().max().unwrap_or_default().max(self.folded.max)
+    }
+
+    /// Volume per second of recorded time (`None` if nothing was recorded)
+    pub fn throughput(&self) -> Option<f64> {
+        let seconds = self.time_total().as_secs_f64();
+        (self.volume > 0.0 && seconds > 0.0).then(|| self.volume / seconds)
+    }
+
     pub fn print(&self) {
         println!("{} files in {} (avg {} per file)", self.count(),
             fmt_duration(&self.time_total()), fmt_duration(&self.time_avg()));
     }
 
-    pub fn print_nthreads(&self, nthreads: u32) {
-        println!("{} files in approx. {} (avg {} per file)", self.count(),
-            fmt_duration(&(self.time_total() / nthreads)), fmt_duration(&self.time_avg()));
+    /// Like `print()`, but calls the summed up time of all threads "busy"
+    pub fn print_busy(&self) {
+        println!("{} files, busy for {} (avg {} per file)", self.count(),
+            fmt_duration(&self.time_total()), fmt_duration(&self.time_avg()));
+    }
+
+    /// Prints the distribution of the recorded times and the throughput (in `unit` per second)
+    pub fn print_distribution(&self, unit: &str) {
+        if self.times.is_empty() && self.folded.count == 0 {
+            return;
+        }
+        print!("    min {}, p50 {}, p95 {}, max {}", fmt_duration(&self.time_min()),
+            fmt_duration(&self.time_percentile(0.5)), fmt_duration(&self.time_percentile(0.95)),
+            fmt_duration(&self.time_max()));
+        match self.throughput() {
+            Some(throughput) => println!(", {:.2} {}/s", throughput, unit),
+            None => println!(),
+        }
     }
 
     pub fn extend(&mut self, other: &StatisticsItem) {
         self.count += other.count;
         self.times.extend(&other.times);
+        self.folded.extend(&other.folded);
+        self.stamps.extend(&other.stamps);
+        self.volume += other.volume;
+    }
+}
+
+impl FoldedTimes {
+    fn add(&mut self, time: time::Duration) {
+        self.count += 1;
+        self.total += time;
+        self.min = Some(self.min.map_or(time, |min| min.min(time)));
+        self.max = self.max.max(time);
+        for (bucket, bound) in self.buckets.iter_mut().zip(TIME_BUCKETS) {
+            if time.as_secs_f64() <= bound {
+                *bucket += 1;
+            }
+        }
+    }
+
+    fn extend(&mut self, other: &FoldedTimes) {
+        self.count += other.count;
+        self.total += other.total;
+        self.min = self.min.into_iter().chain(other.min).min();
+        self.max = self.max.max(other.max);
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += other;
+        }
     }
 }
 
 impl Statistics {
-    pub fn print_nthreads(&self, nthreads: u32) {
+    /// Time spent working on files, summed up over all threads
+    pub fn busy_time(&self) -> time::Duration {
+        self.decoded.time_total() + self.encoded.time_total() + self.copied.time_total() + self.moved.time_total()
+            + self.cached.time_total()
+    }
+
+    /// Busy time per wall time, i.e. the average number of threads working at the same time
+    pub fn speedup(&self) -> f64 {
+        match self.wall_time.as_secs_f64() {
+            wall if wall > 0.0 => self.busy_time().as_secs_f64() / wall,
+            _ => 0.0,
+        }
+    }
+
+    /// What was done with the file, for the statistics of a single job
+    ///
+    /// One of `convert`, `cache`, `copy`, `move`, `ignore` or `none`.
+    pub fn job_action(&self) -> &'static str {
+        if self.decoded.count() > 0 {
+            "convert"
+        } else if self.cached.count() > 0 {
+            "cache"
+        } else if self.copied.count() > 0 {
+            "copy"
+        } else if self.moved.count() > 0 {
+            "move"
+        } else if self.quarantined.count() > 0 {
+            "quarantine"
+        } else if self.ignored.count() > 0 {
+            "ignore"
+        } else {
+            "none"
+        }
+    }
+
+    /// Time from starting to finishing the file, for the statistics of a single job
+    pub fn job_time(&self) -> time::Duration {
+        self.extensions.values().map(|item| item.time_total()).sum()
+    }
+
+    pub fn print_summary(&self, nthreads: u32) {
         print!("Total ");
         self.total.print();
+        println!("Wall time {}, busy for {} (speedup {:.2}x with {} threads)", fmt_duration(&self.wall_time),
+            fmt_duration(&self.busy_time()), self.speedup(), nthreads);
+        if !self.cpu_time.is_zero() {
+            println!("CPU time {} ({:.2} cores on average)", fmt_duration(&self.cpu_time),
+                self.cpu_time.as_secs_f64() / self.wall_time.as_secs_f64().max(f64::EPSILON));
+        }
         print!("Decoded ");
-        self.decoded.print_nthreads(nthreads);
+        self.decoded.print_busy();
+        self.decoded.print_distribution("MP");
         print!("Encoded ");
-        self.encoded.print_nthreads(nthreads);
+        self.encoded.print_busy();
+        self.encoded.print_distribution("MP");
         print!("Copied ");
-        self.copied.print_nthreads(nthreads);
+        self.copied.print_busy();
+        self.copied.print_distribution("MiB");
         print!("Moved ");
-        self.moved.print_nthreads(nthreads);
+        self.moved.print_busy();
+        if self.cached.count() > 0 {
+            print!("Restored from cache ");
+            self.cached.print_busy();
+        }
         print!("Ignored ");
-        self.ignored.print_nthreads(nthreads);
+        self.ignored.print_busy();
         print!("Encountered errors on ");
-        self.errors.print_nthreads(nthreads);
+        self.errors.print_busy();
+        if self.timeouts.count() > 0 {
+            println!("Timed out on {} files", self.timeouts.count());
+        }
+        if self.retried.count() > 0 {
+            println!("Retried {} times after transient errors", self.retried.count());
+        }
+        if self.quarantined.count() > 0 {
+            println!("Quarantined {} corrupt raws", self.quarantined.count());
+        }
+        if self.salvaged.count() > 0 {
+            println!("Salvaged {} corrupt raws", self.salvaged.count());
+        }
+        self.print_extensions();
+    }
+
+    /// Records the processing time of `file` for the per-extension breakdown
+    pub fn record_extension(&mut self, file: &Path, time: time::Duration) {
+        let extension = file.extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_else(|| String::from("(none)"));
+        self.extensions.entry(extension).or_default().record(time);
+    }
+
+    /// Prints a table of the processing times per extension
+    pub fn print_extensions(&self) {
+        if self.extensions.is_empty() {
+            return;
+        }
+        println!();
+        println!("{:<10} {:>7} {:>14} {:>12} {:>12} {:>12}", "Extension", "Files", "Total", "Avg", "p95", "Max");
+        for (extension, item) in &self.extensions {
+            println!("{:<10} {:>7} {:>14} {:>12} {:>12} {:>12}", extension, item.count(),
+                fmt_duration(&item.time_total()), fmt_duration(&item.time_avg()),
+                fmt_duration(&item.time_percentile(0.95)), fmt_duration(&item.time_max()));
+        }
+    }
+
+    pub fn print_windows(&self) {
+        for (name, window) in WINDOWS {
+            println!("Last {}: {} files ({} decoded, {} copied, {} moved, {} errors)", name,
+                self.total.count_since(window), self.decoded.count_since(window),
+                self.copied.count_since(window), self.moved.count_since(window),
+                self.errors.count_since(window));
+        }
+    }
+
+    pub fn prune_windows(&mut self) {
+        let max_age = WINDOWS.iter().map(|(_, w)| *w).max().unwrap_or_default();
+        self.total.prune(max_age);
+        self.decoded.prune(max_age);
+        self.encoded.prune(max_age);
+        self.copied.prune(max_age);
+        self.moved.prune(max_age);
+        self.cached.prune(max_age);
+        self.errors.prune(max_age);
+        self.timeouts.prune(max_age);
+        self.retried.prune(max_age);
+        self.quarantined.prune(max_age);
+        self.salvaged.prune(max_age);
+        self.ignored.prune(max_age);
+    }
+
+    /// Bounds the memory of statistics that are extended indefinitely (e.g. the lifetime statistics in watch mode)
+    ///
+    /// Prunes the rolling windows, folds all but the most recent times into totals (see
+    /// `StatisticsItem::compact()`) and drops the checksums and report entries, which have to be
+    /// written before.
+    pub fn compact(&mut self) {
+        self.prune_windows();
+        let items = [
+            &mut self.total, &mut self.decoded, &mut self.encoded, &mut self.copied, &mut self.moved,
+            &mut self.cached, &mut self.errors, &mut self.timeouts, &mut self.retried, &mut self.quarantined,
+            &mut self.salvaged, &mut self.ignored,
+        ];
+        for item in items.into_iter().chain(self.extensions.values_mut()) {
+            item.compact();
+        }
+        self.checksums = Vec::new();
+        self.report = Vec::new();
     }
 
     pub fn extend(&mut self, other: &Statistics) -> &mut Statistics {
@@ -85,9 +374,37 @@ impl Statistics {
         self.encoded.extend(&other.encoded);
         self.copied.extend(&other.copied);
         self.moved.extend(&other.moved);
+        self.cached.extend(&other.cached);
         self.errors.extend(&other.errors);
+        self.timeouts.extend(&other.timeouts);
+        self.retried.extend(&other.retried);
+        self.quarantined.extend(&other.quarantined);
+        self.salvaged.extend(&other.salvaged);
         self.ignored.extend(&other.ignored);
+        self.checksums.extend(other.checksums.iter().cloned());
+        self.report.extend(other.report.iter().cloned());
+        self.wall_time += other.wall_time;
+        self.cpu_time += other.cpu_time;
+        for (extension, item) in &other.extensions {
+            self.extensions.entry(extension.clone()).or_default().extend(item);
+        }
 
         self
     }
 }
+
+/// CPU time (user and system) the process has used so far, `None` if it can not be determined
+#[cfg(unix)]
+pub fn process_cpu_time() -> Option<time::Duration> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let duration = |t: libc::timeval| time::Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    Some(duration(usage.ru_utime) + duration(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+pub fn process_cpu_time() -> Option<time::Duration> {
+    None
+}
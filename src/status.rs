@@ -0,0 +1,134 @@
+use crate::*;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+
+/// Time a client gets to send its request and to take the response, so a stalled client can not block the server
+const CLIENT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Progress of the current run as reported by the status server
+#[derive(Default)]
+struct RunStatus {
+    total: usize,
+    done: usize,
+    started: Option<Instant>,
+    last_file: String,
+    decoded: u32,
+    encoded: u32,
+    copied: u32,
+    moved: u32,
+    ignored: u32,
+    errors: u32,
+    activity: Option<Activity>,
+    /// Statistics of all finished files, for the metrics
+    statistics: Statistics,
+}
+
+/// Tiny HTTP server answering every request with the progress of the run as JSON
+///
+/// Requests for `/metrics` are answered with the statistics in the Prometheus text format instead.
+#[derive(Clone)]
+pub struct StatusServer {
+    status: Arc<Mutex<RunStatus>>,
+}
+
+
+impl StatusServer {
+    /// Binds to `addr` and serves the status from a background thread
+    pub fn start(addr: &str) -> Result<StatusServer, String> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| format!("unable to listen on {} ({})", addr, e))?;
+        let server = StatusServer { status: Arc::default() };
+
+        let status = server.status.clone();
+        std::thread::Builder::new()
+            .name(String::from("status-server"))
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let _ = respond(stream, &status);
+                }
+            })
+            .map_err(|e| e.to_string())?;
+
+        Ok(server)
+    }
+
+    /// Shows which files the threads of `batch` are working on
+    pub fn watch_batch(&self, batch: &Batch) {
+        self.status.lock().unwrap().activity = Some(batch.activity_handle());
+    }
+
+    pub fn update(&self, progress: &Progress) {
+        let mut status = self.status.lock().unwrap();
+        match progress {
+            Progress::Started { total, .. } => {
+                status.total += total;
+                status.started.get_or_insert_with(Instant::now);
+            },
+            Progress::Discovered { count } => status.total += count,
+            Progress::JobFinished { file, statistics, .. } => {
+                status.done += 1;
+                status.last_file = file.display().to_string();
+                status.decoded += statistics.decoded.count();
+                status.encoded += statistics.encoded.count();
+                status.copied += statistics.copied.count();
+                status.moved += statistics.moved.count();
+                status.ignored += statistics.ignored.count();
+                status.errors += statistics.errors.count();
+                status.statistics.extend(statistics);
+            },
+            Progress::Deferred { .. } | Progress::JobStarted { .. } | Progress::JobFailed { .. } | Progress::Cancelled { .. } => (),
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, status: &Mutex<RunStatus>) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    // only the path of the request line matters, but the request has to be read before answering
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf)?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (content_type, body) = if path == "/metrics" {
+        ("text/plain; version=0.0.4", prometheus_metrics(&status.lock().unwrap().statistics))
+    } else {
+        ("application/json", status_json(&status.lock().unwrap()))
+    };
+    write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                    Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{}", content_type, body.len(), body)
+}
+
+/// Escapes a string for use inside a JSON string literal
+pub fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn status_json(status: &RunStatus) -> String {
+    let active = match &status.activity {
+        Some(activity) => activity.lock().unwrap().iter()
+            .map(|(thread, file)| format!("\"{}\":\"{}\"", json_escape(thread), json_escape(file)))
+            .collect::<Vec<_>>()
+            .join(","),
+        None => String::new(),
+    };
+    let elapsed = status.started.map(|s| s.elapsed().as_secs_f64()).unwrap_or(0.0);
+
+    format!("{{\"total\":{},\"done\":{},\"elapsed_secs\":{:.1},\"last_file\":\"{}\",\"active\":{{{}}},\
+             \"statistics\":{{\"decoded\":{},\"encoded\":{},\"copied\":{},\"moved\":{},\"ignored\":{},\"errors\":{}}}}}",
+        status.total, status.done, elapsed, json_escape(&status.last_file), active,
+        status.decoded, status.encoded, status.copied, status.moved, status.ignored, status.errors)
+}
@@ -0,0 +1,191 @@
+use crate::*;
+
+use std::sync::Arc;
+
+
+/// Backend that converted files are uploaded to
+pub trait Storage: Send + Sync {
+    /// Whether an object already exists at `key`
+    fn exists(&self, key: &str) -> Result<bool, String>;
+
+    /// Uploads the local file `path` to `key`
+    fn put(&self, path: &Path, key: &str) -> Result<(), String>;
+
+    /// Human readable location of `key` for messages
+    fn describe(&self, key: &str) -> String;
+
+    /// Completes the output after the last upload (e.g. writes the index of an archive)
+    fn finish(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Output that is written to a local staging directory and uploaded to a `Storage` backend
+///
+/// Jobs write their outputs below `staging` as usual, each file is uploaded and removed
+/// again as soon as it is complete.
+#[derive(Clone)]
+pub struct RemoteOutput {
+    pub staging: PathBuf,
+    storage: Arc<dyn Storage>,
+}
+
+
+impl RemoteOutput {
+    /// Opens the backend for `url` and creates a fresh staging directory
+    pub fn open(url: &str) -> Result<RemoteOutput, String> {
+        let storage: Arc<dyn Storage> = match url.split_once("://") {
+            Some(("s3", _)) => open_s3(url)?,
+            Some(("sftp", _)) => open_sftp(url)?,
+            Some((scheme, _)) => return Err(format!("unsupported output scheme {:?}", scheme)),
+            None => return Err(format!("{:?} is not a remote location", url)),
+        };
+
+        RemoteOutput::with_storage(storage)
+    }
+
+    /// Writes all outputs into the archive `path` (see `ArchiveStorage`)
+    pub fn archive(path: &Path) -> Result<RemoteOutput, String> {
+        RemoteOutput::with_storage(open_archive(path)?)
+    }
+
+    fn with_storage(storage: Arc<dyn Storage>) -> Result<RemoteOutput, String> {
+        let staging = std::env::temp_dir().join(format!("raw-to-img-staging-{}", std::process::id()));
+        fs::create_dir_all(&staging).map_err(|e| e.to_string())?;
+        Ok(RemoteOutput { staging, storage })
+    }
+
+    /// Object key of a file in the staging directory
+    pub fn key(&self, local: &Path) -> String {
+        let relative = local.strip_prefix(&self.staging).unwrap_or(local);
+        relative.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Whether the remote counterpart of `local` already exists (errors count as missing)
+    pub fn exists(&self, local: &Path) -> bool {
+        self.storage.exists(&self.key(local)).unwrap_or(false)
+    }
+
+    /// Uploads `local` and removes it from the staging directory
+    pub fn upload(&self, local: &Path) -> Result<(), String> {
+        let key = self.key(local);
+        self.storage.put(local, &key)?;
+        let _ = fs::remove_file(local);
+        println!("Uploaded {:?} to {}", local, self.storage.describe(&key));
+        Ok(())
+    }
+
+    /// Completes the output once all files have been uploaded
+    pub fn finish(&self) -> Result<(), String> {
+        self.storage.finish()
+    }
+
+    /// Removes the staging directory and everything left in it
+    pub fn cleanup(&self) {
+        let _ = fs::remove_dir_all(&self.staging);
+    }
+}
+
+/// Whether `path` refers to a remote location like `s3://bucket/prefix` or `sftp://host/path`
+pub fn is_remote(path: &Path) -> bool {
+    path.to_string_lossy().contains("://")
+}
+
+#[cfg(feature = "s3")]
+fn open_s3(url: &str) -> Result<Arc<dyn Storage>, String> {
+    Ok(Arc::new(S3Storage::new(url)?))
+}
+
+#[cfg(not(feature = "s3"))]
+fn open_s3(_url: &str) -> Result<Arc<dyn Storage>, String> {
+    Err(String::from("This build does not support S3 output (enable the s3 feature)"))
+}
+
+#[cfg(feature = "archive")]
+fn open_archive(path: &Path) -> Result<Arc<dyn Storage>, String> {
+    Ok(Arc::new(ArchiveStorage::create(path)?))
+}
+
+#[cfg(not(feature = "archive"))]
+fn open_archive(_path: &Path) -> Result<Arc<dyn Storage>, String> {
+    Err(String::from("This build does not support archive output (enable the archive feature)"))
+}
+
+#[cfg(feature = "sftp")]
+fn open_sftp(url: &str) -> Result<Arc<dyn Storage>, String> {
+    Ok(Arc::new(SftpStorage::new(url)?))
+}
+
+#[cfg(not(feature = "sftp"))]
+fn open_sftp(_url: &str) -> Result<Arc<dyn Storage>, String> {
+    Err(String::from("This build does not support SFTP output (enable the sftp feature)"))
+}
+
+/// S3 (or S3 compatible) bucket
+///
+/// Credentials are read from the usual `AWS_*` environment variables and `~/.aws/credentials`,
+/// the region from `AWS_REGION` and a custom endpoint (e.g. MinIO) from `AWS_ENDPOINT_URL`.
+#[cfg(feature = "s3")]
+pub struct S3Storage {
+    bucket: Box<s3::Bucket>,
+    prefix: String,
+}
+
+#[cfg(feature = "s3")]
+impl S3Storage {
+    /// Connects to the bucket of `url` (`s3://bucket/prefix`)
+    pub fn new(url: &str) -> Result<S3Storage, String> {
+        let location = url.strip_prefix("s3://")
+            .ok_or_else(|| format!("{:?} is not an s3:// url", url))?;
+        let (bucket_name, prefix) = location.split_once('/').unwrap_or((location, ""));
+
+        let region_name = std::env::var("AWS_REGION").unwrap_or_else(|_| String::from("us-east-1"));
+        let region = match std::env::var("AWS_ENDPOINT_URL") {
+            Ok(endpoint) => s3::Region::Custom { region: region_name, endpoint },
+            Err(_) => region_name.parse().map_err(|e| format!("invalid region ({})", e))?,
+        };
+        let credentials = s3::creds::Credentials::default()
+            .map_err(|e| format!("unable to load S3 credentials ({})", e))?;
+        let bucket = s3::Bucket::new(bucket_name, region, credentials)
+            .map_err(|e| e.to_string())?
+            .with_path_style();
+
+        Ok(S3Storage { bucket, prefix: prefix.trim_matches('/').to_string() })
+    }
+
+    fn object(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{}", self.prefix, key)
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+impl Storage for S3Storage {
+    fn exists(&self, key: &str) -> Result<bool, String> {
+        match self.bucket.head_object(self.object(key)) {
+            Ok((_, code)) => Ok(code == 200),
+            Err(s3::error::S3Error::HttpFailWithBody(404, _)) => Ok(false),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn put(&self, path: &Path, key: &str) -> Result<(), String> {
+        let content = fs::read(path).map_err(|e| e.to_string())?;
+        let response = self.bucket.put_object(self.object(key), &content)
+            .map_err(|e| format!("unable to upload {} ({})", self.describe(key), e))?;
+        match response.status_code() {
+            200..=299 => Ok(()),
+            code => Err(format!("unable to upload {} (HTTP {})", self.describe(key), code)),
+        }
+    }
+
+    fn describe(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket.name(), self.object(key))
+    }
+}
@@ -0,0 +1,156 @@
+use crate::*;
+
+
+/// Number of entries of the table encoding linear values after the color matrix
+const ENCODE_STEPS: usize = 4096;
+/// Luminance weights of linear sRGB (Rec. 709)
+const LUMA: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Look of the converted images
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Style {
+    /// Colors as decoded
+    #[default]
+    Neutral,
+    /// Black and white with strong contrast
+    HighContrastBw,
+    /// Muted colors with firm contrast, similar to slide film
+    Chrome,
+    /// Slightly warm with soft contrast and restrained saturation, flattering skin tones
+    Portrait,
+}
+
+/// 3D lookup table read from a `.cube` file
+#[derive(Clone, Debug)]
+pub struct Lut3d {
+    size: usize,
+    /// Output colors with red changing fastest, then green, then blue
+    table: Vec<[f32; 3]>,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+}
+
+
+impl Style {
+    /// Color matrix (applied to linear values) and contrast (applied to gamma encoded values) of the style
+    fn parameters(&self) -> ([[f32; 3]; 3], f32) {
+        match self {
+            Style::Neutral => (saturation_matrix(1.0), 0.0),
+            Style::HighContrastBw => ([LUMA; 3], 0.6),
+            Style::Chrome => (saturation_matrix(0.75), 0.3),
+            Style::Portrait => {
+                let gains = [1.04, 1.0, 0.95];
+                let matrix = saturation_matrix(0.9);
+                (std::array::from_fn(|row| matrix[row].map(|v| v * gains[row])), -0.1)
+            },
+        }
+    }
+
+    /// Applies the style to an image
+    pub fn apply(&self, image: &mut imagepipe::SRGBImage) {
+        if *self == Style::Neutral {
+            return;
+        }
+
+        let (matrix, contrast) = self.parameters();
+        let linear = pixelops::srgb8_to_linear_table();
+        let encode: Vec<u8> = (0..ENCODE_STEPS)
+            .map(|i| {
+                let v = pixelops::linear_to_srgb(i as f32 / (ENCODE_STEPS - 1) as f32);
+                (pixelops::s_curve(v, contrast) * 255.0).round() as u8
+            })
+            .collect();
+
+        let max = (ENCODE_STEPS - 1) as f32;
+        for pixel in image.data.chunks_exact_mut(3) {
+            let rgb = [linear[pixel[0] as usize], linear[pixel[1] as usize], linear[pixel[2] as usize]];
+            for (value, row) in pixel.iter_mut().zip(&matrix) {
+                let mixed = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2];
+                *value = encode[(mixed * max).clamp(0.0, max).round() as usize];
+            }
+        }
+    }
+}
+
+impl Lut3d {
+    /// Reads a 3D LUT in the Adobe/Resolve `.cube` format
+    pub fn load(path: &Path) -> Result<Lut3d, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read LUT {:?} ({})", path, e))?;
+        let error = |line: usize, message: &str| format!("Invalid LUT {:?}, line {}: {}", path, line + 1, message);
+
+        let mut size = 0;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("TITLE") {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let keyword = fields.next().unwrap_or_default();
+            let numbers = |fields: std::str::SplitWhitespace| -> Result<[f32; 3], String> {
+                let values: Vec<f32> = fields.map(|v| v.parse().map_err(|_| error(i, "invalid number")))
+                    .collect::<Result<_, _>>()?;
+                values.try_into().map_err(|_| error(i, "expected three values"))
+            };
+            match keyword {
+                "LUT_3D_SIZE" => size = fields.next().and_then(|s| s.parse().ok())
+                    .filter(|s| (2..=256).contains(s))
+                    .ok_or_else(|| error(i, "invalid LUT_3D_SIZE"))?,
+                "LUT_1D_SIZE" => return Err(error(i, "1D LUTs are not supported")),
+                "DOMAIN_MIN" => domain_min = numbers(fields)?,
+                "DOMAIN_MAX" => domain_max = numbers(fields)?,
+                _ => table.push(numbers(line.split_whitespace())?),
+            }
+        }
+
+        if size == 0 {
+            return Err(format!("Invalid LUT {:?}: missing LUT_3D_SIZE", path));
+        }
+        if table.len() != size * size * size {
+            return Err(format!("Invalid LUT {:?}: expected {} entries, found {}", path, size * size * size, table.len()));
+        }
+        Ok(Lut3d { size, table, domain_min, domain_max })
+    }
+
+    /// Maps every pixel of the (gamma encoded) image through the LUT with trilinear interpolation
+    pub fn apply(&self, image: &mut imagepipe::SRGBImage) {
+        let max = (self.size - 1) as f32;
+        let at = |r: usize, g: usize, b: usize| self.table[(b * self.size + g) * self.size + r];
+
+        for pixel in image.data.chunks_exact_mut(3) {
+            let coordinates: [f32; 3] = std::array::from_fn(|c| {
+                let range = (self.domain_max[c] - self.domain_min[c]).max(f32::EPSILON);
+                ((pixel[c] as f32 / 255.0 - self.domain_min[c]) / range * max).clamp(0.0, max)
+            });
+            let low = coordinates.map(|v| v.floor() as usize);
+            let high = low.map(|v| (v + 1).min(self.size - 1));
+            let [fr, fg, fb] = std::array::from_fn(|c| coordinates[c] - low[c] as f32);
+
+            for (c, value) in pixel.iter_mut().enumerate() {
+                let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+                let corner = |r: usize, g: usize, b: usize| at(r, g, b)[c];
+                let bottom = lerp(
+                    lerp(corner(low[0], low[1], low[2]), corner(high[0], low[1], low[2]), fr),
+                    lerp(corner(low[0], high[1], low[2]), corner(high[0], high[1], low[2]), fr),
+                    fg);
+                let top = lerp(
+                    lerp(corner(low[0], low[1], high[2]), corner(high[0], low[1], high[2]), fr),
+                    lerp(corner(low[0], high[1], high[2]), corner(high[0], high[1], high[2]), fr),
+                    fg);
+                *value = (lerp(bottom, top, fb) * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+/// Matrix scaling the saturation of linear colors around their luminance
+fn saturation_matrix(saturation: f32) -> [[f32; 3]; 3] {
+    std::array::from_fn(|row| std::array::from_fn(|column| {
+        let identity = if row == column { 1.0 } else { 0.0 };
+        LUMA[column] * (1.0 - saturation) + identity * saturation
+    }))
+}
@@ -0,0 +1,123 @@
+use crate::*;
+
+
+/// Fraction of pixels allowed to clip at either end when stretching the levels
+const LEVELS_CLIP: f32 = 0.001;
+
+/// Brightest channel value from which on a pixel is treated as (nearly) clipped
+const HIGHLIGHT_THRESHOLD: u8 = 230;
+
+/// Curve mapping linear scene values to output values
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum ToneCurve {
+    /// Plain sRGB gamma, values above white are clipped
+    #[default]
+    Linear,
+    /// Film-like curve with a soft shoulder that compresses highlights instead of clipping them
+    Filmic,
+    /// Gentle S-curve similar to the out-of-camera JPEGs of most bodies
+    CameraLike,
+}
+
+/// Tone mapping adjustments applied to decoded images
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ToneSettings {
+    /// Stretch the black and white points of every image to the full range
+    pub auto_levels: bool,
+    /// Rebuild clipped highlights as neutral tones instead of keeping the color casts of partially clipped channels
+    pub highlight_recovery: bool,
+    pub curve: ToneCurve,
+    /// Contrast adjustment between -1 (flat) and 1 (strong), 0 leaves the image unchanged
+    pub contrast: f32,
+}
+
+
+impl ToneSettings {
+    /// Whether these settings change the image at all
+    pub fn is_identity(&self) -> bool {
+        !self.auto_levels && !self.highlight_recovery && self.curve == ToneCurve::Linear && self.contrast == 0.0
+    }
+
+    /// Applies auto levels, highlight recovery, the tone curve and the contrast adjustment to an image
+    pub fn apply(&self, image: &mut imagepipe::SRGBImage) {
+        if self.auto_levels {
+            auto_levels(&mut image.data);
+        }
+        if self.highlight_recovery {
+            recover_highlights(&mut image.data);
+        }
+        if self.curve != ToneCurve::Linear || self.contrast != 0.0 {
+            pixelops::apply_lut_rgb8(&mut image.data, &self.lut());
+        }
+    }
+
+    /// Lookup table combining the tone curve (on linear values) and the contrast (on gamma encoded values)
+    fn lut(&self) -> [u8; 256] {
+        let linear = pixelops::srgb8_to_linear_table();
+        let contrast = self.contrast.clamp(-1.0, 1.0);
+        std::array::from_fn(|i| {
+            let v = match self.curve {
+                ToneCurve::Linear => pixelops::linear_to_srgb(linear[i]),
+                ToneCurve::Filmic => pixelops::linear_to_srgb(filmic(linear[i])),
+                ToneCurve::CameraLike => pixelops::s_curve(pixelops::linear_to_srgb(linear[i]), 0.35),
+            };
+            let v = pixelops::s_curve(v, contrast);
+            (v * 255.0).round().clamp(0.0, 255.0) as u8
+        })
+    }
+}
+
+/// ACES filmic approximation (Narkowicz), scaled so that white stays white
+fn filmic(v: f32) -> f32 {
+    let aces = |x: f32| (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+    // expose up first, so midtones keep their brightness while the shoulder takes the highlights
+    const EXPOSURE: f32 = 1.6;
+    aces(v * EXPOSURE) / aces(EXPOSURE)
+}
+
+/// Desaturates pixels with clipped channels towards their brightest channel
+///
+/// A partially clipped pixel has lost the true ratio of its channels, which shows up as magenta or cyan
+/// casts in skies and specular highlights. The closer the brightest channel is to clipping, the more the
+/// other channels are pulled up to it, so the highlight blends smoothly into white.
+fn recover_highlights(data: &mut [u8]) {
+    let range = (255 - HIGHLIGHT_THRESHOLD) as f32;
+    for pixel in data.chunks_exact_mut(3) {
+        let max = pixel.iter().copied().max().unwrap_or_default();
+        if max <= HIGHLIGHT_THRESHOLD {
+            continue;
+        }
+
+        let weight = (max - HIGHLIGHT_THRESHOLD) as f32 / range;
+        for value in pixel.iter_mut() {
+            *value = (*value as f32 + (max - *value) as f32 * weight).round() as u8;
+        }
+    }
+}
+
+/// Stretches the levels so the darkest and brightest pixels (ignoring outliers) span the full range
+///
+/// All channels are stretched by the same amount, so the white balance is left untouched.
+fn auto_levels(data: &mut [u8]) {
+    let mut histogram = [0usize; 256];
+    for pixel in data.chunks_exact(3) {
+        let luma = (pixel[0] as u32 * 54 + pixel[1] as u32 * 183 + pixel[2] as u32 * 19) >> 8;
+        histogram[luma as usize] += 1;
+    }
+
+    let total: usize = histogram.iter().sum();
+    let clip = (total as f32 * LEVELS_CLIP) as usize;
+    let percentile = |levels: &mut dyn Iterator<Item = usize>| {
+        let mut seen = 0;
+        levels.find(|&level| { seen += histogram[level]; seen > clip }).unwrap_or_default()
+    };
+    let black = percentile(&mut (0..256));
+    let white = percentile(&mut (0..256).rev());
+    if white <= black || (black == 0 && white == 255) {
+        return;
+    }
+
+    let scale = 255.0 / (white - black) as f32;
+    let lut: [u8; 256] = std::array::from_fn(|i| ((i as f32 - black as f32) * scale).round().clamp(0.0, 255.0) as u8);
+    pixelops::apply_lut_rgb8(data, &lut);
+}
@@ -0,0 +1,126 @@
+use crate::*;
+
+use sha2::Digest;
+use std::io::{Read, Write};
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+/// Size of the chunks copied between checks of the rate limit
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// Time after which an idle rate limit forgets earlier transfers, so they do not allow bursts
+const IDLE_RESET: time::Duration = time::Duration::from_secs(1);
+
+/// Rate limit shared by all copies of the process (`None` if unlimited)
+static IO_LIMIT: OnceLock<RateLimit> = OnceLock::new();
+
+/// Whether copies are read back and compared against their source
+static VERIFY_COPIES: AtomicBool = AtomicBool::new(false);
+
+/// Whether files are moved to the trash instead of being deleted
+static USE_TRASH: AtomicBool = AtomicBool::new(false);
+
+struct RateLimit {
+    bytes_per_second: f64,
+    /// Start of the current window and number of bytes transferred since
+    window: Mutex<(Instant, u64)>,
+}
+
+
+impl RateLimit {
+    /// Accounts for `bytes` that were just transferred, sleeping until they are within the limit
+    fn consume(&self, bytes: u64) {
+        let delay = {
+            let mut window = self.window.lock().unwrap();
+            let elapsed = window.0.elapsed();
+            let allowed = time::Duration::from_secs_f64(window.1 as f64 / self.bytes_per_second);
+            if elapsed > allowed + IDLE_RESET {
+                *window = (Instant::now(), 0);
+            }
+            window.1 += bytes;
+            time::Duration::from_secs_f64(window.1 as f64 / self.bytes_per_second).saturating_sub(window.0.elapsed())
+        };
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+/// Limits the throughput of all following copies to `bytes_per_second` (combined over all threads)
+///
+/// Only the first call has an effect.
+pub fn set_io_limit(bytes_per_second: u64) {
+    let _ = IO_LIMIT.set(RateLimit {
+        bytes_per_second: bytes_per_second.max(1) as f64,
+        window: Mutex::new((Instant::now(), 0)),
+    });
+}
+
+/// Makes all following copies read back their destination and compare its hash against the source
+pub fn set_verify_copies(verify: bool) {
+    VERIFY_COPIES.store(verify, Ordering::Relaxed);
+}
+
+/// Makes all following calls of `discard_file()` move files to the trash instead of deleting them
+pub fn set_use_trash(use_trash: bool) {
+    USE_TRASH.store(use_trash, Ordering::Relaxed);
+}
+
+/// Deletes a file the user may want back (unlike temporary files), or moves it to the trash
+/// with `set_use_trash()`
+pub fn discard_file(path: &Path) -> io::Result<()> {
+    #[cfg(not(target_arch = "wasm32"))]
+    if USE_TRASH.load(Ordering::Relaxed) {
+        return trash::delete(path).map_err(io::Error::other);
+    }
+    fs::remove_file(path)
+}
+
+/// Copies a file like `fs::copy()`, respecting the rate limit set with `set_io_limit()`
+///
+/// With `set_verify_copies()`, the data is hashed while it is copied, and the copy fails unless both
+/// the source and the destination have the same hash when read again. Returns the number of bytes copied.
+pub fn copy_file(from: &Path, to: &Path) -> io::Result<u64> {
+    let limit = IO_LIMIT.get();
+    let verify = VERIFY_COPIES.load(Ordering::Relaxed);
+    if limit.is_none() && !verify {
+        return fs::copy(from, to);
+    }
+
+    let mut hasher = sha2::Sha256::new();
+    let mut source = fs::File::open(from)?;
+    let permissions = source.metadata()?.permissions();
+    let mut target = fs::File::create(to)?;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        let read = match source.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        target.write_all(&buffer[..read])?;
+        total += read as u64;
+        if verify {
+            hasher.update(&buffer[..read]);
+        }
+        if let Some(limit) = limit {
+            limit.consume(read as u64);
+        }
+    }
+    target.set_permissions(permissions)?;
+    target.sync_all()?;
+    drop(target);
+
+    if verify {
+        let expected = format!("{:x}", hasher.finalize());
+        for (path, side) in [(from, "source"), (to, "copy")] {
+            let actual = hash_file(path, ChecksumAlgo::Sha256).map_err(io::Error::other)?;
+            if actual != expected {
+                return Err(io::Error::other(format!("verification failed, {} has hash {} instead of {}", side, actual, expected)));
+            }
+        }
+    }
+    Ok(total)
+}
@@ -0,0 +1,301 @@
+use crate::*;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen};
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Gauge, List, Paragraph, Row, Table};
+
+
+/// Number of finished jobs kept for the job table
+const RECENT_JOBS: usize = 200;
+
+/// Terminal interface showing the progress of the batches instead of printing it
+///
+/// Runs on its own thread until dropped. `p` pauses and resumes starting new jobs, `+` and `-`
+/// change the number of decode threads, `c` cancels the run (pressing it again aborts right away)
+/// and the arrow keys scroll the error log.
+pub struct Tui {
+    state: Arc<Mutex<TuiState>>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct TuiState {
+    total: usize,
+    done: usize,
+    started: Option<Instant>,
+    converted: u32,
+    copied: u32,
+    moved: u32,
+    ignored: u32,
+    errors: u32,
+    /// Name, outcome and duration of the most recently finished jobs
+    recent: VecDeque<(String, &'static str, time::Duration)>,
+    error_log: Vec<String>,
+    /// Number of error log lines scrolled past
+    scroll: usize,
+    activity: Option<Activity>,
+    /// Whether the screen has to be redrawn from scratch (e.g. after other output)
+    clear: bool,
+}
+
+/// Original stdout and stderr of the process while the interface is shown, restored when dropped
+///
+/// Messages printed by the jobs would end up in the middle of the interface, so stdout is discarded
+/// and the lines written to stderr are added to the error log. The interface draws on a duplicate
+/// of the original stdout.
+#[cfg(unix)]
+struct Redirect {
+    stdout: std::os::fd::OwnedFd,
+    stderr: std::os::fd::OwnedFd,
+}
+
+
+impl Tui {
+    /// Takes over the terminal, `cancelled` is set when the user cancels the run
+    pub fn start(control: RunControl, cancelled: Arc<AtomicBool>) -> Result<Tui, String> {
+        let state = Arc::new(Mutex::new(TuiState::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        let (redirect, output) = Redirect::start(&state)
+            .map_err(|e| format!("unable to redirect the output ({})", e))?;
+        #[cfg(not(unix))]
+        let output = io::stdout();
+        let mut terminal = init_terminal(output)
+            .map_err(|e| format!("unable to set up the terminal ({})", e))?;
+
+        let thread = {
+            let (state, stopped) = (state.clone(), stopped.clone());
+            thread::Builder::new()
+                .name(String::from("tui"))
+                .spawn(move || {
+                    while !stopped.load(Ordering::Relaxed) {
+                        {
+                            let mut state = state.lock().unwrap();
+                            if std::mem::take(&mut state.clear) {
+                                let _ = terminal.clear();
+                            }
+                            let cancelled = cancelled.load(Ordering::Relaxed);
+                            let _ = terminal.draw(|frame| draw(frame, &state, &control, cancelled));
+                        }
+                        if let Err(e) = handle_events(&state, &control, &cancelled) {
+                            state.lock().unwrap().error_log.push(format!("Unable to read terminal events ({})", e));
+                        }
+                    }
+                    // the terminal is restored through the original stdout
+                    #[cfg(unix)]
+                    drop(redirect);
+                    ratatui::restore();
+                })
+                .map_err(|e| { ratatui::restore(); e.to_string() })?
+        };
+
+        Ok(Tui { state, stopped, thread: Some(thread) })
+    }
+
+    /// Shows the workers of `batch`
+    pub fn watch_batch(&self, batch: &Batch) {
+        let mut state = self.state.lock().unwrap();
+        state.activity = Some(batch.activity_handle());
+        state.clear = true;
+    }
+
+    pub fn update(&self, progress: &Progress) {
+        let mut state = self.state.lock().unwrap();
+        match progress {
+            Progress::Started { total, .. } => {
+                state.total += total;
+                state.started.get_or_insert_with(Instant::now);
+            },
+            Progress::Discovered { count } => state.total += count,
+            Progress::Deferred { .. } | Progress::JobStarted { .. } => (),
+            Progress::JobFailed { file, error } => state.error_log.push(format!("{}: {}", file.display(), error)),
+            Progress::JobFinished { file, statistics, .. } => {
+                state.done += 1;
+                state.converted += statistics.decoded.count();
+                state.copied += statistics.copied.count();
+                state.moved += statistics.moved.count();
+                state.ignored += statistics.ignored.count();
+                state.errors += statistics.errors.count();
+                let outcome = if statistics.errors.count() > 0 { "error" } else { statistics.job_action() };
+                state.recent.push_front((file.display().to_string(), outcome, statistics.job_time()));
+                state.recent.truncate(RECENT_JOBS);
+            },
+            Progress::Cancelled { done, total, .. } => {
+                state.error_log.push(format!("Cancelled after {} of {} jobs", done, total));
+            },
+        }
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Switches the terminal behind `output` to the interface
+fn init_terminal<W: io::Write>(mut output: W) -> io::Result<Terminal<CrosstermBackend<W>>> {
+    enable_raw_mode()?;
+    ratatui::crossterm::execute!(output, EnterAlternateScreen)
+        .and_then(|_| Terminal::new(CrosstermBackend::new(output)))
+        .inspect_err(|_| { let _ = disable_raw_mode(); })
+}
+
+#[cfg(unix)]
+impl Redirect {
+    /// Redirects stdout and stderr, returning the redirect and a duplicate of the original stdout
+    fn start(state: &Arc<Mutex<TuiState>>) -> io::Result<(Redirect, io::BufWriter<fs::File>)> {
+        use std::io::BufRead;
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        let dup = |fd: i32| match unsafe { libc::dup(fd) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+        };
+        let mut pipe = [0; 2];
+        if unsafe { libc::pipe(pipe.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (reader, writer) = unsafe { (fs::File::from_raw_fd(pipe[0]), OwnedFd::from_raw_fd(pipe[1])) };
+        let null = fs::OpenOptions::new().write(true).open("/dev/null")?;
+        let redirect = Redirect { stdout: dup(libc::STDOUT_FILENO)?, stderr: dup(libc::STDERR_FILENO)? };
+        let output = fs::File::from(dup(redirect.stdout.as_raw_fd())?);
+
+        let _ = io::Write::flush(&mut io::stdout());
+        unsafe {
+            libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO);
+            libc::dup2(writer.as_raw_fd(), libc::STDERR_FILENO);
+        }
+        // ends once stderr is restored, which closes the last writing end of the pipe
+        let state = state.clone();
+        thread::spawn(move || {
+            for line in io::BufReader::new(reader).lines().map_while(Result::ok) {
+                state.lock().unwrap().error_log.push(line);
+            }
+        });
+        Ok((redirect, io::BufWriter::new(output)))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        use std::os::fd::AsRawFd;
+        unsafe {
+            libc::dup2(self.stdout.as_raw_fd(), libc::STDOUT_FILENO);
+            libc::dup2(self.stderr.as_raw_fd(), libc::STDERR_FILENO);
+        }
+    }
+}
+
+fn handle_events(state: &Mutex<TuiState>, control: &RunControl, cancelled: &AtomicBool) -> io::Result<()> {
+    if !event::poll(time::Duration::from_millis(200))? {
+        return Ok(());
+    }
+    let key = match event::read()? {
+        Event::Key(key) if key.kind == KeyEventKind::Press => key,
+        Event::Resize(..) => {
+            state.lock().unwrap().clear = true;
+            return Ok(());
+        },
+        _ => return Ok(()),
+    };
+
+    match key.code {
+        KeyCode::Char('p') if control.is_paused() => control.resume(),
+        KeyCode::Char('p') => control.pause(),
+        KeyCode::Char('+') => change_threads(state, control, control.threads() + 1),
+        KeyCode::Char('-') => change_threads(state, control, control.threads().saturating_sub(1)),
+        // Ctrl-C arrives as a key press in raw mode
+        KeyCode::Char('c') | KeyCode::Char('q') | KeyCode::Esc => cancel(cancelled),
+        KeyCode::Up => {
+            let mut state = state.lock().unwrap();
+            state.scroll = state.scroll.saturating_sub(1);
+        },
+        KeyCode::Down => {
+            let mut state = state.lock().unwrap();
+            state.scroll = (state.scroll + 1).min(state.error_log.len().saturating_sub(1));
+        },
+        _ => (),
+    }
+    Ok(())
+}
+
+fn change_threads(state: &Mutex<TuiState>, control: &RunControl, threads: usize) {
+    if let Err(e) = control.set_threads(threads) {
+        state.lock().unwrap().error_log.push(format!("Unable to change the number of threads ({})", e));
+    }
+}
+
+/// Stops new jobs the first time and aborts the second time, like Ctrl-C does without the interface
+fn cancel(cancelled: &AtomicBool) {
+    if cancelled.swap(true, Ordering::Relaxed) {
+        ratatui::restore();
+        for file in remove_partial_outputs() {
+            println!("Removed partial output {:?}", file);
+        }
+        std::process::exit(130);
+    }
+}
+
+fn draw(frame: &mut Frame, state: &TuiState, control: &RunControl, cancelled: bool) {
+    let workers: Vec<(String, String)> = match &state.activity {
+        Some(activity) => activity.lock().unwrap().iter().map(|(t, f)| (t.clone(), f.clone())).collect(),
+        None => Vec::new(),
+    };
+    let [header, threads, jobs, errors, help] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(workers.len().max(1) as u16 + 3),
+        Constraint::Min(5),
+        Constraint::Length(8),
+        Constraint::Length(1),
+    ]).areas(frame.area());
+
+    let paused = control.is_paused();
+    let run_state = match (cancelled, paused, control.threads()) {
+        (true, _, _) => String::from("cancelling"),
+        (false, true, _) => String::from("paused"),
+        (false, false, 0) => String::from("running"),
+        (false, false, threads) => format!("running with {} threads", threads),
+    };
+    let elapsed = state.started.map(|s| s.elapsed()).unwrap_or_default();
+    let ratio = if state.total > 0 { (state.done as f64 / state.total as f64).min(1.0) } else { 0.0 };
+    let label = format!("{}/{} files, {} elapsed, {} converted, {} copied, {} moved, {} ignored, {} errors",
+        state.done, state.total, fmt_duration(&elapsed), state.converted, state.copied, state.moved,
+        state.ignored, state.errors);
+    frame.render_widget(Gauge::default()
+        .block(Block::bordered().title(format!(" raw-to-img ({}) ", run_state)))
+        .gauge_style(Style::new().fg(if paused { Color::Yellow } else { Color::Green }))
+        .ratio(ratio)
+        .label(label), header);
+
+    let rows = workers.into_iter().map(|(thread, file)| Row::new(vec![thread, file]));
+    frame.render_widget(Table::new(rows, [Constraint::Length(28), Constraint::Fill(1)])
+        .header(Row::new(vec!["Thread", "File"]).style(Style::new().bold()))
+        .block(Block::bordered().title(" Threads ")), threads);
+
+    let rows = state.recent.iter().map(|(name, outcome, duration)| {
+        let style = if *outcome == "error" { Style::new().red() } else { Style::new() };
+        Row::new(vec![name.clone(), outcome.to_string(), fmt_duration(duration)]).style(style)
+    });
+    frame.render_widget(Table::new(rows, [Constraint::Fill(1), Constraint::Length(8), Constraint::Length(12)])
+        .header(Row::new(vec!["File", "Result", "Time"]).style(Style::new().bold()))
+        .block(Block::bordered().title(" Finished jobs ")), jobs);
+
+    frame.render_widget(List::new(state.error_log.iter().skip(state.scroll).map(String::as_str))
+        .style(Style::new().red())
+        .block(Block::bordered().title(format!(" Errors ({}) ", state.error_log.len()))), errors);
+
+    frame.render_widget(Paragraph::new("p pause/resume   +/- threads   c cancel (twice to abort)   up/down scroll errors")
+        .style(Style::new().dim()), help);
+}
@@ -0,0 +1,133 @@
+use crate::*;
+
+
+/// Outputs written after their run finished by more than this are considered modified by someone else
+const MODIFIED_TOLERANCE: f64 = 2.0;
+
+/// Result of undoing a run
+#[derive(Default)]
+pub struct UndoReport {
+    /// Outputs that were deleted
+    pub deleted: Vec<PathBuf>,
+    /// Moved files that were moved back, as (output, original location)
+    pub restored: Vec<(PathBuf, PathBuf)>,
+    /// Files that were left alone, with the reason
+    pub skipped: Vec<(PathBuf, String)>,
+}
+
+
+impl UndoReport {
+    pub fn is_ok(&self) -> bool {
+        self.skipped.is_empty()
+    }
+
+    pub fn print(&self) {
+        for file in &self.deleted {
+            println!("Deleted {:?}", file);
+        }
+        for (output, input) in &self.restored {
+            println!("Moved {:?} back to {:?}", output, input);
+        }
+        for (file, reason) in &self.skipped {
+            println!("Unable to revert {:?} ({})", file, reason);
+        }
+        println!("{} deleted, {} moved back, {} not reverted", self.deleted.len(), self.restored.len(), self.skipped.len());
+    }
+}
+
+/// Reverses the run `run` recorded in `index`
+///
+/// Outputs the run created are deleted (see `discard_file()`) and files it moved are moved back
+/// to their original location. Files that changed since the run, and moved files whose original
+/// location is taken again, are left alone and reported. Reverted files are removed from the index, so `--sync`
+/// processes them again. With `dry_run`, nothing is changed.
+pub fn undo_run(index: &Index, run: i64, dry_run: bool) -> Result<UndoReport, String> {
+    let output_base = index.runs()?.into_iter()
+        .find(|r| r.id == run)
+        .ok_or_else(|| format!("run {} not found in the index", run))?
+        .output;
+
+    let mut report = UndoReport::default();
+    for entry in index.run_entries(run)?.iter().rev().filter(|e| e.status == "ok") {
+        let reverted = match entry.action.as_str() {
+            "move" => restore_moved(entry, dry_run, &mut report),
+            "convert" | "cache" | "copy" => {
+                let deleted = delete_output(&entry.output, entry, dry_run, &mut report);
+                match &entry.original {
+                    Some(original) => delete_output(original, entry, dry_run, &mut report) && deleted,
+                    None => deleted,
+                }
+            },
+            _ => true,
+        };
+
+        if reverted && !dry_run {
+            index.forget(run, &entry.input)?;
+            for file in [Some(&entry.output), entry.original.as_ref()].into_iter().flatten() {
+                remove_empty_parents(file, &output_base);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Deletes `output` of `entry`, unless it was changed after the run
+fn delete_output(output: &Path, entry: &IndexEntry, dry_run: bool, report: &mut UndoReport) -> bool {
+    let metadata = match output.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return true,
+        Err(e) => { report.skipped.push((output.to_path_buf(), e.to_string())); return false },
+    };
+    let modified = metadata.modified().map(unix_time).unwrap_or(0.0);
+    if modified > entry.time + MODIFIED_TOLERANCE {
+        report.skipped.push((output.to_path_buf(), String::from("modified after the run")));
+        return false;
+    }
+
+    if !dry_run {
+        if let Err(e) = discard_file(output) {
+            report.skipped.push((output.to_path_buf(), e.to_string()));
+            return false;
+        }
+    }
+    report.deleted.push(output.to_path_buf());
+    true
+}
+
+/// Moves the output of a moved file back to where it came from, if it is still unchanged
+fn restore_moved(entry: &IndexEntry, dry_run: bool, report: &mut UndoReport) -> bool {
+    if entry.input.exists() {
+        report.skipped.push((entry.output.clone(), format!("{:?} exists again", entry.input)));
+        return false;
+    }
+    match hash_file(&entry.output, ChecksumAlgo::Sha256) {
+        Ok(hash) if hash == entry.hash => (),
+        Ok(_) => { report.skipped.push((entry.output.clone(), String::from("modified after the run"))); return false },
+        Err(e) => { report.skipped.push((entry.output.clone(), e)); return false },
+    }
+
+    if !dry_run {
+        let result = match entry.input.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }.and_then(|_| fs::rename(&entry.output, &entry.input));
+        if let Err(e) = result {
+            report.skipped.push((entry.output.clone(), e.to_string()));
+            return false;
+        }
+    }
+    report.restored.push((entry.output.clone(), entry.input.clone()));
+    true
+}
+
+/// Removes the directories containing `file` up to `base` as long as they are empty
+fn remove_empty_parents(file: &Path, base: &Path) {
+    let mut dir = file.parent();
+    while let Some(current) = dir {
+        if !current.starts_with(base) || current == base || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
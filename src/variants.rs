@@ -0,0 +1,87 @@
+use crate::*;
+
+
+/// Output of every converted raw in a certain size and format, put into its own directory below the output base
+///
+/// All variants of a raw are encoded from a single decode. Raws keep their path relative to the
+/// input base inside the directory of each variant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variant {
+    /// Name of the variant, which is also the directory it is put into
+    pub name: String,
+    /// Maximum length of the longer side in pixels, `None` to keep the decoded size
+    pub size: Option<usize>,
+    /// Format of the variant, `None` for the format of the job
+    pub format: Option<EncodedType>,
+    /// JPEG quality of the variant, `None` for the quality of the job
+    pub quality: Option<u8>,
+}
+
+
+impl Variant {
+    /// Encoder of this variant, falling back to the format and quality of `settings`
+    pub fn encoder(&self, settings: &JobSettings) -> EncoderType {
+        let quality = self.quality.unwrap_or(settings.jpeg_quality);
+        match (self.format, settings.encoder) {
+            (Some(format), _) => EncoderType::new(format, quality),
+            (None, EncoderType::JpegEncoder(_)) => EncoderType::JpegEncoder(quality),
+            (None, encoder) => encoder,
+        }
+    }
+
+    /// `image` scaled down to the size of this variant, `None` if it already fits
+    pub fn resized(&self, image: &imagepipe::SRGBImage) -> Option<imagepipe::SRGBImage> {
+        let size = self.size?;
+        let longer = image.width.max(image.height);
+        if longer <= size {
+            return None;
+        }
+        let width = (image.width * size / longer).max(1);
+        let height = (image.height * size / longer).max(1);
+        let data = pixelops::resize_rgb8(&image.data, image.width, image.height, width, height);
+        Some(imagepipe::SRGBImage { data, width, height })
+    }
+
+    /// Path of this variant for a raw whose first variant (`first`) is written to `output`
+    pub fn path(&self, output: &Path, output_base: &Path, first: &Variant, encoder: EncoderType) -> PathBuf {
+        let relative = output.strip_prefix(output_base.join(&first.name))
+            .or_else(|_| output.strip_prefix(output_base))
+            .unwrap_or(output);
+        output_base.join(&self.name).join(relative).with_extension(encoder.extension())
+    }
+}
+
+/// Parses variants like `web=2048,q80`, `thumb=400,q70` or `full=orig,tiff`
+///
+/// The spec consists of the size of the longer side (or `orig`), a JPEG quality (`q` followed by
+/// the quality) and a format in any order, everything that is left out is taken from the job.
+pub fn parse_variant(s: &str) -> Result<Variant, String> {
+    let (name, spec) = s.split_once('=')
+        .ok_or_else(|| format!("invalid variant {:?} (expected NAME=SPEC)", s))?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+        return Err(format!("invalid variant {:?} (the name has to be a plain directory name)", s));
+    }
+
+    let mut variant = Variant { name: name.to_string(), size: None, format: None, quality: None };
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let lower = part.to_lowercase();
+        if lower == "orig" {
+            variant.size = None;
+        } else if let Ok(size) = lower.parse::<usize>() {
+            if size == 0 {
+                return Err(format!("invalid variant {:?} (the size has to be positive)", s));
+            }
+            variant.size = Some(size);
+        } else if let Some(quality) = lower.strip_prefix('q').and_then(|q| q.parse::<u8>().ok()) {
+            if !(1..=100).contains(&quality) {
+                return Err(format!("invalid variant {:?} (the quality has to be between 1 and 100)", s));
+            }
+            variant.quality = Some(quality);
+        } else {
+            variant.format = Some(clap::ValueEnum::from_str(&lower, true)
+                .map_err(|_| format!("invalid variant {:?} (unknown size, quality or format {:?})", s, part))?);
+        }
+    }
+    Ok(variant)
+}
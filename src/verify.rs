@@ -0,0 +1,95 @@
+use crate::*;
+
+use std::collections::HashSet;
+
+
+/// Result of comparing an input tree against the output tree it was converted into
+#[derive(Default)]
+pub struct VerifyReport {
+    /// Input files that should have been converted or copied, but have no output
+    pub missing: Vec<PathBuf>,
+    /// Output files that do not belong to any input file
+    pub orphaned: Vec<PathBuf>,
+    /// Output files that are empty or can not be decoded, with the reason
+    pub corrupt: Vec<(PathBuf, String)>,
+}
+
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.orphaned.is_empty() && self.corrupt.is_empty()
+    }
+
+    pub fn print(&self) {
+        for file in &self.missing {
+            println!("Missing output for {:?}", file);
+        }
+        for file in &self.orphaned {
+            println!("No source for {:?}", file);
+        }
+        for (file, reason) in &self.corrupt {
+            println!("Corrupt output {:?} ({})", file, reason);
+        }
+        println!("{} missing, {} without source, {} corrupt",
+            self.missing.len(), self.orphaned.len(), self.corrupt.len());
+    }
+}
+
+/// Checks that every file of `batch` has its expected counterpart below `output_base`
+///
+/// The expected output paths are planned by the batch (see `Batch::for_verification()`) the same way
+/// a conversion run would, so the batch should be set up with the options used for the run.
+pub fn verify_trees(mut batch: Batch, output_base: &Path) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    let mut expected = HashSet::new();
+
+    for (input, job) in batch.planned_jobs() {
+        let job = match job {
+            Ok(job) if input.is_file() => job,
+            _ => continue,
+        };
+        let outputs = job.expected_outputs();
+        // corrupt raws only leave their quarantined copy behind
+        match job.quarantine_copy().filter(|quarantined| quarantined.exists()) {
+            Some(quarantined) => { expected.insert(quarantined.to_path_buf()); },
+            None => if outputs.iter().any(|o| !o.exists()) {
+                report.missing.push(input);
+            },
+        }
+        expected.extend(outputs);
+    }
+
+    let manifests = [ChecksumAlgo::Sha256, ChecksumAlgo::Sha512].map(|a| output_base.join(a.manifest_name()));
+    for output in scan_dir(output_base, None, true, true) {
+        if !output.is_file() || manifests.contains(&output) {
+            continue;
+        }
+        if let Err(e) = check_output(&output) {
+            report.corrupt.push((output.clone(), e));
+        }
+        if !expected.contains(&output) {
+            report.orphaned.push(output);
+        }
+    }
+
+    report
+}
+
+/// Rejects empty files and images whose header can not be decoded
+fn check_output(path: &Path) -> Result<(), String> {
+    let metadata = path.metadata().map_err(|e| e.to_string())?;
+    if metadata.len() == 0 {
+        return Err(String::from("empty file"));
+    }
+
+    if matches!(extension_kind(path), FileKind::Image) {
+        image::ImageReader::open(path)
+            .map_err(|e| e.to_string())?
+            .with_guessed_format()
+            .map_err(|e| e.to_string())?
+            .into_dimensions()
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
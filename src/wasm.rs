@@ -0,0 +1,56 @@
+use crate::*;
+
+use wasm_bindgen::prelude::*;
+
+
+/// Decoded preview of a raw file as RGBA pixels, ready to be put into a canvas `ImageData`
+#[wasm_bindgen]
+pub struct Preview {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+
+#[wasm_bindgen]
+impl Preview {
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// RGBA pixels, row by row
+    #[wasm_bindgen(getter)]
+    pub fn pixels(&self) -> Vec<u8> {
+        self.pixels.clone()
+    }
+}
+
+/// Decodes an uploaded raw file into a preview whose longer side is at most `max_size` pixels
+///
+/// The raw is decoded at half resolution, which is plenty for previews and keeps decoding in the
+/// browser (without threads) reasonably fast.
+#[wasm_bindgen(js_name = decodePreview)]
+pub fn decode_preview(data: &[u8], max_size: u32) -> Result<Preview, JsError> {
+    let options = DecodeOptions { fast_decode: Some(FastDecode::Half), ..DecodeOptions::default() };
+    let decoded = decode_raw_bytes(data, DecoderChoice::Auto, false, &options)
+        .map_err(|e| JsError::new(&e.to_string()))?;
+
+    let longer = decoded.width.max(decoded.height);
+    let max_size = (max_size as usize).max(1);
+    let (width, height, rgb) = if longer > max_size {
+        let width = (decoded.width * max_size / longer).max(1);
+        let height = (decoded.height * max_size / longer).max(1);
+        (width, height, pixelops::resize_rgb8(&decoded.data, decoded.width, decoded.height, width, height))
+    } else {
+        (decoded.width, decoded.height, decoded.data)
+    };
+
+    let pixels = rgb.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect();
+    Ok(Preview { width: width as u32, height: height as u32, pixels })
+}
@@ -0,0 +1,72 @@
+use crate::*;
+
+use std::sync::Mutex;
+
+
+/// URL that receives the run summary as JSON once all files are processed (`--webhook`)
+///
+/// The payload is the `run-summary` progress event with a `status` (`ok` or `error`) and a
+/// human-readable `text`, so chat services like Slack or Mattermost show it as a message. With
+/// `include_failures`, the `job-failed` events of the run are added as `failures`.
+/// Runs that fail before converting anything post a `run-failed` event with the `error` instead.
+pub struct Webhook {
+    url: String,
+    include_failures: bool,
+    /// `job-failed` events recorded so far
+    failures: Mutex<Vec<String>>,
+}
+
+
+impl Webhook {
+    pub fn new(url: &str, include_failures: bool) -> Result<Webhook, String> {
+        if !is_url(url) {
+            return Err(format!("webhook {:?} is not an http(s) URL", url));
+        }
+        if !cfg!(feature = "http") {
+            return Err(String::from("This build does not support webhooks (enable the http feature)"));
+        }
+        Ok(Webhook { url: url.to_string(), include_failures, failures: Mutex::default() })
+    }
+
+    pub fn record(&self, progress: &Progress) {
+        if let (true, Progress::JobFailed { .. }) = (self.include_failures, progress) {
+            self.failures.lock().unwrap().push(format!("{{{}}}", progress_event(progress)));
+        }
+    }
+
+    /// Posts the summary of the run
+    pub fn send(&self, statistics: &Statistics) -> Result<(), String> {
+        let status = if statistics.errors.count() > 0 { "error" } else { "ok" };
+        let text = format!("raw-to-img finished: {} files in {}, {} errors", statistics.total.count(),
+            fmt_duration(&statistics.wall_time), statistics.errors.count());
+        let mut payload = format!("{{{},\"status\":\"{}\",\"text\":\"{}\"", summary_event(statistics), status,
+            json_escape(&text));
+        if self.include_failures {
+            payload.push_str(&format!(",\"failures\":[{}]", self.failures.lock().unwrap().join(",")));
+        }
+        payload.push('}');
+        post_json(&self.url, &payload)
+    }
+
+    /// Posts that the run failed with `error` before converting anything, e.g. because of an invalid configuration
+    pub fn send_failure(&self, error: &str) -> Result<(), String> {
+        let text = format!("raw-to-img failed: {}", error);
+        let payload = format!("{{\"event\":\"run-failed\",\"status\":\"error\",\"error\":\"{}\",\"text\":\"{}\"}}",
+            json_escape(error), json_escape(&text));
+        post_json(&self.url, &payload)
+    }
+}
+
+#[cfg(feature = "http")]
+fn post_json(url: &str, payload: &str) -> Result<(), String> {
+    ureq::post(url)
+        .set("Content-Type", "application/json")
+        .send_string(payload)
+        .map(|_| ())
+        .map_err(|e| format!("unable to post to webhook {} ({})", url, e))
+}
+
+#[cfg(not(feature = "http"))]
+fn post_json(url: &str, _payload: &str) -> Result<(), String> {
+    Err(format!("unable to post to webhook {} (this build does not support http, enable the http feature)", url))
+}
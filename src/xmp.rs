@@ -0,0 +1,43 @@
+use crate::*;
+
+use std::io::Read;
+
+
+/// Number of bytes at the start of a raw file that are searched for embedded XMP
+const EMBEDDED_SCAN_SIZE: u64 = 1024 * 1024;
+
+
+/// Paths of the XMP sidecars that may belong to `path` (`IMG_0001.xmp` and `IMG_0001.CR2.xmp`)
+pub fn sidecar_paths(path: &Path) -> Vec<PathBuf> {
+    let mut full = path.as_os_str().to_owned();
+    full.push(".xmp");
+    vec![path.with_extension("xmp"), path.with_extension("XMP"), PathBuf::from(full)]
+}
+
+/// Reads the star rating of a file from its XMP sidecar or from XMP embedded in the file itself
+///
+/// Rejected files have a rating of -1. Returns `None` if no rating could be found.
+pub fn xmp_rating(path: &Path) -> Option<i32> {
+    for sidecar in sidecar_paths(path) {
+        if let Ok(content) = fs::read_to_string(&sidecar) {
+            if let Some(rating) = parse_rating(&content) {
+                return Some(rating);
+            }
+        }
+    }
+
+    // embedded XMP lives in the first IFD of tiff based raws, so only the start of the file is searched
+    let mut head = Vec::new();
+    fs::File::open(path).ok()?
+        .take(EMBEDDED_SCAN_SIZE)
+        .read_to_end(&mut head).ok()?;
+    parse_rating(&String::from_utf8_lossy(&head))
+}
+
+/// Extracts `xmp:Rating` from an XMP packet, written either as attribute or as element
+fn parse_rating(xmp: &str) -> Option<i32> {
+    let start = xmp.find("xmp:Rating")? + "xmp:Rating".len();
+    let rest = xmp[start..].trim_start_matches(['=', '"', '\'', '>', ' ']);
+    let end = rest.find(|c: char| c != '-' && !c.is_ascii_digit())?;
+    rest[..end].parse().ok()
+}